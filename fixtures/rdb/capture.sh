@@ -0,0 +1,39 @@
+#!/bin/sh
+# Captures redis-6.2.rdb and redis-7.2.rdb: dumps SAVEd by the real servers (the official docker images), for the
+# tests in src/persistence/rdb that check the parser against what redis actually writes, not only against
+# generate.py. The servers pick the encodings themselves: ziplists / intsets (6.2), listpacks (7.2), and hash
+# tables / skiplists for the big collections.
+#
+#   sh fixtures/rdb/capture.sh
+#
+# Check the captured files in (test_decode_server_dumps needs them, and is ignored until they are), and rerun this
+# only when changing the dataset (and the tests that check it).
+
+set -eu
+HERE=$(cd "$(dirname "$0")" && pwd)
+
+for version in 6.2 7.2; do
+    container=$(docker run -d --rm "redis:$version")
+    trap 'docker rm -f "$container" >/dev/null' EXIT
+    cli() { docker exec "$container" redis-cli "$@" >/dev/null; }
+    until docker exec "$container" redis-cli ping >/dev/null 2>&1; do sleep 0.1; done
+
+    cli set string "hello world"
+    cli set int 1000000
+    cli set lzf abcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefghabcdefgh
+    cli rpush list a 12 -300
+    cli sadd set x y z
+    cli sadd intset 1 2 3
+    cli hset hash f1 v1 f2 100
+    cli zadd zset 1.5 a 2 b
+    # Past hash-max-*-entries and zset-max-*-entries (128).
+    cli hset bighash $(seq 1 200 | sed 's/.*/f& v&/')
+    cli zadd bigzset $(seq 1 200 | sed 's/.*/& m&/')
+    cli set expiring v
+    cli pexpireat expiring 4102444800000
+    cli save
+
+    docker cp "$container:/data/dump.rdb" "$HERE/redis-$version.rdb"
+    docker rm -f "$container" >/dev/null
+    trap - EXIT
+done
@@ -0,0 +1,830 @@
+// The ACL subsystem: users, what they are allowed to run, and which keys / channels they may touch.
+//
+// A user is described by a list of rules, the same ones redis' ACL SETUSER understands:
+//   on / off                       enable / disable the user
+//   >password <password            add / remove a password (stored as a SHA-256 hash)
+//   #hash !hash                    add / remove a password by its hash
+//   nopass / resetpass             accept any password / forget all passwords
+//   ~pattern %R~pattern %W~pattern allow keys matching the pattern (read+write / read / write), allkeys = ~*
+//   resetkeys                      forget all key patterns
+//   &pattern allchannels           allow pub/sub channels matching the pattern, resetchannels forgets them
+//   +command -command              allow / deny a command
+//   +@category -@category          allow / deny every command of a category (see cmd::spec::CATEGORIES)
+//   allcommands / nocommands       aliases of +@all / -@all
+//   reset                          back to a brand new user: off, no passwords, keys, channels or commands
+//
+// The check happens in network::request_handler, before the command is parsed and executed.
+
+use crate::{
+    backend::unix_time_ms,
+    cmd::spec::{self, CommandSpec, KeyAccess},
+    config::ServerConfig,
+    glob::glob_match,
+    RespArray, RespFrame,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::Instant,
+};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+// How many entries ACL LOG keeps (redis' acllog-max-len default).
+const ACL_LOG_MAX_LEN: usize = 128;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AclError {
+    #[error("Error in ACL SETUSER modifier '{0}': {1}")]
+    InvalidRule(String, &'static str),
+    #[error("The 'default' user cannot be removed")]
+    DeleteDefaultUser,
+    #[error("This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.")]
+    NoAclFile,
+    #[error("{0}:{1}: {2}")]
+    AclFile(String, usize, String),
+    #[error("There was an error trying to save the ACLs. Please check the server logs for more information")]
+    Io(String),
+}
+
+// Why a command was refused; mapped to the NOPERM replies (and ACL LOG reasons) by the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclDenied {
+    // The user does not exist anymore (deleted with ACL DELUSER after it authenticated).
+    NoUser,
+    Command(String),
+    Key(String),
+    Channel(String),
+}
+
+impl AclDenied {
+    pub fn reply(&self, username: &str) -> String {
+        match self {
+            AclDenied::NoUser => "NOAUTH Authentication required.".to_string(),
+            AclDenied::Command(cmd) => format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                username, cmd
+            ),
+            AclDenied::Key(_) => "NOPERM No permissions to access a key".to_string(),
+            AclDenied::Channel(_) => "NOPERM No permissions to access a channel".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    name: String,
+    enabled: bool,
+    nopass: bool,
+    // Hex encoded SHA-256 of each password.
+    passwords: BTreeSet<String>,
+    // +@all: also covers commands that are not in the command table (e.g. unknown ones).
+    allcommands: bool,
+    // The effective set of allowed commands, recomputed as rules are applied.
+    commands: BTreeSet<&'static str>,
+    // The command rules as given, so ACL LIST / GETUSER can describe the user the way it was configured.
+    command_rules: Vec<String>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>,
+}
+
+impl User {
+    // A brand new user: disabled, without passwords, and not allowed to do anything.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            allcommands: false,
+            commands: BTreeSet::new(),
+            command_rules: Vec::new(),
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn apply_rules<S: AsRef<str>>(&mut self, rules: &[S]) -> Result<(), AclError> {
+        for rule in rules {
+            self.apply_rule(rule.as_ref())?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_rule(&mut self, rule: &str) -> Result<(), AclError> {
+        let invalid = |reason| AclError::InvalidRule(rule.to_string(), reason);
+        let lower = rule.to_ascii_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.apply_rule("~*")?,
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.apply_rule("&*")?,
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.apply_rule("+@all")?,
+            "nocommands" => self.apply_rule("-@all")?,
+            "reset" => *self = User::new(std::mem::take(&mut self.name)),
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.passwords.insert(hash_password(password));
+                    self.nopass = false;
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    if !self.passwords.remove(&hash_password(password)) {
+                        return Err(invalid("no such password"));
+                    }
+                } else if let Some(hash) = rule.strip_prefix('#') {
+                    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(invalid("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters"));
+                    }
+                    self.passwords.insert(hash.to_ascii_lowercase());
+                    self.nopass = false;
+                } else if let Some(hash) = rule.strip_prefix('!') {
+                    if !self.passwords.remove(&hash.to_ascii_lowercase()) {
+                        return Err(invalid("no such password"));
+                    }
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    self.add_key_pattern(pattern, true, true);
+                } else if let Some(rest) = rule.strip_prefix('%') {
+                    let (perms, pattern) = rest
+                        .split_once('~')
+                        .ok_or_else(|| invalid("Syntax error"))?;
+                    let perms = perms.to_ascii_uppercase();
+                    if perms.is_empty() || !perms.chars().all(|c| c == 'R' || c == 'W') {
+                        return Err(invalid("Syntax error"));
+                    }
+                    self.add_key_pattern(pattern, perms.contains('R'), perms.contains('W'));
+                } else if let Some(pattern) = rule.strip_prefix('&') {
+                    if !self.channels.iter().any(|c| c == pattern) {
+                        self.channels.push(pattern.to_string());
+                    }
+                } else if let Some(rest) = lower.strip_prefix('+') {
+                    self.apply_command_rule(rest, true, rule)?;
+                } else if let Some(rest) = lower.strip_prefix('-') {
+                    self.apply_command_rule(rest, false, rule)?;
+                } else {
+                    return Err(invalid("Syntax error"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_key_pattern(&mut self, pattern: &str, read: bool, write: bool) {
+        match self.keys.iter_mut().find(|k| k.pattern == pattern) {
+            Some(existing) => {
+                existing.read |= read;
+                existing.write |= write;
+            }
+            None => self.keys.push(KeyPattern {
+                pattern: pattern.to_string(),
+                read,
+                write,
+            }),
+        }
+    }
+
+    fn apply_command_rule(
+        &mut self,
+        target: &str,
+        allow: bool,
+        rule: &str,
+    ) -> Result<(), AclError> {
+        if let Some(category) = target.strip_prefix('@') {
+            if category == "all" {
+                // +@all / -@all replace everything said about commands before.
+                self.allcommands = allow;
+                self.commands = if allow {
+                    spec::commands().iter().map(|spec| spec.name).collect()
+                } else {
+                    BTreeSet::new()
+                };
+                self.command_rules = vec![format!("{}@all", if allow { '+' } else { '-' })];
+                return Ok(());
+            }
+            if !spec::CATEGORIES.contains(&category) {
+                return Err(AclError::InvalidRule(
+                    rule.to_string(),
+                    "Unknown command category",
+                ));
+            }
+            for spec in spec::commands().iter().filter(|s| s.in_category(category)) {
+                self.set_command(spec.name, allow);
+            }
+        } else {
+            let spec = spec::lookup(target.as_bytes())
+                .ok_or_else(|| AclError::InvalidRule(rule.to_string(), "Unknown command"))?;
+            self.set_command(spec.name, allow);
+        }
+        self.command_rules
+            .push(format!("{}{}", if allow { '+' } else { '-' }, target));
+        Ok(())
+    }
+
+    fn set_command(&mut self, name: &'static str, allow: bool) {
+        if allow {
+            self.commands.insert(name);
+        } else {
+            self.commands.remove(name);
+            // Once anything is taken away, +@all no longer covers commands outside the table.
+            self.allcommands = false;
+        }
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        if self.nopass {
+            return true;
+        }
+        let hash = hash_password(password);
+        // Compare against every stored hash without stopping early, in constant time per comparison.
+        self.passwords.iter().fold(false, |found, stored| {
+            found | bool::from(stored.as_bytes().ct_eq(hash.as_bytes()))
+        })
+    }
+
+    fn can_run(&self, spec: Option<&CommandSpec>) -> bool {
+        match spec {
+            Some(spec) => self.allcommands || self.commands.contains(spec.name),
+            None => self.allcommands,
+        }
+    }
+
+    fn can_access_key(&self, key: &[u8], access: KeyAccess) -> bool {
+        self.keys.iter().any(|k| {
+            let allowed = match access {
+                KeyAccess::Read => k.read,
+                KeyAccess::Write => k.write,
+            };
+            allowed && glob_match(k.pattern.as_bytes(), key)
+        })
+    }
+
+    pub fn can_access_channel(&self, channel: &[u8]) -> bool {
+        self.channels
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), channel))
+    }
+
+    // PSUBSCRIBE patterns are not matched against the allowed channels but compared literally, like redis:
+    // a user allowed "news.*" may subscribe to the pattern "news.*" but not to "news.s*".
+    pub fn can_subscribe_pattern(&self, pattern: &[u8]) -> bool {
+        self.channels
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.as_bytes() == pattern)
+    }
+
+    fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    fn describe_commands(&self) -> String {
+        if self.command_rules.is_empty() {
+            "-@all".to_string()
+        } else {
+            self.command_rules.join(" ")
+        }
+    }
+
+    fn describe_keys(&self) -> String {
+        self.keys
+            .iter()
+            .map(|k| match (k.read, k.write) {
+                (true, true) => format!("~{}", k.pattern),
+                (true, false) => format!("%R~{}", k.pattern),
+                _ => format!("%W~{}", k.pattern),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn describe_channels(&self) -> String {
+        self.channels
+            .iter()
+            .map(|c| format!("&{}", c))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // The rules that recreate this user, as printed by ACL LIST and written to the ACL file.
+    pub fn describe(&self) -> String {
+        let mut parts = vec![format!("user {}", self.name)];
+        parts.extend(self.flags().iter().map(|f| f.to_string()));
+        parts.extend(self.passwords.iter().map(|h| format!("#{}", h)));
+        if self.keys.is_empty() {
+            parts.push("resetkeys".to_string());
+        } else {
+            parts.push(self.describe_keys());
+        }
+        if self.channels.is_empty() {
+            parts.push("resetchannels".to_string());
+        } else {
+            parts.push(self.describe_channels());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+
+    // The reply of ACL GETUSER.
+    pub fn to_frame(&self) -> RespFrame {
+        let mut map = crate::RespMap::new();
+        let strings = |items: Vec<String>| -> RespFrame {
+            RespArray::new(
+                items
+                    .into_iter()
+                    .map(|s| crate::BulkString::from(s).into())
+                    .collect::<Vec<RespFrame>>(),
+            )
+            .into()
+        };
+        map.insert(
+            "flags".to_string(),
+            strings(self.flags().iter().map(|s| s.to_string()).collect()),
+        );
+        map.insert(
+            "passwords".to_string(),
+            strings(self.passwords.iter().cloned().collect()),
+        );
+        map.insert(
+            "commands".to_string(),
+            crate::BulkString::from(self.describe_commands()).into(),
+        );
+        map.insert(
+            "keys".to_string(),
+            crate::BulkString::from(self.describe_keys()).into(),
+        );
+        map.insert(
+            "channels".to_string(),
+            crate::BulkString::from(self.describe_channels()).into(),
+        );
+        map.insert("selectors".to_string(), RespArray::new(vec![]).into());
+        map.into()
+    }
+}
+
+fn hash_password(password: &str) -> String {
+    hex::encode(Sha256::digest(password.as_bytes()))
+}
+
+// The default user, as redis creates it: everything is allowed, and the password is requirepass if there is one.
+fn default_user(requirepass: Option<&str>) -> User {
+    let mut user = User::new("default");
+    let password = match requirepass {
+        Some(password) => format!(">{}", password),
+        None => "nopass".to_string(),
+    };
+    user.apply_rules(&["on", password.as_str(), "~*", "&*", "+@all"])
+        .expect("default user rules are valid");
+    user
+}
+
+#[derive(Debug, Clone)]
+pub struct AclLogEntry {
+    pub count: u64,
+    pub reason: &'static str,
+    pub object: String,
+    pub username: String,
+    pub entry_id: u64,
+    pub created: Instant,
+    pub timestamp_created: u64,
+    pub timestamp_last_updated: u64,
+}
+
+#[derive(Debug, Default)]
+struct AclLog {
+    entries: VecDeque<AclLogEntry>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+pub struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+    log: Mutex<AclLog>,
+    aclfile: Option<PathBuf>,
+    requirepass: Option<String>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new(&ServerConfig::default())
+    }
+}
+
+impl Acl {
+    pub fn new(config: &ServerConfig) -> Self {
+        let default = default_user(config.requirepass.as_deref());
+        Self {
+            users: RwLock::new(BTreeMap::from([(default.name.clone(), default)])),
+            log: Mutex::new(AclLog::default()),
+            aclfile: config.aclfile.clone(),
+            requirepass: config.requirepass.clone(),
+        }
+    }
+
+    // Whether new connections start unauthenticated; otherwise they are logged in as "default".
+    pub fn requires_auth(&self) -> bool {
+        let users = self.users.read().unwrap();
+        match users.get("default") {
+            Some(user) => !(user.enabled && user.nopass),
+            None => true,
+        }
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        let users = self.users.read().unwrap();
+        match users.get(username) {
+            Some(user) => user.enabled && user.check_password(password),
+            None => false,
+        }
+    }
+
+    pub fn get_user(&self, username: &str) -> Option<User> {
+        self.users.read().unwrap().get(username).cloned()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.users.read().unwrap().keys().cloned().collect()
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.users
+            .read()
+            .unwrap()
+            .values()
+            .map(User::describe)
+            .collect()
+    }
+
+    // Creates the user if needed, then applies the rules. Either all rules apply or none does.
+    pub fn set_user<S: AsRef<str>>(&self, username: &str, rules: &[S]) -> Result<(), AclError> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users
+            .get(username)
+            .cloned()
+            .unwrap_or_else(|| User::new(username));
+        user.apply_rules(rules)?;
+        users.insert(username.to_string(), user);
+        Ok(())
+    }
+
+    // Returns how many of the users existed.
+    pub fn del_users(&self, usernames: &[String]) -> Result<usize, AclError> {
+        if usernames.iter().any(|u| u == "default") {
+            return Err(AclError::DeleteDefaultUser);
+        }
+        let mut users = self.users.write().unwrap();
+        Ok(usernames
+            .iter()
+            .filter(|u| users.remove(u.as_str()).is_some())
+            .count())
+    }
+
+    // Checks whether username may run the command in frame (an array with the command name first).
+    pub fn check(&self, username: &str, frame: &RespFrame) -> Result<(), AclDenied> {
+        let users = self.users.read().unwrap();
+        let user = users.get(username).ok_or(AclDenied::NoUser)?;
+
+        let RespFrame::Array(args) = frame else {
+            return Ok(());
+        };
+        let name = match args.first() {
+            Some(RespFrame::BulkString(name)) => String::from_utf8_lossy(name).to_ascii_lowercase(),
+            _ => return Ok(()),
+        };
+        let spec = spec::lookup(name.as_bytes());
+        if !user.can_run(spec) {
+            return Err(AclDenied::Command(name));
+        }
+        if let Some(spec) = spec {
+            for (key, access) in spec.keys(args) {
+                if !user.can_access_key(key, access) {
+                    return Err(AclDenied::Key(String::from_utf8_lossy(key).to_string()));
+                }
+            }
+            for (channel, pattern) in spec.channels(args) {
+                let allowed = if pattern {
+                    user.can_subscribe_pattern(channel)
+                } else {
+                    user.can_access_channel(channel)
+                };
+                if !allowed {
+                    return Err(AclDenied::Channel(
+                        String::from_utf8_lossy(channel).to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Records a denied command or a failed AUTH in ACL LOG.
+    // Repeated identical failures are folded into one entry with a counter, like redis does.
+    pub fn log_denied(&self, reason: &'static str, object: &str, username: &str) {
+        let now = unix_time_ms();
+        let mut log = self.log.lock().unwrap();
+        if let Some(entry) = log
+            .entries
+            .iter_mut()
+            .find(|e| e.reason == reason && e.object == object && e.username == username)
+        {
+            entry.count += 1;
+            entry.timestamp_last_updated = now;
+            return;
+        }
+
+        let entry_id = log.next_id;
+        log.next_id += 1;
+        log.entries.push_front(AclLogEntry {
+            count: 1,
+            reason,
+            object: object.to_string(),
+            username: username.to_string(),
+            entry_id,
+            created: Instant::now(),
+            timestamp_created: now,
+            timestamp_last_updated: now,
+        });
+        log.entries.truncate(ACL_LOG_MAX_LEN);
+    }
+
+    // The most recent entries first.
+    pub fn log_entries(&self, count: usize) -> Vec<AclLogEntry> {
+        let log = self.log.lock().unwrap();
+        log.entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn reset_log(&self) {
+        self.log.lock().unwrap().entries.clear();
+    }
+
+    // ACL LOAD: replaces all users with the content of the ACL file. Nothing changes if the file has an error.
+    pub fn load(&self) -> Result<(), AclError> {
+        let path = self.aclfile.as_ref().ok_or(AclError::NoAclFile)?;
+        let users = self.parse_file(path)?;
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    // ACL SAVE: writes every user to the ACL file, one "user ..." line each.
+    pub fn save(&self) -> Result<(), AclError> {
+        let path = self.aclfile.as_ref().ok_or(AclError::NoAclFile)?;
+        let mut content = self.list().join("\n");
+        content.push('\n');
+        // Write to a temporary file first, so a crash never leaves a half written ACL file behind.
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, content)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| AclError::Io(e.to_string()))
+    }
+
+    fn parse_file(&self, path: &Path) -> Result<BTreeMap<String, User>, AclError> {
+        let file_error =
+            |line: usize, msg: String| AclError::AclFile(path.display().to_string(), line, msg);
+        let content = std::fs::read_to_string(path).map_err(|e| file_error(0, e.to_string()))?;
+
+        let mut users = BTreeMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            if parts.next() != Some("user") {
+                return Err(file_error(
+                    i + 1,
+                    "line should start with user keyword".to_string(),
+                ));
+            }
+            let name = parts
+                .next()
+                .ok_or_else(|| file_error(i + 1, "missing user name".to_string()))?;
+            if users.contains_key(name) {
+                return Err(file_error(
+                    i + 1,
+                    format!("duplicate user '{}' found", name),
+                ));
+            }
+            let mut user = User::new(name);
+            let rules = parts.collect::<Vec<_>>();
+            user.apply_rules(&rules)
+                .map_err(|e| file_error(i + 1, e.to_string()))?;
+            users.insert(name.to_string(), user);
+        }
+
+        // The default user always exists; if the file does not define it, it keeps the standard definition.
+        users
+            .entry("default".to_string())
+            .or_insert_with(|| default_user(self.requirepass.as_deref()));
+        Ok(users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BulkString;
+    use anyhow::Result;
+
+    fn command(args: &[&str]) -> RespFrame {
+        RespArray::new(
+            args.iter()
+                .map(|a| BulkString::from(*a).into())
+                .collect::<Vec<RespFrame>>(),
+        )
+        .into()
+    }
+
+    #[test]
+    fn test_default_user() {
+        let acl = Acl::default();
+        assert!(!acl.requires_auth());
+        assert!(acl.authenticate("default", "anything"));
+        assert_eq!(
+            acl.list(),
+            vec!["user default on nopass ~* &* +@all".to_string()]
+        );
+
+        let acl = Acl::new(&ServerConfig {
+            requirepass: Some("foobared".to_string()),
+            ..Default::default()
+        });
+        assert!(acl.requires_auth());
+        assert!(acl.authenticate("default", "foobared"));
+        assert!(!acl.authenticate("default", "foobar"));
+    }
+
+    #[test]
+    fn test_setuser_and_check() -> Result<()> {
+        let acl = Acl::default();
+        acl.set_user(
+            "alice",
+            &[
+                "on",
+                ">secret",
+                "~cache:*",
+                "%R~shared:*",
+                "+@read",
+                "+set",
+                "-hgetall",
+            ],
+        )?;
+        assert!(acl.authenticate("alice", "secret"));
+        assert!(!acl.authenticate("alice", "wrong"));
+
+        assert_eq!(acl.check("alice", &command(&["get", "cache:1"])), Ok(()));
+        assert_eq!(
+            acl.check("alice", &command(&["SET", "cache:1", "v"])),
+            Ok(())
+        );
+        assert_eq!(acl.check("alice", &command(&["get", "shared:1"])), Ok(()));
+        assert_eq!(
+            acl.check("alice", &command(&["set", "shared:1", "v"])),
+            Err(AclDenied::Key("shared:1".to_string()))
+        );
+        assert_eq!(
+            acl.check("alice", &command(&["get", "other"])),
+            Err(AclDenied::Key("other".to_string()))
+        );
+        assert_eq!(
+            acl.check("alice", &command(&["hgetall", "cache:1"])),
+            Err(AclDenied::Command("hgetall".to_string()))
+        );
+        assert_eq!(
+            acl.check("alice", &command(&["hset", "cache:1", "f", "v"])),
+            Err(AclDenied::Command("hset".to_string()))
+        );
+        assert_eq!(
+            acl.check("bob", &command(&["get", "x"])),
+            Err(AclDenied::NoUser)
+        );
+
+        let user = acl.get_user("alice").unwrap();
+        assert_eq!(user.describe_keys(), "~cache:* %R~shared:*");
+        assert_eq!(user.describe_commands(), "+@read +set -hgetall");
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_channels() -> Result<()> {
+        let acl = Acl::default();
+        acl.set_user("alice", &["on", "nopass", "+@pubsub", "&news.*"])?;
+        assert_eq!(
+            acl.check("alice", &command(&["subscribe", "news.sports"])),
+            Ok(())
+        );
+        assert_eq!(
+            acl.check("alice", &command(&["publish", "weather", "sunny"])),
+            Err(AclDenied::Channel("weather".to_string()))
+        );
+        // Patterns must be allowed literally.
+        assert_eq!(
+            acl.check("alice", &command(&["psubscribe", "news.*"])),
+            Ok(())
+        );
+        assert_eq!(
+            acl.check("alice", &command(&["psubscribe", "news.s*"])),
+            Err(AclDenied::Channel("news.s*".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_setuser_errors_are_atomic() -> Result<()> {
+        let acl = Acl::default();
+        acl.set_user("alice", &["on", ">secret"])?;
+        let err = acl
+            .set_user("alice", &["off", "+nosuchcommand"])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error in ACL SETUSER modifier '+nosuchcommand': Unknown command"
+        );
+        assert!(acl.get_user("alice").unwrap().enabled);
+        assert!(acl.set_user("alice", &["+@nosuchcategory"]).is_err());
+        assert!(acl.set_user("alice", &["%X~foo"]).is_err());
+        assert!(acl.set_user("alice", &["bogus"]).is_err());
+
+        assert_eq!(acl.del_users(&["alice".to_string(), "bob".to_string()])?, 1);
+        assert_eq!(
+            acl.del_users(&["default".to_string()]),
+            Err(AclError::DeleteDefaultUser)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_commands_need_allcommands() -> Result<()> {
+        let acl = Acl::default();
+        acl.set_user("alice", &["on", "nopass", "~*", "+@all", "-shutdown"])?;
+        assert!(acl.check("alice", &command(&["nosuchcommand"])).is_err());
+        assert!(acl.check("default", &command(&["nosuchcommand"])).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_acl_log() {
+        let acl = Acl::default();
+        acl.log_denied("auth", "AUTH", "alice");
+        acl.log_denied("auth", "AUTH", "alice");
+        acl.log_denied("key", "secret", "bob");
+
+        let entries = acl.log_entries(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].username, "bob");
+        assert_eq!(entries[1].count, 2);
+        assert_eq!(acl.log_entries(1).len(), 1);
+
+        acl.reset_log();
+        assert!(acl.log_entries(10).is_empty());
+    }
+
+    #[test]
+    fn test_acl_file_save_and_load() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("simple-redis-{}.acl", std::process::id()));
+        let acl = Acl::new(&ServerConfig {
+            aclfile: Some(path.clone()),
+            ..Default::default()
+        });
+        acl.set_user("alice", &["on", ">secret", "~cache:*", "&news", "+get"])?;
+        acl.save()?;
+
+        acl.del_users(&["alice".to_string()])?;
+        assert!(acl.get_user("alice").is_none());
+        acl.load()?;
+        assert!(acl.authenticate("alice", "secret"));
+        assert_eq!(acl.check("alice", &command(&["get", "cache:1"])), Ok(()));
+        assert!(acl.get_user("alice").unwrap().can_access_channel(b"news"));
+
+        std::fs::write(&path, "user alice on +nosuchcommand\n")?;
+        assert!(acl.load().is_err());
+        // A broken file leaves the current users untouched.
+        assert!(acl.get_user("alice").is_some());
+
+        std::fs::remove_file(&path)?;
+        assert_eq!(Acl::default().load(), Err(AclError::NoAclFile));
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+// The value of a hash key. The entry of the key in Backend.hmap already guards it, so it needs no locks of its own.
+// Small hashes are a flat list of fields, like the listpack encoding of redis: no buckets, no spare capacity
+// for them, and a lookup is a scan of a few fields. Once a hash has more than hash-max-listpack-entries fields,
+// or a field or value longer than hash-max-listpack-value bytes, it is converted to a hash table. Like in redis,
+// it never goes back.
+
+use crate::{config::ServerConfig, RespFrame};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hash {
+    Listpack(Vec<(String, RespFrame)>),
+    Table(HashMap<String, RespFrame>),
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Self::Listpack(Vec::new())
+    }
+}
+
+impl Hash {
+    // Builds a hash from its fields, in the encoding it would have if they were set one by one.
+    pub fn from_fields(
+        fields: impl IntoIterator<Item = (String, RespFrame)>,
+        config: &ServerConfig,
+    ) -> Self {
+        let mut hash = Self::default();
+        for (field, value) in fields {
+            hash.insert(field, value, config);
+        }
+        hash
+    }
+
+    pub fn get(&self, field: &str) -> Option<&RespFrame> {
+        match self {
+            Self::Listpack(fields) => fields.iter().find(|(f, _)| f == field).map(|(_, v)| v),
+            Self::Table(fields) => fields.get(field),
+        }
+    }
+
+    // Sets a field, converting the hash to a table if it outgrows the listpack limits.
+    // Returns the value the field had.
+    pub fn insert(
+        &mut self,
+        field: String,
+        value: RespFrame,
+        config: &ServerConfig,
+    ) -> Option<RespFrame> {
+        let fields = match self {
+            Self::Table(fields) => return fields.insert(field, value),
+            Self::Listpack(fields) => fields,
+        };
+        if let Some((_, old)) = fields.iter_mut().find(|(f, _)| *f == field) {
+            let too_long = value_len(&value) > config.hash_max_listpack_value;
+            let old = std::mem::replace(old, value);
+            if too_long {
+                self.convert();
+            }
+            return Some(old);
+        }
+        let too_long = field.len().max(value_len(&value)) > config.hash_max_listpack_value;
+        fields.push((field, value));
+        if too_long || fields.len() > config.hash_max_listpack_entries {
+            self.convert();
+        }
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Listpack(fields) => fields.len(),
+            Self::Table(fields) => fields.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&String, &RespFrame)> + '_> {
+        match self {
+            Self::Listpack(fields) => Box::new(fields.iter().map(|(f, v)| (f, v))),
+            Self::Table(fields) => Box::new(fields.iter()),
+        }
+    }
+
+    pub fn into_fields(self) -> Vec<(String, RespFrame)> {
+        match self {
+            Self::Listpack(fields) => fields,
+            Self::Table(fields) => fields.into_iter().collect(),
+        }
+    }
+
+    // The name of the encoding for OBJECT ENCODING.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Self::Listpack(_) => "listpack",
+            Self::Table(_) => "hashtable",
+        }
+    }
+
+    fn convert(&mut self) {
+        if let Self::Listpack(fields) = self {
+            *self = Self::Table(std::mem::take(fields).into_iter().collect());
+        }
+    }
+}
+
+// The length hash-max-listpack-value is compared with. Values that are not strings are never small.
+fn value_len(value: &RespFrame) -> usize {
+    match value {
+        RespFrame::BulkString(s) => s.len(),
+        RespFrame::SimpleString(s) => s.len(),
+        RespFrame::Integer(_) | RespFrame::Double(_) | RespFrame::Boolean(_) => 0,
+        _ => usize::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BulkString;
+
+    fn value(s: &str) -> RespFrame {
+        BulkString::from(s).into()
+    }
+
+    #[test]
+    fn test_hash_encoding() {
+        let config = ServerConfig {
+            hash_max_listpack_entries: 2,
+            hash_max_listpack_value: 4,
+            ..Default::default()
+        };
+        let mut hash = Hash::default();
+        assert_eq!(hash.insert("a".to_string(), value("1"), &config), None);
+        assert_eq!(hash.insert("b".to_string(), value("2"), &config), None);
+        assert_eq!(
+            hash.insert("a".to_string(), value("3"), &config),
+            Some(value("1"))
+        );
+        assert_eq!(hash.encoding(), "listpack");
+        assert_eq!(hash.get("a"), Some(&value("3")));
+
+        // Too many fields.
+        hash.insert("c".to_string(), value("4"), &config);
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.len(), 3);
+        assert_eq!(hash.get("c"), Some(&value("4")));
+
+        // A value, new or replaced, that is too long.
+        let hash = Hash::from_fields([("a".to_string(), value("12345"))], &config);
+        assert_eq!(hash.encoding(), "hashtable");
+        let mut hash = Hash::from_fields([("a".to_string(), value("1"))], &config);
+        hash.insert("a".to_string(), value("12345"), &config);
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.into_fields(), vec![("a".to_string(), value("12345"))]);
+    }
+}
@@ -0,0 +1,655 @@
+// Memory accounting and eviction, for maxmemory / maxmemory-policy.
+// Every key is charged an estimate of what it costs: the key itself, its value (the RespFrame payload, the fields
+// of a hash, the encoding of a module value), its TTL, and a fixed overhead for the map entries holding them.
+// The estimates are kept up to date by the backend methods that change a key, so the used memory is just a sum.
+// Like in redis, eviction is approximate: it samples maxmemory-samples keys and evicts the best candidate among
+// them, the least recently used (lru), least frequently used (lfu) or soonest to expire (ttl).
+
+use super::{unix_time_ms, Backend, Hash};
+use crate::{
+    config::{MaxmemoryPolicy, NotifyKeyspaceEvents, ServerConfig},
+    RespFrame,
+};
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// A map entry: the hash and the bucket bookkeeping, plus the String header of the key.
+const ENTRY_OVERHEAD: usize = 32 + size_of::<String>();
+// What every key costs besides its name and value: its entries in the dataset and in the accounting.
+// The accounting shares one copy of the name between its maps, behind the two counters of an Arc.
+const KEY_OVERHEAD: usize = 2 * ENTRY_OVERHEAD + size_of::<KeyStats>() + 16;
+// The entry of a key in expires, besides its name.
+const EXPIRE_OVERHEAD: usize = ENTRY_OVERHEAD + size_of::<u64>();
+
+// The LFU counter of redis: a logarithmic counter that new keys start at, so they are not evicted right away.
+// It grows slower the higher it is (LFU_LOG_FACTOR) and decays by one every LFU_DECAY_MINUTES without access.
+const LFU_INIT: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_MINUTES: u64 = 1;
+
+#[derive(Debug)]
+pub(crate) struct Memory {
+    // The sum of the sizes of all keys, and the most it has been.
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    // The name of a key is allocated once, and shared with the samples below.
+    keys: DashMap<Arc<str>, KeyStats>,
+    // All keys and the keys with a TTL, indexed so random ones can be sampled. All keys are only indexed
+    // when maxmemory-policy evicts from them (sample_all); the keys with a TTL always are, for active expiry.
+    all: Mutex<KeySample>,
+    volatile: Mutex<KeySample>,
+    sample_all: bool,
+    // State of the xorshift generator used for sampling and the LFU counters.
+    rng: AtomicU64,
+}
+
+// What the accounting knows about a key.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KeyStats {
+    // Estimated bytes used by the key.
+    size: usize,
+    volatile: bool,
+    // Last access, in unix time milliseconds.
+    access: u64,
+    lfu: u8,
+    // Minutes (unix time) of the last LFU decay.
+    lfu_decayed: u64,
+}
+
+// What MEMORY STATS reports, in bytes.
+#[derive(Debug)]
+pub struct MemoryStats {
+    pub peak: usize,
+    // The whole dataset, the overheads below included.
+    pub used: usize,
+    pub keys: usize,
+    // The entries of the keys in the dataset and in the accounting.
+    pub keys_overhead: usize,
+    // The entries of the keys with a TTL in expires.
+    pub expires_overhead: usize,
+    pub replication_backlog: usize,
+    // Only known with glibc on Linux.
+    pub allocator: Option<AllocatorStats>,
+}
+
+#[derive(Debug)]
+pub struct AllocatorStats {
+    // Handed out to the program.
+    pub allocated: usize,
+    // Taken from the system by the allocator: the difference is fragmentation within its arenas.
+    pub active: usize,
+    // Resident set size of the process.
+    pub resident: usize,
+}
+
+// What OBJECT reports about a key.
+#[derive(Debug)]
+pub struct ObjectInfo {
+    pub encoding: &'static str,
+    // Seconds since the last access.
+    pub idle: u64,
+    // The LFU counter (see LFU_INIT).
+    pub frequency: u8,
+}
+
+impl MemoryStats {
+    // The data itself, without the overheads.
+    pub fn dataset(&self) -> usize {
+        self.used
+            .saturating_sub(self.keys_overhead + self.expires_overhead)
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeySample {
+    keys: Vec<Arc<str>>,
+    positions: HashMap<Arc<str>, usize>,
+}
+
+impl Memory {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        let mut seed = [0u8; 8];
+        getrandom::getrandom(&mut seed).expect("no random source available");
+        let policy = config.maxmemory_policy;
+        Self {
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            keys: DashMap::new(),
+            all: Mutex::default(),
+            volatile: Mutex::default(),
+            sample_all: config.maxmemory > 0
+                && policy != MaxmemoryPolicy::NoEviction
+                && !policy.is_volatile(),
+            // xorshift never leaves 0.
+            rng: AtomicU64::new(u64::from_ne_bytes(seed) | 1),
+        }
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    // Records the new size of a key that was created or changed. A change counts as an access.
+    fn set(&self, key: &str, size: usize, volatile: bool) {
+        let now = unix_time_ms();
+        let mut stats = match self.keys.get_mut(key) {
+            Some(stats) => stats,
+            None => {
+                let name: Arc<str> = Arc::from(key);
+                if self.sample_all {
+                    self.all.lock().unwrap().insert(&name);
+                }
+                self.keys.entry(name).or_insert_with(|| KeyStats::new(now))
+            }
+        };
+        self.resize(&mut stats, size);
+        if stats.volatile != volatile {
+            let mut sample = self.volatile.lock().unwrap();
+            match volatile {
+                true => sample.insert(stats.key()),
+                false => sample.remove(key),
+            }
+            stats.volatile = volatile;
+        }
+        stats.touch(now, self.random());
+    }
+
+    // Adds and removes bytes to the size of a key, e.g. for a field set in a hash.
+    fn grow(&self, key: &str, added: usize, removed: usize) {
+        if let Some(mut stats) = self.keys.get_mut(key) {
+            let size = (stats.size + added).saturating_sub(removed);
+            self.resize(&mut stats, size);
+            stats.touch(unix_time_ms(), self.random());
+        }
+    }
+
+    fn resize(&self, stats: &mut KeyStats, size: usize) {
+        if size > stats.size {
+            let grown = size - stats.size;
+            let used = self.used.fetch_add(grown, Ordering::Relaxed) + grown;
+            self.peak.fetch_max(used, Ordering::Relaxed);
+        } else {
+            self.used.fetch_sub(stats.size - size, Ordering::Relaxed);
+        }
+        stats.size = size;
+    }
+
+    fn remove(&self, key: &str) {
+        if let Some((_, stats)) = self.keys.remove(key) {
+            self.used.fetch_sub(stats.size, Ordering::Relaxed);
+            if self.sample_all {
+                self.all.lock().unwrap().remove(key);
+            }
+            if stats.volatile {
+                self.volatile.lock().unwrap().remove(key);
+            }
+        }
+    }
+
+    // Records a read of the key.
+    fn access(&self, key: &str) {
+        if let Some(mut stats) = self.keys.get_mut(key) {
+            stats.touch(unix_time_ms(), self.random());
+        }
+    }
+
+    // Sets the last access (unix time in milliseconds) and the LFU counter of a key.
+    fn set_access(&self, key: &str, access: Option<u64>, lfu: Option<u8>) {
+        if let Some(mut stats) = self.keys.get_mut(key) {
+            if let Some(access) = access {
+                stats.access = access;
+            }
+            if let Some(lfu) = lfu {
+                stats.lfu = lfu;
+                stats.lfu_decayed = unix_time_ms() / 60_000;
+            }
+        }
+    }
+
+    fn clear(&self) {
+        self.keys.clear();
+        *self.all.lock().unwrap() = KeySample::default();
+        *self.volatile.lock().unwrap() = KeySample::default();
+        self.used.store(0, Ordering::Relaxed);
+    }
+
+    fn stats(&self, key: &str) -> Option<KeyStats> {
+        self.keys.get(key).map(|stats| *stats)
+    }
+
+    // Up to count random keys (all of them, or only the ones with a TTL) with their stats.
+    fn sample(&self, volatile: bool, count: usize) -> Vec<(Arc<str>, KeyStats)> {
+        // Not holding the sample lock while reading the stats: set takes them in the other order.
+        let keys = {
+            let sample = match volatile {
+                true => self.volatile.lock().unwrap(),
+                false => self.all.lock().unwrap(),
+            };
+            if sample.keys.is_empty() {
+                return Vec::new();
+            }
+            (0..count)
+                .map(|_| sample.keys[self.random() as usize % sample.keys.len()].clone())
+                .collect::<Vec<_>>()
+        };
+        keys.into_iter()
+            .filter_map(|key| self.stats(&key).map(|stats| (key, stats)))
+            .collect()
+    }
+
+    // Up to count random keys with a TTL, for active expiry.
+    pub(crate) fn sample_volatile(&self, count: usize) -> Vec<Arc<str>> {
+        let sample = self.volatile.lock().unwrap();
+        if sample.keys.is_empty() {
+            return Vec::new();
+        }
+        (0..count.min(sample.keys.len()))
+            .map(|_| sample.keys[self.random() as usize % sample.keys.len()].clone())
+            .collect()
+    }
+
+    fn random(&self) -> u64 {
+        let mut x = self.rng.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.store(x, Ordering::Relaxed);
+        x
+    }
+}
+
+impl KeyStats {
+    fn new(now: u64) -> Self {
+        Self {
+            size: 0,
+            volatile: false,
+            access: now,
+            lfu: LFU_INIT,
+            lfu_decayed: now / 60_000,
+        }
+    }
+
+    // The LFU counter, decayed for the time since the key was last accessed.
+    fn frequency(&self, now: u64) -> u8 {
+        let periods = (now / 60_000).saturating_sub(self.lfu_decayed) / LFU_DECAY_MINUTES;
+        self.lfu.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    // An access: the LFU counter decays, then grows with a probability that falls as it gets higher.
+    fn touch(&mut self, now: u64, random: u64) {
+        let counter = self.frequency(now);
+        let base = counter.saturating_sub(LFU_INIT) as f64;
+        let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        let r = (random >> 11) as f64 / (1u64 << 53) as f64;
+        self.lfu = if r < p {
+            counter.saturating_add(1)
+        } else {
+            counter
+        };
+        self.lfu_decayed = now / 60_000;
+        self.access = now;
+    }
+}
+
+impl KeySample {
+    fn insert(&mut self, key: &Arc<str>) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.clone(), self.keys.len());
+            self.keys.push(key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(position) = self.positions.remove(key) {
+            self.keys.swap_remove(position);
+            if let Some(moved) = self.keys.get(position) {
+                self.positions.insert(moved.clone(), position);
+            }
+        }
+    }
+}
+
+impl Backend {
+    // Bytes used by the dataset, as estimated by the accounting.
+    pub fn used_memory(&self) -> usize {
+        self.memory.used()
+    }
+
+    // The estimated size of a key (MEMORY USAGE), None if it does not exist. The size of a hash is extrapolated
+    // from samples of its fields; 0 counts all of them.
+    pub fn memory_usage(&self, key: &str, samples: usize) -> Option<usize> {
+        self.expire_if_needed(key);
+        self.key_size(key, samples)
+    }
+
+    // The encoding and access information of a key (OBJECT); looking at them is not an access.
+    // None if the key does not exist.
+    pub fn object_info(&self, key: &str) -> Option<ObjectInfo> {
+        self.expire_if_needed(key);
+        let encoding = self.encoding(key)?;
+        let stats = self.memory.stats(key)?;
+        let now = unix_time_ms();
+        Some(ObjectInfo {
+            encoding,
+            idle: now.saturating_sub(stats.access) / 1000,
+            frequency: stats.frequency(now),
+        })
+    }
+
+    // Sets how long ago a key was last accessed and its LFU counter, e.g. to what they were on the server a
+    // key was dumped on (RESTORE IDLETIME / FREQ).
+    pub(crate) fn set_access(&self, key: &str, idle_seconds: Option<u64>, frequency: Option<u8>) {
+        let access =
+            idle_seconds.map(|idle| unix_time_ms().saturating_sub(idle.saturating_mul(1000)));
+        self.memory.set_access(key, access, frequency);
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        let keys = self.memory.keys.len();
+        let volatile = self.memory.volatile.lock().unwrap().keys.len();
+        MemoryStats {
+            peak: self.memory.peak.load(Ordering::Relaxed),
+            used: self.memory.used(),
+            keys,
+            keys_overhead: keys * KEY_OVERHEAD,
+            expires_overhead: volatile * EXPIRE_OVERHEAD,
+            replication_backlog: self.replication.backlog_size(),
+            allocator: allocator_stats(),
+        }
+    }
+
+    // Brings the accounting of key up to date after a change (it may also have been removed).
+    pub(crate) fn account(&self, key: &str) {
+        match self.key_size(key, 0) {
+            Some(size) => self.memory.set(key, size, self.expires.contains_key(key)),
+            None => self.memory.remove(key),
+        }
+    }
+
+    // Accounts for a field set in an existing hash, without going over the other fields: size is the size of
+    // its value (see frame_size), old the value it had, and listpack whether the hash is in that encoding.
+    pub(crate) fn account_field(
+        &self,
+        key: &str,
+        field: &str,
+        size: usize,
+        old: Option<&RespFrame>,
+        listpack: bool,
+    ) {
+        match old {
+            Some(old) => self.memory.grow(key, size, frame_size(old)),
+            None => self
+                .memory
+                .grow(key, field_overhead(listpack) + field.len() + size, 0),
+        }
+    }
+
+    pub(crate) fn forget(&self, key: &str) {
+        self.memory.remove(key);
+    }
+
+    pub(crate) fn forget_all(&self) {
+        self.memory.clear();
+    }
+
+    pub(crate) fn accessed(&self, key: &str) {
+        self.memory.access(key);
+    }
+
+    // The estimated size of a key, None if it does not exist. With samples > 0 only that many fields of a hash
+    // are looked at, and the others are assumed to be of the same size.
+    fn key_size(&self, key: &str, samples: usize) -> Option<usize> {
+        let value = if let Some(value) = self.map.get(key) {
+            frame_size(&value)
+        } else if let Some(hash) = self.hmap.get(key) {
+            let sampled = match samples {
+                0 => hash.len(),
+                samples => samples.min(hash.len()),
+            };
+            let size = hash
+                .iter()
+                .take(sampled)
+                .map(|(field, value)| field_size(field, value, &hash))
+                .sum::<usize>();
+            size_of::<Hash>() + size * hash.len() / sampled.max(1)
+        } else if let Some(value) = self.module_values.get(key) {
+            size_of::<Box<dyn crate::module::ModuleValue>>() + value.encode().len()
+        } else {
+            return None;
+        };
+        let expire = match self.expires.contains_key(key) {
+            true => EXPIRE_OVERHEAD + key.len(),
+            false => 0,
+        };
+        Some(KEY_OVERHEAD + key.len() + value + expire)
+    }
+
+    // Evicts keys by maxmemory-policy until the dataset fits in maxmemory again. Returns the evicted keys, and
+    // whether the dataset fits now: it does not under noeviction, or when there is no key left to evict.
+    pub(crate) fn evict(&self) -> (Vec<String>, bool) {
+        let (limit, policy) = (self.config.maxmemory, self.config.maxmemory_policy);
+        let mut evicted = Vec::new();
+        while limit > 0 && self.memory.used() > limit {
+            let Some(key) = self.eviction_candidate(policy) else {
+                return (evicted, false);
+            };
+            if self.evict_key(&key) {
+                evicted.push(key.to_string());
+            }
+        }
+        (evicted, true)
+    }
+
+    // The best key to evict among a sample, None if there is nothing to sample (or the policy is noeviction).
+    fn eviction_candidate(&self, policy: MaxmemoryPolicy) -> Option<Arc<str>> {
+        let now = unix_time_ms();
+        let samples = self
+            .memory
+            .sample(policy.is_volatile(), self.config.maxmemory_samples);
+        let best = match policy {
+            MaxmemoryPolicy::NoEviction => return None,
+            MaxmemoryPolicy::AllKeysRandom | MaxmemoryPolicy::VolatileRandom => {
+                samples.into_iter().next()
+            }
+            MaxmemoryPolicy::AllKeysLru | MaxmemoryPolicy::VolatileLru => {
+                samples.into_iter().min_by_key(|(_, stats)| stats.access)
+            }
+            MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu => samples
+                .into_iter()
+                .min_by_key(|(_, stats)| (stats.frequency(now), stats.access)),
+            MaxmemoryPolicy::VolatileTtl => samples
+                .into_iter()
+                .min_by_key(|(key, _)| self.expires.get(&**key).map_or(u64::MAX, |at| *at)),
+        };
+        best.map(|(key, _)| key)
+    }
+
+    // Removes a key to free memory, which fires the "evicted" event. Returns false if it was gone already.
+    fn evict_key(&self, key: &str) -> bool {
+        let _barrier = self.write_guard();
+        if self.remove_if_expired(key) || !self.contains(key) {
+            return false;
+        }
+        self.remove_key(key);
+        self.persistence.add_dirty(1);
+        self.notify(NotifyKeyspaceEvents::EVICTED, "evicted", key);
+        true
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn allocator_stats() -> Option<AllocatorStats> {
+    // SAFETY: both only read counters of the process.
+    let (info, page_size) = unsafe { (libc::mallinfo2(), libc::sysconf(libc::_SC_PAGESIZE)) };
+    // The second field is the resident set size, in pages.
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident = statm.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(AllocatorStats {
+        allocated: info.uordblks + info.hblkhd,
+        active: info.arena + info.hblkhd,
+        resident: resident * usize::try_from(page_size).ok()?,
+    })
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn allocator_stats() -> Option<AllocatorStats> {
+    None
+}
+
+// A RespFrame with its payload.
+pub(crate) fn frame_size(frame: &RespFrame) -> usize {
+    let payload = match frame {
+        RespFrame::SimpleString(s) => s.len(),
+        RespFrame::Error(e) => e.len(),
+        RespFrame::BulkString(s) => s.len(),
+        RespFrame::Array(items) => items.iter().map(frame_size).sum(),
+        RespFrame::Set(items) => items.iter().map(frame_size).sum(),
+        RespFrame::Push(items) => items.iter().map(frame_size).sum(),
+        RespFrame::Map(map) => map
+            .iter()
+            .map(|(key, value)| ENTRY_OVERHEAD + key.len() + frame_size(value))
+            .sum(),
+        RespFrame::Integer(_)
+        | RespFrame::NullBulkString(_)
+        | RespFrame::NullArray(_)
+        | RespFrame::Null(_)
+        | RespFrame::Boolean(_)
+        | RespFrame::Double(_) => 0,
+    };
+    size_of::<RespFrame>() + payload
+}
+
+// A field of a hash with its value.
+fn field_size(field: &str, value: &RespFrame, hash: &Hash) -> usize {
+    field_overhead(matches!(hash, Hash::Listpack(_))) + field.len() + frame_size(value)
+}
+
+// What a field costs besides its name and value: an entry in the table, or just the String header of the name
+// in a listpack (the RespFrame is counted by frame_size).
+fn field_overhead(listpack: bool) -> usize {
+    match listpack {
+        true => size_of::<String>(),
+        false => ENTRY_OVERHEAD,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{unix_time_ms, Backend};
+    use crate::{config::ServerConfig, BulkString, RespFrame};
+
+    fn with_policy(policy: &str, maxmemory: usize) -> Backend {
+        let mut config = ServerConfig::default();
+        config.set("maxmemory-policy", policy).unwrap();
+        config.maxmemory = maxmemory;
+        config.maxmemory_samples = 100;
+        Backend::with_config(config)
+    }
+
+    fn value(s: &str) -> RespFrame {
+        BulkString::from(s).into()
+    }
+
+    #[test]
+    fn test_memory_accounting() {
+        let backend = Backend::new();
+        assert_eq!(backend.used_memory(), 0);
+        backend.set("k".to_string(), value("v"));
+        let small = backend.used_memory();
+        assert!(small > 0);
+        backend.set("k".to_string(), value(&"v".repeat(1000)));
+        assert_eq!(backend.used_memory(), small + 999);
+        backend.expire_at("k", u64::MAX);
+        assert!(backend.used_memory() > small + 999);
+        // Without maxmemory only the keys with a TTL are indexed for sampling.
+        assert!(backend.memory.all.lock().unwrap().keys.is_empty());
+        assert_eq!(backend.memory.volatile.lock().unwrap().keys.len(), 1);
+
+        backend.hset("h".to_string(), "f".to_string(), value("v"));
+        let with_hash = backend.used_memory();
+        backend.hset("h".to_string(), "f".to_string(), value("vvv"));
+        assert_eq!(backend.used_memory(), with_hash + 2);
+        backend.hset("h".to_string(), "g".to_string(), value("v"));
+        let hash_size = backend.key_size("h", 0).unwrap();
+        assert_eq!(backend.memory.stats("h").unwrap().size, hash_size);
+
+        // A key holds one type: writing another replaces it, and only the new value is accounted for.
+        backend.set("h".to_string(), value("v"));
+        assert_eq!(backend.hget("h", "f"), None);
+        assert_eq!(
+            backend.memory.stats("h").unwrap().size,
+            backend.key_size("h", 0).unwrap()
+        );
+        backend.hset("k".to_string(), "f".to_string(), value("v"));
+        assert_eq!(backend.get("k"), None);
+        assert_eq!(backend.expire_time("k"), Some(u64::MAX));
+        assert_eq!(
+            backend.memory.stats("k").unwrap().size,
+            backend.key_size("k", 0).unwrap()
+        );
+
+        backend.del("k");
+        backend.del("h");
+        assert_eq!(backend.used_memory(), 0);
+        assert!(backend.memory.all.lock().unwrap().keys.is_empty());
+        assert!(backend.memory.volatile.lock().unwrap().keys.is_empty());
+    }
+
+    #[test]
+    fn test_eviction_policies() {
+        let backend = with_policy("noeviction", 1);
+        backend.set("k".to_string(), value("v"));
+        assert_eq!(backend.evict(), (vec![], false));
+        assert!(backend.exists("k"));
+
+        // Only the keys with a TTL can go.
+        let backend = with_policy("volatile-lru", 1);
+        backend.set("k".to_string(), value("v"));
+        backend.set("t".to_string(), value("v"));
+        backend.expire_at("t", u64::MAX);
+        assert_eq!(backend.evict(), (vec!["t".to_string()], false));
+        assert!(backend.exists("k"));
+
+        let backend = with_policy("allkeys-random", 1);
+        backend.set("a".to_string(), value("v"));
+        backend.set("b".to_string(), value("v"));
+        let (evicted, fits) = backend.evict();
+        assert_eq!(evicted.len(), 2);
+        assert!(fits);
+        assert_eq!(backend.used_memory(), 0);
+
+        // With (almost certainly) every key in the samples, the policies pick exactly: a limit of two keys
+        // evicts the worst one.
+        let expire = unix_time_ms() + 60_000;
+        let size = {
+            let backend = Backend::new();
+            backend.set("a".to_string(), value("v"));
+            backend.expire_at("a", expire);
+            backend.used_memory()
+        };
+        for (policy, worst) in [
+            ("allkeys-lru", "b"),
+            ("allkeys-lfu", "c"),
+            ("volatile-ttl", "a"),
+        ] {
+            let backend = with_policy(policy, 2 * size);
+            for (key, ttl) in [("a", 0), ("b", 2), ("c", 1)] {
+                backend.set(key.to_string(), value("v"));
+                backend.expire_at(key, expire + ttl);
+            }
+            let stats = |key: &str| backend.memory.keys.get_mut(key).unwrap();
+            stats("a").access = 3;
+            stats("b").access = 1;
+            stats("c").access = 2;
+            stats("a").lfu = 7;
+            stats("b").lfu = 9;
+            stats("c").lfu = 6;
+            let (evicted, fits) = backend.evict();
+            assert!(fits, "{}", policy);
+            assert_eq!(evicted, vec![worst.to_string()], "{}", policy);
+        }
+    }
+}
@@ -1,7 +1,29 @@
-use crate::RespFrame;
+mod hash;
+mod memory;
+mod watch;
+
+use crate::{
+    acl::Acl,
+    cluster::Cluster,
+    config::{NotifyKeyspaceEvents, ServerConfig},
+    module::{self, ModuleType, ModuleValue},
+    persistence::{aof::Aof, snapshot::Value, Persistence},
+    pubsub::Broker,
+    replication::Replication,
+    scripting::Scripting,
+    shutdown::Shutdown,
+    RespFrame,
+};
 use dashmap::DashMap;
+use memory::Memory;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use watch::KeyVersion;
+
+pub use hash::Hash;
+pub use memory::{AllocatorStats, MemoryStats, ObjectInfo};
+pub use watch::WatchedKeys;
 
 // The backend.rs file defines a backend storage system for your Redis-like application.
 // It provides functionality to store, retrieve, and manage key-value pairs and hash maps, mimicking the behavior of a Redis backend.
@@ -12,7 +34,40 @@ pub struct Backend(Arc<BackendInner>);
 #[derive(Debug)]
 pub struct BackendInner {
     pub(crate) map: DashMap<String, RespFrame>,
-    pub(crate) hmap: DashMap<String, DashMap<String, RespFrame>>,
+    pub(crate) hmap: DashMap<String, Hash>,
+    // Values of the types modules registered, see module.rs.
+    pub(crate) module_values: DashMap<String, Box<dyn ModuleValue>>,
+    // Absolute expire time (unix time in milliseconds) of the keys that have a TTL.
+    // Expired keys are removed the next time they are accessed, or by network::active_expire.
+    pub(crate) expires: DashMap<String, u64>,
+    // Modification versions of the keys connections WATCH, see watch.rs.
+    pub(crate) watched: DashMap<String, KeyVersion>,
+    // Estimated memory used by every key, and their access times for eviction, see memory.rs.
+    pub(crate) memory: Memory,
+    // Every write to map / hmap / expires holds this in shared mode; a snapshot holds it exclusively
+    // while it copies the dataset, so it never sees half of a multi-step change.
+    pub(crate) write_barrier: RwLock<()>,
+    // Every client command holds this in shared mode while it executes; EXEC holds it exclusively, so no
+    // other client's command runs between the commands of a transaction.
+    pub(crate) exec_barrier: RwLock<()>,
+    // Change counter and save state used by SAVE / BGSAVE and the save points.
+    pub(crate) persistence: Persistence,
+    // The append-only file, when appendonly is on (opened by persistence::aof::open).
+    pub(crate) aof: Aof,
+    // Replication ID, offset and backlog, the connected replicas, and the link to the primary on a replica.
+    pub(crate) replication: Replication,
+    // Node ID, slot ownership and the other nodes, when cluster-enabled is set.
+    pub(crate) cluster: Cluster,
+    // The pub/sub channels and patterns with their subscribers.
+    pub(crate) pubsub: Broker,
+    // The script cache and the script being executed, see scripting.rs.
+    pub(crate) scripting: Scripting,
+    // Shared shutdown coordinator: the accept loop, every connection task and the SHUTDOWN command all use it.
+    pub(crate) shutdown: Shutdown,
+    // The configuration the server was started with (requirepass, ...).
+    pub(crate) config: ServerConfig,
+    // Users and their permissions; with only requirepass configured there is just the "default" user.
+    pub(crate) acl: Acl,
 }
 
 impl Deref for Backend {
@@ -34,6 +89,21 @@ impl Default for BackendInner {
         Self {
             map: DashMap::new(),
             hmap: DashMap::new(),
+            module_values: DashMap::new(),
+            expires: DashMap::new(),
+            watched: DashMap::new(),
+            memory: Memory::new(&ServerConfig::default()),
+            write_barrier: RwLock::new(()),
+            exec_barrier: RwLock::new(()),
+            persistence: Persistence::default(),
+            aof: Aof::default(),
+            replication: Replication::default(),
+            cluster: Cluster::new(&ServerConfig::default()),
+            pubsub: Broker::default(),
+            scripting: Scripting::default(),
+            shutdown: Shutdown::new(),
+            config: ServerConfig::default(),
+            acl: Acl::default(),
         }
     }
 }
@@ -43,12 +113,180 @@ impl Backend {
         Self::default()
     }
 
+    pub fn with_config(config: ServerConfig) -> Self {
+        Self(Arc::new(BackendInner {
+            acl: Acl::new(&config),
+            cluster: Cluster::new(&config),
+            memory: Memory::new(&config),
+            config,
+            ..Default::default()
+        }))
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    // Whether new connections must AUTH before they can run commands.
+    pub fn requires_auth(&self) -> bool {
+        self.acl.requires_auth()
+    }
+
+    // Checks a username / password pair against the ACL users.
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.acl.authenticate(username, password)
+    }
+
+    pub fn persistence(&self) -> &Persistence {
+        &self.persistence
+    }
+
+    pub fn aof(&self) -> &Aof {
+        &self.aof
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    pub fn cluster(&self) -> &Cluster {
+        &self.cluster
+    }
+
+    // Taken by every method that modifies the keyspace, see write_barrier.
+    fn write_guard(&self) -> RwLockReadGuard<'_, ()> {
+        self.write_barrier.read().unwrap()
+    }
+
+    // Sets the absolute expire time of a key, in unix time milliseconds.
+    // Returns false (and does nothing) if the key does not exist.
+    pub fn expire_at(&self, key: &str, at_ms: u64) -> bool {
+        let _barrier = self.write_guard();
+        self.remove_if_expired(key);
+        if !self.contains(key) {
+            return false;
+        }
+        self.expires.insert(key.to_string(), at_ms);
+        self.account(key);
+        self.persistence.add_dirty(1);
+        self.touch(key);
+        self.notify(NotifyKeyspaceEvents::GENERIC, "expire", key);
+        true
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.expire_if_needed(key);
+        self.contains(key)
+    }
+
+    // Whether the key holds a value of any type, expired or not.
+    fn contains(&self, key: &str) -> bool {
+        self.map.contains_key(key)
+            || self.hmap.contains_key(key)
+            || self.module_values.contains_key(key)
+    }
+
+    // Removes a key whatever its type. Returns false if it did not exist.
+    pub fn del(&self, key: &str) -> bool {
+        let _barrier = self.write_guard();
+        if self.remove_if_expired(key) {
+            return false;
+        }
+        if !self.contains(key) {
+            return false;
+        }
+        self.remove_key(key);
+        self.persistence.add_dirty(1);
+        self.notify(NotifyKeyspaceEvents::GENERIC, "del", key);
+        true
+    }
+
+    // The absolute expire time of a key in unix time milliseconds, None if it has no TTL.
+    pub fn expire_time(&self, key: &str) -> Option<u64> {
+        self.expire_if_needed(key);
+        self.expires.get(key).map(|at| *at)
+    }
+
+    // Removes the key if its TTL ran out. Called before every read, so an expired key is never returned.
+    fn expire_if_needed(&self, key: &str) {
+        if self.is_expired(key) {
+            let _barrier = self.write_guard();
+            self.remove_if_expired(key);
+        }
+    }
+
+    fn is_expired(&self, key: &str) -> bool {
+        matches!(self.expires.get(key), Some(at) if *at <= unix_time_ms())
+    }
+
+    // Removes the key if its TTL ran out, which is when the "expired" event fires. Returns whether it did.
+    // The caller must hold the write guard.
+    fn remove_if_expired(&self, key: &str) -> bool {
+        if !self.is_expired(key) {
+            return false;
+        }
+        self.remove_key(key);
+        self.notify(NotifyKeyspaceEvents::EXPIRED, "expired", key);
+        true
+    }
+
+    // One round of active expiry: looks at up to count random keys with a TTL and removes the expired ones.
+    // Returns how many keys it looked at, and the ones it removed.
+    pub(crate) fn expire_sample(&self, count: usize) -> (usize, Vec<String>) {
+        let sampled = self.memory.sample_volatile(count);
+        let _barrier = self.write_guard();
+        let expired = sampled
+            .iter()
+            .filter(|key| self.remove_if_expired(key))
+            .map(|key| key.to_string())
+            .collect();
+        (sampled.len(), expired)
+    }
+
+    // Publishes a keyspace notification for an event of the class on key, if notify-keyspace-events asks for it.
+    // There is only database 0.
+    pub(crate) fn notify(&self, class: NotifyKeyspaceEvents, event: &str, key: &str) {
+        let events = self.config.notify_keyspace_events;
+        if !events.contains(class) {
+            return;
+        }
+        if events.contains(NotifyKeyspaceEvents::KEYSPACE) {
+            let channel = format!("__keyspace@0__:{}", key);
+            self.pubsub.publish(&channel, event.as_bytes());
+        }
+        if events.contains(NotifyKeyspaceEvents::KEYEVENT) {
+            let channel = format!("__keyevent@0__:{}", event);
+            self.pubsub.publish(&channel, key.as_bytes());
+        }
+    }
+
+    // Removes the key with its value and TTL.
+    // The caller must hold the write guard (the barrier is not reentrant).
+    fn remove_key(&self, key: &str) {
+        self.expires.remove(key);
+        self.map.remove(key);
+        self.hmap.remove(key);
+        self.module_values.remove(key);
+        self.forget(key);
+        self.touch(key);
+    }
+
     // &self
     // The method takes an immutable reference to self, meaning it does not modify the Backend instance.
     // This allows multiple threads or parts of the program to call get concurrently, as long as no mutation occurs.
     // Using &str instead of String avoids unnecessary allocations because &str is a borrowed reference to an existing string, while String is an owned type that requires memory allocation.
 
     pub fn get(&self, key: &str) -> Option<RespFrame> {
+        self.expire_if_needed(key);
+        self.accessed(key);
         self.map.get(key).map(|v| v.value().clone()) // Deref is involved here.
                                                      // self.map is a DashMap<String, RespFrame>, which is a thread-safe hash map.
                                                      // The get method of DashMap is used to retrieve a reference to the value associated with the given key.
@@ -73,7 +311,21 @@ impl Backend {
 
     // The reason the set function does not include Option<RespFrame> in its return type is that the current implementation chooses to ignore the return value of the DashMap::insert method.
     pub fn set(&self, key: String, value: RespFrame) {
-        self.map.insert(key, value);
+        let _barrier = self.write_guard();
+        self.remove_if_expired(&key);
+        let new = !self.contains(&key);
+        // Like SET in redis, a new value discards the TTL of the old one, and replaces it whatever its type.
+        self.expires.remove(&key);
+        self.hmap.remove(&key);
+        self.module_values.remove(&key);
+        self.map.insert(key.clone(), value);
+        self.account(&key);
+        self.persistence.add_dirty(1);
+        self.touch(&key);
+        if new {
+            self.notify(NotifyKeyspaceEvents::NEW, "new", &key);
+        }
+        self.notify(NotifyKeyspaceEvents::STRING, "set", &key);
     }
     // Return Scenarios
     // If the Key Already Exists:
@@ -91,9 +343,9 @@ impl Backend {
     // The Ref type is a smart pointer that provides safe access to the value in a concurrent environment.
 
     // 以下几个关键点，
-    // DashMap:     .hmap.get(key) 是直接作用于 DashMap<String, DashMap<String, RespFrame>> 的
+    // DashMap:     .hmap.get(key) 是直接作用于 DashMap<String, Hash> 的
     // Option:      .and_then 与 .map 是作用于 Option<Ref<'_, String, ...>> 的
-    // Deref:       v.get(field) 与 v.value() 是作用于 Ref<'_, DashMap<String, RespFrame>> 的，用到 Deref trait。
+    // Deref:       v.get(field) 是作用于 Ref<'_, String, Hash> 的，用到 Deref trait。
 
     // .and_then 不能被 .map 替代，因为前者中的 F: FnOnce(T) -> Option<U>，作为整个.and_then 的返回，
     // .map 中的 F: Fn(&T) -> U，然后返回 Some(f(x))，相当于在 Some(U)，
@@ -107,36 +359,56 @@ impl Backend {
 
     // 所以，.and_then(.map()) 可以嵌套使用。
     pub fn hget(&self, key: &str, field: &str) -> Option<RespFrame> {
+        self.expire_if_needed(key);
+        self.accessed(key);
         self.hmap
             .get(key) // Since DashMap directly provides the get method, no Deref is involved here.
             .and_then(|v| {
-                v.get(field) // Deref is involved. v is not directly a DashMap<String, RespFrame>; it is a Ref<'_, DashMap<String, RespFrame>>.
-                    .map(
-                        |v| {
-                            v.value() // Deref is involved too.
-                                .clone()
-                        }, // returns a copy of the RespFrame instance, not a reference to it.
-                    )
+                v.get(field) // Deref is involved. v is not directly a Hash; it is a Ref<'_, String, Hash>.
+                    .cloned() // returns a copy of the RespFrame instance, not a reference to it.
             })
     }
 
     // The purpose of this code is to:
-    // Retrieve the inner DashMap<String, RespFrame> associated with the given key in the outer DashMap.
-    // If the key does not exist in the outer DashMap, create a new, default DashMap<String, RespFrame> and insert it into the outer DashMap.
-    // This ensures that the key always has an associated DashMap<String, RespFrame> for storing field-value pairs.
+    // Retrieve the inner Hash associated with the given key in the outer DashMap.
+    // If the key does not exist in the outer DashMap, create a new, default (empty, listpack encoded) Hash and insert it into the outer DashMap.
+    // This ensures that the key always has an associated Hash for storing field-value pairs.
 
     // 关于变量名，令人混淆这件事：
     // Yes, you are absolutely correct!
     // The name hmap in the hset function can indeed be confusing because it shadows the hmap field of BackendInner.
-    // While the hmap field in BackendInner refers to the entire outer DashMap<String, DashMap<String, RespFrame>>,
+    // While the hmap field in BackendInner refers to the entire outer DashMap<String, Hash>,
     // the hmap variable in the hset function refers to an entry (or more specifically,
-    //     a reference to the inner DashMap<String, RespFrame> associated with a specific key in the outer DashMap).
+    //     a reference to the inner Hash associated with a specific key in the outer DashMap).
 
     // Yes, changing the name from hmap to hmap_entry (or something similar) would be better because it makes the code more descriptive and avoids confusion between the hmap field of BackendInner and the local variable in the hset function.
     pub fn hset(&self, key: String, field: String, value: RespFrame) {
+        let _barrier = self.write_guard();
+        // A field written to an expired hash starts a new hash.
+        self.remove_if_expired(&key);
+        let new = !self.contains(&key);
+        // A key holds a single type: the hash replaces a string or module value of the same name.
+        self.map.remove(&key);
+        self.module_values.remove(&key);
+        let new_hash = !self.hmap.contains_key(&key);
         // 下面这个变量名令人产生歧义，修改为 hmap_entry 更好
-        let hmap = self.hmap.entry(key).or_default(); // .entry 返回一个 Enum Entry，然后 Entry.or_default 返回 RefMut<'a, K, V>
-        hmap.insert(field, value);
+        let mut hmap = self.hmap.entry(key.clone()).or_default(); // .entry 返回一个 Enum Entry，然后 Entry.or_default 返回 RefMut<'a, K, V>
+        let listpack = matches!(*hmap, Hash::Listpack(_));
+        let size = memory::frame_size(&value);
+        let old = hmap.insert(field.clone(), value, &self.config);
+        let converted = listpack && matches!(*hmap, Hash::Table(_));
+        drop(hmap);
+        // Only the field is accounted for, a big hash is not walked on every HSET (only when it is converted).
+        match new_hash || converted {
+            true => self.account(&key),
+            false => self.account_field(&key, &field, size, old.as_ref(), listpack),
+        }
+        self.persistence.add_dirty(1);
+        self.touch(&key);
+        if new {
+            self.notify(NotifyKeyspaceEvents::NEW, "new", &key);
+        }
+        self.notify(NotifyKeyspaceEvents::HASH, "hset", &key);
         // RefMut<'_, K, V> is a DashMap-exclusive type, not something from the standard library.
         // RefMut<'_, String, DashMap<..., ...>>, is because RefMut implements the DerefMut trait,
         // which allows it to behave like the underlying DashMap when accessing its methods.
@@ -187,7 +459,150 @@ impl Backend {
     //     }
     // }
 
-    pub fn hgetall(&self, key: &str) -> Option<DashMap<String, RespFrame>> {
+    pub fn hgetall(&self, key: &str) -> Option<Hash> {
+        self.expire_if_needed(key);
+        self.accessed(key);
         self.hmap.get(key).map(|v| v.clone())
     }
+
+    // Removes every key. The caller must hold the write barrier exclusively (it is used to replace the whole dataset).
+    pub(crate) fn clear(&self) {
+        self.map.clear();
+        self.hmap.clear();
+        self.module_values.clear();
+        self.expires.clear();
+        self.forget_all();
+        self.touch_all();
+    }
+
+    // The whole value stored under key, whatever its type (DUMP).
+    pub fn value(&self, key: &str) -> Option<Value> {
+        if let Some(value) = self.get(key) {
+            return Some(Value::String(value));
+        }
+        if let Some(value) = self.module_values.get(key) {
+            return Some(Value::Module {
+                type_name: value.type_name().to_string(),
+                data: value.encode(),
+            });
+        }
+        self.hgetall(key)
+            .map(|hash| Value::Hash(hash.into_fields()))
+    }
+
+    // The internal encoding of a value (OBJECT ENCODING), with the name redis gives the encoding it would use:
+    // strings are int, embstr (up to 44 bytes) or raw, hashes a listpack or hashtable; the other types kept as RespFrames (see persistence::rdb)
+    // are a quicklist (lists), hashtable (sets) or skiplist (sorted sets).
+    fn encoding(&self, key: &str) -> Option<&'static str> {
+        if let Some(hash) = self.hmap.get(key) {
+            return Some(hash.encoding());
+        }
+        if self.module_values.contains_key(key) {
+            return Some("raw");
+        }
+        let value = self.map.get(key)?;
+        Some(match value.value() {
+            RespFrame::BulkString(s) if is_integer(s) => "int",
+            RespFrame::BulkString(s) if s.len() <= 44 => "embstr",
+            RespFrame::Integer(_) => "int",
+            RespFrame::Array(_) => "quicklist",
+            RespFrame::Set(_) => "hashtable",
+            RespFrame::Map(_) => "skiplist",
+            _ => "raw",
+        })
+    }
+
+    // The value of a module type stored under key; None if there is none, or it is of another type.
+    pub fn module_value<T: ModuleType>(&self, key: &str) -> Option<T> {
+        self.expire_if_needed(key);
+        self.accessed(key);
+        self.module_values
+            .get(key)
+            .and_then(|value| value.as_any().downcast_ref::<T>().cloned())
+    }
+
+    // Stores a value of a module type, replacing whatever the key held but keeping its TTL (a module changes its
+    // values in place, e.g. increments a counter). event is the keyspace event of the module class it fires.
+    pub fn set_module_value<T: ModuleType>(&self, key: String, value: T, event: &str) {
+        let _barrier = self.write_guard();
+        self.remove_if_expired(&key);
+        let new = !self.contains(&key);
+        self.map.remove(&key);
+        self.hmap.remove(&key);
+        self.module_values.insert(key.clone(), Box::new(value));
+        self.account(&key);
+        self.persistence.add_dirty(1);
+        self.touch(&key);
+        if new {
+            self.notify(NotifyKeyspaceEvents::NEW, "new", &key);
+        }
+        self.notify(NotifyKeyspaceEvents::MODULE, event, &key);
+    }
+
+    // Stores a whole value with an optional absolute expire time in unix ms (RESTORE).
+    // Returns false and changes nothing if the key exists and replace is not set.
+    // A value whose expire time has already passed is not stored, but still replaces the old one.
+    pub fn restore(
+        &self,
+        key: String,
+        value: Value,
+        expire_at: Option<u64>,
+        replace: bool,
+    ) -> bool {
+        let _barrier = self.write_guard();
+        self.remove_if_expired(&key);
+        let new = !self.contains(&key);
+        if !new {
+            if !replace {
+                return false;
+            }
+            self.remove_key(&key);
+        }
+        self.persistence.add_dirty(1);
+        if expire_at.is_some_and(|at| at <= unix_time_ms()) {
+            return true;
+        }
+
+        if let Some(at) = expire_at {
+            self.expires.insert(key.clone(), at);
+        }
+        match value {
+            Value::String(value) => {
+                self.map.insert(key.clone(), value);
+            }
+            Value::Hash(fields) => {
+                self.hmap
+                    .insert(key.clone(), Hash::from_fields(fields, &self.config));
+            }
+            // The payload was checked when it was parsed (see rdb::parse_dump).
+            Value::Module { type_name, data } => {
+                if let Some(value) = module::decode_value(&type_name, &data) {
+                    self.module_values.insert(key.clone(), value);
+                }
+            }
+        }
+        self.account(&key);
+        self.touch(&key);
+        if new {
+            self.notify(NotifyKeyspaceEvents::NEW, "new", &key);
+        }
+        self.notify(NotifyKeyspaceEvents::GENERIC, "restore", &key);
+        true
+    }
+}
+
+// Whether a string is an integer the way redis keeps it as one: a 64 bit integer without a plus sign or
+// leading zeros.
+fn is_integer(s: &[u8]) -> bool {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .is_some_and(|n| n.to_string().as_bytes() == s)
+}
+
+pub(crate) fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
@@ -0,0 +1,137 @@
+// WATCH / UNWATCH support: optimistic locking for MULTI / EXEC.
+// The backend keeps a modification version for every key some connection watches; every change to such a key
+// (writes, deletes, expiry, eviction, replacing the whole dataset) bumps it. EXEC compares the versions with the
+// ones seen by WATCH and runs nothing if any of them moved.
+// Keys nobody watches have no version, so this costs nothing for them.
+
+use super::Backend;
+
+// Version and number of watchers of a watched key.
+#[derive(Debug, Default)]
+pub(crate) struct KeyVersion {
+    version: u64,
+    watchers: usize,
+}
+
+impl Backend {
+    // Starts watching key for one more connection; returns its current version.
+    fn watch_key(&self, key: &str) -> u64 {
+        // An expired key counts as a change from now on, not from before WATCH.
+        self.expire_if_needed(key);
+        let mut entry = self.watched.entry(key.to_string()).or_default();
+        entry.watchers += 1;
+        entry.version
+    }
+
+    fn unwatch_key(&self, key: &str) {
+        self.watched.remove_if_mut(key, |_, entry| {
+            entry.watchers -= 1;
+            entry.watchers == 0
+        });
+    }
+
+    fn key_version(&self, key: &str) -> u64 {
+        // A key whose TTL ran out since WATCH was changed, even if nobody touched it since.
+        self.expire_if_needed(key);
+        self.watched.get(key).map_or(0, |entry| entry.version)
+    }
+
+    // Called for every change to key.
+    pub(crate) fn touch(&self, key: &str) {
+        if let Some(mut entry) = self.watched.get_mut(key) {
+            entry.version += 1;
+        }
+    }
+
+    // Called when the whole dataset is replaced.
+    pub(crate) fn touch_all(&self) {
+        self.watched
+            .iter_mut()
+            .for_each(|mut entry| entry.version += 1);
+    }
+}
+
+// The keys a connection watches, with the versions they had when WATCH ran.
+// Dropping it (when the connection closes) unwatches them.
+#[derive(Debug)]
+pub struct WatchedKeys {
+    backend: Backend,
+    keys: Vec<(String, u64)>,
+}
+
+impl WatchedKeys {
+    pub fn new(backend: &Backend) -> Self {
+        Self {
+            backend: backend.clone(),
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: String) {
+        if self.keys.iter().any(|(watched, _)| *watched == key) {
+            return;
+        }
+        let version = self.backend.watch_key(&key);
+        self.keys.push((key, version));
+    }
+
+    // Whether one of the keys changed since it was watched.
+    pub fn dirty(&self) -> bool {
+        self.keys
+            .iter()
+            .any(|(key, version)| self.backend.key_version(key) != *version)
+    }
+
+    // Whether key, one of the watched keys, changed since it was watched.
+    pub fn changed(&self, key: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|(watched, version)| watched == key && self.backend.key_version(key) != *version)
+    }
+
+    pub fn clear(&mut self) {
+        for (key, _) in self.keys.drain(..) {
+            self.backend.unwatch_key(&key);
+        }
+    }
+}
+
+impl Drop for WatchedKeys {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BulkString, RespFrame};
+
+    #[test]
+    fn test_watch() {
+        let backend = Backend::new();
+        let value = |s: &str| RespFrame::from(BulkString::from(s));
+        backend.set("k".to_string(), value("v1"));
+
+        let mut watch = WatchedKeys::new(&backend);
+        watch.add("k".to_string());
+        watch.add("missing".to_string());
+        assert!(!watch.dirty());
+        // Keys nobody watches are not tracked.
+        backend.set("other".to_string(), value("v"));
+        assert!(!watch.dirty());
+        backend.set("missing".to_string(), value("v"));
+        assert!(watch.dirty());
+        assert!(watch.changed("missing"));
+        assert!(!watch.changed("k"));
+
+        watch.clear();
+        assert!(backend.watched.is_empty());
+        watch.add("k".to_string());
+        backend.expire_at("k", 1);
+        assert!(watch.dirty());
+
+        drop(watch);
+        assert!(backend.watched.is_empty());
+    }
+}
@@ -0,0 +1,1036 @@
+// Cluster mode, following redis cluster.
+//
+// The keyspace is split into 16384 hash slots: a key belongs to slot CRC16(key) mod 16384, where only the part
+// between the first '{' and the next '}' is hashed when that part is not empty ("{user1}.name" and "{user1}.age"
+// share a slot). Every slot is served by one node; a command for a key in a slot served elsewhere is answered
+// with -MOVED <slot> <ip>:<port>, and cluster-aware clients resend it there.
+//
+// The nodes tell each other which slots they serve over the cluster bus, a second TCP port (port + 10000 by
+// default). Every node keeps a link to every other node and sends it a PING every PING_PERIOD; the other node
+// answers with a PONG. Both carry the sender's address, the slots it claims (a 16384 bit bitmap) and its config
+// epoch, plus the addresses of the nodes the sender knows, so a node that met one node of the cluster
+// (CLUSTER MEET) gets to know all of them. A claim for a slot wins over the current owner when it comes with a
+// higher config epoch (or, at the same epoch, from the node with the smaller ID), so all nodes agree eventually.
+//
+// Slots move between nodes one key at a time: the target node marks the slot IMPORTING and the source node marks
+// it MIGRATING (CLUSTER SETSLOT), then MIGRATE moves the keys over. Meanwhile the source node serves the keys it
+// still has and answers -ASK <slot> <ip>:<port> for the others; a client follows an ASK only for the next command,
+// which it prefixes with ASKING so the importing node serves a slot it does not own yet. CLUSTER SETSLOT <slot>
+// NODE <id> ends the migration on both nodes.
+//
+// Unlike redis, the bus messages are RESP arrays, so nodes of this server only form clusters with each other.
+
+use crate::{
+    backend::unix_time_ms, cmd::spec, network::RespFrameCodec, replication, Backend, BulkString,
+    RespArray, RespFrame, SimpleError,
+};
+use anyhow::{anyhow, bail, Result};
+use crc::{Crc, CRC_16_XMODEM};
+use futures::SinkExt;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+use tracing::{info, warn};
+
+pub const SLOTS: usize = 16384;
+
+// The CRC16 redis uses for key slots (XMODEM: polynomial 0x1021, no reflection, init 0).
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+
+// How often every node is sent a PING.
+const PING_PERIOD: Duration = Duration::from_secs(1);
+
+// The slot of a key, honouring hash tags.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let hashed = key
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|start| {
+            let rest = &key[start + 1..];
+            let end = rest.iter().position(|&b| b == b'}')?;
+            (end > 0).then(|| &rest[..end])
+        })
+        .unwrap_or(key);
+    CRC16.checksum(hashed) % SLOTS as u16
+}
+
+// Why a command has to go to another node.
+#[derive(Debug, PartialEq)]
+pub enum Redirect {
+    // The keys are in different slots.
+    CrossSlot,
+    // The slot is served by the node at the address.
+    Moved(u16, String),
+    // No node serves the slot.
+    Unassigned(u16),
+    // The slot is being migrated and the keys are (or are going to be) on the node at the address.
+    Ask(u16, String),
+    // The slot is being migrated and some of the keys have moved already.
+    TryAgain,
+}
+
+impl Redirect {
+    pub fn reply(&self) -> SimpleError {
+        match self {
+            Redirect::CrossSlot => {
+                SimpleError::new("CROSSSLOT Keys in request don't hash to the same slot")
+            }
+            Redirect::Moved(slot, addr) => SimpleError::new(format!("MOVED {} {}", slot, addr)),
+            Redirect::Unassigned(_) => SimpleError::new("CLUSTERDOWN Hash slot not served"),
+            Redirect::Ask(slot, addr) => SimpleError::new(format!("ASK {} {}", slot, addr)),
+            Redirect::TryAgain => {
+                SimpleError::new("TRYAGAIN Multiple keys request during rehashing of slot")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cluster {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    myself: String,
+    // The highest config epoch seen in the cluster.
+    current_epoch: u64,
+    // Every known node, this one included.
+    nodes: HashMap<String, Node>,
+    // The ID of the node serving each slot.
+    slots: Vec<Option<String>>,
+    // The nodes a bus link task is running for.
+    links: HashSet<String>,
+    // Slots of this node being moved to another node, and slots being moved here from another node.
+    migrating: HashMap<u16, String>,
+    importing: HashMap<u16, String>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    addr: NodeAddr,
+    config_epoch: u64,
+    // Unix time in milliseconds of the PING still waiting for its PONG (0 if none), and of the last PONG.
+    ping_sent: u64,
+    pong_received: u64,
+    connected: bool,
+}
+
+// What the nodes tell each other about a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NodeAddr {
+    id: String,
+    // Empty until known: a node learns its own IP from the connections of the other nodes.
+    ip: String,
+    port: u16,
+    bus_port: u16,
+}
+
+// A node as reported by CLUSTER NODES / SLOTS / SHARDS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub bus_port: u16,
+    pub myself: bool,
+    pub failing: bool,
+    pub connected: bool,
+    pub config_epoch: u64,
+    pub ping_sent: u64,
+    pub pong_received: u64,
+    // Ranges of slots (first, last) the node serves.
+    pub slots: Vec<(u16, u16)>,
+    // Only known for this node: the slots being migrated to / imported from other nodes, with their node ID.
+    pub migrating: Vec<(u16, String)>,
+    pub importing: Vec<(u16, String)>,
+}
+
+// CLUSTER INFO
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterInfo {
+    pub slots_assigned: usize,
+    pub known_nodes: usize,
+    // Nodes serving at least one slot.
+    pub size: usize,
+    pub current_epoch: u64,
+    pub my_epoch: u64,
+}
+
+impl Cluster {
+    pub fn new(config: &crate::config::ServerConfig) -> Self {
+        let myself = replication::new_replid();
+        let node = Node {
+            addr: NodeAddr {
+                id: myself.clone(),
+                ip: config.cluster_announce_ip.clone().unwrap_or_default(),
+                port: config.port,
+                bus_port: config.cluster_bus_port(),
+            },
+            config_epoch: 0,
+            ping_sent: 0,
+            pong_received: 0,
+            connected: true,
+        };
+        Self {
+            state: Mutex::new(State {
+                myself: myself.clone(),
+                current_epoch: 0,
+                nodes: HashMap::from([(myself, node)]),
+                slots: vec![None; SLOTS],
+                links: HashSet::new(),
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn myself(&self) -> String {
+        self.state.lock().unwrap().myself.clone()
+    }
+
+    // Checks that this node serves the keys of a command (given as a raw frame).
+    // asking is set when the command follows ASKING; exists tells whether a key is in the dataset.
+    pub fn check_frame(
+        &self,
+        frame: &RespFrame,
+        asking: bool,
+        exists: impl Fn(&[u8]) -> bool,
+    ) -> Result<(), Redirect> {
+        let (Some(spec), RespFrame::Array(args)) = (spec::lookup_frame(frame), frame) else {
+            return Ok(());
+        };
+        // Shard channels are not in the dataset: the owner of their slot serves them, even while it migrates.
+        let channels = spec.shard_channels(args);
+        if !channels.is_empty() {
+            return self.check_keys(channels, asking, |_| true);
+        }
+        let keys = spec.keys(args).into_iter().map(|(key, _)| key);
+        // RESTORE-ASKING is how MIGRATE sends the keys: it always counts as asking.
+        let asking = asking || spec.name == "restore-asking";
+        match self.check_keys(keys, asking, exists) {
+            // MIGRATE runs on the source node for as long as the slot is migrating, whichever keys are left.
+            Err(Redirect::Ask(..) | Redirect::TryAgain) if spec.name == "migrate" => Ok(()),
+            ret => ret,
+        }
+    }
+
+    pub fn check_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+        asking: bool,
+        exists: impl Fn(&[u8]) -> bool,
+    ) -> Result<(), Redirect> {
+        let mut slot = None;
+        let mut keys_count = 0;
+        let mut missing = 0;
+        for key in keys {
+            let key_slot = key_hash_slot(key);
+            if slot.is_some_and(|slot| slot != key_slot) {
+                return Err(Redirect::CrossSlot);
+            }
+            slot = Some(key_slot);
+            keys_count += 1;
+            if !exists(key) {
+                missing += 1;
+            }
+        }
+        let Some(slot) = slot else {
+            return Ok(());
+        };
+        let state = self.state.lock().unwrap();
+        let address = |id: &str| {
+            let addr = &state.nodes[id].addr;
+            format!("{}:{}", addr.ip, addr.port)
+        };
+        match &state.slots[slot as usize] {
+            Some(owner) if *owner == state.myself => match state.migrating.get(&slot) {
+                // Keys that are not here any more are on the target node, or will be created there.
+                Some(target) if missing > 0 => {
+                    if missing < keys_count {
+                        Err(Redirect::TryAgain)
+                    } else {
+                        Err(Redirect::Ask(slot, address(target)))
+                    }
+                }
+                _ => Ok(()),
+            },
+            _ if asking && state.importing.contains_key(&slot) => {
+                // Until every key arrived, a command for several keys may miss some of them.
+                if keys_count > 1 && missing > 0 {
+                    Err(Redirect::TryAgain)
+                } else {
+                    Ok(())
+                }
+            }
+            Some(owner) => Err(Redirect::Moved(slot, address(owner))),
+            None => Err(Redirect::Unassigned(slot)),
+        }
+    }
+
+    // CLUSTER ADDSLOTS: this node starts serving the slots, which must not be served by any node yet.
+    pub fn add_slots(&self, slots: &[u16]) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let mut seen = HashSet::new();
+        for &slot in slots {
+            if state.slots[slot as usize].is_some() {
+                return Err(format!("Slot {} is already busy", slot));
+            }
+            if !seen.insert(slot) {
+                return Err(format!("Slot {} specified multiple times", slot));
+            }
+        }
+        let myself = state.myself.clone();
+        for &slot in slots {
+            state.slots[slot as usize] = Some(myself.clone());
+        }
+        Ok(())
+    }
+
+    // CLUSTER SETSLOT <slot> NODE <id>: assigns the slot to a node. Taking over a slot from another node
+    // bumps this node's config epoch, so the other nodes accept the new owner.
+    // keys is the number of keys this node holds in the slot. Returns whether this node gave the slot away.
+    pub fn set_slot_node(&self, slot: u16, id: &str, keys: usize) -> Result<bool, String> {
+        let mut state = self.state.lock().unwrap();
+        if !state.nodes.contains_key(id) {
+            return Err(format!("I don't know about node {}", id));
+        }
+        let owner = state.slots[slot as usize].clone();
+        let myself = state.myself.clone();
+        if owner.as_deref() == Some(myself.as_str()) && id != myself && keys > 0 {
+            return Err(format!(
+                "Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                slot
+            ));
+        }
+        if id == myself && owner.as_ref().is_some_and(|owner| *owner != myself) {
+            state.current_epoch += 1;
+            let epoch = state.current_epoch;
+            state.nodes.get_mut(&myself).unwrap().config_epoch = epoch;
+            info!(
+                "Configuration epoch bumped to {} to take over slot {}",
+                epoch, slot
+            );
+        }
+        state.slots[slot as usize] = Some(id.to_string());
+        // The slot is where it was migrated to.
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+        Ok(owner.as_deref() == Some(myself.as_str()) && id != myself)
+    }
+
+    // CLUSTER SETSLOT <slot> MIGRATING <id>: the keys of a slot this node serves are being moved to the node.
+    pub fn set_slot_migrating(&self, slot: u16, id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.slots[slot as usize].as_deref() != Some(state.myself.as_str()) {
+            return Err(format!("I'm not the owner of hash slot {}", slot));
+        }
+        if !state.nodes.contains_key(id) || id == state.myself {
+            return Err(format!("I don't know about node {}", id));
+        }
+        state.migrating.insert(slot, id.to_string());
+        Ok(())
+    }
+
+    // CLUSTER SETSLOT <slot> IMPORTING <id>: the keys of a slot are being moved here from the node.
+    pub fn set_slot_importing(&self, slot: u16, id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.slots[slot as usize].as_deref() == Some(state.myself.as_str()) {
+            return Err(format!("I'm already the owner of hash slot {}", slot));
+        }
+        if !state.nodes.contains_key(id) || id == state.myself {
+            return Err(format!("I don't know about node {}", id));
+        }
+        state.importing.insert(slot, id.to_string());
+        Ok(())
+    }
+
+    // CLUSTER SETSLOT <slot> STABLE: cancels a migration.
+    pub fn set_slot_stable(&self, slot: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+    }
+
+    pub fn nodes(&self, node_timeout: u64) -> Vec<NodeInfo> {
+        let state = self.state.lock().unwrap();
+        let mut ranges: HashMap<&str, Vec<(u16, u16)>> = HashMap::new();
+        for (first, last, owner) in slot_ranges(&state.slots) {
+            ranges.entry(owner).or_default().push((first, last));
+        }
+        let now = unix_time_ms();
+        let mut nodes = state
+            .nodes
+            .values()
+            .map(|node| {
+                let myself = node.addr.id == state.myself;
+                NodeInfo {
+                    id: node.addr.id.clone(),
+                    ip: node.addr.ip.clone(),
+                    port: node.addr.port,
+                    bus_port: node.addr.bus_port,
+                    myself,
+                    failing: !myself && now.saturating_sub(node.pong_received) > node_timeout,
+                    connected: node.connected,
+                    config_epoch: node.config_epoch,
+                    ping_sent: node.ping_sent,
+                    pong_received: node.pong_received,
+                    slots: ranges.remove(node.addr.id.as_str()).unwrap_or_default(),
+                    migrating: if myself {
+                        sorted_slots(&state.migrating)
+                    } else {
+                        Vec::new()
+                    },
+                    importing: if myself {
+                        sorted_slots(&state.importing)
+                    } else {
+                        Vec::new()
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        nodes
+    }
+
+    // CLUSTER SLOTS: the ranges of slots and the node serving each of them, in slot order.
+    pub fn slots(&self) -> Vec<(u16, u16, NodeInfo)> {
+        let nodes = self.nodes(u64::MAX);
+        let mut slots = nodes
+            .iter()
+            .flat_map(|node| {
+                node.slots
+                    .iter()
+                    .map(move |&(first, last)| (first, last, node.clone()))
+            })
+            .collect::<Vec<_>>();
+        slots.sort_by_key(|(first, _, _)| *first);
+        slots
+    }
+
+    pub fn info(&self) -> ClusterInfo {
+        let state = self.state.lock().unwrap();
+        let owners = state.slots.iter().flatten().collect::<HashSet<_>>();
+        ClusterInfo {
+            slots_assigned: state.slots.iter().flatten().count(),
+            known_nodes: state.nodes.len(),
+            size: owners.len(),
+            current_epoch: state.current_epoch,
+            my_epoch: state.nodes[&state.myself].config_epoch,
+        }
+    }
+}
+
+impl State {
+    fn message(&self, kind: MessageKind) -> Message {
+        let myself = &self.nodes[&self.myself];
+        let mut slots = vec![0u8; SLOTS / 8];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_deref() == Some(self.myself.as_str()) {
+                slots[slot / 8] |= 1 << (slot % 8);
+            }
+        }
+        Message {
+            kind,
+            sender: myself.addr.clone(),
+            config_epoch: myself.config_epoch,
+            current_epoch: self.current_epoch,
+            slots,
+            gossip: self
+                .nodes
+                .values()
+                .filter(|node| node.addr.id != self.myself && !node.addr.ip.is_empty())
+                .map(|node| node.addr.clone())
+                .collect(),
+        }
+    }
+
+    // Takes in what a message says about its sender and the nodes it knows. Unknown senders are only added
+    // when add is set (a MEET, or the PONG answering our MEET). Returns the newly learned nodes, which need a link,
+    // and the slots this node lost to the sender.
+    fn process(&mut self, message: Message, peer_ip: &str, add: bool) -> (Vec<NodeAddr>, Vec<u16>) {
+        let mut sender = message.sender;
+        if sender.id == self.myself || (!add && !self.nodes.contains_key(&sender.id)) {
+            return (Vec::new(), Vec::new());
+        }
+        if sender.ip.is_empty() {
+            sender.ip = peer_ip.to_string();
+        }
+        let mut learned = Vec::new();
+        let mut lost = Vec::new();
+        let node = self.nodes.entry(sender.id.clone()).or_insert_with(|| {
+            info!(
+                "Node {} ({}:{}) added to the cluster",
+                sender.id, sender.ip, sender.port
+            );
+            learned.push(sender.clone());
+            Node {
+                addr: sender.clone(),
+                config_epoch: 0,
+                ping_sent: 0,
+                pong_received: unix_time_ms(),
+                connected: false,
+            }
+        });
+        node.addr = sender.clone();
+        node.config_epoch = message.config_epoch;
+        self.current_epoch = self
+            .current_epoch
+            .max(message.current_epoch)
+            .max(message.config_epoch);
+
+        for slot in 0..SLOTS {
+            if message.slots[slot / 8] & (1 << (slot % 8)) == 0 {
+                continue;
+            }
+            let wins = match &self.slots[slot] {
+                None => true,
+                Some(owner) if *owner == sender.id => false,
+                Some(owner) => {
+                    let epoch = self.nodes.get(owner).map_or(0, |node| node.config_epoch);
+                    message.config_epoch > epoch
+                        || (message.config_epoch == epoch && sender.id < *owner)
+                }
+            };
+            if wins {
+                if self.slots[slot].as_deref() == Some(self.myself.as_str()) {
+                    warn!("Slot {} taken over by node {}", slot, sender.id);
+                    self.migrating.remove(&(slot as u16));
+                    lost.push(slot as u16);
+                }
+                self.slots[slot] = Some(sender.id.clone());
+            }
+        }
+
+        for addr in message.gossip {
+            if addr.id != self.myself && !self.nodes.contains_key(&addr.id) {
+                info!(
+                    "Node {} ({}:{}) learned from {}",
+                    addr.id, addr.ip, addr.port, sender.id
+                );
+                self.nodes.insert(
+                    addr.id.clone(),
+                    Node {
+                        addr: addr.clone(),
+                        config_epoch: 0,
+                        ping_sent: 0,
+                        pong_received: unix_time_ms(),
+                        connected: false,
+                    },
+                );
+                learned.push(addr);
+            }
+        }
+        learned.retain(|addr| self.links.insert(addr.id.clone()));
+        (learned, lost)
+    }
+}
+
+// The contiguous ranges of slots served by the same node: (first, last, node ID).
+fn slot_ranges(slots: &[Option<String>]) -> Vec<(u16, u16, &str)> {
+    let mut ranges: Vec<(u16, u16, &str)> = Vec::new();
+    for (slot, owner) in slots.iter().enumerate() {
+        let Some(owner) = owner else {
+            continue;
+        };
+        match ranges.last_mut() {
+            Some((_, last, id)) if *id == owner && *last as usize + 1 == slot => {
+                *last = slot as u16
+            }
+            _ => ranges.push((slot as u16, slot as u16, owner)),
+        }
+    }
+    ranges
+}
+
+fn sorted_slots(slots: &HashMap<u16, String>) -> Vec<(u16, String)> {
+    let mut slots = slots
+        .iter()
+        .map(|(slot, id)| (*slot, id.clone()))
+        .collect::<Vec<_>>();
+    slots.sort();
+    slots
+}
+
+// The keys this node holds in a slot.
+pub fn keys_in_slot(backend: &Backend, slot: u16) -> Vec<String> {
+    let keys = backend
+        .map
+        .iter()
+        .map(|entry| entry.key().clone())
+        .chain(backend.hmap.iter().map(|entry| entry.key().clone()))
+        .chain(
+            backend
+                .module_values
+                .iter()
+                .map(|entry| entry.key().clone()),
+        )
+        .filter(|key| key_hash_slot(key.as_bytes()) == slot)
+        .collect::<Vec<_>>();
+    // Collected first: exists() removes expired keys, which must not happen while iterating. Unlike a read, it
+    // neither copies the value nor counts as an access (OBJECT IDLETIME, eviction).
+    keys.into_iter().filter(|key| backend.exists(key)).collect()
+}
+
+// CLUSTER MEET: connects to the node at the address, which then joins the cluster.
+pub fn meet(backend: &Backend, ip: &str, port: u16, bus_port: u16) -> Result<(), String> {
+    if ip.parse::<IpAddr>().is_err() || port == 0 || bus_port == 0 {
+        return Err(format!("Invalid node address specified: {}:{}", ip, port));
+    }
+    tokio::spawn(link(backend.clone(), None, ip.to_string(), bus_port));
+    Ok(())
+}
+
+// Runs the cluster bus: answers the PINGs and MEETs of the other nodes until the server shuts down.
+pub async fn run(listener: TcpListener, backend: Backend) {
+    let token = backend.shutdown.token();
+    loop {
+        let (stream, raddr) = tokio::select! {
+            _ = token.cancelled() => return,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Cluster bus accept failed: {}", e);
+                    continue;
+                }
+            },
+        };
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_bus_connection(stream, raddr.ip(), &backend).await {
+                warn!("Cluster bus connection from {} closed: {}", raddr, e);
+            }
+        });
+    }
+}
+
+async fn serve_bus_connection(stream: TcpStream, peer_ip: IpAddr, backend: &Backend) -> Result<()> {
+    learn_my_ip(backend, &stream);
+    let mut framed = Framed::new(stream, RespFrameCodec);
+    let token = backend.shutdown.token();
+    loop {
+        let frame = tokio::select! {
+            _ = token.cancelled() => return Ok(()),
+            frame = framed.next() => match frame {
+                Some(frame) => frame?,
+                None => return Ok(()),
+            },
+        };
+        let message = Message::try_from(frame)?;
+        let add = message.kind == MessageKind::Meet;
+        let (reply, learned, lost) = {
+            let mut state = backend.cluster.state.lock().unwrap();
+            let (learned, lost) = state.process(message, &peer_ip.to_string(), add);
+            (state.message(MessageKind::Pong), learned, lost)
+        };
+        backend.pubsub.remove_slots(&lost);
+        spawn_links(backend, learned);
+        framed.send(reply.into()).await?;
+    }
+}
+
+fn spawn_links(backend: &Backend, nodes: Vec<NodeAddr>) {
+    for node in nodes {
+        tokio::spawn(link(backend.clone(), Some(node.id), node.ip, node.bus_port));
+    }
+}
+
+// The IP the other nodes reach this node at, when it is not configured: the local address of a bus connection.
+fn learn_my_ip(backend: &Backend, stream: &TcpStream) {
+    let Ok(addr) = stream.local_addr() else {
+        return;
+    };
+    let mut state = backend.cluster.state.lock().unwrap();
+    let myself = state.myself.clone();
+    let node = state.nodes.get_mut(&myself).unwrap();
+    if node.addr.ip.is_empty() {
+        node.addr.ip = addr.ip().to_string();
+    }
+}
+
+// The link to another node: sends it a PING every PING_PERIOD and processes its PONGs, reconnecting when the
+// connection is lost. id is None for a node that is being met: the first PONG tells its ID, and the link
+// ends if it turns out to be a node that is known (and linked) already.
+async fn link(backend: Backend, mut id: Option<String>, ip: String, bus_port: u16) {
+    let token = backend.shutdown.token();
+    loop {
+        let ret = tokio::select! {
+            _ = token.cancelled() => return,
+            ret = ping_loop(&backend, &mut id, &ip, bus_port) => ret,
+        };
+        let Some(node_id) = &id else {
+            // The node could not be met.
+            if let Err(e) = ret {
+                warn!("Cluster MEET with {}:{} failed: {}", ip, bus_port, e);
+            }
+            return;
+        };
+        match ret {
+            Ok(()) => return,
+            Err(e) => {
+                let mut state = backend.cluster.state.lock().unwrap();
+                if let Some(node) = state.nodes.get_mut(node_id) {
+                    if node.connected {
+                        warn!("Cluster bus link to {} lost: {}", node_id, e);
+                    }
+                    node.connected = false;
+                }
+            }
+        }
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = tokio::time::sleep(PING_PERIOD) => {}
+        }
+    }
+}
+
+// Returns Ok(()) when the link is not needed (any more), an error when the connection failed.
+async fn ping_loop(
+    backend: &Backend,
+    id: &mut Option<String>,
+    ip: &str,
+    bus_port: u16,
+) -> Result<()> {
+    let timeout = Duration::from_millis(backend.config.cluster_node_timeout);
+    let stream = tokio::time::timeout(timeout, TcpStream::connect((ip, bus_port)))
+        .await
+        .map_err(|_| anyhow!("connect timed out"))??;
+    learn_my_ip(backend, &stream);
+    let mut framed = Framed::new(stream, RespFrameCodec);
+    loop {
+        let kind = if id.is_some() {
+            MessageKind::Ping
+        } else {
+            MessageKind::Meet
+        };
+        let message = {
+            let mut state = backend.cluster.state.lock().unwrap();
+            if let Some(node) = id.as_ref().and_then(|id| state.nodes.get_mut(id)) {
+                if node.ping_sent == 0 {
+                    node.ping_sent = unix_time_ms();
+                }
+            }
+            state.message(kind)
+        };
+        framed.send(message.into()).await?;
+        let frame = match tokio::time::timeout(timeout, framed.next()).await {
+            Ok(Some(frame)) => frame?,
+            Ok(None) => bail!("connection closed"),
+            Err(_) => bail!("no PONG within the node timeout"),
+        };
+        let pong = Message::try_from(frame)?;
+        let sender = pong.sender.id.clone();
+
+        let (learned, lost) = {
+            let mut state = backend.cluster.state.lock().unwrap();
+            if id.is_none() {
+                // The node we met: a link to it is only needed if there is none yet.
+                if sender == state.myself || !state.links.insert(sender.clone()) {
+                    return Ok(());
+                }
+                *id = Some(sender.clone());
+            }
+            if id.as_deref() != Some(sender.as_str()) {
+                bail!("the node at {}:{} is {} now", ip, bus_port, sender);
+            }
+            let processed = state.process(pong, ip, kind == MessageKind::Meet);
+            if let Some(node) = state.nodes.get_mut(&sender) {
+                node.ping_sent = 0;
+                node.pong_received = unix_time_ms();
+                node.connected = true;
+            }
+            processed
+        };
+        backend.pubsub.remove_slots(&lost);
+        spawn_links(backend, learned);
+        tokio::time::sleep(PING_PERIOD).await;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageKind {
+    Ping,
+    Meet,
+    Pong,
+}
+
+// A bus message: [kind, id, ip, port, bus port, config epoch, current epoch, slot bitmap,
+//                 then id, ip, port, bus port of every node the sender knows]
+#[derive(Debug)]
+struct Message {
+    kind: MessageKind,
+    sender: NodeAddr,
+    config_epoch: u64,
+    current_epoch: u64,
+    slots: Vec<u8>,
+    gossip: Vec<NodeAddr>,
+}
+
+impl From<Message> for RespFrame {
+    fn from(message: Message) -> Self {
+        let kind = match message.kind {
+            MessageKind::Ping => "PING",
+            MessageKind::Meet => "MEET",
+            MessageKind::Pong => "PONG",
+        };
+        let mut frames: Vec<RespFrame> = vec![BulkString::from(kind).into()];
+        let push_addr = |frames: &mut Vec<RespFrame>, addr: &NodeAddr| {
+            frames.push(BulkString::from(addr.id.as_str()).into());
+            frames.push(BulkString::from(addr.ip.as_str()).into());
+            frames.push(BulkString::from(addr.port.to_string()).into());
+            frames.push(BulkString::from(addr.bus_port.to_string()).into());
+        };
+        push_addr(&mut frames, &message.sender);
+        frames.push(BulkString::from(message.config_epoch.to_string()).into());
+        frames.push(BulkString::from(message.current_epoch.to_string()).into());
+        frames.push(BulkString::new(message.slots).into());
+        for addr in &message.gossip {
+            push_addr(&mut frames, addr);
+        }
+        RespArray::new(frames).into()
+    }
+}
+
+impl TryFrom<RespFrame> for Message {
+    type Error = anyhow::Error;
+
+    fn try_from(frame: RespFrame) -> Result<Self> {
+        let RespFrame::Array(array) = frame else {
+            bail!("invalid cluster bus message");
+        };
+        let mut args = Vec::with_capacity(array.len());
+        for arg in array.0 {
+            match arg {
+                RespFrame::BulkString(arg) => args.push(arg.0),
+                _ => bail!("invalid cluster bus message"),
+            }
+        }
+        if args.len() < 8 || (args.len() - 8) % 4 != 0 || args[7].len() != SLOTS / 8 {
+            bail!("invalid cluster bus message");
+        }
+        let text = |arg: &[u8]| String::from_utf8(arg.to_vec());
+        let number = |arg: &[u8]| -> Result<u64> { Ok(std::str::from_utf8(arg)?.parse()?) };
+        let addr = |args: &[Vec<u8>]| -> Result<NodeAddr> {
+            Ok(NodeAddr {
+                id: text(&args[0])?,
+                ip: text(&args[1])?,
+                port: u16::try_from(number(&args[2])?)?,
+                bus_port: u16::try_from(number(&args[3])?)?,
+            })
+        };
+        let kind = match args[0].to_ascii_uppercase().as_slice() {
+            b"PING" => MessageKind::Ping,
+            b"MEET" => MessageKind::Meet,
+            b"PONG" => MessageKind::Pong,
+            _ => bail!("invalid cluster bus message type"),
+        };
+        Ok(Message {
+            kind,
+            sender: addr(&args[1..5])?,
+            config_epoch: number(&args[5])?,
+            current_epoch: number(&args[6])?,
+            slots: args[7].clone(),
+            gossip: args[8..].chunks(4).map(addr).collect::<Result<_>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn test_key_hash_slot() {
+        // The values CLUSTER KEYSLOT returns in redis.
+        assert_eq!(key_hash_slot(b"somekey"), 11058);
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"foo{hash_tag}"), 2515);
+        assert_eq!(key_hash_slot(b"{user1000}.following"), 3443);
+        // An empty tag does not count, and only the first tag is used.
+        assert_eq!(key_hash_slot(b"{}foo"), 9500);
+        assert_eq!(key_hash_slot(b"{foo}{bar}"), key_hash_slot(b"foo"));
+    }
+
+    #[test]
+    fn test_keys_in_slot() {
+        let backend = Backend::new();
+        backend.set("foo".to_string(), crate::BulkString::from("v").into());
+        backend.set("bar".to_string(), crate::BulkString::from("v").into());
+        backend.set_access("foo", Some(100), None);
+        assert_eq!(keys_in_slot(&backend, 12182), vec!["foo".to_string()]);
+        // Listing the keys of a slot is not an access.
+        assert!(backend.object_info("foo").unwrap().idle >= 100);
+    }
+
+    #[test]
+    fn test_check_keys() {
+        let cluster = Cluster::new(&ServerConfig::default());
+        assert_eq!(
+            cluster.check_keys([&b"foo"[..]], false, |_| true),
+            Err(Redirect::Unassigned(12182))
+        );
+        cluster.add_slots(&[12182]).unwrap();
+        assert_eq!(
+            cluster.check_keys([&b"foo"[..], b"{foo}.bar"], false, |_| true),
+            Ok(())
+        );
+        assert_eq!(
+            cluster.check_keys([&b"foo"[..], b"bar"], false, |_| true),
+            Err(Redirect::CrossSlot)
+        );
+        assert_eq!(
+            Redirect::CrossSlot.reply(),
+            SimpleError::new("CROSSSLOT Keys in request don't hash to the same slot")
+        );
+        assert_eq!(
+            cluster.add_slots(&[1, 12182]),
+            Err("Slot 12182 is already busy".to_string())
+        );
+        assert_eq!(
+            cluster.add_slots(&[1, 1]),
+            Err("Slot 1 specified multiple times".to_string())
+        );
+    }
+
+    #[test]
+    fn test_process_messages() -> Result<()> {
+        let a = Cluster::new(&ServerConfig::default());
+        let b = Cluster::new(&ServerConfig {
+            port: 7001,
+            ..Default::default()
+        });
+        b.add_slots(&[5, 6, 7, 100]).unwrap();
+        let meet = b.state.lock().unwrap().message(MessageKind::Meet);
+        // Through RESP, as on the bus.
+        let meet = Message::try_from(RespFrame::from(meet))?;
+
+        let mut state = a.state.lock().unwrap();
+        let (learned, lost) = state.process(meet, "10.0.0.2", true);
+        assert!(lost.is_empty());
+        assert_eq!(learned.len(), 1);
+        assert_eq!(learned[0].ip, "10.0.0.2");
+        assert_eq!(learned[0].port, 7001);
+        drop(state);
+        // "axh" is in slot 5, served by b now.
+        assert_eq!(key_hash_slot(b"axh"), 5);
+        assert_eq!(
+            a.check_keys([&b"axh"[..]], false, |_| true),
+            Err(Redirect::Moved(5, "10.0.0.2:7001".to_string()))
+        );
+        let slots = a.slots();
+        assert_eq!(
+            slots
+                .iter()
+                .map(|(first, last, node)| (*first, *last, node.port))
+                .collect::<Vec<_>>(),
+            vec![(5, 7, 7001), (100, 100, 7001)]
+        );
+
+        // A claim for a slot with a higher config epoch wins.
+        let b_id = b.myself();
+        a.add_slots(&[200]).unwrap();
+        {
+            let mut state = b.state.lock().unwrap();
+            state.slots[200] = Some(b_id.clone());
+            state.nodes.get_mut(&b_id).unwrap().config_epoch = 1;
+        }
+        let ping = b.state.lock().unwrap().message(MessageKind::Ping);
+        let (_, lost) = a.state.lock().unwrap().process(ping, "10.0.0.2", false);
+        assert_eq!(lost, vec![200]);
+        assert_eq!(
+            a.state.lock().unwrap().slots[200].as_deref(),
+            Some(b_id.as_str())
+        );
+        assert_eq!(a.info().current_epoch, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrating_and_importing() -> Result<()> {
+        let source = Cluster::new(&ServerConfig {
+            cluster_announce_ip: Some("10.0.0.1".to_string()),
+            ..Default::default()
+        });
+        let target = Cluster::new(&ServerConfig {
+            port: 7001,
+            cluster_announce_ip: Some("10.0.0.2".to_string()),
+            ..Default::default()
+        });
+        source.add_slots(&[12182]).unwrap();
+        let meet = source.state.lock().unwrap().message(MessageKind::Meet);
+        target.state.lock().unwrap().process(meet, "10.0.0.1", true);
+        let meet = target.state.lock().unwrap().message(MessageKind::Meet);
+        source.state.lock().unwrap().process(meet, "10.0.0.2", true);
+        let (source_id, target_id) = (source.myself(), target.myself());
+
+        assert_eq!(
+            target.set_slot_migrating(12182, &source_id),
+            Err("I'm not the owner of hash slot 12182".to_string())
+        );
+        assert_eq!(
+            source.set_slot_importing(12182, &target_id),
+            Err("I'm already the owner of hash slot 12182".to_string())
+        );
+        assert_eq!(
+            source.set_slot_migrating(12182, "nosuchnode"),
+            Err("I don't know about node nosuchnode".to_string())
+        );
+        source.set_slot_migrating(12182, &target_id).unwrap();
+        target.set_slot_importing(12182, &source_id).unwrap();
+
+        // The source serves the keys it still has and sends the client to the target for the others.
+        let keys = [&b"foo"[..], b"{foo}.bar"];
+        assert_eq!(source.check_keys(keys, false, |_| true), Ok(()));
+        assert_eq!(
+            source.check_keys(keys, false, |_| false),
+            Err(Redirect::Ask(12182, "10.0.0.2:7001".to_string()))
+        );
+        assert_eq!(
+            source.check_keys(keys, false, |key| key == b"foo"),
+            Err(Redirect::TryAgain)
+        );
+        // The target only serves the slot after ASKING.
+        assert_eq!(
+            target.check_keys([&b"foo"[..]], false, |_| false),
+            Err(Redirect::Moved(12182, "10.0.0.1:6379".to_string()))
+        );
+        assert_eq!(target.check_keys([&b"foo"[..]], true, |_| false), Ok(()));
+        assert_eq!(
+            target.check_keys(keys, true, |key| key == b"foo"),
+            Err(Redirect::TryAgain)
+        );
+        assert_eq!(
+            target
+                .nodes(u64::MAX)
+                .into_iter()
+                .find(|node| node.myself)
+                .unwrap()
+                .importing,
+            vec![(12182, source_id.clone())]
+        );
+
+        // NODE ends the migration.
+        assert_eq!(target.set_slot_node(12182, &target_id, 0), Ok(false));
+        assert_eq!(target.check_keys([&b"foo"[..]], false, |_| false), Ok(()));
+        let ping = target.state.lock().unwrap().message(MessageKind::Ping);
+        let (_, lost) = source
+            .state
+            .lock()
+            .unwrap()
+            .process(ping, "10.0.0.2", false);
+        assert_eq!(lost, vec![12182]);
+        assert_eq!(
+            source.check_keys([&b"foo"[..]], false, |_| false),
+            Err(Redirect::Moved(12182, "10.0.0.2:7001".to_string()))
+        );
+        assert!(source.state.lock().unwrap().migrating.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+// ACL SETUSER / GETUSER / DELUSER / LIST / USERS / WHOAMI / CAT / LOG / LOAD / SAVE
+// The rules themselves are implemented in crate::acl; this file only parses the command and shapes the replies.
+
+use super::{
+    extract_args, extract_string, spec, validate_variadic_command, Acl, AclSubcommand,
+    CommandExecutor, SetUser, RESP_OK,
+};
+use crate::{cmd::CommandError, BulkString, RespArray, RespFrame, RespMap, RespNull, SimpleError};
+use std::fmt;
+
+// How many entries ACL LOG returns when no count is given.
+const ACL_LOG_DEFAULT_COUNT: usize = 10;
+
+impl Acl {
+    pub fn is_whoami(&self) -> bool {
+        self.subcommand == AclSubcommand::WhoAmI
+    }
+}
+
+// >password and <password carry it in clear text, #hash and !hash its SHA-256.
+impl fmt::Debug for SetUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| match rule.chars().next() {
+                Some('>' | '<' | '#' | '!') => "(redacted)",
+                _ => rule.as_str(),
+            })
+            .collect::<Vec<&str>>();
+        f.debug_struct("SetUser")
+            .field("username", &self.username)
+            .field("rules", &rules)
+            .finish()
+    }
+}
+
+impl CommandExecutor for Acl {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        let acl = backend.acl();
+        let err = |e: crate::acl::AclError| -> RespFrame {
+            SimpleError::new(format!("ERR {}", e)).into()
+        };
+        let strings = |items: Vec<String>| -> RespFrame {
+            RespArray::new(
+                items
+                    .into_iter()
+                    .map(|s| BulkString::from(s).into())
+                    .collect::<Vec<RespFrame>>(),
+            )
+            .into()
+        };
+
+        match self.subcommand {
+            AclSubcommand::SetUser(SetUser { username, rules }) => {
+                match acl.set_user(&username, &rules) {
+                    Ok(()) => RESP_OK.clone(),
+                    Err(e) => err(e),
+                }
+            }
+            AclSubcommand::GetUser(username) => match acl.get_user(&username) {
+                Some(user) => user.to_frame(),
+                None => RespFrame::Null(RespNull),
+            },
+            AclSubcommand::DelUser(usernames) => match acl.del_users(&usernames) {
+                Ok(n) => RespFrame::Integer(n as i64),
+                Err(e) => err(e),
+            },
+            AclSubcommand::List => strings(acl.list()),
+            AclSubcommand::Users => strings(acl.usernames()),
+            // Outside of a client connection there is nobody logged in but the default user.
+            AclSubcommand::WhoAmI => BulkString::from("default").into(),
+            AclSubcommand::Cat(None) => {
+                strings(spec::CATEGORIES.iter().map(|c| c.to_string()).collect())
+            }
+            AclSubcommand::Cat(Some(category)) => {
+                let category = category.to_ascii_lowercase();
+                if !spec::CATEGORIES.contains(&category.as_str()) {
+                    return SimpleError::new(format!("ERR Unknown category '{}'", category)).into();
+                }
+                strings(
+                    spec::commands()
+                        .iter()
+                        .filter(|s| s.in_category(&category))
+                        .map(|s| s.name.to_string())
+                        .collect(),
+                )
+            }
+            AclSubcommand::Log(count) => {
+                let entries = acl.log_entries(count.unwrap_or(ACL_LOG_DEFAULT_COUNT));
+                let frames = entries
+                    .into_iter()
+                    .map(|entry| {
+                        let mut map = RespMap::new();
+                        map.insert("count".to_string(), RespFrame::Integer(entry.count as i64));
+                        map.insert("reason".to_string(), BulkString::from(entry.reason).into());
+                        map.insert("context".to_string(), BulkString::from("toplevel").into());
+                        map.insert("object".to_string(), BulkString::from(entry.object).into());
+                        map.insert(
+                            "username".to_string(),
+                            BulkString::from(entry.username).into(),
+                        );
+                        map.insert(
+                            "age-seconds".to_string(),
+                            RespFrame::Double(entry.created.elapsed().as_secs_f64()),
+                        );
+                        map.insert("client-info".to_string(), BulkString::from("").into());
+                        map.insert(
+                            "entry-id".to_string(),
+                            RespFrame::Integer(entry.entry_id as i64),
+                        );
+                        map.insert(
+                            "timestamp-created".to_string(),
+                            RespFrame::Integer(entry.timestamp_created as i64),
+                        );
+                        map.insert(
+                            "timestamp-last-updated".to_string(),
+                            RespFrame::Integer(entry.timestamp_last_updated as i64),
+                        );
+                        map.into()
+                    })
+                    .collect::<Vec<RespFrame>>();
+                RespArray::new(frames).into()
+            }
+            AclSubcommand::LogReset => {
+                acl.reset_log();
+                RESP_OK.clone()
+            }
+            AclSubcommand::Load => match acl.load() {
+                Ok(()) => RESP_OK.clone(),
+                Err(e) => err(e),
+            },
+            AclSubcommand::Save => match acl.save() {
+                Ok(()) => RESP_OK.clone(),
+                Err(e) => err(e),
+            },
+        }
+    }
+}
+
+impl TryFrom<RespArray> for Acl {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["acl"], 1)?;
+
+        let mut args = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<Vec<String>, CommandError>>()?
+            .into_iter();
+        let name = args.next().unwrap_or_default().to_ascii_lowercase();
+        let args = args.collect::<Vec<String>>();
+
+        let wrong_args = || {
+            CommandError::InvalidArgument(format!(
+                "wrong number of arguments for 'acl|{}' command",
+                name
+            ))
+        };
+        let subcommand = match (name.as_str(), args.len()) {
+            ("setuser", n) if n >= 1 => {
+                let mut args = args.into_iter();
+                AclSubcommand::SetUser(SetUser {
+                    username: args.next().unwrap_or_default(),
+                    rules: args.collect(),
+                })
+            }
+            ("getuser", 1) => AclSubcommand::GetUser(args.into_iter().next().unwrap_or_default()),
+            ("deluser", n) if n >= 1 => AclSubcommand::DelUser(args),
+            ("list", 0) => AclSubcommand::List,
+            ("users", 0) => AclSubcommand::Users,
+            ("whoami", 0) => AclSubcommand::WhoAmI,
+            ("cat", 0) => AclSubcommand::Cat(None),
+            ("cat", 1) => AclSubcommand::Cat(args.into_iter().next()),
+            ("log", 0) => AclSubcommand::Log(None),
+            ("log", 1) if args[0].eq_ignore_ascii_case("reset") => AclSubcommand::LogReset,
+            ("log", 1) => AclSubcommand::Log(Some(args[0].parse().map_err(|_| {
+                CommandError::InvalidArgument("value is out of range, must be positive".to_string())
+            })?)),
+            ("load", 0) => AclSubcommand::Load,
+            ("save", 0) => AclSubcommand::Save,
+            (
+                "setuser" | "getuser" | "deluser" | "list" | "users" | "whoami" | "cat" | "log"
+                | "load" | "save",
+                _,
+            ) => return Err(wrong_args()),
+            _ => {
+                return Err(CommandError::InvalidCommand(format!(
+                    "unknown subcommand '{}'. Try ACL HELP.",
+                    name
+                )))
+            }
+        };
+        Ok(Acl { subcommand })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backend, RespDecode};
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    fn acl(args: &[&str]) -> Result<Acl, CommandError> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(format!("*{}\r\n", args.len() + 1).as_bytes());
+        for arg in std::iter::once(&"acl").chain(args) {
+            buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        RespArray::decode(&mut buf)
+            .map_err(CommandError::from)?
+            .try_into()
+    }
+
+    #[test]
+    fn test_acl_from_resp_array() -> Result<()> {
+        assert_eq!(
+            acl(&["SETUSER", "alice", "on", ">pw", "~*"])?.subcommand,
+            AclSubcommand::SetUser(SetUser {
+                username: "alice".to_string(),
+                rules: vec!["on".to_string(), ">pw".to_string(), "~*".to_string()],
+            })
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                acl(&["setuser", "alice", "on", ">pw", "#abc", "~*"])?
+            ),
+            r#"Acl { subcommand: SetUser(SetUser { username: "alice", rules: ["on", "(redacted)", "(redacted)", "~*"] }) }"#
+        );
+        assert!(acl(&["whoami"])?.is_whoami());
+        assert_eq!(acl(&["log", "5"])?.subcommand, AclSubcommand::Log(Some(5)));
+        assert_eq!(acl(&["log", "RESET"])?.subcommand, AclSubcommand::LogReset);
+        assert!(acl(&["getuser"]).is_err());
+        assert!(acl(&["log", "-1"]).is_err());
+        assert!(acl(&["nosuchsubcommand"]).is_err());
+        assert!(acl(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_acl_commands() -> Result<()> {
+        let backend = Backend::new();
+        assert_eq!(
+            acl(&["setuser", "alice", "on", ">pw", "~cache:*", "+get"])?.execute(&backend),
+            RESP_OK.clone()
+        );
+        assert!(matches!(
+            acl(&["setuser", "alice", "+nosuchcommand"])?.execute(&backend),
+            RespFrame::Error(_)
+        ));
+        assert_eq!(
+            acl(&["users"])?.execute(&backend),
+            RespArray::new(vec![
+                BulkString::from("alice").into(),
+                BulkString::from("default").into(),
+            ])
+            .into()
+        );
+
+        let RespFrame::Map(user) = acl(&["getuser", "alice"])?.execute(&backend) else {
+            panic!("GETUSER must return a map");
+        };
+        assert_eq!(user.get("keys"), Some(&BulkString::from("~cache:*").into()));
+        assert_eq!(user.get("commands"), Some(&BulkString::from("+get").into()));
+        assert_eq!(
+            acl(&["getuser", "bob"])?.execute(&backend),
+            RespFrame::Null(RespNull)
+        );
+
+        let RespFrame::Array(commands) = acl(&["cat", "hash"])?.execute(&backend) else {
+            panic!("CAT must return an array");
+        };
+        assert!(commands.contains(&BulkString::from("hget").into()));
+        assert!(matches!(
+            acl(&["cat", "nosuchcategory"])?.execute(&backend),
+            RespFrame::Error(_)
+        ));
+
+        assert_eq!(
+            acl(&["deluser", "alice", "bob"])?.execute(&backend),
+            RespFrame::Integer(1)
+        );
+        assert!(matches!(
+            acl(&["deluser", "default"])?.execute(&backend),
+            RespFrame::Error(_)
+        ));
+        // No aclfile configured.
+        assert!(matches!(
+            acl(&["save"])?.execute(&backend),
+            RespFrame::Error(_)
+        ));
+        Ok(())
+    }
+}
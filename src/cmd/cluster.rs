@@ -0,0 +1,513 @@
+// CLUSTER INFO / MYID / NODES / SLOTS / SHARDS / KEYSLOT / COUNTKEYSINSLOT / GETKEYSINSLOT / ADDSLOTS /
+//         ADDSLOTSRANGE / SETSLOT / MEET, and ASKING
+// The cluster itself is implemented in crate::cluster; this file only parses the command and shapes the replies.
+
+use super::{
+    extract_args, extract_string, validate_command, validate_variadic_command, Asking, Cluster,
+    ClusterSubcommand, CommandExecutor, RESP_OK,
+};
+use crate::{
+    cluster::{self, NodeInfo, SLOTS},
+    cmd::CommandError,
+    BulkString, RespArray, RespFrame, RespMap, SimpleError,
+};
+
+impl CommandExecutor for Cluster {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        if !backend.config.cluster_enabled {
+            return SimpleError::new("ERR This instance has cluster support disabled").into();
+        }
+        let cluster = &backend.cluster;
+        let err = |e: String| -> RespFrame { SimpleError::new(format!("ERR {}", e)).into() };
+        let bulk = |s: String| -> RespFrame { BulkString::from(s).into() };
+
+        match self.subcommand {
+            ClusterSubcommand::Info => {
+                let info = cluster.info();
+                let state = if info.slots_assigned == SLOTS {
+                    "ok"
+                } else {
+                    "fail"
+                };
+                let failing = cluster
+                    .nodes(backend.config.cluster_node_timeout)
+                    .into_iter()
+                    .filter(|node| node.failing)
+                    .map(|node| slot_count(&node))
+                    .sum::<usize>();
+                bulk(format!(
+                    "cluster_enabled:1\r\n\
+                     cluster_state:{}\r\n\
+                     cluster_slots_assigned:{}\r\n\
+                     cluster_slots_ok:{}\r\n\
+                     cluster_slots_pfail:{}\r\n\
+                     cluster_slots_fail:0\r\n\
+                     cluster_known_nodes:{}\r\n\
+                     cluster_size:{}\r\n\
+                     cluster_current_epoch:{}\r\n\
+                     cluster_my_epoch:{}\r\n",
+                    state,
+                    info.slots_assigned,
+                    info.slots_assigned - failing,
+                    failing,
+                    info.known_nodes,
+                    info.size,
+                    info.current_epoch,
+                    info.my_epoch,
+                ))
+            }
+            ClusterSubcommand::MyId => bulk(cluster.myself()),
+            ClusterSubcommand::Nodes => bulk(
+                cluster
+                    .nodes(backend.config.cluster_node_timeout)
+                    .iter()
+                    .map(|node| format!("{}\n", node_line(node)))
+                    .collect(),
+            ),
+            ClusterSubcommand::Slots => RespArray::new(
+                cluster
+                    .slots()
+                    .into_iter()
+                    .map(|(first, last, node)| {
+                        RespArray::new(vec![
+                            RespFrame::Integer(first as i64),
+                            RespFrame::Integer(last as i64),
+                            RespArray::new(vec![
+                                bulk(node.ip),
+                                RespFrame::Integer(node.port as i64),
+                                bulk(node.id),
+                            ])
+                            .into(),
+                        ])
+                        .into()
+                    })
+                    .collect::<Vec<RespFrame>>(),
+            )
+            .into(),
+            ClusterSubcommand::Shards => RespArray::new(
+                cluster
+                    .nodes(backend.config.cluster_node_timeout)
+                    .into_iter()
+                    .filter(|node| !node.slots.is_empty())
+                    .map(|node| shard(node, backend.replication.offset()))
+                    .collect::<Vec<RespFrame>>(),
+            )
+            .into(),
+            ClusterSubcommand::KeySlot(key) => {
+                RespFrame::Integer(cluster::key_hash_slot(key.as_bytes()) as i64)
+            }
+            ClusterSubcommand::CountKeysInSlot(slot) => {
+                RespFrame::Integer(cluster::keys_in_slot(backend, slot).len() as i64)
+            }
+            ClusterSubcommand::AddSlots(slots) => match cluster.add_slots(&slots) {
+                Ok(()) => RESP_OK.clone(),
+                Err(e) => err(e),
+            },
+            ClusterSubcommand::AddSlotsRange(ranges) => {
+                let slots = ranges
+                    .into_iter()
+                    .flat_map(|(first, last)| first..=last)
+                    .collect::<Vec<_>>();
+                match cluster.add_slots(&slots) {
+                    Ok(()) => RESP_OK.clone(),
+                    Err(e) => err(e),
+                }
+            }
+            ClusterSubcommand::GetKeysInSlot(slot, count) => {
+                let mut keys = cluster::keys_in_slot(backend, slot);
+                keys.sort();
+                RespArray::new(
+                    keys.into_iter()
+                        .take(count)
+                        .map(bulk)
+                        .collect::<Vec<RespFrame>>(),
+                )
+                .into()
+            }
+            ClusterSubcommand::SetSlotNode(slot, id) => {
+                let keys = cluster::keys_in_slot(backend, slot).len();
+                match cluster.set_slot_node(slot, &id, keys) {
+                    Ok(given_away) => {
+                        if given_away {
+                            backend.pubsub.remove_slots(&[slot]);
+                        }
+                        RESP_OK.clone()
+                    }
+                    Err(e) => err(e),
+                }
+            }
+            ClusterSubcommand::SetSlotMigrating(slot, id) => {
+                match cluster.set_slot_migrating(slot, &id) {
+                    Ok(()) => RESP_OK.clone(),
+                    Err(e) => err(e),
+                }
+            }
+            ClusterSubcommand::SetSlotImporting(slot, id) => {
+                match cluster.set_slot_importing(slot, &id) {
+                    Ok(()) => RESP_OK.clone(),
+                    Err(e) => err(e),
+                }
+            }
+            ClusterSubcommand::SetSlotStable(slot) => {
+                cluster.set_slot_stable(slot);
+                RESP_OK.clone()
+            }
+            ClusterSubcommand::Meet(ip, port, bus_port) => {
+                match cluster::meet(backend, &ip, port, bus_port) {
+                    Ok(()) => RESP_OK.clone(),
+                    Err(e) => err(e),
+                }
+            }
+        }
+    }
+}
+
+fn slot_count(node: &NodeInfo) -> usize {
+    node.slots
+        .iter()
+        .map(|(first, last)| (last - first) as usize + 1)
+        .sum()
+}
+
+// <id> <ip:port@cport> <flags> <master> <ping-sent> <pong-recv> <config-epoch> <link-state> <slot> <slot> ...
+fn node_line(node: &NodeInfo) -> String {
+    let mut flags = if node.myself {
+        "myself,master".to_string()
+    } else {
+        "master".to_string()
+    };
+    if node.failing {
+        flags.push_str(",fail?");
+    }
+    let link = if node.connected {
+        "connected"
+    } else {
+        "disconnected"
+    };
+    let mut line = format!(
+        "{} {}:{}@{} {} - {} {} {} {}",
+        node.id,
+        node.ip,
+        node.port,
+        node.bus_port,
+        flags,
+        node.ping_sent,
+        node.pong_received,
+        node.config_epoch,
+        link
+    );
+    for (first, last) in &node.slots {
+        if first == last {
+            line.push_str(&format!(" {}", first));
+        } else {
+            line.push_str(&format!(" {}-{}", first, last));
+        }
+    }
+    for (slot, id) in &node.migrating {
+        line.push_str(&format!(" [{}->-{}]", slot, id));
+    }
+    for (slot, id) in &node.importing {
+        line.push_str(&format!(" [{}-<-{}]", slot, id));
+    }
+    line
+}
+
+// A shard of CLUSTER SHARDS: its slot ranges and its nodes (only the primary, there are no cluster replicas).
+fn shard(node: NodeInfo, offset: u64) -> RespFrame {
+    let slots = node
+        .slots
+        .iter()
+        .flat_map(|&(first, last)| [first, last])
+        .map(|slot| RespFrame::Integer(slot as i64))
+        .collect::<Vec<_>>();
+    let health = if node.failing { "fail" } else { "online" };
+
+    let mut entry = RespMap::new();
+    entry.insert("id".to_string(), BulkString::from(node.id).into());
+    entry.insert("port".to_string(), RespFrame::Integer(node.port as i64));
+    entry.insert("ip".to_string(), BulkString::from(node.ip.as_str()).into());
+    entry.insert("endpoint".to_string(), BulkString::from(node.ip).into());
+    entry.insert("role".to_string(), BulkString::from("master").into());
+    entry.insert(
+        "replication-offset".to_string(),
+        RespFrame::Integer(if node.myself { offset as i64 } else { 0 }),
+    );
+    entry.insert("health".to_string(), BulkString::from(health).into());
+
+    let mut shard = RespMap::new();
+    shard.insert("slots".to_string(), RespArray::new(slots).into());
+    shard.insert(
+        "nodes".to_string(),
+        RespArray::new(vec![entry.into()]).into(),
+    );
+    shard.into()
+}
+
+impl TryFrom<RespArray> for Cluster {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["cluster"], 1)?;
+
+        let mut args = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<Vec<String>, CommandError>>()?
+            .into_iter();
+        let name = args.next().unwrap_or_default().to_ascii_lowercase();
+        let args = args.collect::<Vec<String>>();
+
+        let wrong_args = || {
+            CommandError::InvalidArgument(format!(
+                "wrong number of arguments for 'cluster|{}' command",
+                name
+            ))
+        };
+        let subcommand = match (name.as_str(), args.len()) {
+            ("info", 0) => ClusterSubcommand::Info,
+            ("myid", 0) => ClusterSubcommand::MyId,
+            ("nodes", 0) => ClusterSubcommand::Nodes,
+            ("slots", 0) => ClusterSubcommand::Slots,
+            ("shards", 0) => ClusterSubcommand::Shards,
+            ("keyslot", 1) => ClusterSubcommand::KeySlot(args[0].clone()),
+            ("countkeysinslot", 1) => ClusterSubcommand::CountKeysInSlot(parse_slot(&args[0])?),
+            ("addslots", n) if n >= 1 => ClusterSubcommand::AddSlots(
+                args.iter()
+                    .map(|slot| parse_slot(slot))
+                    .collect::<Result<_, _>>()?,
+            ),
+            ("addslotsrange", n) if n >= 2 && n % 2 == 0 => {
+                let mut ranges = Vec::new();
+                for pair in args.chunks(2) {
+                    let (first, last) = (parse_slot(&pair[0])?, parse_slot(&pair[1])?);
+                    if first > last {
+                        return Err(CommandError::InvalidArgument(format!(
+                            "start slot number {} is greater than end slot number {}",
+                            first, last
+                        )));
+                    }
+                    ranges.push((first, last));
+                }
+                ClusterSubcommand::AddSlotsRange(ranges)
+            }
+            ("getkeysinslot", 2) => {
+                let count = args[1].parse::<usize>().map_err(|_| {
+                    CommandError::InvalidArgument("Invalid number of keys".to_string())
+                })?;
+                ClusterSubcommand::GetKeysInSlot(parse_slot(&args[0])?, count)
+            }
+            ("setslot", 3) if args[1].eq_ignore_ascii_case("node") => {
+                ClusterSubcommand::SetSlotNode(parse_slot(&args[0])?, args[2].clone())
+            }
+            ("setslot", 3) if args[1].eq_ignore_ascii_case("migrating") => {
+                ClusterSubcommand::SetSlotMigrating(parse_slot(&args[0])?, args[2].clone())
+            }
+            ("setslot", 3) if args[1].eq_ignore_ascii_case("importing") => {
+                ClusterSubcommand::SetSlotImporting(parse_slot(&args[0])?, args[2].clone())
+            }
+            ("setslot", 2) if args[1].eq_ignore_ascii_case("stable") => {
+                ClusterSubcommand::SetSlotStable(parse_slot(&args[0])?)
+            }
+            ("setslot", n) if n >= 2 => {
+                return Err(CommandError::InvalidArgument(
+                    "Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+                        .to_string(),
+                ))
+            }
+            ("meet", 2 | 3) => {
+                let port = |arg: &str| {
+                    arg.parse::<u16>().map_err(|_| {
+                        CommandError::InvalidArgument(format!(
+                            "Invalid base port specified: {}",
+                            arg
+                        ))
+                    })
+                };
+                let base_port = port(&args[1])?;
+                let bus_port = match args.get(2) {
+                    Some(bus_port) => port(bus_port)?,
+                    None => base_port.wrapping_add(10000),
+                };
+                ClusterSubcommand::Meet(args[0].clone(), base_port, bus_port)
+            }
+            (
+                "info" | "myid" | "nodes" | "slots" | "shards" | "keyslot" | "countkeysinslot"
+                | "getkeysinslot" | "addslots" | "addslotsrange" | "setslot" | "meet",
+                _,
+            ) => return Err(wrong_args()),
+            _ => {
+                return Err(CommandError::InvalidCommand(format!(
+                    "unknown subcommand '{}'. Try CLUSTER HELP.",
+                    name
+                )))
+            }
+        };
+        Ok(Cluster { subcommand })
+    }
+}
+
+// The flag it sets is kept by network::request_handler; this only validates the command.
+impl CommandExecutor for Asking {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        if !backend.config.cluster_enabled {
+            return SimpleError::new("ERR This instance has cluster support disabled").into();
+        }
+        RESP_OK.clone()
+    }
+}
+
+impl TryFrom<RespArray> for Asking {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["asking"], 0)?;
+        Ok(Asking)
+    }
+}
+
+fn parse_slot(arg: &str) -> Result<u16, CommandError> {
+    arg.parse::<u16>()
+        .ok()
+        .filter(|&slot| (slot as usize) < SLOTS)
+        .ok_or_else(|| CommandError::InvalidArgument("Invalid or out of range slot".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, Backend, RespDecode};
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    fn cluster(args: &[&str]) -> Result<Cluster, CommandError> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(format!("*{}\r\n", args.len() + 1).as_bytes());
+        for arg in std::iter::once(&"cluster").chain(args) {
+            buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        RespArray::decode(&mut buf)
+            .map_err(CommandError::from)?
+            .try_into()
+    }
+
+    #[test]
+    fn test_cluster_from_resp_array() -> Result<()> {
+        assert_eq!(
+            cluster(&["ADDSLOTS", "1", "2"])?.subcommand,
+            ClusterSubcommand::AddSlots(vec![1, 2])
+        );
+        assert_eq!(
+            cluster(&["addslotsrange", "0", "100"])?.subcommand,
+            ClusterSubcommand::AddSlotsRange(vec![(0, 100)])
+        );
+        assert_eq!(
+            cluster(&["setslot", "7", "node", "abc"])?.subcommand,
+            ClusterSubcommand::SetSlotNode(7, "abc".to_string())
+        );
+        assert_eq!(
+            cluster(&["meet", "127.0.0.1", "7000"])?.subcommand,
+            ClusterSubcommand::Meet("127.0.0.1".to_string(), 7000, 17000)
+        );
+        assert_eq!(
+            cluster(&["setslot", "7", "MIGRATING", "abc"])?.subcommand,
+            ClusterSubcommand::SetSlotMigrating(7, "abc".to_string())
+        );
+        assert_eq!(
+            cluster(&["setslot", "7", "stable"])?.subcommand,
+            ClusterSubcommand::SetSlotStable(7)
+        );
+        assert_eq!(
+            cluster(&["getkeysinslot", "7", "10"])?.subcommand,
+            ClusterSubcommand::GetKeysInSlot(7, 10)
+        );
+        assert!(cluster(&["setslot", "7", "importing"]).is_err());
+        assert!(cluster(&["addslots", "16384"]).is_err());
+        assert!(cluster(&["addslotsrange", "5", "1"]).is_err());
+        assert!(cluster(&["keyslot"]).is_err());
+        assert!(cluster(&["nosuchsubcommand"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cluster_commands() -> Result<()> {
+        let backend = Backend::new();
+        assert_eq!(
+            cluster(&["keyslot", "somekey"])?.execute(&backend),
+            SimpleError::new("ERR This instance has cluster support disabled").into()
+        );
+
+        let backend = Backend::with_config(ServerConfig {
+            port: 7000,
+            cluster_enabled: true,
+            cluster_announce_ip: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            cluster(&["keyslot", "somekey"])?.execute(&backend),
+            RespFrame::Integer(11058)
+        );
+        assert_eq!(
+            cluster(&["addslotsrange", "0", "99", "200", "200"])?.execute(&backend),
+            RESP_OK.clone()
+        );
+        assert_eq!(
+            cluster(&["addslots", "50"])?.execute(&backend),
+            SimpleError::new("ERR Slot 50 is already busy").into()
+        );
+
+        let id = backend.cluster.myself();
+        let RespFrame::BulkString(nodes) = cluster(&["nodes"])?.execute(&backend) else {
+            panic!("NODES must return a bulk string");
+        };
+        assert_eq!(
+            String::from_utf8(nodes.0)?,
+            format!(
+                "{} 127.0.0.1:7000@17000 myself,master - 0 0 0 connected 0-99 200\n",
+                id
+            )
+        );
+        assert_eq!(
+            cluster(&["slots"])?.execute(&backend),
+            RespArray::new(vec![
+                RespArray::new(vec![
+                    RespFrame::Integer(0),
+                    RespFrame::Integer(99),
+                    RespArray::new(vec![
+                        BulkString::from("127.0.0.1").into(),
+                        RespFrame::Integer(7000),
+                        BulkString::from(id.as_str()).into(),
+                    ])
+                    .into(),
+                ])
+                .into(),
+                RespArray::new(vec![
+                    RespFrame::Integer(200),
+                    RespFrame::Integer(200),
+                    RespArray::new(vec![
+                        BulkString::from("127.0.0.1").into(),
+                        RespFrame::Integer(7000),
+                        BulkString::from(id.as_str()).into(),
+                    ])
+                    .into(),
+                ])
+                .into(),
+            ])
+            .into()
+        );
+
+        backend.set("foo".to_string(), BulkString::from("bar").into());
+        assert_eq!(
+            cluster(&["countkeysinslot", "12182"])?.execute(&backend),
+            RespFrame::Integer(1)
+        );
+        assert_eq!(
+            cluster(&["setslot", "1", "node", "nosuchnode"])?.execute(&backend),
+            SimpleError::new("ERR I don't know about node nosuchnode").into()
+        );
+        let RespFrame::BulkString(info) = cluster(&["info"])?.execute(&backend) else {
+            panic!("INFO must return a bulk string");
+        };
+        let info = String::from_utf8(info.0)?;
+        assert!(info.contains("cluster_state:fail\r\n"));
+        assert!(info.contains("cluster_slots_assigned:101\r\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+// Connection commands, i.e. commands that change the state of the client connection rather than the keyspace.
+// Executing them only validates against the backend; network::stream_handler keeps the per-connection state
+// (e.g. whether the connection is authenticated) and updates it from the reply.
+
+use super::{
+    extract_args, extract_string, validate_command, validate_variadic_command, Auth,
+    CommandExecutor, Hello, Ping, Quit, RESP_OK,
+};
+use crate::{
+    cmd::CommandError, Backend, BulkString, RespArray, RespFrame, RespMap, SimpleError,
+    SimpleString,
+};
+use std::fmt;
+
+impl Auth {
+    pub fn username(&self) -> &str {
+        self.username.as_deref().unwrap_or("default")
+    }
+}
+
+impl CommandExecutor for Auth {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        if self.username.is_none() && !backend.requires_auth() {
+            return SimpleError::new(
+                "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?",
+            )
+            .into();
+        }
+
+        if backend.authenticate(self.username(), &self.password) {
+            RESP_OK.clone()
+        } else {
+            backend.acl.log_denied("auth", "AUTH", self.username());
+            SimpleError::new("WRONGPASS invalid username-password pair or user is disabled.").into()
+        }
+    }
+}
+
+impl Ping {
+    pub fn message(&self) -> Option<&[u8]> {
+        self.message.as_deref()
+    }
+}
+
+impl CommandExecutor for Ping {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        match self.message {
+            Some(message) => BulkString::new(message).into(),
+            None => SimpleString::new("PONG").into(),
+        }
+    }
+}
+
+impl CommandExecutor for Quit {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        RESP_OK.clone()
+    }
+}
+
+impl Hello {
+    // Authenticates the connection if AUTH was given and switches its protocol (resp3) if protover was,
+    // then replies with the server's properties, as a map under RESP3. user is the ACL user of the connection.
+    pub fn run(
+        self,
+        backend: &Backend,
+        id: u64,
+        user: &mut Option<String>,
+        resp3: &mut bool,
+    ) -> RespFrame {
+        let protover = match self.protover.as_deref().map(str::parse::<i64>) {
+            None => None,
+            Some(Ok(protover @ (2 | 3))) => Some(protover),
+            Some(Ok(_)) => {
+                return SimpleError::new("NOPROTO unsupported protocol version").into();
+            }
+            Some(Err(_)) => {
+                return SimpleError::new("ERR Protocol version is not an integer or out of range")
+                    .into();
+            }
+        };
+        if let Some(auth) = self.auth {
+            let username = auth.username().to_string();
+            let frame = auth.execute(backend);
+            if matches!(frame, RespFrame::Error(_)) {
+                return frame;
+            }
+            *user = Some(username);
+        }
+        if user.is_none() {
+            return SimpleError::new("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time").into();
+        }
+        if let Some(protover) = protover {
+            *resp3 = protover == 3;
+        }
+
+        let mode = if backend.config.cluster_enabled {
+            "cluster"
+        } else {
+            "standalone"
+        };
+        let role = if backend.replication.is_replica() {
+            "replica"
+        } else {
+            "master"
+        };
+        let fields: Vec<(&str, RespFrame)> = vec![
+            ("server", BulkString::from("redis").into()),
+            (
+                "version",
+                BulkString::from(env!("CARGO_PKG_VERSION")).into(),
+            ),
+            ("proto", RespFrame::Integer(if *resp3 { 3 } else { 2 })),
+            ("id", RespFrame::Integer(id as i64)),
+            ("mode", BulkString::from(mode).into()),
+            ("role", BulkString::from(role).into()),
+            ("modules", RespArray::new(vec![]).into()),
+        ];
+        if *resp3 {
+            let mut map = RespMap::new();
+            for (name, value) in fields {
+                map.insert(name.to_string(), value);
+            }
+            map.into()
+        } else {
+            RespArray::new(
+                fields
+                    .into_iter()
+                    .flat_map(|(name, value)| [BulkString::from(name).into(), value])
+                    .collect::<Vec<_>>(),
+            )
+            .into()
+        }
+    }
+}
+
+impl CommandExecutor for Hello {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new("ERR HELLO can only be used by a client connection").into()
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("username", &self.username)
+            .field("password", &"(redacted)")
+            .finish()
+    }
+}
+
+impl TryFrom<RespArray> for Auth {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["auth"], 1)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        match (args.next(), args.next(), args.next()) {
+            (Some(RespFrame::BulkString(password)), None, None) => Ok(Auth {
+                username: None,
+                password: String::from_utf8(password.0)?,
+            }),
+            (
+                Some(RespFrame::BulkString(username)),
+                Some(RespFrame::BulkString(password)),
+                None,
+            ) => Ok(Auth {
+                username: Some(String::from_utf8(username.0)?),
+                password: String::from_utf8(password.0)?,
+            }),
+            _ => Err(CommandError::InvalidArgument("syntax error".to_string())),
+        }
+    }
+}
+
+impl TryFrom<RespArray> for Ping {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["ping"], 0)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        match (args.next(), args.next()) {
+            (None, None) => Ok(Ping { message: None }),
+            (Some(RespFrame::BulkString(message)), None) => Ok(Ping {
+                message: Some(message.0),
+            }),
+            _ => Err(CommandError::InvalidArgument(
+                "wrong number of arguments for 'ping' command".to_string(),
+            )),
+        }
+    }
+}
+
+impl TryFrom<RespArray> for Quit {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["quit"], 0)?;
+        Ok(Quit)
+    }
+}
+
+impl TryFrom<RespArray> for Hello {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["hello"], 0)?;
+
+        let mut args = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<Vec<String>, CommandError>>()?
+            .into_iter();
+        let protover = args.next();
+        let mut auth = None;
+        while let Some(option) = args.next() {
+            match (option.to_ascii_lowercase().as_str(), args.len()) {
+                ("auth", n) if n >= 2 => {
+                    auth = Some(Auth {
+                        username: args.next(),
+                        password: args.next().unwrap(),
+                    });
+                }
+                // Connections have no names, so the name is not kept.
+                ("setname", n) if n >= 1 => {
+                    args.next();
+                }
+                _ => {
+                    return Err(CommandError::InvalidArgument(format!(
+                        "Syntax error in HELLO option '{}'",
+                        option
+                    )))
+                }
+            }
+        }
+        Ok(Hello { protover, auth })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, Backend, RespDecode};
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_auth_from_resp_array() -> Result<()> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*2\r\n$4\r\nauth\r\n$8\r\nfoobared\r\n");
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Auth = frame.try_into()?;
+        assert_eq!(result.username(), "default");
+        assert_eq!(result.password, "foobared");
+        assert!(!format!("{:?}", result).contains("foobared"));
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*3\r\n$4\r\nauth\r\n$5\r\nalice\r\n$8\r\nfoobared\r\n");
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Auth = frame.try_into()?;
+        assert_eq!(result.username(), "alice");
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*1\r\n$4\r\nauth\r\n");
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Result<Auth, CommandError> = frame.try_into();
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ping_and_hello_from_resp_array() -> Result<()> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*2\r\n$4\r\nPING\r\n$5\r\nhello\r\n");
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Ping = frame.try_into()?;
+        assert_eq!(result.message(), Some(&b"hello"[..]));
+        assert_eq!(
+            result.execute(&Backend::new()),
+            BulkString::from("hello").into()
+        );
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(
+            b"*7\r\n$5\r\nhello\r\n$1\r\n3\r\n$4\r\nAUTH\r\n$5\r\nalice\r\n$8\r\nfoobared\r\n$7\r\nsetname\r\n$3\r\napp\r\n",
+        );
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Hello = frame.try_into()?;
+        assert_eq!(result.protover.as_deref(), Some("3"));
+        assert_eq!(result.auth.as_ref().map(Auth::username), Some("alice"));
+        assert!(!format!("{:?}", result).contains("foobared"));
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*3\r\n$5\r\nhello\r\n$1\r\n3\r\n$4\r\nauth\r\n");
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Result<Hello, CommandError> = frame.try_into();
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hello_command() {
+        let hello = |protover: Option<&str>, auth: Option<(&str, &str)>| Hello {
+            protover: protover.map(|s| s.to_string()),
+            auth: auth.map(|(username, password)| Auth {
+                username: Some(username.to_string()),
+                password: password.to_string(),
+            }),
+        };
+
+        let backend = Backend::with_config(ServerConfig {
+            requirepass: Some("foobared".to_string()),
+            ..Default::default()
+        });
+        let (mut user, mut resp3) = (None, false);
+        let frame = hello(Some("3"), None).run(&backend, 7, &mut user, &mut resp3);
+        assert!(matches!(frame, RespFrame::Error(e) if e.starts_with("NOAUTH")));
+        let frame = hello(Some("4"), None).run(&backend, 7, &mut user, &mut resp3);
+        assert!(matches!(frame, RespFrame::Error(e) if e.starts_with("NOPROTO")));
+
+        let frame =
+            hello(Some("3"), Some(("default", "foobared"))).run(&backend, 7, &mut user, &mut resp3);
+        assert_eq!(user.as_deref(), Some("default"));
+        assert!(resp3);
+        let RespFrame::Map(map) = frame else {
+            panic!("expected a map, got {:?}", frame);
+        };
+        assert_eq!(map.get("proto"), Some(&RespFrame::Integer(3)));
+        assert_eq!(map.get("id"), Some(&RespFrame::Integer(7)));
+
+        // Without protover the protocol stays as it is.
+        let frame = hello(None, None).run(&backend, 7, &mut user, &mut resp3);
+        assert!(matches!(frame, RespFrame::Map(_)));
+        let frame = hello(Some("2"), None).run(&backend, 7, &mut user, &mut resp3);
+        assert!(!resp3);
+        assert!(matches!(frame, RespFrame::Array(array) if array.len() == 14));
+    }
+
+    #[test]
+    fn test_auth_command() {
+        let auth = |username: Option<&str>, password: &str| Auth {
+            username: username.map(|s| s.to_string()),
+            password: password.to_string(),
+        };
+
+        let backend = Backend::with_config(ServerConfig {
+            requirepass: Some("foobared".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(auth(None, "foobared").execute(&backend), RESP_OK.clone());
+        assert_eq!(
+            auth(Some("default"), "foobared").execute(&backend),
+            RESP_OK.clone()
+        );
+        let wrongpass: RespFrame =
+            SimpleError::new("WRONGPASS invalid username-password pair or user is disabled.")
+                .into();
+        assert_eq!(auth(None, "foobare").execute(&backend), wrongpass);
+        assert_eq!(auth(Some("alice"), "foobared").execute(&backend), wrongpass);
+        // Failed attempts end up in ACL LOG.
+        assert_eq!(backend.acl().log_entries(10).len(), 2);
+
+        // Without requirepass a plain AUTH is a configuration mistake.
+        let backend = Backend::new();
+        assert!(matches!(
+            auth(None, "foobared").execute(&backend),
+            RespFrame::Error(_)
+        ));
+    }
+}
@@ -36,9 +36,8 @@ impl CommandExecutor for HGetAll {
         match hmap {
             Some(hmap) => {
                 let mut data = Vec::with_capacity(hmap.len());
-                for v in hmap.iter() {
-                    let key = v.key().to_owned();
-                    data.push((key, v.value().clone()));
+                for (key, value) in hmap.iter() {
+                    data.push((key.to_owned(), value.clone()));
                 }
 
                 // sort_by is a method provided by Rust's Vec type.
@@ -0,0 +1,722 @@
+// Commands that work on keys regardless of the type of their value.
+
+use super::{
+    extract_args, extract_integer, extract_string, validate_command, validate_variadic_command,
+    CommandExecutor, Del, Dump, Migrate, Object, ObjectSubcommand, Restore, RESP_OK,
+};
+use crate::{
+    backend::unix_time_ms, cmd::CommandError, network::RespFrameCodec, persistence::rdb,
+    BulkString, RespArray, RespFrame, RespNull, SimpleError, SimpleString, WatchedKeys,
+};
+use futures::SinkExt;
+use std::{fmt, time::Duration};
+use tokio::net::TcpStream;
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+impl CommandExecutor for Dump {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        match backend.value(&self.key) {
+            Some(value) => BulkString::new(rdb::dump(&value)).into(),
+            None => RespFrame::Null(RespNull),
+        }
+    }
+}
+
+impl CommandExecutor for Restore {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        // Checked in the same order as redis, so the same request fails with the same error.
+        if self.ttl < 0 {
+            return SimpleError::new("ERR Invalid TTL value, must be >= 0").into();
+        }
+        if self.idletime.is_some_and(|idle| idle < 0) {
+            return SimpleError::new("ERR Invalid IDLETIME value, must be >= 0").into();
+        }
+        if self.freq.is_some_and(|freq| !(0..=255).contains(&freq)) {
+            return SimpleError::new("ERR Invalid FREQ value, must be >= 0 and <= 255").into();
+        }
+        if !self.replace && backend.value(&self.key).is_some() {
+            return SimpleError::new("BUSYKEY Target key name already exists.").into();
+        }
+        if !rdb::is_valid_dump(&self.payload) {
+            return SimpleError::new("ERR DUMP payload version or checksum are wrong").into();
+        }
+        let Ok(value) = rdb::parse_dump(&self.payload) else {
+            return SimpleError::new("ERR Bad data format").into();
+        };
+
+        let expire_at = match (self.ttl, self.absttl) {
+            (0, _) => None,
+            (ttl, true) => Some(ttl as u64),
+            (ttl, false) => Some(unix_time_ms().saturating_add(ttl as u64)),
+        };
+        // The key may have been created since the check above.
+        if !backend.restore(self.key.clone(), value, expire_at, self.replace) {
+            return SimpleError::new("BUSYKEY Target key name already exists.").into();
+        }
+        backend.set_access(
+            &self.key,
+            self.idletime.map(|idle| idle as u64),
+            self.freq.map(|freq| freq as u8),
+        );
+        RESP_OK.clone()
+    }
+}
+
+impl Restore {
+    // A relative TTL logged as received would start again from zero when the AOF is replayed or a replica applies
+    // the command late, bringing back keys that expired. Like redis, the command is executed and logged with the
+    // absolute expire time instead (ABSTTL). Returns the frame to log.
+    pub(crate) fn pin_expire_time(&mut self, logged: RespFrame) -> RespFrame {
+        if self.absttl || self.ttl <= 0 {
+            return logged;
+        }
+        let RespFrame::Array(array) = logged else {
+            return logged;
+        };
+        self.ttl = unix_time_ms().saturating_add(self.ttl as u64) as i64;
+        self.absttl = true;
+        let mut args = array.0;
+        args[2] = BulkString::from(self.ttl.to_string()).into();
+        args.push(BulkString::from("ABSTTL").into());
+        RespArray::new(args).into()
+    }
+}
+
+impl CommandExecutor for Del {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        let deleted = self.keys.iter().filter(|key| backend.del(key)).count();
+        RespFrame::Integer(deleted as i64)
+    }
+}
+
+impl CommandExecutor for Object {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        // Like redis, the access information the eviction policy does not use is not reported.
+        let lfu = backend.config().maxmemory_policy.is_lfu();
+        match self.subcommand {
+            ObjectSubcommand::IdleTime if lfu => {
+                return SimpleError::new(
+                    "ERR An LFU maxmemory policy is selected, idle time not tracked. Please note that when \
+                     switching between policies at runtime LRU and LFU data will take some time to adjust.",
+                )
+                .into()
+            }
+            ObjectSubcommand::Freq if !lfu => {
+                return SimpleError::new(
+                    "ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note \
+                     that when switching between policies at runtime LRU and LFU data will take some time to \
+                     adjust.",
+                )
+                .into()
+            }
+            _ => {}
+        }
+        let Some(info) = backend.object_info(&self.key) else {
+            return RespFrame::Null(RespNull);
+        };
+        match self.subcommand {
+            ObjectSubcommand::Encoding => BulkString::from(info.encoding).into(),
+            ObjectSubcommand::IdleTime => RespFrame::Integer(info.idle as i64),
+            ObjectSubcommand::Freq => RespFrame::Integer(info.frequency as i64),
+            // Values are never shared between keys.
+            ObjectSubcommand::RefCount => RespFrame::Integer(1),
+        }
+    }
+}
+
+impl CommandExecutor for Migrate {
+    fn execute(self, _: &crate::Backend) -> RespFrame {
+        SimpleError::new("ERR MIGRATE can only be used by a client connection").into()
+    }
+}
+
+impl fmt::Debug for Migrate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migrate")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("keys", &self.keys)
+            .field("db", &self.db)
+            .field("timeout", &self.timeout)
+            .field("copy", &self.copy)
+            .field("replace", &self.replace)
+            .field(
+                "auth",
+                &self
+                    .auth
+                    .as_ref()
+                    .map(|(username, _)| (username, "(redacted)")),
+            )
+            .finish()
+    }
+}
+
+impl Migrate {
+    // Sends the keys to the target server. Returns the reply and the keys to delete here: the ones the target
+    // restored, unless COPY is set.
+    // Unlike redis, the server does not stop while the keys are in flight, so the keys are watched before they
+    // are dumped: the caller must not delete one that changed since (see WatchedKeys::changed), or the write is lost.
+    pub async fn run(&self, backend: &crate::Backend) -> (RespFrame, Vec<String>, WatchedKeys) {
+        let mut watched = WatchedKeys::new(backend);
+        for key in &self.keys {
+            watched.add(key.clone());
+        }
+        let mut restores = Vec::new();
+        let mut keys = Vec::new();
+        for key in &self.keys {
+            let Some(value) = backend.value(key) else {
+                continue;
+            };
+            // RESTORE takes the remaining time to live, 0 meaning none.
+            let ttl = backend
+                .expire_time(key)
+                .map_or(0, |at| at.saturating_sub(unix_time_ms()).max(1));
+            let mut args = vec![
+                "RESTORE-ASKING".into(),
+                key.as_bytes().to_vec(),
+                ttl.to_string().into_bytes(),
+                rdb::dump(&value),
+            ];
+            if self.replace {
+                args.push("REPLACE".into());
+            }
+            restores.push(command(args));
+            keys.push(key.clone());
+        }
+        if keys.is_empty() {
+            return (SimpleString::new("NOKEY").into(), Vec::new(), watched);
+        }
+
+        let mut commands = Vec::new();
+        match &self.auth {
+            Some((Some(username), password)) => commands.push(command(vec![
+                "AUTH".into(),
+                username.as_bytes().to_vec(),
+                password.as_bytes().to_vec(),
+            ])),
+            Some((None, password)) => {
+                commands.push(command(vec!["AUTH".into(), password.as_bytes().to_vec()]))
+            }
+            None => {}
+        }
+        commands.push(command(vec![
+            "SELECT".into(),
+            self.db.to_string().into_bytes(),
+        ]));
+        let setup = commands.len();
+        commands.extend(restores);
+
+        let timeout = Duration::from_millis(self.timeout);
+        let connect = TcpStream::connect((self.host.as_str(), self.port));
+        let Ok(Ok(stream)) = tokio::time::timeout(timeout, connect).await else {
+            let e = SimpleError::new("IOERR error or timeout connecting to the client");
+            return (e.into(), Vec::new(), watched);
+        };
+        let mut framed = Framed::new(stream, RespFrameCodec);
+
+        // Pipelined: every command is sent before the replies are read.
+        let count = commands.len();
+        let send = async {
+            for command in commands {
+                framed.feed(command).await?;
+            }
+            framed.flush().await
+        };
+        if !matches!(tokio::time::timeout(timeout, send).await, Ok(Ok(()))) {
+            let e = SimpleError::new("IOERR error or timeout writing to target instance");
+            return (e.into(), Vec::new(), watched);
+        }
+
+        let mut restored = Vec::new();
+        let mut failure = None;
+        for i in 0..count {
+            let reply = match tokio::time::timeout(timeout, framed.next()).await {
+                Ok(Some(Ok(reply))) => reply,
+                _ => {
+                    // The keys restored so far are on the target already.
+                    failure = Some(SimpleError::new(
+                        "IOERR error or timeout reading to target instance",
+                    ));
+                    break;
+                }
+            };
+            match reply {
+                RespFrame::Error(e) => {
+                    let e = SimpleError::new(format!(
+                        "ERR Target instance replied with error: {}",
+                        e.0
+                    ));
+                    if i < setup {
+                        return (e.into(), Vec::new(), watched);
+                    }
+                    failure.get_or_insert(e);
+                }
+                _ if i >= setup => restored.push(keys[i - setup].clone()),
+                _ => {}
+            }
+        }
+
+        let reply = match failure {
+            Some(e) => e.into(),
+            None => RESP_OK.clone(),
+        };
+        if self.copy {
+            restored.clear();
+        }
+        (reply, restored, watched)
+    }
+}
+
+fn command(args: Vec<Vec<u8>>) -> RespFrame {
+    RespArray::new(
+        args.into_iter()
+            .map(|arg| BulkString::new(arg).into())
+            .collect::<Vec<RespFrame>>(),
+    )
+    .into()
+}
+
+impl TryFrom<RespArray> for Del {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["del"], 1)?;
+
+        let keys = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<_, _>>()?;
+        Ok(Del { keys })
+    }
+}
+
+impl TryFrom<RespArray> for Migrate {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["migrate"], 5)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        let host = extract_string(args.next().unwrap())?;
+        let port = u16::try_from(extract_integer(args.next().unwrap())?).map_err(|_| {
+            CommandError::InvalidArgument("value is not an integer or out of range".to_string())
+        })?;
+        let key = extract_string(args.next().unwrap())?;
+        let db = extract_integer(args.next().unwrap())?;
+        // Like redis, a timeout that is not positive means one second.
+        let timeout = match extract_integer(args.next().unwrap())? {
+            timeout if timeout <= 0 => 1000,
+            timeout => timeout as u64,
+        };
+
+        let mut migrate = Migrate {
+            host,
+            port,
+            keys: Vec::new(),
+            db,
+            timeout,
+            copy: false,
+            replace: false,
+            auth: None,
+        };
+        let mut keys = None;
+        while let Some(arg) = args.next() {
+            match extract_string(arg)?.to_ascii_lowercase().as_str() {
+                "copy" => migrate.copy = true,
+                "replace" => migrate.replace = true,
+                "auth" => {
+                    let password = extract_string(args.next().ok_or_else(syntax_error)?)?;
+                    migrate.auth = Some((None, password));
+                }
+                "auth2" => {
+                    let username = extract_string(args.next().ok_or_else(syntax_error)?)?;
+                    let password = extract_string(args.next().ok_or_else(syntax_error)?)?;
+                    migrate.auth = Some((Some(username), password));
+                }
+                "keys" => {
+                    if !key.is_empty() {
+                        return Err(CommandError::InvalidArgument(
+                            "When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                                .to_string(),
+                        ));
+                    }
+                    keys = Some(
+                        args.by_ref()
+                            .map(extract_string)
+                            .collect::<Result<_, _>>()?,
+                    );
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        migrate.keys = keys.unwrap_or_else(|| vec![key]);
+        Ok(migrate)
+    }
+}
+
+impl TryFrom<RespArray> for Dump {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["dump"], 1)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        match args.next() {
+            Some(RespFrame::BulkString(key)) => Ok(Dump {
+                key: String::from_utf8(key.0)?,
+            }),
+            _ => Err(CommandError::InvalidArgument("Invalid key".to_string())),
+        }
+    }
+}
+
+impl TryFrom<RespArray> for Object {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["object"], 1)?;
+
+        let mut args = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<Vec<String>, CommandError>>()?
+            .into_iter();
+        let name = args.next().unwrap_or_default().to_ascii_lowercase();
+        let subcommand = match name.as_str() {
+            "encoding" => ObjectSubcommand::Encoding,
+            "idletime" => ObjectSubcommand::IdleTime,
+            "freq" => ObjectSubcommand::Freq,
+            "refcount" => ObjectSubcommand::RefCount,
+            _ => {
+                return Err(CommandError::InvalidCommand(format!(
+                    "unknown subcommand '{}'. Try OBJECT HELP.",
+                    name
+                )))
+            }
+        };
+        match (args.next(), args.next()) {
+            (Some(key), None) => Ok(Object { subcommand, key }),
+            _ => Err(CommandError::InvalidArgument(format!(
+                "wrong number of arguments for 'object|{}' command",
+                name
+            ))),
+        }
+    }
+}
+
+impl TryFrom<RespArray> for Restore {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        // RESTORE-ASKING is RESTORE, only redirected differently in cluster mode (see cluster::Cluster::check_frame).
+        let name = match value.first() {
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"restore-asking") => {
+                "restore-asking"
+            }
+            _ => "restore",
+        };
+        validate_variadic_command(&value, &[name], 3)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        let key = extract_string(args.next().unwrap())?;
+        let ttl = extract_integer(args.next().unwrap())?;
+        let payload = match args.next() {
+            Some(RespFrame::BulkString(payload)) => payload.0,
+            _ => return Err(CommandError::InvalidArgument("Invalid payload".to_string())),
+        };
+
+        let mut restore = Restore {
+            key,
+            ttl,
+            payload,
+            replace: false,
+            absttl: false,
+            idletime: None,
+            freq: None,
+        };
+        while let Some(arg) = args.next() {
+            match extract_string(arg)?.to_ascii_lowercase().as_str() {
+                "replace" => restore.replace = true,
+                "absttl" => restore.absttl = true,
+                // IDLETIME and FREQ belong to different eviction policies and exclude each other.
+                "idletime" if restore.freq.is_none() => {
+                    let value = args.next().ok_or_else(syntax_error)?;
+                    restore.idletime = Some(extract_integer(value)?);
+                }
+                "freq" if restore.idletime.is_none() => {
+                    let value = args.next().ok_or_else(syntax_error)?;
+                    restore.freq = Some(extract_integer(value)?);
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        Ok(restore)
+    }
+}
+
+fn syntax_error() -> CommandError {
+    CommandError::InvalidArgument("syntax error".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cmd::Command, RespDecode};
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    fn restore_command(args: &[&[u8]]) -> Result<Restore> {
+        let mut frames: Vec<RespFrame> = vec![BulkString::from("restore").into()];
+        frames.extend(args.iter().map(|arg| BulkString::new(*arg).into()));
+        Ok(RespArray::new(frames).try_into()?)
+    }
+
+    fn error(frame: RespFrame) -> String {
+        match frame {
+            RespFrame::Error(e) => e.0,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_restore_from_resp_array() -> Result<()> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(
+            b"*6\r\n$7\r\nRESTORE\r\n$3\r\nkey\r\n$4\r\n1000\r\n$3\r\nabc\r\n$7\r\nREPLACE\r\n$6\r\nABSTTL\r\n",
+        );
+        let frame = RespArray::decode(&mut buf)?;
+        let Command::Restore(result) = Command::try_from(frame)? else {
+            panic!("not a RESTORE");
+        };
+        assert_eq!(result.key, "key");
+        assert_eq!(result.ttl, 1000);
+        assert_eq!(result.payload, b"abc");
+        assert!(result.replace && result.absttl);
+
+        let result = restore_command(&[b"key", b"0", b"abc", b"IDLETIME", b"10"])?;
+        assert_eq!(result.idletime, Some(10));
+        assert!(
+            restore_command(&[b"key", b"0", b"abc", b"IDLETIME", b"10", b"FREQ", b"5"]).is_err()
+        );
+        assert!(restore_command(&[b"key", b"0", b"abc", b"FREQ"]).is_err());
+        assert!(restore_command(&[b"key", b"ttl", b"abc"]).is_err());
+        assert!(restore_command(&[b"key", b"0"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_restore_command() -> Result<()> {
+        let backend = crate::Backend::new();
+        backend.set("hello".to_string(), BulkString::from("world").into());
+        backend.hset(
+            "map".to_string(),
+            "field".to_string(),
+            BulkString::from("value").into(),
+        );
+
+        let dump = |key: &str| {
+            Dump {
+                key: key.to_string(),
+            }
+            .execute(&backend)
+        };
+        assert_eq!(dump("missing"), RespFrame::Null(RespNull));
+        let RespFrame::BulkString(string) = dump("hello") else {
+            panic!("DUMP did not return a bulk string");
+        };
+        let RespFrame::BulkString(hash) = dump("map") else {
+            panic!("DUMP did not return a bulk string");
+        };
+
+        // The key still exists.
+        let result = restore_command(&[b"hello", b"0", &string])?.execute(&backend);
+        assert_eq!(error(result), "BUSYKEY Target key name already exists.");
+
+        let result = restore_command(&[b"copy", b"0", &string])?.execute(&backend);
+        assert_eq!(result, RESP_OK.clone());
+        assert_eq!(backend.get("copy"), Some(BulkString::from("world").into()));
+        assert_eq!(backend.expire_time("copy"), None);
+
+        // A hash replacing a string, with a TTL.
+        let result = restore_command(&[b"copy", b"60000", &hash, b"REPLACE"])?.execute(&backend);
+        assert_eq!(result, RESP_OK.clone());
+        assert_eq!(backend.get("copy"), None);
+        assert_eq!(
+            backend.hget("copy", "field"),
+            Some(BulkString::from("value").into())
+        );
+        assert!(backend
+            .expire_time("copy")
+            .is_some_and(|at| at > unix_time_ms()));
+
+        // An absolute TTL in the past only deletes the old value.
+        let result =
+            restore_command(&[b"copy", b"1", &string, b"REPLACE", b"ABSTTL"])?.execute(&backend);
+        assert_eq!(result, RESP_OK.clone());
+        assert_eq!(backend.value("copy"), None);
+
+        let mut corrupted = string.0.clone();
+        corrupted[2] ^= 0xFF;
+        let result = restore_command(&[b"new", b"0", &corrupted])?.execute(&backend);
+        assert_eq!(
+            error(result),
+            "ERR DUMP payload version or checksum are wrong"
+        );
+        let result = restore_command(&[b"new", b"-1", &string])?.execute(&backend);
+        assert_eq!(error(result), "ERR Invalid TTL value, must be >= 0");
+        let result = restore_command(&[b"new", b"0", &string, b"FREQ", b"256"])?.execute(&backend);
+        assert_eq!(
+            error(result),
+            "ERR Invalid FREQ value, must be >= 0 and <= 255"
+        );
+        Ok(())
+    }
+
+    fn migrate_command(args: &[&str]) -> Result<Migrate, CommandError> {
+        let mut frames: Vec<RespFrame> = vec![BulkString::from("migrate").into()];
+        frames.extend(args.iter().map(|arg| BulkString::from(*arg).into()));
+        RespArray::new(frames).try_into()
+    }
+
+    #[test]
+    fn test_migrate_from_resp_array() -> Result<()> {
+        let migrate = migrate_command(&["127.0.0.1", "7001", "key", "0", "5000", "COPY"])?;
+        assert_eq!(migrate.port, 7001);
+        assert_eq!(migrate.keys, vec!["key".to_string()]);
+        assert_eq!(migrate.timeout, 5000);
+        assert!(migrate.copy && !migrate.replace);
+
+        let migrate = migrate_command(&[
+            "127.0.0.1",
+            "7001",
+            "",
+            "0",
+            "0",
+            "REPLACE",
+            "AUTH2",
+            "user",
+            "pass",
+            "KEYS",
+            "a",
+            "b",
+        ])?;
+        assert_eq!(migrate.keys, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(migrate.timeout, 1000);
+        assert_eq!(
+            migrate.auth,
+            Some((Some("user".to_string()), "pass".to_string()))
+        );
+        assert!(!format!("{:?}", migrate).contains("pass"));
+
+        assert!(migrate_command(&["127.0.0.1", "7001", "key", "0", "0", "KEYS", "a"]).is_err());
+        assert!(migrate_command(&["127.0.0.1", "70000", "key", "0", "0"]).is_err());
+        assert!(migrate_command(&["127.0.0.1", "7001", "key", "0", "0", "AUTH"]).is_err());
+        assert!(migrate_command(&["127.0.0.1", "7001", "key", "0"]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_del_and_migrate_without_keys() -> Result<()> {
+        let backend = crate::Backend::new();
+        backend.set("a".to_string(), BulkString::from("1").into());
+        backend.hset(
+            "b".to_string(),
+            "field".to_string(),
+            BulkString::from("value").into(),
+        );
+        let del = Del::try_from(RespArray::new(vec![
+            BulkString::from("DEL").into(),
+            BulkString::from("a").into(),
+            BulkString::from("b").into(),
+            BulkString::from("c").into(),
+        ]))?;
+        assert_eq!(del.execute(&backend), RespFrame::Integer(2));
+        assert_eq!(backend.value("b"), None);
+
+        // Nothing to move: the target is not even contacted.
+        let migrate = migrate_command(&["127.0.0.1", "1", "a", "0", "100"])?;
+        let (reply, deleted, _) = migrate.run(&backend).await;
+        assert_eq!(reply, SimpleString::new("NOKEY").into());
+        assert!(deleted.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_command() -> Result<()> {
+        let object = |backend: &crate::Backend, args: &[&str]| -> Result<RespFrame> {
+            let mut frames: Vec<RespFrame> = vec![BulkString::from("object").into()];
+            frames.extend(args.iter().map(|arg| BulkString::from(*arg).into()));
+            let cmd: Object = RespArray::new(frames).try_into()?;
+            Ok(cmd.execute(backend))
+        };
+        let backend = crate::Backend::new();
+        assert!(object(&backend, &["encoding"]).is_err());
+        assert!(object(&backend, &["encoding", "a", "b"]).is_err());
+        assert!(object(&backend, &["nosuch", "a"]).is_err());
+
+        for (value, encoding) in [
+            ("123", "int"),
+            ("-7", "int"),
+            ("0123", "embstr"),
+            ("hello", "embstr"),
+            (&"x".repeat(45), "raw"),
+        ] {
+            backend.set("k".to_string(), BulkString::from(value).into());
+            assert_eq!(
+                object(&backend, &["ENCODING", "k"])?,
+                BulkString::from(encoding).into()
+            );
+        }
+        backend.hset(
+            "h".to_string(),
+            "f".to_string(),
+            BulkString::from("v").into(),
+        );
+        assert_eq!(
+            object(&backend, &["encoding", "h"])?,
+            BulkString::from("listpack").into()
+        );
+        backend.hset(
+            "h".to_string(),
+            "f".to_string(),
+            BulkString::from("v".repeat(65)).into(),
+        );
+        assert_eq!(
+            object(&backend, &["encoding", "h"])?,
+            BulkString::from("hashtable").into()
+        );
+        assert_eq!(object(&backend, &["refcount", "h"])?, RespFrame::Integer(1));
+        assert_eq!(
+            object(&backend, &["encoding", "missing"])?,
+            RespFrame::Null(RespNull)
+        );
+
+        // RESTORE sets the access information, and only reads update it.
+        let payload = rdb::dump(&backend.value("k").unwrap());
+        let args: &[&[u8]] = &[b"r", b"0", &payload, b"IDLETIME", b"100"];
+        assert_eq!(restore_command(args)?.execute(&backend), RESP_OK.clone());
+        assert_eq!(
+            object(&backend, &["idletime", "r"])?,
+            RespFrame::Integer(100)
+        );
+        assert_eq!(
+            object(&backend, &["idletime", "r"])?,
+            RespFrame::Integer(100)
+        );
+        backend.get("r");
+        assert_eq!(object(&backend, &["idletime", "r"])?, RespFrame::Integer(0));
+        // FREQ needs an LFU policy, IDLETIME one that is not.
+        assert!(matches!(
+            object(&backend, &["freq", "r"])?,
+            RespFrame::Error(_)
+        ));
+
+        let mut config = crate::config::ServerConfig::default();
+        config.set("maxmemory-policy", "allkeys-lfu")?;
+        let backend = crate::Backend::with_config(config);
+        let args: &[&[u8]] = &[b"r", b"0", &payload, b"FREQ", b"42"];
+        assert_eq!(restore_command(args)?.execute(&backend), RESP_OK.clone());
+        assert_eq!(object(&backend, &["freq", "r"])?, RespFrame::Integer(42));
+        assert!(matches!(
+            object(&backend, &["idletime", "r"])?,
+            RespFrame::Error(_)
+        ));
+        Ok(())
+    }
+}
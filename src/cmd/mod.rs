@@ -8,10 +8,26 @@
 // 这四个 structs 通过 CommandExecutor trait 实现了执行命令的功能。
 // CommandExecutor trait 内部的 execute 方法是对 Backend 中的 Dashmap 数据的操作，包括 get set hget hset hgetall 等操作。
 
+mod acl;
+mod cluster;
+mod connection;
 mod hmap;
+mod keyspace;
 mod map;
-
-use crate::{Backend, RespArray, RespError, RespFrame, SimpleString};
+mod pubsub;
+mod replication;
+mod scripting;
+mod server;
+pub mod spec;
+mod transaction;
+
+use crate::{
+    module::{self, ModuleCommand},
+    pubsub::Kind,
+    scripting::RestorePolicy,
+    shutdown::ShutdownRequest,
+    Backend, RespArray, RespError, RespFrame, SimpleString,
+};
 use enum_dispatch::enum_dispatch;
 use lazy_static::lazy_static;
 use thiserror::Error;
@@ -54,6 +70,44 @@ pub enum Command {
     HGet(HGet),
     HSet(HSet),
     HGetAll(HGetAll),
+    Shutdown(Shutdown),
+    Save(Save),
+    BgSave(BgSave),
+    LastSave(LastSave),
+    BgRewriteAof(BgRewriteAof),
+    Auth(Auth),
+    Acl(Acl),
+    Dump(Dump),
+    Restore(Restore),
+    ReplicaOf(ReplicaOf),
+    Role(Role),
+    ReplConf(ReplConf),
+    PSync(PSync),
+    Wait(Wait),
+    WaitAof(WaitAof),
+    Cluster(Cluster),
+    Asking(Asking),
+    Del(Del),
+    Migrate(Migrate),
+    Ping(Ping),
+    Hello(Hello),
+    Quit(Quit),
+    Subscribe(Subscribe),
+    Unsubscribe(Unsubscribe),
+    Publish(Publish),
+    PubSub(PubSub),
+    Multi(Multi),
+    Exec(Exec),
+    Discard(Discard),
+    Watch(Watch),
+    Unwatch(Unwatch),
+    Eval(Eval),
+    Script(Script),
+    Fcall(Fcall),
+    Function(Function),
+    Memory(Memory),
+    Object(Object),
+    Module(ModuleCommand),
     // unrecognized command
     Unrecognized(Unrecognized),
 }
@@ -150,6 +204,359 @@ pub struct HGetAll {
     sort: bool,
 }
 
+// SHUTDOWN [NOSAVE|SAVE] [NOW] [FORCE]
+// Only carries the options; the actual draining of connections happens in network::serve.
+#[derive(Debug)]
+pub struct Shutdown {
+    request: ShutdownRequest,
+}
+
+// SAVE: writes a snapshot in the foreground, the reply is sent once it is on disk.
+#[derive(Debug)]
+pub struct Save;
+
+// BGSAVE: starts writing a snapshot on a background thread and replies right away.
+#[derive(Debug)]
+pub struct BgSave;
+
+// LASTSAVE: unix time (seconds) of the last successful save.
+#[derive(Debug)]
+pub struct LastSave;
+
+// BGREWRITEAOF: compacts the append-only file in the background.
+#[derive(Debug)]
+pub struct BgRewriteAof;
+
+// AUTH [username] password
+// Without a username the "default" user is assumed, which is the only user when just requirepass is configured.
+// Debug is implemented by hand in connection.rs so the password never ends up in the logs.
+pub struct Auth {
+    username: Option<String>,
+    password: String,
+}
+
+// ACL <subcommand> [arguments ...]
+#[derive(Debug)]
+pub struct Acl {
+    subcommand: AclSubcommand,
+}
+
+// ACL SETUSER username [rule ...]
+// Debug is implemented by hand in acl.rs so the passwords of the rules never end up in the logs.
+#[derive(PartialEq)]
+pub struct SetUser {
+    username: String,
+    rules: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AclSubcommand {
+    SetUser(SetUser),
+    GetUser(String),
+    DelUser(Vec<String>),
+    List,
+    Users,
+    // Answered by network::request_handler, which knows the user of the connection.
+    WhoAmI,
+    Cat(Option<String>),
+    // ACL LOG [count | RESET]
+    Log(Option<usize>),
+    LogReset,
+    Load,
+    Save,
+}
+
+// DUMP key: the value serialized in redis' format, to be passed to RESTORE (on this or another server).
+#[derive(Debug)]
+pub struct Dump {
+    key: String,
+}
+
+// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]
+// ttl is in milliseconds, 0 for no expiry; with ABSTTL it is an absolute unix time in milliseconds.
+// IDLETIME and FREQ set the access information of the key (see OBJECT).
+#[derive(Debug)]
+pub struct Restore {
+    key: String,
+    ttl: i64,
+    payload: Vec<u8>,
+    replace: bool,
+    absttl: bool,
+    idletime: Option<i64>,
+    freq: Option<i64>,
+}
+
+// REPLICAOF host port | REPLICAOF NO ONE (also accepted as SLAVEOF)
+// None stops replicating and turns the server into a primary.
+#[derive(Debug)]
+pub struct ReplicaOf {
+    master: Option<(String, u16)>,
+}
+
+// ROLE: whether this server is a primary or a replica, with its replication offset and peers.
+#[derive(Debug)]
+pub struct Role;
+
+// REPLCONF option value [option value ...]: sent by a replica to its primary before PSYNC.
+// listening-port is remembered by network::request_handler (ROLE shows it); other options are accepted and ignored.
+// Once replicating, the replica sends REPLCONF ACK offset [FACK aof-offset] on the stream connection.
+#[derive(Debug)]
+pub struct ReplConf {
+    options: Vec<(String, String)>,
+}
+
+// PSYNC replid offset: starts replication on this connection.
+// Answered by network::request_handler, which hands the connection over to the replication stream.
+#[derive(Debug)]
+pub struct PSync {
+    replid: String,
+    offset: i64,
+}
+
+// WAIT numreplicas timeout: blocks until numreplicas replicas acknowledged the connection's last write,
+// or timeout milliseconds passed (0 waits forever). Answered by network::request_handler.
+#[derive(Debug)]
+pub struct Wait {
+    numreplicas: i64,
+    timeout: i64,
+}
+
+// WAITAOF numlocal numreplicas timeout: like WAIT, for the write being fsynced to the AOF of this server
+// (numlocal, 0 or 1) and of numreplicas replicas.
+#[derive(Debug)]
+pub struct WaitAof {
+    numlocal: i64,
+    numreplicas: i64,
+    timeout: i64,
+}
+
+// CLUSTER <subcommand> [arguments ...]
+#[derive(Debug)]
+pub struct Cluster {
+    subcommand: ClusterSubcommand,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClusterSubcommand {
+    Info,
+    MyId,
+    Nodes,
+    Slots,
+    Shards,
+    KeySlot(String),
+    CountKeysInSlot(u16),
+    AddSlots(Vec<u16>),
+    // ADDSLOTSRANGE first last [first last ...]
+    AddSlotsRange(Vec<(u16, u16)>),
+    // GETKEYSINSLOT slot count
+    GetKeysInSlot(u16, usize),
+    // SETSLOT slot NODE node-id | MIGRATING node-id | IMPORTING node-id | STABLE
+    SetSlotNode(u16, String),
+    SetSlotMigrating(u16, String),
+    SetSlotImporting(u16, String),
+    SetSlotStable(u16),
+    // MEET ip port [cluster-bus-port]
+    Meet(String, u16, u16),
+}
+
+// ASKING: the next command of the connection is served even if its slot is still being imported.
+// The flag is kept by network::request_handler.
+#[derive(Debug)]
+pub struct Asking;
+
+// DEL key [key ...]
+#[derive(Debug)]
+pub struct Del {
+    keys: Vec<String>,
+}
+
+// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [AUTH password | AUTH2 username password]
+//         [KEYS key [key ...]]
+// Moves keys to another server with DUMP payloads and RESTORE-ASKING, then deletes them here (unless COPY).
+// timeout is in milliseconds and applies to every network operation. Answered by network::request_handler.
+// Debug is implemented by hand in keyspace.rs so the password never ends up in the logs.
+pub struct Migrate {
+    host: String,
+    port: u16,
+    keys: Vec<String>,
+    db: i64,
+    timeout: u64,
+    copy: bool,
+    replace: bool,
+    // (username, password)
+    auth: Option<(Option<String>, String)>,
+}
+
+// PING [message]
+#[derive(Debug)]
+pub struct Ping {
+    message: Option<Vec<u8>>,
+}
+
+// QUIT: network::stream_handler closes the connection once the reply is sent.
+#[derive(Debug)]
+pub struct Quit;
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]
+// Switches the connection to RESP2 or RESP3 (network::request_handler keeps the protocol) and optionally
+// authenticates it. SETNAME is accepted and ignored, connections have no names.
+#[derive(Debug)]
+pub struct Hello {
+    protover: Option<String>,
+    auth: Option<Auth>,
+}
+
+// SUBSCRIBE channel [channel ...] / PSUBSCRIBE pattern [pattern ...] / SSUBSCRIBE shardchannel [shardchannel ...]
+// Answered by network::request_handler, the subscriptions belong to the connection.
+#[derive(Debug)]
+pub struct Subscribe {
+    kind: Kind,
+    channels: Vec<String>,
+}
+
+// UNSUBSCRIBE [channel ...] / PUNSUBSCRIBE [pattern ...] / SUNSUBSCRIBE [shardchannel ...]:
+// without arguments, from every channel (pattern, shard channel).
+#[derive(Debug)]
+pub struct Unsubscribe {
+    kind: Kind,
+    channels: Vec<String>,
+}
+
+// PUBLISH channel message / SPUBLISH shardchannel message
+#[derive(Debug)]
+pub struct Publish {
+    sharded: bool,
+    channel: String,
+    message: Vec<u8>,
+}
+
+// PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT | SHARDCHANNELS [pattern] | SHARDNUMSUB [shardchannel ...]
+#[derive(Debug)]
+pub struct PubSub {
+    subcommand: PubSubSubcommand,
+}
+
+// Channels and NumSub are about channels (Kind::Channel) or shard channels (Kind::Shard).
+#[derive(Debug, PartialEq)]
+pub enum PubSubSubcommand {
+    Channels(Kind, Option<String>),
+    NumSub(Kind, Vec<String>),
+    NumPat,
+}
+
+// MULTI / EXEC / DISCARD: the transaction state belongs to the connection, network::request_handler
+// queues the commands after MULTI and runs them on EXEC.
+#[derive(Debug)]
+pub struct Multi;
+
+#[derive(Debug)]
+pub struct Exec;
+
+#[derive(Debug)]
+pub struct Discard;
+
+// WATCH key [key ...] / UNWATCH: the watched keys belong to the connection too.
+#[derive(Debug)]
+pub struct Watch {
+    keys: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Unwatch;
+
+// EVAL script numkeys [key ...] [arg ...] / EVALSHA sha1 numkeys [key ...] [arg ...]
+#[derive(Debug)]
+pub struct Eval {
+    script: EvalScript,
+    keys: Vec<Vec<u8>>,
+    args: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EvalScript {
+    Body(String),
+    Sha(String),
+}
+
+// SCRIPT LOAD script / EXISTS sha1 [sha1 ...] / FLUSH [ASYNC|SYNC] / KILL
+#[derive(Debug)]
+pub struct Script {
+    subcommand: ScriptSubcommand,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScriptSubcommand {
+    Load(String),
+    Exists(Vec<String>),
+    Flush,
+    Kill,
+}
+
+// FCALL function numkeys [key ...] [arg ...] / FCALL_RO function numkeys [key ...] [arg ...]
+#[derive(Debug)]
+pub struct Fcall {
+    function: String,
+    keys: Vec<Vec<u8>>,
+    args: Vec<Vec<u8>>,
+    read_only: bool,
+}
+
+// FUNCTION LOAD [REPLACE] code / LIST [LIBRARYNAME pattern] [WITHCODE] / DELETE library / DUMP /
+// RESTORE payload [FLUSH|APPEND|REPLACE] / FLUSH [ASYNC|SYNC] / KILL
+#[derive(Debug)]
+pub struct Function {
+    subcommand: FunctionSubcommand,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FunctionSubcommand {
+    Load {
+        code: String,
+        replace: bool,
+    },
+    List {
+        pattern: Option<String>,
+        withcode: bool,
+    },
+    Delete(String),
+    Dump,
+    Restore {
+        payload: Vec<u8>,
+        policy: RestorePolicy,
+    },
+    Flush,
+    Kill,
+}
+
+// MEMORY USAGE key [SAMPLES count] / STATS / DOCTOR
+#[derive(Debug)]
+pub struct Memory {
+    subcommand: MemorySubcommand,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MemorySubcommand {
+    // samples is the number of fields of a hash looked at, 0 for all of them.
+    Usage { key: String, samples: usize },
+    Stats,
+    Doctor,
+}
+
+// OBJECT ENCODING key / IDLETIME key / FREQ key / REFCOUNT key
+#[derive(Debug)]
+pub struct Object {
+    subcommand: ObjectSubcommand,
+    key: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ObjectSubcommand {
+    Encoding,
+    IdleTime,
+    Freq,
+    RefCount,
+}
+
 #[derive(Debug)]
 pub struct Unrecognized;
 
@@ -220,18 +627,62 @@ impl TryFrom<RespArray> for Command {
             // In this case, converts cmd (a wrapper around Vec<u8>) into a &[u8] (a byte slice).
             // This is useful because many operations (e.g., comparisons, pattern matching) require working with slices rather than owned vectors.
             // b"get" is of type &[u8]
-            Some(RespFrame::BulkString(ref cmd)) => match cmd.as_ref() {
+            // Command names are case-insensitive: most client libraries send "AUTH", "GET", ... in upper case.
+            Some(RespFrame::BulkString(ref cmd)) => match cmd.to_ascii_lowercase().as_slice() {
                 // BulkString is a wrapper of vec<u8>，所以二级 match 语句，进一步通过 AsRef<u8>，来比对 b"get"，b"set" 之类的 byte string literal，也就是 byte slice。
                 b"get" => Ok(Get::try_from(v)?.into()),
                 b"set" => Ok(Set::try_from(v)?.into()),
                 b"hget" => Ok(HGet::try_from(v)?.into()),
                 b"hset" => Ok(HSet::try_from(v)?.into()),
                 b"hgetall" => Ok(HGetAll::try_from(v)?.into()),
+                b"shutdown" => Ok(Shutdown::try_from(v)?.into()),
+                b"save" => Ok(Save::try_from(v)?.into()),
+                b"bgsave" => Ok(BgSave::try_from(v)?.into()),
+                b"lastsave" => Ok(LastSave::try_from(v)?.into()),
+                b"bgrewriteaof" => Ok(BgRewriteAof::try_from(v)?.into()),
+                b"auth" => Ok(Auth::try_from(v)?.into()),
+                b"acl" => Ok(Acl::try_from(v)?.into()),
+                b"dump" => Ok(Dump::try_from(v)?.into()),
+                b"restore" | b"restore-asking" => Ok(Restore::try_from(v)?.into()),
+                b"replicaof" | b"slaveof" => Ok(ReplicaOf::try_from(v)?.into()),
+                b"role" => Ok(Role::try_from(v)?.into()),
+                b"replconf" => Ok(ReplConf::try_from(v)?.into()),
+                b"psync" => Ok(PSync::try_from(v)?.into()),
+                b"wait" => Ok(Wait::try_from(v)?.into()),
+                b"waitaof" => Ok(WaitAof::try_from(v)?.into()),
+                b"cluster" => Ok(Cluster::try_from(v)?.into()),
+                b"asking" => Ok(Asking::try_from(v)?.into()),
+                b"del" => Ok(Del::try_from(v)?.into()),
+                b"migrate" => Ok(Migrate::try_from(v)?.into()),
+                b"ping" => Ok(Ping::try_from(v)?.into()),
+                b"hello" => Ok(Hello::try_from(v)?.into()),
+                b"quit" => Ok(Quit::try_from(v)?.into()),
+                b"subscribe" | b"psubscribe" | b"ssubscribe" => Ok(Subscribe::try_from(v)?.into()),
+                b"unsubscribe" | b"punsubscribe" | b"sunsubscribe" => {
+                    Ok(Unsubscribe::try_from(v)?.into())
+                }
+                b"publish" | b"spublish" => Ok(Publish::try_from(v)?.into()),
+                b"pubsub" => Ok(PubSub::try_from(v)?.into()),
+                b"multi" => Ok(Multi::try_from(v)?.into()),
+                b"exec" => Ok(Exec::try_from(v)?.into()),
+                b"discard" => Ok(Discard::try_from(v)?.into()),
+                b"watch" => Ok(Watch::try_from(v)?.into()),
+                b"unwatch" => Ok(Unwatch::try_from(v)?.into()),
+                b"eval" | b"evalsha" => Ok(Eval::try_from(v)?.into()),
+                b"script" => Ok(Script::try_from(v)?.into()),
+                b"fcall" | b"fcall_ro" => Ok(Fcall::try_from(v)?.into()),
+                b"function" => Ok(Function::try_from(v)?.into()),
+                b"memory" => Ok(Memory::try_from(v)?.into()),
+                b"object" => Ok(Object::try_from(v)?.into()),
+                // Commands of the registered modules, see crate::module.
+                name => match module::parse_command(name, v) {
+                    Some(cmd) => cmd,
+                    None => Ok(Unrecognized.into()),
+                },
                 // _ => Err(CommandError::InvalidCommand(format!(
                 //     "Invalid command: {}",
                 //     String::from_utf8_lossy(cmd.as_ref())
                 // ))),
-                _ => Ok(Unrecognized.into()),
             },
             _ => Err(CommandError::InvalidCommand(
                 "Command must have a BulkString as the first argument".to_string(),
@@ -259,6 +710,29 @@ fn validate_command(
         )));
     }
 
+    validate_command_names(value, names)
+}
+
+// Same as validate_command, but for commands that take a variable number of arguments
+// (e.g. SHUTDOWN [NOSAVE|SAVE] [NOW] [FORCE]); only a lower bound is checked here,
+// the command's own TryFrom implementation validates the rest.
+fn validate_variadic_command(
+    value: &RespArray,
+    names: &[&'static str],
+    min_args: usize,
+) -> Result<(), CommandError> {
+    if value.len() < min_args + names.len() {
+        return Err(CommandError::InvalidArgument(format!(
+            "{} command must have at least {} argument",
+            names.join(" "),
+            min_args
+        )));
+    }
+
+    validate_command_names(value, names)
+}
+
+fn validate_command_names(value: &RespArray, names: &[&'static str]) -> Result<(), CommandError> {
     // .to_ascii_lowercase(): Makes sure command matching is case-insensitive.
     // "!= name.as_bytes()": Converts the expected command name (e.g., "set") to a byte slice
     // Then compares it to the received command, in lowercase.
@@ -308,6 +782,23 @@ fn extract_args(value: RespArray, start: usize) -> Result<Vec<RespFrame>, Comman
     Ok(value.0.into_iter().skip(start).collect::<Vec<RespFrame>>()) // 充分利用了 iterator 的级联操作
 }
 
+// Converts an argument that must be a bulk string (a key, a field, an option, ...) into a String.
+fn extract_string(frame: RespFrame) -> Result<String, CommandError> {
+    match frame {
+        RespFrame::BulkString(s) => Ok(String::from_utf8(s.0)?),
+        _ => Err(CommandError::InvalidArgument(
+            "Argument must be a BulkString".to_string(),
+        )),
+    }
+}
+
+// Converts an argument that must be an integer (a count, a timeout, a port, ...).
+fn extract_integer(frame: RespFrame) -> Result<i64, CommandError> {
+    extract_string(frame)?.parse().map_err(|_| {
+        CommandError::InvalidArgument("value is not an integer or out of range".to_string())
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,321 @@
+// SUBSCRIBE / PSUBSCRIBE / SSUBSCRIBE / UNSUBSCRIBE / PUNSUBSCRIBE / SUNSUBSCRIBE / PUBLISH / SPUBLISH /
+// PUBSUB CHANNELS / NUMSUB / NUMPAT / SHARDCHANNELS / SHARDNUMSUB
+// The broker is in crate::pubsub; the (un)subscriptions belong to a connection, so network::request_handler
+// runs them with the connection's Subscription instead of executing them.
+
+use super::{
+    extract_args, extract_string, validate_command, validate_variadic_command, CommandExecutor,
+    PubSub, PubSubSubcommand, Publish, Subscribe, Unsubscribe,
+};
+use crate::{
+    cmd::CommandError,
+    pubsub::{Kind, Message, Subscription},
+    Backend, BulkString, RespArray, RespFrame, RespNullBulkString, SimpleError,
+};
+
+impl Subscribe {
+    // One confirmation per channel (pattern), in the order they were given.
+    pub fn run(self, backend: &Backend, subscription: &Subscription) -> Vec<Message> {
+        let counts = backend
+            .pubsub
+            .subscribe(subscription, self.kind, &self.channels);
+        self.channels
+            .into_iter()
+            .zip(counts)
+            .map(|(channel, count)| confirmation(self.kind.subscribe_reply(), Some(channel), count))
+            .collect()
+    }
+}
+
+impl Unsubscribe {
+    // One confirmation per channel (pattern); a single one with a null channel when there was nothing to unsubscribe from.
+    pub fn run(self, backend: &Backend, subscription: &Subscription) -> Vec<Message> {
+        let reply = self.kind.unsubscribe_reply();
+        backend
+            .pubsub
+            .unsubscribe(subscription, self.kind, &self.channels)
+            .into_iter()
+            .map(|(channel, count)| confirmation(reply, channel, count))
+            .collect()
+    }
+}
+
+fn confirmation(reply: &str, channel: Option<String>, count: usize) -> Message {
+    let channel = match channel {
+        Some(channel) => BulkString::from(channel).into(),
+        None => RespFrame::NullBulkString(RespNullBulkString),
+    };
+    vec![
+        BulkString::from(reply).into(),
+        channel,
+        RespFrame::Integer(count as i64),
+    ]
+}
+
+impl CommandExecutor for Subscribe {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new(format!(
+            "ERR {} can only be used by a client connection",
+            self.kind.subscribe_reply().to_ascii_uppercase()
+        ))
+        .into()
+    }
+}
+
+impl CommandExecutor for Unsubscribe {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new(format!(
+            "ERR {} can only be used by a client connection",
+            self.kind.unsubscribe_reply().to_ascii_uppercase()
+        ))
+        .into()
+    }
+}
+
+impl CommandExecutor for Publish {
+    fn execute(self, backend: &Backend) -> RespFrame {
+        let receivers = if self.sharded {
+            backend.pubsub.spublish(&self.channel, &self.message)
+        } else {
+            backend.pubsub.publish(&self.channel, &self.message)
+        };
+        RespFrame::Integer(receivers as i64)
+    }
+}
+
+impl CommandExecutor for PubSub {
+    fn execute(self, backend: &Backend) -> RespFrame {
+        let pubsub = &backend.pubsub;
+        match self.subcommand {
+            PubSubSubcommand::Channels(kind, pattern) => RespArray::new(
+                pubsub
+                    .channels(kind, pattern.as_deref())
+                    .into_iter()
+                    .map(|channel| BulkString::from(channel).into())
+                    .collect::<Vec<_>>(),
+            )
+            .into(),
+            // A flat array of channel, count pairs, like redis (also under RESP3).
+            PubSubSubcommand::NumSub(kind, channels) => RespArray::new(
+                channels
+                    .into_iter()
+                    .flat_map(|channel| {
+                        let count = pubsub.numsub(kind, &channel) as i64;
+                        [BulkString::from(channel).into(), RespFrame::Integer(count)]
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .into(),
+            PubSubSubcommand::NumPat => RespFrame::Integer(pubsub.numpat() as i64),
+        }
+    }
+}
+
+impl TryFrom<RespArray> for Subscribe {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        let kind = match value.first() {
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"psubscribe") => {
+                Kind::Pattern
+            }
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"ssubscribe") => {
+                Kind::Shard
+            }
+            _ => Kind::Channel,
+        };
+        validate_variadic_command(&value, &[kind.subscribe_reply()], 1)?;
+
+        let channels = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<_, _>>()?;
+        Ok(Subscribe { kind, channels })
+    }
+}
+
+impl TryFrom<RespArray> for Unsubscribe {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        let kind = match value.first() {
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"punsubscribe") => {
+                Kind::Pattern
+            }
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"sunsubscribe") => {
+                Kind::Shard
+            }
+            _ => Kind::Channel,
+        };
+        validate_variadic_command(&value, &[kind.unsubscribe_reply()], 0)?;
+
+        let channels = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<_, _>>()?;
+        Ok(Unsubscribe { kind, channels })
+    }
+}
+
+impl TryFrom<RespArray> for Publish {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        let sharded = matches!(
+            value.first(),
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"spublish")
+        );
+        let name = if sharded { "spublish" } else { "publish" };
+        validate_command(&value, &[name], 2)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        match (args.next(), args.next()) {
+            (Some(RespFrame::BulkString(channel)), Some(RespFrame::BulkString(message))) => {
+                Ok(Publish {
+                    sharded,
+                    channel: String::from_utf8(channel.0)?,
+                    message: message.0,
+                })
+            }
+            _ => Err(CommandError::InvalidArgument(
+                "Invalid channel or message".to_string(),
+            )),
+        }
+    }
+}
+
+impl TryFrom<RespArray> for PubSub {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["pubsub"], 1)?;
+
+        let mut args = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<Vec<String>, CommandError>>()?
+            .into_iter();
+        let name = args.next().unwrap_or_default().to_ascii_lowercase();
+        let mut args = args.collect::<Vec<String>>();
+
+        let subcommand = match (name.as_str(), args.len()) {
+            ("channels", 0) => PubSubSubcommand::Channels(Kind::Channel, None),
+            ("channels", 1) => PubSubSubcommand::Channels(Kind::Channel, args.pop()),
+            ("shardchannels", 0) => PubSubSubcommand::Channels(Kind::Shard, None),
+            ("shardchannels", 1) => PubSubSubcommand::Channels(Kind::Shard, args.pop()),
+            ("numsub", _) => PubSubSubcommand::NumSub(Kind::Channel, args),
+            ("shardnumsub", _) => PubSubSubcommand::NumSub(Kind::Shard, args),
+            ("numpat", 0) => PubSubSubcommand::NumPat,
+            ("channels" | "shardchannels" | "numpat", _) => {
+                return Err(CommandError::InvalidArgument(format!(
+                    "wrong number of arguments for 'pubsub|{}' command",
+                    name
+                )))
+            }
+            _ => {
+                return Err(CommandError::InvalidCommand(format!(
+                    "unknown subcommand '{}'. Try PUBSUB HELP.",
+                    name
+                )))
+            }
+        };
+        Ok(PubSub { subcommand })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RespDecode;
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    fn array(args: &[&str]) -> Result<RespArray> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+        for arg in args {
+            buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        Ok(RespArray::decode(&mut buf)?)
+    }
+
+    #[test]
+    fn test_pubsub_from_resp_array() -> Result<()> {
+        let subscribe: Subscribe = array(&["PSUBSCRIBE", "news.*", "weather"])?.try_into()?;
+        assert_eq!(subscribe.kind, Kind::Pattern);
+        assert_eq!(subscribe.channels, vec!["news.*", "weather"]);
+        assert!(Subscribe::try_from(array(&["subscribe"])?).is_err());
+
+        let unsubscribe: Unsubscribe = array(&["unsubscribe"])?.try_into()?;
+        assert_eq!(unsubscribe.kind, Kind::Channel);
+        assert!(unsubscribe.channels.is_empty());
+
+        let publish: Publish = array(&["publish", "news", "hello"])?.try_into()?;
+        assert_eq!(publish.channel, "news");
+        assert_eq!(publish.message, b"hello");
+        assert!(!publish.sharded);
+        let publish: Publish = array(&["SPUBLISH", "news", "hello"])?.try_into()?;
+        assert!(publish.sharded);
+        let subscribe: Subscribe = array(&["ssubscribe", "news"])?.try_into()?;
+        assert_eq!(subscribe.kind, Kind::Shard);
+        assert!(Publish::try_from(array(&["publish", "news"])?).is_err());
+
+        let pubsub = |args: &[&str]| -> Result<PubSubSubcommand> {
+            let pubsub: PubSub = array(args)?.try_into()?;
+            Ok(pubsub.subcommand)
+        };
+        assert_eq!(
+            pubsub(&["pubsub", "channels", "n*"])?,
+            PubSubSubcommand::Channels(Kind::Channel, Some("n*".to_string()))
+        );
+        assert_eq!(
+            pubsub(&["pubsub", "NUMSUB", "a", "b"])?,
+            PubSubSubcommand::NumSub(Kind::Channel, vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            pubsub(&["pubsub", "shardchannels"])?,
+            PubSubSubcommand::Channels(Kind::Shard, None)
+        );
+        assert_eq!(pubsub(&["pubsub", "numpat"])?, PubSubSubcommand::NumPat);
+        assert!(pubsub(&["pubsub", "numpat", "x"]).is_err());
+        assert!(pubsub(&["pubsub", "nosuchsubcommand"]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pubsub_commands() -> Result<()> {
+        let backend = Backend::new();
+        let subscription = Subscription::new(&backend, 1);
+        let subscribe: Subscribe = array(&["subscribe", "news", "weather"])?.try_into()?;
+        let replies = subscribe.run(&backend, &subscription);
+        assert_eq!(
+            replies[1],
+            vec![
+                BulkString::from("subscribe").into(),
+                BulkString::from("weather").into(),
+                RespFrame::Integer(2),
+            ]
+        );
+
+        let publish: Publish = array(&["publish", "news", "hello"])?.try_into()?;
+        assert_eq!(publish.execute(&backend), RespFrame::Integer(1));
+        let numsub: PubSub = array(&["pubsub", "numsub", "news", "sports"])?.try_into()?;
+        assert_eq!(
+            numsub.execute(&backend),
+            RespArray::new(vec![
+                BulkString::from("news").into(),
+                RespFrame::Integer(1),
+                BulkString::from("sports").into(),
+                RespFrame::Integer(0),
+            ])
+            .into()
+        );
+
+        let unsubscribe: Unsubscribe = array(&["punsubscribe"])?.try_into()?;
+        assert_eq!(
+            unsubscribe.run(&backend, &subscription),
+            vec![vec![
+                BulkString::from("punsubscribe").into(),
+                RespFrame::NullBulkString(RespNullBulkString),
+                RespFrame::Integer(2),
+            ]]
+        );
+        Ok(())
+    }
+}
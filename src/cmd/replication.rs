@@ -0,0 +1,365 @@
+// Replication commands: REPLICAOF turns the server into a replica (or back into a primary), ROLE reports the
+// replication state, and REPLCONF / PSYNC are what a replica sends to its primary (see replication.rs).
+// WAIT and WAITAOF block the client until its writes reached the replicas; they are answered by network.rs.
+
+use super::{
+    extract_args, extract_integer, extract_string, validate_command, validate_variadic_command,
+    CommandExecutor, PSync, ReplConf, ReplicaOf, Role, Wait, WaitAof, RESP_OK,
+};
+use crate::{
+    cmd::CommandError,
+    replication::{self, LinkStatus, RoleInfo},
+    Backend, BulkString, RespArray, RespFrame, SimpleError, SimpleString,
+};
+use std::time::Duration;
+
+impl ReplConf {
+    // The port the replica accepts clients on, as announced with REPLCONF listening-port.
+    pub fn listening_port(&self) -> Option<u16> {
+        self.options
+            .iter()
+            .find(|(option, _)| option == "listening-port")
+            .and_then(|(_, value)| value.parse().ok())
+    }
+
+    // REPLCONF ACK offset [FACK aof-offset]: the offsets the replica received and fsynced to its AOF.
+    pub fn ack(&self) -> Option<(u64, Option<u64>)> {
+        let value = |name: &str| {
+            self.options
+                .iter()
+                .find(|(option, _)| option == name)
+                .and_then(|(_, value)| value.parse().ok())
+        };
+        value("ack").map(|offset| (offset, value("fack")))
+    }
+}
+
+impl Wait {
+    // The number of replicas and the timeout (None waits forever), or the error reply.
+    pub fn target(&self, backend: &Backend) -> Result<(usize, Option<Duration>), SimpleError> {
+        if backend.replication.is_replica() {
+            return Err(SimpleError::new(
+                "ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated.",
+            ));
+        }
+        Ok((
+            self.numreplicas.max(0) as usize,
+            parse_timeout(self.timeout)?,
+        ))
+    }
+}
+
+impl WaitAof {
+    // The number of replicas and the timeout (None waits forever), or the error reply.
+    // numlocal only needs checking: the local AOF is fsynced right away, not waited for.
+    pub fn target(&self, backend: &Backend) -> Result<(usize, Option<Duration>), SimpleError> {
+        if backend.replication.is_replica() {
+            return Err(SimpleError::new(
+                "ERR WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated.",
+            ));
+        }
+        let timeout = parse_timeout(self.timeout)?;
+        if self.numlocal > 0 && !backend.aof.is_enabled() {
+            return Err(SimpleError::new(
+                "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled.",
+            ));
+        }
+        Ok((self.numreplicas.max(0) as usize, timeout))
+    }
+}
+
+fn parse_timeout(timeout: i64) -> Result<Option<Duration>, SimpleError> {
+    match timeout {
+        timeout if timeout < 0 => Err(SimpleError::new("ERR timeout is negative")),
+        0 => Ok(None),
+        timeout => Ok(Some(Duration::from_millis(timeout as u64))),
+    }
+}
+
+impl PSync {
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+}
+
+impl CommandExecutor for ReplicaOf {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        if backend.config.cluster_enabled {
+            return SimpleError::new("ERR REPLICAOF not allowed in cluster mode.").into();
+        }
+        SimpleString::new(replication::replicaof(backend, self.master)).into()
+    }
+}
+
+impl CommandExecutor for Role {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        let bulk = |s: String| -> RespFrame { BulkString::from(s).into() };
+        let frames: Vec<RespFrame> = match backend.replication.role() {
+            RoleInfo::Master { offset, replicas } => vec![
+                bulk("master".to_string()),
+                RespFrame::Integer(offset as i64),
+                RespArray::new(
+                    replicas
+                        .into_iter()
+                        .map(|(ip, port, ack_offset)| {
+                            RespArray::new(vec![
+                                bulk(ip),
+                                bulk(port.to_string()),
+                                bulk(ack_offset.to_string()),
+                            ])
+                            .into()
+                        })
+                        .collect::<Vec<RespFrame>>(),
+                )
+                .into(),
+            ],
+            RoleInfo::Replica {
+                host,
+                port,
+                status,
+                offset,
+            } => {
+                let status = match status {
+                    LinkStatus::Connecting => "connecting",
+                    LinkStatus::Sync => "sync",
+                    LinkStatus::Connected => "connected",
+                };
+                vec![
+                    bulk("slave".to_string()),
+                    bulk(host),
+                    RespFrame::Integer(port as i64),
+                    bulk(status.to_string()),
+                    RespFrame::Integer(offset as i64),
+                ]
+            }
+        };
+        RespArray::new(frames).into()
+    }
+}
+
+impl CommandExecutor for ReplConf {
+    fn execute(self, _: &crate::Backend) -> RespFrame {
+        RESP_OK.clone()
+    }
+}
+
+impl CommandExecutor for PSync {
+    fn execute(self, _: &crate::Backend) -> RespFrame {
+        // Only reached when the command is not sent over a client connection (e.g. replayed from the AOF).
+        SimpleError::new("ERR PSYNC can only be used by a replica connection").into()
+    }
+}
+
+impl CommandExecutor for Wait {
+    fn execute(self, _: &crate::Backend) -> RespFrame {
+        // Only reached when the command is not sent over a client connection (e.g. replayed from the AOF).
+        SimpleError::new("ERR WAIT can only be used by a client connection").into()
+    }
+}
+
+impl CommandExecutor for WaitAof {
+    fn execute(self, _: &crate::Backend) -> RespFrame {
+        SimpleError::new("ERR WAITAOF can only be used by a client connection").into()
+    }
+}
+
+impl TryFrom<RespArray> for ReplicaOf {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        let name = match value.first() {
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"slaveof") => "slaveof",
+            _ => "replicaof",
+        };
+        validate_command(&value, &[name], 2)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        let host = extract_string(args.next().unwrap())?;
+        let port = extract_string(args.next().unwrap())?;
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            return Ok(ReplicaOf { master: None });
+        }
+        let port = port
+            .parse()
+            .map_err(|_| CommandError::InvalidArgument("Invalid master port".to_string()))?;
+        Ok(ReplicaOf {
+            master: Some((host, port)),
+        })
+    }
+}
+
+impl TryFrom<RespArray> for Role {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["role"], 0)?;
+        Ok(Role)
+    }
+}
+
+impl TryFrom<RespArray> for ReplConf {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["replconf"], 0)?;
+
+        let args = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        if args.len() % 2 != 0 {
+            return Err(CommandError::InvalidArgument("syntax error".to_string()));
+        }
+        let options = args
+            .chunks(2)
+            .map(|pair| (pair[0].to_ascii_lowercase(), pair[1].clone()))
+            .collect();
+        Ok(ReplConf { options })
+    }
+}
+
+impl TryFrom<RespArray> for PSync {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["psync"], 2)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        let replid = extract_string(args.next().unwrap())?;
+        let offset = extract_string(args.next().unwrap())?
+            .parse()
+            .map_err(|_| CommandError::InvalidArgument("Invalid offset".to_string()))?;
+        Ok(PSync { replid, offset })
+    }
+}
+
+impl TryFrom<RespArray> for Wait {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["wait"], 2)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        Ok(Wait {
+            numreplicas: extract_integer(args.next().unwrap())?,
+            timeout: extract_integer(args.next().unwrap())?,
+        })
+    }
+}
+
+impl TryFrom<RespArray> for WaitAof {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["waitaof"], 3)?;
+
+        let mut args = extract_args(value, 1)?.into_iter();
+        Ok(WaitAof {
+            numlocal: extract_integer(args.next().unwrap())?,
+            numreplicas: extract_integer(args.next().unwrap())?,
+            timeout: extract_integer(args.next().unwrap())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cmd::Command, Backend, RespDecode};
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    fn parse(input: &[u8]) -> Result<Command> {
+        let mut buf = BytesMut::from(input);
+        Ok(Command::try_from(RespArray::decode(&mut buf)?)?)
+    }
+
+    #[test]
+    fn test_replication_commands_from_resp_array() -> Result<()> {
+        let Command::ReplicaOf(cmd) =
+            parse(b"*3\r\n$7\r\nSLAVEOF\r\n$9\r\nlocalhost\r\n$4\r\n6380\r\n")?
+        else {
+            panic!("not a REPLICAOF");
+        };
+        assert_eq!(cmd.master, Some(("localhost".to_string(), 6380)));
+        let Command::ReplicaOf(cmd) = parse(b"*3\r\n$9\r\nreplicaof\r\n$2\r\nNO\r\n$3\r\none\r\n")?
+        else {
+            panic!("not a REPLICAOF");
+        };
+        assert_eq!(cmd.master, None);
+        assert!(parse(b"*3\r\n$9\r\nreplicaof\r\n$4\r\nhost\r\n$4\r\nport\r\n").is_err());
+
+        let Command::ReplConf(cmd) =
+            parse(b"*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6380\r\n")?
+        else {
+            panic!("not a REPLCONF");
+        };
+        assert_eq!(cmd.listening_port(), Some(6380));
+        assert!(parse(b"*2\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n").is_err());
+
+        let Command::PSync(cmd) = parse(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")? else {
+            panic!("not a PSYNC");
+        };
+        assert_eq!((cmd.replid(), cmd.offset()), ("?", -1));
+
+        let Command::ReplConf(cmd) = parse(
+            b"*5\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n$3\r\n120\r\n$4\r\nFACK\r\n$3\r\n100\r\n",
+        )?
+        else {
+            panic!("not a REPLCONF");
+        };
+        assert_eq!(cmd.ack(), Some((120, Some(100))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_commands() -> Result<()> {
+        let backend = Backend::new();
+        let Command::Wait(wait) = parse(b"*3\r\n$4\r\nWAIT\r\n$1\r\n2\r\n$3\r\n500\r\n")? else {
+            panic!("not a WAIT");
+        };
+        assert_eq!(
+            wait.target(&backend),
+            Ok((2, Some(Duration::from_millis(500))))
+        );
+        let Command::Wait(wait) = parse(b"*3\r\n$4\r\nWAIT\r\n$1\r\n1\r\n$2\r\n-1\r\n")? else {
+            panic!("not a WAIT");
+        };
+        assert_eq!(
+            wait.target(&backend),
+            Err(SimpleError::new("ERR timeout is negative"))
+        );
+        assert!(parse(b"*3\r\n$4\r\nWAIT\r\n$3\r\none\r\n$1\r\n0\r\n").is_err());
+
+        let Command::WaitAof(wait) =
+            parse(b"*4\r\n$7\r\nWAITAOF\r\n$1\r\n0\r\n$1\r\n1\r\n$1\r\n0\r\n")?
+        else {
+            panic!("not a WAITAOF");
+        };
+        assert_eq!(wait.target(&backend), Ok((1, None)));
+        let Command::WaitAof(wait) =
+            parse(b"*4\r\n$7\r\nWAITAOF\r\n$1\r\n1\r\n$1\r\n0\r\n$1\r\n0\r\n")?
+        else {
+            panic!("not a WAITAOF");
+        };
+        assert_eq!(
+            wait.target(&backend),
+            Err(SimpleError::new(
+                "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_role_command() {
+        let backend = Backend::new();
+        assert_eq!(
+            Role.execute(&backend),
+            RespArray::new(vec![
+                BulkString::from("master").into(),
+                RespFrame::Integer(0),
+                RespArray::new(Vec::new()).into(),
+            ])
+            .into()
+        );
+    }
+}
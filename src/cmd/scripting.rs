@@ -0,0 +1,561 @@
+// EVAL / EVALSHA / SCRIPT LOAD / EXISTS / FLUSH / KILL, FCALL / FCALL_RO and
+// FUNCTION LOAD / LIST / DELETE / DUMP / RESTORE / FLUSH / KILL
+// The Lua side is in crate::scripting. The commands a script runs with redis.call are checked and logged like the
+// commands of the connection that runs the script, so network::request_handler runs EVAL and FCALL with its own call.
+
+use super::{
+    extract_args, extract_integer, extract_string, validate_variadic_command, CommandExecutor,
+    Eval, EvalScript, Fcall, Function, FunctionSubcommand, Script, ScriptSubcommand, RESP_OK,
+};
+use crate::{
+    cmd::CommandError, glob::glob_match, persistence::rdb, scripting::RestorePolicy, Backend,
+    BulkString, RespArray, RespFrame, RespMap, RespNull, RespSet, SimpleError,
+};
+
+impl Eval {
+    // Runs the script; call executes the commands of redis.call / redis.pcall.
+    pub fn run(self, backend: &Backend, call: &mut dyn FnMut(RespFrame) -> RespFrame) -> RespFrame {
+        let body = match self.script {
+            // Like in redis, EVAL caches the script too.
+            EvalScript::Body(body) => {
+                backend.scripting.load(&body);
+                body
+            }
+            EvalScript::Sha(sha) => match backend.scripting.get(&sha) {
+                Some(body) => body,
+                None => {
+                    return SimpleError::new("NOSCRIPT No matching script. Please use EVAL.").into()
+                }
+            },
+        };
+        backend.scripting.run(&body, self.keys, self.args, call)
+    }
+}
+
+impl CommandExecutor for Eval {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new("ERR EVAL can only be used by a client connection").into()
+    }
+}
+
+impl CommandExecutor for Script {
+    fn execute(self, backend: &Backend) -> RespFrame {
+        let scripting = &backend.scripting;
+        match self.subcommand {
+            ScriptSubcommand::Load(body) => BulkString::from(scripting.load(&body)).into(),
+            ScriptSubcommand::Exists(shas) => RespArray::new(
+                shas.iter()
+                    .map(|sha| RespFrame::Integer(scripting.exists(sha) as i64))
+                    .collect::<Vec<_>>(),
+            )
+            .into(),
+            ScriptSubcommand::Flush => {
+                scripting.flush();
+                RESP_OK.clone()
+            }
+            ScriptSubcommand::Kill => match scripting.kill() {
+                Ok(()) => RESP_OK.clone(),
+                Err(e) => SimpleError::new(e).into(),
+            },
+        }
+    }
+}
+
+impl Fcall {
+    // Calls the function; call executes the commands of redis.call / redis.pcall.
+    pub fn run(self, backend: &Backend, call: &mut dyn FnMut(RespFrame) -> RespFrame) -> RespFrame {
+        backend
+            .scripting
+            .fcall(&self.function, self.keys, self.args, self.read_only, call)
+    }
+}
+
+impl Function {
+    pub fn is_kill(&self) -> bool {
+        self.subcommand == FunctionSubcommand::Kill
+    }
+}
+
+impl CommandExecutor for Fcall {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new("ERR FCALL can only be used by a client connection").into()
+    }
+}
+
+impl CommandExecutor for Function {
+    fn execute(self, backend: &Backend) -> RespFrame {
+        let scripting = &backend.scripting;
+        match self.subcommand {
+            FunctionSubcommand::Load { code, replace } => {
+                match scripting.load_library(&code, replace) {
+                    Ok(name) => BulkString::from(name).into(),
+                    Err(e) => SimpleError::new(e).into(),
+                }
+            }
+            FunctionSubcommand::List { pattern, withcode } => RespArray::new(
+                scripting
+                    .libraries()
+                    .into_iter()
+                    .filter(|library| {
+                        pattern.as_ref().is_none_or(|pattern| {
+                            glob_match(pattern.as_bytes(), library.name.as_bytes())
+                        })
+                    })
+                    .map(|library| {
+                        let functions = library
+                            .functions
+                            .into_iter()
+                            .map(|function| {
+                                let mut map = RespMap::new();
+                                map.insert(
+                                    "name".to_string(),
+                                    BulkString::from(function.name).into(),
+                                );
+                                map.insert("description".to_string(), RespFrame::Null(RespNull));
+                                let flags = function
+                                    .flags
+                                    .into_iter()
+                                    .map(|flag| BulkString::from(flag).into())
+                                    .collect::<Vec<RespFrame>>();
+                                map.insert("flags".to_string(), RespSet::new(flags).into());
+                                map.into()
+                            })
+                            .collect::<Vec<RespFrame>>();
+                        let mut map = RespMap::new();
+                        map.insert(
+                            "library_name".to_string(),
+                            BulkString::from(library.name).into(),
+                        );
+                        map.insert("engine".to_string(), BulkString::from("LUA").into());
+                        map.insert("functions".to_string(), RespArray::new(functions).into());
+                        if withcode {
+                            map.insert(
+                                "library_code".to_string(),
+                                BulkString::from(library.code).into(),
+                            );
+                        }
+                        map.into()
+                    })
+                    .collect::<Vec<RespFrame>>(),
+            )
+            .into(),
+            FunctionSubcommand::Delete(name) => {
+                if scripting.delete_library(&name) {
+                    RESP_OK.clone()
+                } else {
+                    SimpleError::new("ERR Library not found").into()
+                }
+            }
+            FunctionSubcommand::Dump => {
+                let codes = scripting
+                    .libraries()
+                    .into_iter()
+                    .map(|library| library.code)
+                    .collect::<Vec<_>>();
+                BulkString::new(rdb::dump_functions(&codes)).into()
+            }
+            FunctionSubcommand::Restore { payload, policy } => {
+                if !rdb::is_valid_dump(&payload) {
+                    return SimpleError::new("ERR payload version or checksum are wrong").into();
+                }
+                let Ok(codes) = rdb::parse_functions(&payload) else {
+                    return SimpleError::new("ERR given type is not a function").into();
+                };
+                match scripting.restore_libraries(&codes, policy) {
+                    Ok(()) => RESP_OK.clone(),
+                    Err(e) => SimpleError::new(e).into(),
+                }
+            }
+            FunctionSubcommand::Flush => {
+                scripting.flush_libraries();
+                RESP_OK.clone()
+            }
+            FunctionSubcommand::Kill => match scripting.kill() {
+                Ok(()) => RESP_OK.clone(),
+                Err(e) => SimpleError::new(e).into(),
+            },
+        }
+    }
+}
+
+// The arguments of EVAL / FCALL after the command name: the script (or function), then numkeys keys and the arguments.
+type ScriptArgs = (String, Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+fn script_args(value: RespArray) -> Result<ScriptArgs, CommandError> {
+    let mut args = extract_args(value, 1)?.into_iter();
+    let (Some(script), Some(numkeys)) = (args.next(), args.next()) else {
+        return Err(CommandError::InvalidArgument(
+            "Invalid script or numkeys".to_string(),
+        ));
+    };
+    let script = extract_string(script)?;
+    let numkeys = extract_integer(numkeys)?;
+    let args = bytes_args(args)?;
+    if numkeys < 0 {
+        return Err(CommandError::InvalidArgument(
+            "Number of keys can't be negative".to_string(),
+        ));
+    }
+    if numkeys as usize > args.len() {
+        return Err(CommandError::InvalidArgument(
+            "Number of keys can't be greater than number of args".to_string(),
+        ));
+    }
+    let mut keys = args;
+    let args = keys.split_off(numkeys as usize);
+    Ok((script, keys, args))
+}
+
+// Arguments that do not have to be UTF-8: keys, script arguments, a FUNCTION RESTORE payload.
+fn bytes_args(args: impl Iterator<Item = RespFrame>) -> Result<Vec<Vec<u8>>, CommandError> {
+    args.map(|arg| match arg {
+        RespFrame::BulkString(arg) => Ok(arg.0),
+        _ => Err(CommandError::InvalidArgument(
+            "Argument must be a BulkString".to_string(),
+        )),
+    })
+    .collect()
+}
+
+impl TryFrom<RespArray> for Eval {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        let sha = matches!(
+            value.first(),
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"evalsha")
+        );
+        validate_variadic_command(&value, &[if sha { "evalsha" } else { "eval" }], 2)?;
+
+        let (script, keys, args) = script_args(value)?;
+        let script = if sha {
+            EvalScript::Sha(script)
+        } else {
+            EvalScript::Body(script)
+        };
+        Ok(Eval { script, keys, args })
+    }
+}
+
+impl TryFrom<RespArray> for Script {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["script"], 1)?;
+
+        let mut args = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<Vec<String>, CommandError>>()?
+            .into_iter();
+        let name = args.next().unwrap_or_default().to_ascii_lowercase();
+        let mut args = args.collect::<Vec<String>>();
+
+        let subcommand = match (name.as_str(), args.len()) {
+            ("load", 1) => ScriptSubcommand::Load(args.pop().unwrap_or_default()),
+            ("exists", n) if n > 0 => ScriptSubcommand::Exists(args),
+            // The cache is flushed right away either way.
+            ("flush", 0) => ScriptSubcommand::Flush,
+            ("flush", 1) if ["async", "sync"].contains(&args[0].to_ascii_lowercase().as_str()) => {
+                ScriptSubcommand::Flush
+            }
+            ("kill", 0) => ScriptSubcommand::Kill,
+            ("load" | "exists" | "flush" | "kill", _) => {
+                return Err(CommandError::InvalidArgument(format!(
+                    "wrong number of arguments for 'script|{}' command",
+                    name
+                )))
+            }
+            _ => {
+                return Err(CommandError::InvalidCommand(format!(
+                    "unknown subcommand '{}'. Try SCRIPT HELP.",
+                    name
+                )))
+            }
+        };
+        Ok(Script { subcommand })
+    }
+}
+
+impl TryFrom<RespArray> for Fcall {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        let read_only = matches!(
+            value.first(),
+            Some(RespFrame::BulkString(name)) if name.eq_ignore_ascii_case(b"fcall_ro")
+        );
+        validate_variadic_command(&value, &[if read_only { "fcall_ro" } else { "fcall" }], 2)?;
+
+        let (function, keys, args) = script_args(value)?;
+        Ok(Fcall {
+            function,
+            keys,
+            args,
+            read_only,
+        })
+    }
+}
+
+impl TryFrom<RespArray> for Function {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["function"], 1)?;
+
+        let mut args = bytes_args(extract_args(value, 1)?.into_iter())?.into_iter();
+        let text = |arg: Vec<u8>| String::from_utf8_lossy(&arg).into_owned();
+        let name = text(args.next().unwrap_or_default()).to_ascii_lowercase();
+        let mut args = args.collect::<Vec<Vec<u8>>>();
+        let is = |arg: &[u8], option: &str| arg.eq_ignore_ascii_case(option.as_bytes());
+        let wrong_args = || {
+            CommandError::InvalidArgument(format!(
+                "wrong number of arguments for 'function|{}' command",
+                name
+            ))
+        };
+
+        let subcommand = match (name.as_str(), args.len()) {
+            ("load", 1) => FunctionSubcommand::Load {
+                code: text(args.pop().unwrap_or_default()),
+                replace: false,
+            },
+            ("load", 2) if is(&args[0], "replace") => FunctionSubcommand::Load {
+                code: text(args.pop().unwrap_or_default()),
+                replace: true,
+            },
+            ("list", _) => {
+                let (mut pattern, mut withcode) = (None, false);
+                let mut args = args.into_iter();
+                while let Some(arg) = args.next() {
+                    if is(&arg, "withcode") {
+                        withcode = true;
+                    } else if is(&arg, "libraryname") {
+                        let Some(value) = args.next() else {
+                            return Err(CommandError::InvalidArgument(
+                                "library name argument was not given".to_string(),
+                            ));
+                        };
+                        pattern = Some(text(value));
+                    } else {
+                        return Err(CommandError::InvalidArgument(format!(
+                            "Unknown argument {}",
+                            text(arg)
+                        )));
+                    }
+                }
+                FunctionSubcommand::List { pattern, withcode }
+            }
+            ("delete", 1) => FunctionSubcommand::Delete(text(args.pop().unwrap_or_default())),
+            ("dump", 0) => FunctionSubcommand::Dump,
+            ("restore", 1 | 2) => {
+                let policy = match args.get(1) {
+                    None => RestorePolicy::Append,
+                    Some(policy) if is(policy, "append") => RestorePolicy::Append,
+                    Some(policy) if is(policy, "replace") => RestorePolicy::Replace,
+                    Some(policy) if is(policy, "flush") => RestorePolicy::Flush,
+                    Some(_) => {
+                        return Err(CommandError::InvalidArgument(
+                            "Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.".to_string(),
+                        ))
+                    }
+                };
+                FunctionSubcommand::Restore {
+                    payload: args.swap_remove(0),
+                    policy,
+                }
+            }
+            // Like SCRIPT FLUSH, the libraries are dropped right away either way.
+            ("flush", 0) => FunctionSubcommand::Flush,
+            ("flush", 1) if is(&args[0], "async") || is(&args[0], "sync") => {
+                FunctionSubcommand::Flush
+            }
+            ("kill", 0) => FunctionSubcommand::Kill,
+            ("load" | "delete" | "dump" | "restore" | "flush" | "kill", _) => {
+                return Err(wrong_args())
+            }
+            _ => {
+                return Err(CommandError::InvalidCommand(format!(
+                    "unknown subcommand '{}'. Try FUNCTION HELP.",
+                    name
+                )))
+            }
+        };
+        Ok(Function { subcommand })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RespDecode;
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    fn array(args: &[&str]) -> Result<RespArray> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+        for arg in args {
+            buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        Ok(RespArray::decode(&mut buf)?)
+    }
+
+    #[test]
+    fn test_scripting_from_resp_array() -> Result<()> {
+        let eval: Eval = array(&["EVAL", "return 1", "2", "a", "b", "c"])?.try_into()?;
+        assert_eq!(eval.script, EvalScript::Body("return 1".to_string()));
+        assert_eq!(eval.keys, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(eval.args, vec![b"c".to_vec()]);
+        let eval: Eval = array(&["evalsha", "abc", "0"])?.try_into()?;
+        assert_eq!(eval.script, EvalScript::Sha("abc".to_string()));
+        assert!(Eval::try_from(array(&["eval", "return 1", "2", "a"])?).is_err());
+        assert!(Eval::try_from(array(&["eval", "return 1", "-1"])?).is_err());
+        assert!(Eval::try_from(array(&["eval", "return 1"])?).is_err());
+
+        let script: Script = array(&["script", "EXISTS", "a", "b"])?.try_into()?;
+        assert_eq!(
+            script.subcommand,
+            ScriptSubcommand::Exists(vec!["a".to_string(), "b".to_string()])
+        );
+        let script: Script = array(&["script", "flush", "async"])?.try_into()?;
+        assert_eq!(script.subcommand, ScriptSubcommand::Flush);
+        assert!(Script::try_from(array(&["script", "kill", "now"])?).is_err());
+        assert!(Script::try_from(array(&["script", "debug"])?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_commands() -> Result<()> {
+        let backend = Backend::new();
+        let sha = "e0e1f9fabfc9d4800c877a703b823ac0578ff8db";
+        let load: Script = array(&["script", "load", "return 1"])?.try_into()?;
+        assert_eq!(load.execute(&backend), BulkString::from(sha).into());
+        let exists: Script = array(&["script", "exists", sha, "nosuchsha"])?.try_into()?;
+        assert_eq!(
+            exists.execute(&backend),
+            RespArray::new(vec![RespFrame::Integer(1), RespFrame::Integer(0)]).into()
+        );
+
+        let mut no_call = |_| RespFrame::Integer(0);
+        let evalsha: Eval = array(&["evalsha", sha, "0"])?.try_into()?;
+        assert_eq!(evalsha.run(&backend, &mut no_call), RespFrame::Integer(1));
+
+        let flush: Script = array(&["script", "flush"])?.try_into()?;
+        assert_eq!(flush.execute(&backend), RESP_OK.clone());
+        let evalsha: Eval = array(&["evalsha", sha, "0"])?.try_into()?;
+        assert_eq!(
+            evalsha.run(&backend, &mut no_call),
+            SimpleError::new("NOSCRIPT No matching script. Please use EVAL.").into()
+        );
+        let kill: Script = array(&["script", "kill"])?.try_into()?;
+        assert_eq!(
+            kill.execute(&backend),
+            SimpleError::new("NOTBUSY No scripts in execution right now.").into()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_from_resp_array() -> Result<()> {
+        let fcall: Fcall = array(&["FCALL_RO", "f", "1", "k", "a"])?.try_into()?;
+        assert_eq!(fcall.function, "f");
+        assert!(fcall.read_only);
+        assert_eq!(fcall.keys, vec![b"k".to_vec()]);
+        assert_eq!(fcall.args, vec![b"a".to_vec()]);
+        assert!(Fcall::try_from(array(&["fcall", "f", "2", "k"])?).is_err());
+
+        let function: Function = array(&["function", "LOAD", "replace", "code"])?.try_into()?;
+        assert_eq!(
+            function.subcommand,
+            FunctionSubcommand::Load {
+                code: "code".to_string(),
+                replace: true
+            }
+        );
+        let function: Function =
+            array(&["function", "list", "withcode", "libraryname", "my*"])?.try_into()?;
+        assert_eq!(
+            function.subcommand,
+            FunctionSubcommand::List {
+                pattern: Some("my*".to_string()),
+                withcode: true
+            }
+        );
+        let function: Function = array(&["function", "restore", "payload", "FLUSH"])?.try_into()?;
+        assert_eq!(
+            function.subcommand,
+            FunctionSubcommand::Restore {
+                payload: b"payload".to_vec(),
+                policy: RestorePolicy::Flush
+            }
+        );
+        assert!(Function::try_from(array(&["function", "restore", "payload", "merge"])?).is_err());
+        assert!(Function::try_from(array(&["function", "list", "libraryname"])?).is_err());
+        assert!(Function::try_from(array(&["function", "dump", "now"])?).is_err());
+        assert!(Function::try_from(array(&["function", "stats"])?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_commands() -> Result<()> {
+        let backend = Backend::new();
+        let code = "#!lua name=mylib\nredis.register_function('one', function() return 1 end)";
+        let load: Function = array(&["function", "load", code])?.try_into()?;
+        assert_eq!(load.execute(&backend), BulkString::from("mylib").into());
+
+        let mut no_call = |_| RespFrame::Integer(0);
+        let fcall: Fcall = array(&["fcall", "one", "0"])?.try_into()?;
+        assert_eq!(fcall.run(&backend, &mut no_call), RespFrame::Integer(1));
+
+        let list: Function = array(&["function", "list", "libraryname", "other*"])?.try_into()?;
+        assert_eq!(list.execute(&backend), RespArray::new(vec![]).into());
+        let list: Function = array(&["function", "list", "withcode"])?.try_into()?;
+        let RespFrame::Array(libraries) = list.execute(&backend) else {
+            panic!("FUNCTION LIST did not reply with an array");
+        };
+        let RespFrame::Map(library) = &libraries[0] else {
+            panic!("a library is not a map");
+        };
+        assert_eq!(
+            library.0.get("library_name"),
+            Some(&BulkString::from("mylib").into())
+        );
+        assert_eq!(
+            library.0.get("library_code"),
+            Some(&BulkString::from(code).into())
+        );
+
+        let dump: Function = array(&["function", "dump"])?.try_into()?;
+        let RespFrame::BulkString(payload) = dump.execute(&backend) else {
+            panic!("FUNCTION DUMP did not reply with a bulk string");
+        };
+        let delete: Function = array(&["function", "delete", "mylib"])?.try_into()?;
+        assert_eq!(delete.execute(&backend), RESP_OK.clone());
+        let delete: Function = array(&["function", "delete", "mylib"])?.try_into()?;
+        assert_eq!(
+            delete.execute(&backend),
+            SimpleError::new("ERR Library not found").into()
+        );
+
+        let restore = Function {
+            subcommand: FunctionSubcommand::Restore {
+                payload: payload.0,
+                policy: RestorePolicy::Append,
+            },
+        };
+        assert_eq!(restore.execute(&backend), RESP_OK.clone());
+        let fcall: Fcall = array(&["fcall", "one", "0"])?.try_into()?;
+        assert_eq!(fcall.run(&backend, &mut no_call), RespFrame::Integer(1));
+        let restore: Function = array(&["function", "restore", "garbage"])?.try_into()?;
+        assert_eq!(
+            restore.execute(&backend),
+            SimpleError::new("ERR payload version or checksum are wrong").into()
+        );
+
+        let flush: Function = array(&["function", "flush"])?.try_into()?;
+        assert_eq!(flush.execute(&backend), RESP_OK.clone());
+        let fcall: Fcall = array(&["fcall", "one", "0"])?.try_into()?;
+        assert_eq!(
+            fcall.run(&backend, &mut no_call),
+            SimpleError::new("ERR Function not found").into()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,465 @@
+// Server management commands, i.e. commands that act on the server process itself rather than on the keyspace.
+
+use super::{
+    extract_args, extract_string, validate_command, validate_variadic_command, BgRewriteAof,
+    BgSave, CommandExecutor, LastSave, Memory, MemorySubcommand, Save, Shutdown, RESP_OK,
+};
+use crate::{
+    backend::MemoryStats,
+    cmd::CommandError,
+    config::MaxmemoryPolicy,
+    persistence::{self, aof, PersistenceError},
+    shutdown::{SaveMode, ShutdownRequest},
+    Backend, BulkString, RespArray, RespFrame, RespMap, RespNull, SimpleError, SimpleString,
+};
+use tracing::warn;
+
+// Fields of a hash MEMORY USAGE looks at without SAMPLES, like in redis.
+const DEFAULT_USAGE_SAMPLES: usize = 5;
+
+impl Shutdown {
+    // SHUTDOWN NOSAVE is what still gets through while a script keeps the server busy.
+    pub fn is_nosave(&self) -> bool {
+        self.request.save == SaveMode::NoSave
+    }
+}
+
+impl CommandExecutor for Shutdown {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        // Only records the request and cancels the shared token.
+        // The accept loop and every connection task observe the cancellation and wind down on their own,
+        // so this connection also gets to send its reply before it is closed. The snapshot is saved by
+        // network::serve once the in-flight commands have finished, so it holds every write the server accepted.
+        backend.shutdown.trigger(self.request);
+        RESP_OK.clone()
+    }
+}
+
+impl CommandExecutor for Save {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        // Like redis, SAVE does not wait for a running BGSAVE: it refuses.
+        if backend.persistence.bgsave_in_progress() {
+            return SimpleError::new(format!("ERR {}", PersistenceError::InProgress)).into();
+        }
+        match persistence::save(backend) {
+            Ok(()) => RESP_OK.clone(),
+            Err(e) => {
+                warn!("SAVE failed: {}", e);
+                SimpleError::new(format!("ERR {}", e)).into()
+            }
+        }
+    }
+}
+
+impl CommandExecutor for BgSave {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        match persistence::bgsave(backend) {
+            Ok(()) => SimpleString::new("Background saving started").into(),
+            Err(e) => SimpleError::new(format!("ERR {}", e)).into(),
+        }
+    }
+}
+
+impl CommandExecutor for LastSave {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        RespFrame::Integer(backend.persistence.lastsave() as i64)
+    }
+}
+
+impl CommandExecutor for BgRewriteAof {
+    fn execute(self, backend: &crate::Backend) -> RespFrame {
+        match aof::bgrewrite(backend) {
+            Ok(()) => SimpleString::new("Background append only file rewriting started").into(),
+            Err(e) => SimpleError::new(format!("ERR {}", e)).into(),
+        }
+    }
+}
+
+impl CommandExecutor for Memory {
+    fn execute(self, backend: &Backend) -> RespFrame {
+        match self.subcommand {
+            MemorySubcommand::Usage { key, samples } => match backend.memory_usage(&key, samples) {
+                Some(size) => RespFrame::Integer(size as i64),
+                None => RespFrame::Null(RespNull),
+            },
+            MemorySubcommand::Stats => stats(&backend.memory_stats()).into(),
+            MemorySubcommand::Doctor => BulkString::from(doctor(backend)).into(),
+        }
+    }
+}
+
+fn stats(stats: &MemoryStats) -> RespMap {
+    let overhead = stats.keys_overhead + stats.expires_overhead + stats.replication_backlog;
+    let total = stats.used + stats.replication_backlog;
+    let mut map = RespMap::new();
+    let mut integer = |name: &str, value: usize| {
+        map.insert(name.to_string(), RespFrame::Integer(value as i64));
+    };
+    integer("peak.allocated", stats.peak);
+    integer("total.allocated", total);
+    integer("replication.backlog", stats.replication_backlog);
+    integer("overhead.hashtable.main", stats.keys_overhead);
+    integer("overhead.hashtable.expires", stats.expires_overhead);
+    integer("overhead.total", overhead);
+    integer("keys.count", stats.keys);
+    integer("keys.bytes-per-key", stats.used / stats.keys.max(1));
+    integer("dataset.bytes", stats.dataset());
+    if let Some(allocator) = &stats.allocator {
+        integer("allocator.allocated", allocator.allocated);
+        integer("allocator.active", allocator.active);
+        integer("allocator.resident", allocator.resident);
+        integer(
+            "allocator-fragmentation.bytes",
+            allocator.active.saturating_sub(allocator.allocated),
+        );
+        integer(
+            "fragmentation.bytes",
+            allocator.resident.saturating_sub(allocator.allocated),
+        );
+    }
+    let percentage = |part: usize, whole: usize| part as f64 * 100.0 / whole.max(1) as f64;
+    map.insert(
+        "dataset.percentage".to_string(),
+        RespFrame::Double(percentage(stats.dataset(), total)),
+    );
+    map.insert(
+        "peak.percentage".to_string(),
+        RespFrame::Double(percentage(stats.used, stats.peak)),
+    );
+    if let Some(allocator) = &stats.allocator {
+        map.insert(
+            "allocator-fragmentation.ratio".to_string(),
+            RespFrame::Double(allocator.active as f64 / allocator.allocated.max(1) as f64),
+        );
+        map.insert(
+            "fragmentation".to_string(),
+            RespFrame::Double(allocator.resident as f64 / allocator.allocated.max(1) as f64),
+        );
+    }
+    map
+}
+
+// The MEMORY DOCTOR report, in the words of redis: the issues found, or that there are none.
+fn doctor(backend: &Backend) -> String {
+    const MB: usize = 1024 * 1024;
+    let stats = backend.memory_stats();
+    let allocated = stats
+        .allocator
+        .as_ref()
+        .map_or(stats.used, |allocator| allocator.allocated);
+    if allocated < 5 * MB {
+        return "Hi Sam, this instance is empty or is using very little memory, my issues detector can't be used \
+                in these conditions. Please, leave for your mission on Earth and fill it with some data. The new Sam \
+                and I will be back to our programming as soon as I finished rebooting."
+            .to_string();
+    }
+
+    let mut issues = Vec::new();
+    if stats.peak > stats.used * 3 / 2 {
+        issues.push(
+            "Peak memory: In the past this instance used more than 150% the memory that is currently using. \
+             The allocator is normally not able to release memory after a peak, so you can expect to see a big \
+             fragmentation ratio, however this is actually harmless and is only due to the memory peak.",
+        );
+    }
+    if let Some(allocator) = &stats.allocator {
+        let fragmentation = allocator.resident as f64 / allocator.allocated.max(1) as f64;
+        if fragmentation > 1.4 && allocator.resident - allocator.allocated > 10 * MB {
+            issues.push(
+                "High total RSS: This instance has a memory fragmentation and RSS overhead greater than 1.4 \
+                 (this means that the Resident Set Size of the process is much larger than the memory the \
+                 allocator handed out). This problem is usually due either to a large peak memory (check if \
+                 there is a peak memory entry above in the report) or may result from a workload that causes \
+                 the allocator to fragment memory a lot.",
+            );
+        }
+        let fragmentation = allocator.active as f64 / allocator.allocated.max(1) as f64;
+        if fragmentation > 1.1 && allocator.active - allocator.allocated > 10 * MB {
+            issues.push(
+                "High allocator fragmentation: This instance has an allocator internal fragmentation greater \
+                 than 1.1: the allocator holds a lot more memory than it hands out to the program, in pages \
+                 that are only partly used.",
+            );
+        }
+    }
+    let config = backend.config();
+    if config.maxmemory > 0
+        && config.maxmemory_policy == MaxmemoryPolicy::NoEviction
+        && stats.used > config.maxmemory / 10 * 9
+    {
+        issues.push(
+            "Near maxmemory: The dataset uses more than 90% of maxmemory and maxmemory-policy is noeviction, \
+             so writes will soon be refused with OOM errors. Raise maxmemory, delete keys, or pick a policy \
+             that evicts keys.",
+        );
+    }
+
+    if issues.is_empty() {
+        return "Hi Sam, I can't find any memory issue in your instance. I can only account for what occurs \
+                on this base."
+            .to_string();
+    }
+    let mut report =
+        "Sam, I detected a few issues in this Redis instance memory implants:\n\n".to_string();
+    for issue in issues {
+        report.push_str(&format!(" * {}\n\n", issue));
+    }
+    report.push_str("I'm here to keep you safe, Sam. I want to help you.\n");
+    report
+}
+
+impl TryFrom<RespArray> for Save {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["save"], 0)?;
+        Ok(Save)
+    }
+}
+
+impl TryFrom<RespArray> for BgSave {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["bgsave"], 0)?;
+        Ok(BgSave)
+    }
+}
+
+impl TryFrom<RespArray> for BgRewriteAof {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["bgrewriteaof"], 0)?;
+        Ok(BgRewriteAof)
+    }
+}
+
+impl TryFrom<RespArray> for LastSave {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["lastsave"], 0)?;
+        Ok(LastSave)
+    }
+}
+
+impl TryFrom<RespArray> for Memory {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["memory"], 1)?;
+
+        let mut args = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<Vec<String>, CommandError>>()?
+            .into_iter();
+        let name = args.next().unwrap_or_default().to_ascii_lowercase();
+        let args = args.collect::<Vec<String>>();
+
+        let subcommand = match (name.as_str(), args.as_slice()) {
+            ("usage", [key]) => MemorySubcommand::Usage {
+                key: key.clone(),
+                samples: DEFAULT_USAGE_SAMPLES,
+            },
+            ("usage", [key, option, samples]) if option.eq_ignore_ascii_case("samples") => {
+                MemorySubcommand::Usage {
+                    key: key.clone(),
+                    samples: samples.parse().map_err(|_| {
+                        CommandError::InvalidArgument(
+                            "value is not an integer or out of range".to_string(),
+                        )
+                    })?,
+                }
+            }
+            ("usage", [_, ..]) => {
+                return Err(CommandError::InvalidArgument("syntax error".to_string()))
+            }
+            ("stats", []) => MemorySubcommand::Stats,
+            ("doctor", []) => MemorySubcommand::Doctor,
+            ("usage" | "stats" | "doctor", _) => {
+                return Err(CommandError::InvalidArgument(format!(
+                    "wrong number of arguments for 'memory|{}' command",
+                    name
+                )))
+            }
+            _ => {
+                return Err(CommandError::InvalidCommand(format!(
+                    "unknown subcommand '{}'. Try MEMORY HELP.",
+                    name
+                )))
+            }
+        };
+        Ok(Memory { subcommand })
+    }
+}
+
+impl TryFrom<RespArray> for Shutdown {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["shutdown"], 0)?;
+
+        let mut request = ShutdownRequest::default();
+        for arg in extract_args(value, 1)? {
+            let arg = match arg {
+                RespFrame::BulkString(arg) => String::from_utf8(arg.0)?.to_ascii_lowercase(),
+                _ => return Err(CommandError::InvalidArgument("syntax error".to_string())),
+            };
+            match (arg.as_str(), request.save) {
+                // NOSAVE and SAVE are mutually exclusive, and neither may be given twice.
+                ("nosave", SaveMode::Default) => request.save = SaveMode::NoSave,
+                ("save", SaveMode::Default) => request.save = SaveMode::Save,
+                ("now", _) => request.now = true,
+                ("force", _) => request.force = true,
+                _ => return Err(CommandError::InvalidArgument("syntax error".to_string())),
+            }
+        }
+
+        Ok(Shutdown { request })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, Backend, BulkString, RespDecode};
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_shutdown_from_resp_array() -> Result<()> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*1\r\n$8\r\nshutdown\r\n");
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Shutdown = frame.try_into()?;
+        assert_eq!(result.request, ShutdownRequest::default());
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(
+            b"*4\r\n$8\r\nshutdown\r\n$6\r\nNOSAVE\r\n$3\r\nnow\r\n$5\r\nforce\r\n",
+        );
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Shutdown = frame.try_into()?;
+        assert_eq!(
+            result.request,
+            ShutdownRequest {
+                save: SaveMode::NoSave,
+                now: true,
+                force: true,
+            }
+        );
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*3\r\n$8\r\nshutdown\r\n$6\r\nnosave\r\n$4\r\nsave\r\n");
+        let frame = RespArray::decode(&mut buf)?;
+        let result: Result<Shutdown, CommandError> = frame.try_into();
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shutdown_command() {
+        let backend = Backend::new();
+        let request = ShutdownRequest {
+            save: SaveMode::Save,
+            now: false,
+            force: false,
+        };
+        let result = Shutdown { request }.execute(&backend);
+        assert_eq!(result, RESP_OK.clone());
+        assert!(backend.shutdown().is_triggered());
+        // Left to network::serve, after the connections are drained.
+        assert_eq!(backend.shutdown().request(), request);
+    }
+
+    #[test]
+    fn test_save_commands() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("simple-redis-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let backend = Backend::with_config(ServerConfig {
+            dir: dir.clone(),
+            ..Default::default()
+        });
+        backend.set("hello".to_string(), BulkString::from("world").into());
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*1\r\n$4\r\nSAVE\r\n");
+        let cmd: Save = RespArray::decode(&mut buf)?.try_into()?;
+        assert_eq!(cmd.execute(&backend), RESP_OK.clone());
+        assert!(dir.join("dump.srdb").exists());
+        assert_eq!(
+            LastSave.execute(&backend),
+            RespFrame::Integer(backend.persistence().lastsave() as i64)
+        );
+
+        assert_eq!(
+            BgSave.execute(&backend),
+            SimpleString::new("Background saving started").into()
+        );
+        while backend.persistence().bgsave_in_progress() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(backend.persistence().last_bgsave_ok());
+
+        // appendonly is off in this config.
+        assert!(matches!(
+            BgRewriteAof.execute(&backend),
+            RespFrame::Error(_)
+        ));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_command() -> Result<()> {
+        let memory = |args: &[&str]| -> Result<Memory, CommandError> {
+            let args = std::iter::once("memory")
+                .chain(args.iter().copied())
+                .map(|arg| BulkString::from(arg).into())
+                .collect::<Vec<RespFrame>>();
+            RespArray::new(args).try_into()
+        };
+        assert_eq!(
+            memory(&["USAGE", "k", "samples", "0"])?.subcommand,
+            MemorySubcommand::Usage {
+                key: "k".to_string(),
+                samples: 0
+            }
+        );
+        assert!(memory(&["usage", "k", "samples"]).is_err());
+        assert!(memory(&["usage", "k", "samples", "x"]).is_err());
+        assert!(memory(&["stats", "x"]).is_err());
+        assert!(memory(&["malloc-stats"]).is_err());
+
+        let backend = Backend::new();
+        backend.set("k".to_string(), BulkString::from("v").into());
+        for field in ["a", "b", "c"] {
+            backend.hset(
+                "h".to_string(),
+                field.to_string(),
+                BulkString::from("v").into(),
+            );
+        }
+        let usage = |key: &str, samples: &str| {
+            memory(&["usage", key, "samples", samples]).map(|cmd| cmd.execute(&backend))
+        };
+        assert!(matches!(usage("k", "0")?, RespFrame::Integer(size) if size > 1));
+        // Fields of the same size: sampling one of them is exact.
+        assert_eq!(usage("h", "1")?, usage("h", "0")?);
+        assert_eq!(usage("missing", "0")?, RespFrame::Null(RespNull));
+
+        let RespFrame::Map(stats) = memory(&["stats"])?.execute(&backend) else {
+            panic!("MEMORY STATS is a map");
+        };
+        assert_eq!(stats.get("keys.count"), Some(&RespFrame::Integer(2)));
+        assert_eq!(
+            stats.get("total.allocated"),
+            Some(&RespFrame::Integer(backend.used_memory() as i64))
+        );
+
+        let RespFrame::BulkString(report) = memory(&["doctor"])?.execute(&backend) else {
+            panic!("MEMORY DOCTOR is a string");
+        };
+        // The allocator figures are the ones of the whole test process, so what it finds varies.
+        assert!(String::from_utf8(report.0)?.contains("Sam"));
+        Ok(())
+    }
+}
@@ -0,0 +1,680 @@
+// Static metadata about every command the server knows, independent of how the command is parsed.
+// It answers the questions that have to be asked *before* a command runs:
+// which ACL categories it belongs to, whether it reads or writes, and where its keys are in the argument list.
+
+use crate::{module, RespArray, RespFrame};
+
+// ACL categories (the names ACL rules refer to as +@name / -@name) and what they mean.
+pub const CATEGORIES: &[&str] = &[
+    "keyspace",    // works on keys in general
+    "read",        // reads from keys
+    "write",       // writes to keys
+    "string",      // works on strings
+    "hash",        // works on hashes
+    "fast",        // O(1) or O(log N)
+    "slow",        // everything that is not fast
+    "admin",       // administrative commands
+    "dangerous", // potentially dangerous commands (admin commands, commands that can block the server, ...)
+    "connection", // affects the connection or other connections
+    "pubsub",    // works on pub/sub channels
+    "transaction", // MULTI / EXEC and friends
+    "scripting", // runs or manages Lua scripts
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAccess {
+    Read,
+    Write,
+}
+
+// CommandSpec:
+// first_key / last_key / step describe where the keys are, like redis' legacy key specs:
+// keys are at args[first_key], args[first_key + step], ... up to args[last_key] (negative counts from the end).
+// first_key = 0 means the command takes no keys.
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub categories: &'static [&'static str],
+    pub write: bool,
+    pub first_key: usize,
+    pub last_key: isize,
+    pub step: usize,
+}
+
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec {
+        name: "get",
+        categories: &["read", "string", "fast"],
+        write: false,
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "set",
+        categories: &["write", "string", "slow"],
+        write: true,
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "hget",
+        categories: &["read", "hash", "fast"],
+        write: false,
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "hset",
+        categories: &["write", "hash", "fast"],
+        write: true,
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "hgetall",
+        categories: &["read", "hash", "slow"],
+        write: false,
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "shutdown",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "save",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "bgsave",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "lastsave",
+        categories: &["admin", "fast", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "bgrewriteaof",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "auth",
+        categories: &["connection", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "acl",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "dump",
+        categories: &["keyspace", "read", "slow"],
+        write: false,
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "restore",
+        categories: &["keyspace", "write", "slow", "dangerous"],
+        write: true,
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "restore-asking",
+        categories: &["keyspace", "write", "slow", "dangerous"],
+        write: true,
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "replicaof",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "slaveof",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "role",
+        categories: &["admin", "fast", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "replconf",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "psync",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "wait",
+        categories: &["connection", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "waitaof",
+        categories: &["connection", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "cluster",
+        categories: &["admin", "slow", "dangerous"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "asking",
+        categories: &["connection", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "del",
+        categories: &["keyspace", "write", "slow"],
+        write: true,
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+    },
+    // The key is args[3], unless that is empty and the keys follow KEYS (see CommandSpec::keys).
+    CommandSpec {
+        name: "migrate",
+        categories: &["keyspace", "write", "slow", "dangerous"],
+        write: true,
+        first_key: 3,
+        last_key: 3,
+        step: 1,
+    },
+    CommandSpec {
+        name: "ping",
+        categories: &["connection", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "quit",
+        categories: &["connection", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "hello",
+        categories: &["connection", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "subscribe",
+        categories: &["pubsub", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "psubscribe",
+        categories: &["pubsub", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "unsubscribe",
+        categories: &["pubsub", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "punsubscribe",
+        categories: &["pubsub", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "publish",
+        categories: &["pubsub", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "pubsub",
+        categories: &["pubsub", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    // Shard channels are not keys for ACLs, but they are hashed to slots like keys (see CommandSpec::shard_channels).
+    CommandSpec {
+        name: "ssubscribe",
+        categories: &["pubsub", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "sunsubscribe",
+        categories: &["pubsub", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "spublish",
+        categories: &["pubsub", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "multi",
+        categories: &["transaction", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "exec",
+        categories: &["transaction", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "discard",
+        categories: &["transaction", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    CommandSpec {
+        name: "watch",
+        categories: &["transaction", "fast"],
+        write: false,
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "unwatch",
+        categories: &["transaction", "fast"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    // The keys are the numkeys arguments after args[2] (see CommandSpec::keys).
+    CommandSpec {
+        name: "eval",
+        categories: &["scripting", "slow"],
+        write: false,
+        first_key: 3,
+        last_key: -1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "evalsha",
+        categories: &["scripting", "slow"],
+        write: false,
+        first_key: 3,
+        last_key: -1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "script",
+        categories: &["scripting", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    // The keys are the numkeys arguments after args[2], like EVAL.
+    CommandSpec {
+        name: "fcall",
+        categories: &["scripting", "slow"],
+        write: false,
+        first_key: 3,
+        last_key: -1,
+        step: 1,
+    },
+    CommandSpec {
+        name: "fcall_ro",
+        categories: &["scripting", "slow"],
+        write: false,
+        first_key: 3,
+        last_key: -1,
+        step: 1,
+    },
+    // Whether it writes depends on the subcommand (see is_write).
+    CommandSpec {
+        name: "function",
+        categories: &["scripting", "slow"],
+        write: false,
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+    },
+    // The key is the one of MEMORY USAGE.
+    CommandSpec {
+        name: "memory",
+        categories: &["read", "slow"],
+        write: false,
+        first_key: 2,
+        last_key: 2,
+        step: 1,
+    },
+    CommandSpec {
+        name: "object",
+        categories: &["keyspace", "read", "slow"],
+        write: false,
+        first_key: 2,
+        last_key: 2,
+        step: 1,
+    },
+];
+
+impl CommandSpec {
+    pub fn in_category(&self, category: &str) -> bool {
+        self.categories.contains(&category)
+    }
+
+    // Extracts the keys of a command from its arguments (args[0] is the command name).
+    pub fn keys<'a>(&self, args: &'a RespArray) -> Vec<(&'a [u8], KeyAccess)> {
+        if self.first_key == 0 || self.step == 0 {
+            return Vec::new();
+        }
+        let last = if self.last_key < 0 {
+            args.len() as isize + self.last_key
+        } else {
+            self.last_key
+        };
+        let access = if self.write {
+            KeyAccess::Write
+        } else {
+            KeyAccess::Read
+        };
+        if self.name == "migrate"
+            && matches!(args.get(3), Some(RespFrame::BulkString(key)) if key.is_empty())
+        {
+            let keys = args
+                .iter()
+                .skip(6)
+                .position(|arg| matches!(arg, RespFrame::BulkString(arg) if arg.eq_ignore_ascii_case(b"keys")))
+                .map_or(args.len(), |i| i + 7);
+            return args[keys.min(args.len())..]
+                .iter()
+                .filter_map(|arg| match arg {
+                    RespFrame::BulkString(key) => Some((key.as_slice(), access)),
+                    _ => None,
+                })
+                .collect();
+        }
+
+        let last = if ["eval", "evalsha", "fcall", "fcall_ro"].contains(&self.name) {
+            let numkeys = match args.get(2) {
+                Some(RespFrame::BulkString(numkeys)) => std::str::from_utf8(numkeys)
+                    .ok()
+                    .and_then(|numkeys| numkeys.parse::<isize>().ok())
+                    .unwrap_or(0),
+                _ => 0,
+            };
+            self.first_key as isize + numkeys - 1
+        } else {
+            last
+        };
+
+        let mut keys = Vec::new();
+        let mut i = self.first_key;
+        while (i as isize) <= last && i < args.len() {
+            if let RespFrame::BulkString(key) = &args[i] {
+                keys.push((key.as_slice(), access));
+            }
+            i += self.step;
+        }
+        keys
+    }
+
+    // Extracts the pub/sub channels a command touches, each with whether it is a pattern (PSUBSCRIBE).
+    pub fn channels<'a>(&self, args: &'a RespArray) -> Vec<(&'a [u8], bool)> {
+        let (first, pattern) = match self.name {
+            "publish" | "spublish" => (1, false),
+            "subscribe" | "ssubscribe" => (1, false),
+            "psubscribe" => (1, true),
+            _ => return Vec::new(),
+        };
+        let last = if self.name.ends_with("publish") {
+            2
+        } else {
+            args.len()
+        };
+        args.iter()
+            .take(last)
+            .skip(first)
+            .filter_map(|arg| match arg {
+                RespFrame::BulkString(channel) => Some((channel.as_slice(), pattern)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The shard channels of SSUBSCRIBE / SUNSUBSCRIBE / SPUBLISH, which must be served by the node owning their slot.
+    pub fn shard_channels<'a>(&self, args: &'a RespArray) -> Vec<&'a [u8]> {
+        let last = match self.name {
+            "spublish" => 2,
+            "ssubscribe" | "sunsubscribe" => args.len(),
+            _ => return Vec::new(),
+        };
+        args.iter()
+            .take(last)
+            .skip(1)
+            .filter_map(|arg| match arg {
+                RespFrame::BulkString(channel) => Some(channel.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Finds the spec of a command by name (case-insensitive), built-in or added by a module.
+pub fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE
+        .iter()
+        .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+        .or_else(|| module::lookup_spec(name))
+}
+
+// Every command: the built-in ones, then the ones of the registered modules.
+pub fn commands() -> Vec<&'static CommandSpec> {
+    COMMAND_TABLE.iter().chain(module::specs()).collect()
+}
+
+// Whether a command frame changes the dataset: such a command is logged to the AOF and the replication stream, and
+// refused on a read-only replica. That is the write flag of its spec, except for FUNCTION, where
+// LOAD / DELETE / RESTORE / FLUSH write and the other subcommands do not.
+pub fn is_write(frame: &RespFrame) -> bool {
+    match lookup_frame(frame).map(|spec| (spec.name, spec.write)) {
+        None => false,
+        Some(("function", _)) => has_subcommand(frame, &[b"load", b"delete", b"restore", b"flush"]),
+        Some((_, write)) => write,
+    }
+}
+
+// Whether a command frame may add data, so it is refused when the dataset is over maxmemory and nothing can be
+// evicted (the denyoom flag of redis). Writes that only remove data do not: DEL, MIGRATE and
+// FUNCTION DELETE / FLUSH.
+pub fn is_denyoom(frame: &RespFrame) -> bool {
+    match lookup_frame(frame).map(|spec| spec.name) {
+        Some("del" | "migrate") => false,
+        Some("function") => has_subcommand(frame, &[b"load", b"restore"]),
+        _ => is_write(frame),
+    }
+}
+
+// Whether the second element of a command frame is one of names (case-insensitive).
+fn has_subcommand(frame: &RespFrame, names: &[&[u8]]) -> bool {
+    matches!(
+        frame,
+        RespFrame::Array(args) if matches!(
+            args.get(1),
+            Some(RespFrame::BulkString(subcommand))
+                if names.iter().any(|name| subcommand.eq_ignore_ascii_case(name))
+        )
+    )
+}
+
+// Returns the spec for a raw command frame, i.e. looks up the first element of the array.
+pub fn lookup_frame(frame: &RespFrame) -> Option<&'static CommandSpec> {
+    match frame {
+        RespFrame::Array(array) => match array.first() {
+            Some(RespFrame::BulkString(name)) => lookup(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BulkString;
+
+    #[test]
+    fn test_lookup_and_keys() {
+        let spec = lookup(b"HSET").unwrap();
+        assert_eq!(spec.name, "hset");
+        assert!(spec.in_category("hash"));
+
+        let args = RespArray::new(vec![
+            BulkString::from("hset").into(),
+            BulkString::from("map").into(),
+            BulkString::from("field").into(),
+            BulkString::from("value").into(),
+        ]);
+        assert_eq!(spec.keys(&args), vec![(&b"map"[..], KeyAccess::Write)]);
+
+        let spec = lookup(b"shutdown").unwrap();
+        assert!(spec.keys(&args).is_empty());
+        assert!(lookup(b"nosuchcommand").is_none());
+
+        // MIGRATE has its key at args[3], or after KEYS when that is empty.
+        let spec = lookup(b"migrate").unwrap();
+        let migrate = |args: &[&str]| {
+            RespArray::new(
+                args.iter()
+                    .map(|arg| BulkString::from(*arg).into())
+                    .collect::<Vec<RespFrame>>(),
+            )
+        };
+        let args = migrate(&["migrate", "host", "6379", "key", "0", "1000"]);
+        assert_eq!(spec.keys(&args), vec![(&b"key"[..], KeyAccess::Write)]);
+        let args = migrate(&[
+            "migrate", "host", "6379", "", "0", "1000", "COPY", "KEYS", "a", "b",
+        ]);
+        assert_eq!(
+            spec.keys(&args),
+            vec![(&b"a"[..], KeyAccess::Write), (&b"b"[..], KeyAccess::Write)]
+        );
+
+        // Writes that only remove data get through when the dataset is over maxmemory.
+        let frame = |args: &[&str]| RespFrame::from(migrate(args));
+        assert!(is_denyoom(&frame(&["set", "k", "v"])));
+        assert!(!is_denyoom(&frame(&["get", "k"])));
+        assert!(!is_denyoom(&frame(&["del", "k"])));
+        assert!(is_write(&frame(&["function", "flush"])));
+        assert!(!is_denyoom(&frame(&["function", "flush"])));
+        assert!(is_denyoom(&frame(&["FUNCTION", "LOAD", "code"])));
+        assert!(!is_write(&frame(&["function", "list"])));
+
+        // Every category used in the table must be a known category.
+        for spec in COMMAND_TABLE {
+            for category in spec.categories {
+                assert!(CATEGORIES.contains(category), "{}: {}", spec.name, category);
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// MULTI / EXEC / DISCARD / WATCH / UNWATCH
+// The queue of a transaction and the watched keys belong to the connection: network::request_handler keeps them
+// and runs EXEC, so executing these commands anywhere else is an error.
+
+use super::{
+    extract_args, extract_string, validate_command, validate_variadic_command, CommandExecutor,
+    Discard, Exec, Multi, Unwatch, Watch,
+};
+use crate::{cmd::CommandError, Backend, RespArray, RespFrame, SimpleError, WatchedKeys};
+
+impl CommandExecutor for Multi {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new("ERR MULTI can only be used by a client connection").into()
+    }
+}
+
+impl CommandExecutor for Exec {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new("ERR EXEC without MULTI").into()
+    }
+}
+
+impl CommandExecutor for Discard {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new("ERR DISCARD without MULTI").into()
+    }
+}
+
+impl Watch {
+    pub fn run(self, watched: &mut WatchedKeys) {
+        for key in self.keys {
+            watched.add(key);
+        }
+    }
+}
+
+impl CommandExecutor for Watch {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new("ERR WATCH can only be used by a client connection").into()
+    }
+}
+
+impl CommandExecutor for Unwatch {
+    fn execute(self, _backend: &Backend) -> RespFrame {
+        SimpleError::new("ERR UNWATCH can only be used by a client connection").into()
+    }
+}
+
+impl TryFrom<RespArray> for Multi {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["multi"], 0)?;
+        Ok(Multi)
+    }
+}
+
+impl TryFrom<RespArray> for Exec {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["exec"], 0)?;
+        Ok(Exec)
+    }
+}
+
+impl TryFrom<RespArray> for Discard {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["discard"], 0)?;
+        Ok(Discard)
+    }
+}
+
+impl TryFrom<RespArray> for Watch {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_variadic_command(&value, &["watch"], 1)?;
+
+        let keys = extract_args(value, 1)?
+            .into_iter()
+            .map(extract_string)
+            .collect::<Result<_, _>>()?;
+        Ok(Watch { keys })
+    }
+}
+
+impl TryFrom<RespArray> for Unwatch {
+    type Error = CommandError;
+    fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+        validate_command(&value, &["unwatch"], 0)?;
+        Ok(Unwatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cmd::Command, RespDecode};
+    use anyhow::Result;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_transaction_from_resp_array() -> Result<()> {
+        let mut buf = BytesMut::from("*1\r\n$5\r\nMULTI\r\n");
+        let cmd = Command::try_from(RespArray::decode(&mut buf)?)?;
+        assert!(matches!(cmd, Command::Multi(_)));
+
+        let mut buf = BytesMut::from("*2\r\n$4\r\nexec\r\n$1\r\nx\r\n");
+        assert!(Exec::try_from(RespArray::decode(&mut buf)?).is_err());
+
+        let mut buf = BytesMut::from("*3\r\n$5\r\nWATCH\r\n$1\r\na\r\n$1\r\nb\r\n");
+        let watch = Watch::try_from(RespArray::decode(&mut buf)?)?;
+        assert_eq!(watch.keys, vec!["a", "b"]);
+        let mut buf = BytesMut::from("*1\r\n$5\r\nwatch\r\n");
+        assert!(Watch::try_from(RespArray::decode(&mut buf)?).is_err());
+
+        // Without a connection there is no transaction to run or discard.
+        let backend = Backend::new();
+        assert_eq!(
+            Discard.execute(&backend),
+            SimpleError::new("ERR DISCARD without MULTI").into()
+        );
+        Ok(())
+    }
+}
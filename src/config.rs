@@ -0,0 +1,626 @@
+// Server configuration, loaded the same way redis-server does it:
+//
+//   simple-redis [/path/to/redis.conf] [--name value ...]
+//
+// The config file contains one directive per line ("port 6380", "unixsocket /tmp/redis.sock"),
+// blank lines and lines starting with '#' are ignored.
+// Command line options use the same names prefixed with "--" and override the file.
+
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Bad directive or wrong number of arguments: {0}")]
+    UnknownDirective(String),
+    #[error("Invalid value for '{0}': {1}")]
+    InvalidValue(String, String),
+    #[error("Failed to read config file: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub bind: String,
+    // 0 disables the TCP listener, e.g. for sidecars that only talk over the unix socket.
+    pub port: u16,
+    pub unixsocket: Option<PathBuf>,
+    // Permissions of the socket file, written in octal like chmod ("700").
+    pub unixsocketperm: Option<u32>,
+    // 0 disables the TLS listener. When enabled, tls-cert-file and tls-key-file are required.
+    pub tls_port: u16,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    // CA bundle used to verify client certificates.
+    pub tls_ca_cert_file: Option<PathBuf>,
+    pub tls_auth_clients: TlsAuthClients,
+    // When set, clients must AUTH with this password before running any other command.
+    pub requirepass: Option<String>,
+    // File the ACL users are loaded from at startup (and by ACL LOAD), and written to by ACL SAVE.
+    pub aclfile: Option<PathBuf>,
+    // save <seconds> <changes> [<seconds> <changes> ...]: take a snapshot after <seconds> if at least <changes> writes happened.
+    // Empty disables automatic snapshots (save "").
+    pub save: Vec<(u64, u64)>,
+    // The snapshot is written to and loaded from dir/dbfilename.
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    pub snapshot_format: SnapshotFormat,
+    // appendonly yes: log every write to dir/appendfilename, and load that log instead of the snapshot at startup.
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    // replicaof <host> <port>: start as a replica of that primary.
+    pub replicaof: Option<(String, u16)>,
+    // Credentials the replica uses to AUTH with its primary.
+    pub masteruser: Option<String>,
+    pub masterauth: Option<String>,
+    // Replicas reject writes from clients (they only apply what the primary sends).
+    pub replica_read_only: bool,
+    // Bytes of the replication stream kept for replicas that reconnect (partial resync).
+    pub repl_backlog_size: usize,
+    // cluster-enabled yes: keys are sharded over the nodes of a cluster by hash slot (see cluster.rs).
+    pub cluster_enabled: bool,
+    // Port of the cluster bus the nodes gossip over; 0 means port + 10000, like in redis.
+    pub cluster_port: u16,
+    // Milliseconds without an answer after which a node is considered failing.
+    pub cluster_node_timeout: u64,
+    // The IP the other nodes and clients are told to use for this node; by default they use the
+    // address they see the node's connections come from.
+    pub cluster_announce_ip: Option<String>,
+    // Which keyspace notifications are published to pub/sub (none by default, like redis).
+    pub notify_keyspace_events: NotifyKeyspaceEvents,
+    // Milliseconds a script may run before other clients get BUSY and SCRIPT KILL can stop it
+    // (busy-reply-threshold, or lua-time-limit as older configs call it).
+    pub busy_reply_threshold: u64,
+    // Bytes the dataset may use (see backend/memory.rs for how values are counted); 0 means no limit.
+    pub maxmemory: usize,
+    // What happens when a write would go over maxmemory.
+    pub maxmemory_policy: MaxmemoryPolicy,
+    // Keys looked at to pick the one to evict; more samples get closer to true LRU / LFU / TTL order.
+    pub maxmemory_samples: usize,
+    // Hashes with at most this many fields, none of them or their values longer than hash-max-listpack-value
+    // bytes, use the compact encoding (see backend/hash.rs).
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+}
+
+// tls-auth-clients yes|no|optional
+// yes: clients must present a certificate signed by tls-ca-cert-file (the redis default).
+// optional: a certificate is verified if the client presents one, but it is not required.
+// no: client certificates are not requested at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TlsAuthClients {
+    #[default]
+    Yes,
+    Optional,
+    No,
+}
+
+// snapshot-format native|rdb: the format SAVE / BGSAVE write; loading detects the format of the file.
+// rdb writes a redis RDB file that stock redis-server can load (use e.g. dbfilename dump.rdb with it).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnapshotFormat {
+    #[default]
+    Native,
+    Rdb,
+}
+
+// appendfsync always|everysec|no
+// always: fsync after every write, nothing acknowledged is ever lost (and every write pays for a disk flush).
+// everysec: fsync once a second in the background, at most one second of writes is lost (the redis default).
+// no: leave it to the operating system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppendFsync {
+    Always,
+    #[default]
+    EverySec,
+    No,
+}
+
+// maxmemory-policy: the keys evicted to get back under maxmemory. allkeys-* pick among all keys, volatile-* among
+// the keys with a TTL. lru evicts the least recently used key, lfu the least frequently used one, ttl the one that
+// expires first and random any of them. noeviction evicts nothing: writes that add data fail with OOM instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaxmemoryPolicy {
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    pub fn is_lfu(&self) -> bool {
+        matches!(self, Self::AllKeysLfu | Self::VolatileLfu)
+    }
+
+    // Whether only keys with a TTL may be evicted.
+    pub fn is_volatile(&self) -> bool {
+        matches!(
+            self,
+            Self::VolatileLru | Self::VolatileLfu | Self::VolatileRandom | Self::VolatileTtl
+        )
+    }
+}
+
+// notify-keyspace-events, the redis flag string: K publishes keyspace events (__keyspace@0__:<key>, the message
+// is the event), E keyevent events (__keyevent@0__:<event>, the message is the key), and the other flags select
+// the classes of events: g generic (del, expire, restore), $ string, h hash, x expired, e evicted, n new key,
+// and A for "g$lshzxetd". Classes of types this server does not have (l s z t d m) are accepted and never fire.
+// Nothing is published unless K or E is given along with at least one class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NotifyKeyspaceEvents(u16);
+
+impl NotifyKeyspaceEvents {
+    pub const KEYSPACE: Self = Self(1 << 0);
+    pub const KEYEVENT: Self = Self(1 << 1);
+    pub const GENERIC: Self = Self(1 << 2);
+    pub const STRING: Self = Self(1 << 3);
+    pub const LIST: Self = Self(1 << 4);
+    pub const SET: Self = Self(1 << 5);
+    pub const HASH: Self = Self(1 << 6);
+    pub const ZSET: Self = Self(1 << 7);
+    pub const EXPIRED: Self = Self(1 << 8);
+    pub const EVICTED: Self = Self(1 << 9);
+    pub const STREAM: Self = Self(1 << 10);
+    pub const MODULE: Self = Self(1 << 11);
+    pub const KEY_MISS: Self = Self(1 << 12);
+    pub const NEW: Self = Self(1 << 13);
+
+    const FLAGS: [(char, Self); 14] = [
+        ('K', Self::KEYSPACE),
+        ('E', Self::KEYEVENT),
+        ('g', Self::GENERIC),
+        ('$', Self::STRING),
+        ('l', Self::LIST),
+        ('s', Self::SET),
+        ('h', Self::HASH),
+        ('z', Self::ZSET),
+        ('x', Self::EXPIRED),
+        ('e', Self::EVICTED),
+        ('t', Self::STREAM),
+        ('d', Self::MODULE),
+        ('m', Self::KEY_MISS),
+        ('n', Self::NEW),
+    ];
+
+    // None if the string has a character that is not a flag.
+    pub fn parse(flags: &str) -> Option<Self> {
+        let mut parsed = Self::default();
+        for c in flags.chars() {
+            if c == 'A' {
+                parsed.0 |= "g$lshzxetd".chars().fold(0, |bits, c| bits | Self::bit(c));
+            } else {
+                parsed.0 |= Self::FLAGS.iter().find(|(flag, _)| *flag == c)?.1 .0;
+            }
+        }
+        Some(parsed)
+    }
+
+    fn bit(c: char) -> u16 {
+        Self::FLAGS
+            .iter()
+            .find(|(flag, _)| *flag == c)
+            .map_or(0, |(_, bits)| bits.0)
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0".to_string(),
+            port: 6379,
+            unixsocket: None,
+            unixsocketperm: None,
+            tls_port: 0,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: TlsAuthClients::default(),
+            requirepass: None,
+            aclfile: None,
+            // The save points redis uses when it is started without a config file.
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            dir: PathBuf::from("."),
+            // Not dump.rdb: the file uses this server's own snapshot format, not the redis RDB format.
+            dbfilename: "dump.srdb".to_string(),
+            snapshot_format: SnapshotFormat::default(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::default(),
+            replicaof: None,
+            masteruser: None,
+            masterauth: None,
+            replica_read_only: true,
+            repl_backlog_size: 1024 * 1024,
+            cluster_enabled: false,
+            cluster_port: 0,
+            cluster_node_timeout: 15000,
+            cluster_announce_ip: None,
+            notify_keyspace_events: NotifyKeyspaceEvents::default(),
+            busy_reply_threshold: 5000,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::default(),
+            maxmemory_samples: 5,
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+        }
+    }
+}
+
+impl ServerConfig {
+    // Parses the process arguments (without the program name).
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        let mut args = args.into_iter().peekable();
+
+        // An optional config file comes first, so that the options after it can override it.
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            config.load_file(path)?;
+        }
+
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| ConfigError::UnknownDirective(arg.clone()))?;
+            // Values may span several arguments (e.g. --save 900 1), so take everything up to the next option.
+            let mut values = Vec::new();
+            while let Some(value) = args.next_if(|arg| !arg.starts_with("--")) {
+                values.push(value);
+            }
+            config.set(name, &values.join(" "))?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        let content = std::fs::read_to_string(path)?;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            self.set(name, value.trim())?;
+        }
+        Ok(())
+    }
+
+    // Applies a single directive. Names are case-insensitive, like in redis.conf.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let name = name.to_ascii_lowercase();
+        let value = unquote(value);
+        match name.as_str() {
+            "bind" => self.bind = value.to_string(),
+            "port" => self.port = parse_value(&name, value)?,
+            "unixsocket" => self.unixsocket = Some(PathBuf::from(value)),
+            "unixsocketperm" => {
+                let perm = u32::from_str_radix(value, 8)
+                    .map_err(|_| ConfigError::InvalidValue(name.clone(), value.to_string()))?;
+                self.unixsocketperm = Some(perm);
+            }
+            "tls-port" => self.tls_port = parse_value(&name, value)?,
+            "tls-cert-file" => self.tls_cert_file = Some(PathBuf::from(value)),
+            "tls-key-file" => self.tls_key_file = Some(PathBuf::from(value)),
+            "tls-ca-cert-file" => self.tls_ca_cert_file = Some(PathBuf::from(value)),
+            // An empty requirepass ("") turns authentication off again, like in redis.conf.
+            "requirepass" if value.is_empty() => self.requirepass = None,
+            "requirepass" => self.requirepass = Some(value.to_string()),
+            "aclfile" => self.aclfile = Some(PathBuf::from(value)),
+            "save" => self.save = parse_save_points(&name, value)?,
+            "dir" => self.dir = PathBuf::from(value),
+            "dbfilename" => self.dbfilename = value.to_string(),
+            "snapshot-format" => {
+                self.snapshot_format = match value.to_ascii_lowercase().as_str() {
+                    "native" => SnapshotFormat::Native,
+                    "rdb" => SnapshotFormat::Rdb,
+                    _ => return Err(ConfigError::InvalidValue(name, value.to_string())),
+                }
+            }
+            "appendonly" => self.appendonly = parse_yes_no(&name, value)?,
+            "appendfilename" => self.appendfilename = value.to_string(),
+            "appendfsync" => {
+                self.appendfsync = match value.to_ascii_lowercase().as_str() {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::EverySec,
+                    "no" => AppendFsync::No,
+                    _ => return Err(ConfigError::InvalidValue(name, value.to_string())),
+                }
+            }
+            "replicaof" | "slaveof" => {
+                let mut parts = value.split_whitespace();
+                self.replicaof = match (parts.next(), parts.next(), parts.next()) {
+                    (Some(host), Some(port), None) => {
+                        Some((host.to_string(), parse_value(&name, port)?))
+                    }
+                    _ => return Err(ConfigError::InvalidValue(name, value.to_string())),
+                }
+            }
+            "masteruser" => self.masteruser = Some(value.to_string()),
+            "masterauth" => self.masterauth = Some(value.to_string()),
+            "replica-read-only" | "slave-read-only" => {
+                self.replica_read_only = parse_yes_no(&name, value)?
+            }
+            "repl-backlog-size" => self.repl_backlog_size = parse_memory(&name, value)?,
+            "cluster-enabled" => self.cluster_enabled = parse_yes_no(&name, value)?,
+            "cluster-port" => self.cluster_port = parse_value(&name, value)?,
+            "cluster-node-timeout" => self.cluster_node_timeout = parse_value(&name, value)?,
+            "cluster-announce-ip" => self.cluster_announce_ip = Some(value.to_string()),
+            "busy-reply-threshold" | "lua-time-limit" => {
+                self.busy_reply_threshold = parse_value(&name, value)?
+            }
+            "maxmemory" => self.maxmemory = parse_memory(&name, value)?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = match value.to_ascii_lowercase().as_str() {
+                    "noeviction" => MaxmemoryPolicy::NoEviction,
+                    "allkeys-lru" => MaxmemoryPolicy::AllKeysLru,
+                    "allkeys-lfu" => MaxmemoryPolicy::AllKeysLfu,
+                    "allkeys-random" => MaxmemoryPolicy::AllKeysRandom,
+                    "volatile-lru" => MaxmemoryPolicy::VolatileLru,
+                    "volatile-lfu" => MaxmemoryPolicy::VolatileLfu,
+                    "volatile-random" => MaxmemoryPolicy::VolatileRandom,
+                    "volatile-ttl" => MaxmemoryPolicy::VolatileTtl,
+                    _ => return Err(ConfigError::InvalidValue(name, value.to_string())),
+                }
+            }
+            "maxmemory-samples" => match parse_value(&name, value)? {
+                0 => return Err(ConfigError::InvalidValue(name, value.to_string())),
+                samples => self.maxmemory_samples = samples,
+            },
+            // The names of redis before 7.0 are accepted too.
+            "hash-max-listpack-entries" | "hash-max-ziplist-entries" => {
+                self.hash_max_listpack_entries = parse_value(&name, value)?
+            }
+            "hash-max-listpack-value" | "hash-max-ziplist-value" => {
+                self.hash_max_listpack_value = parse_value(&name, value)?
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = NotifyKeyspaceEvents::parse(value)
+                    .ok_or_else(|| ConfigError::InvalidValue(name.clone(), value.to_string()))?
+            }
+            "tls-auth-clients" => {
+                self.tls_auth_clients = match value.to_ascii_lowercase().as_str() {
+                    "yes" => TlsAuthClients::Yes,
+                    "optional" => TlsAuthClients::Optional,
+                    "no" => TlsAuthClients::No,
+                    _ => return Err(ConfigError::InvalidValue(name, value.to_string())),
+                }
+            }
+            _ => return Err(ConfigError::UnknownDirective(name)),
+        }
+        Ok(())
+    }
+
+    pub fn tcp_addr(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+
+    pub fn tls_addr(&self) -> String {
+        format!("{}:{}", self.bind, self.tls_port)
+    }
+
+    pub fn cluster_bus_port(&self) -> u16 {
+        match self.cluster_port {
+            0 => self.port.wrapping_add(10000),
+            port => port,
+        }
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::InvalidValue(name.to_string(), value.to_string()))
+}
+
+fn parse_yes_no(name: &str, value: &str) -> Result<bool, ConfigError> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(ConfigError::InvalidValue(
+            name.to_string(),
+            value.to_string(),
+        )),
+    }
+}
+
+// A size in bytes with an optional unit, like in redis.conf: 1k = 1000, 1kb = 1024, 1m, 1mb, 1g, 1gb.
+fn parse_memory(name: &str, value: &str) -> Result<usize, ConfigError> {
+    let lower = value.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(char::is_alphabetic);
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(ConfigError::InvalidValue(
+                name.to_string(),
+                value.to_string(),
+            ))
+        }
+    };
+    parse_value::<usize>(name, digits)?
+        .checked_mul(unit)
+        .ok_or_else(|| ConfigError::InvalidValue(name.to_string(), value.to_string()))
+}
+
+// "3600 1 300 100" -> [(3600, 1), (300, 100)]; the values must come in pairs.
+fn parse_save_points(name: &str, value: &str) -> Result<Vec<(u64, u64)>, ConfigError> {
+    let values = value.split_whitespace().collect::<Vec<_>>();
+    if values.len() % 2 != 0 {
+        return Err(ConfigError::InvalidValue(
+            name.to_string(),
+            value.to_string(),
+        ));
+    }
+    values
+        .chunks(2)
+        .map(|pair| Ok((parse_value(name, pair[0])?, parse_value(name, pair[1])?)))
+        .collect()
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_config_from_args() -> Result<()> {
+        let config = ServerConfig::from_args(args(
+            "--port 0 --unixsocket /tmp/simple-redis.sock --unixsocketperm 770",
+        ))?;
+        assert_eq!(config.port, 0);
+        assert_eq!(
+            config.unixsocket,
+            Some(PathBuf::from("/tmp/simple-redis.sock"))
+        );
+        assert_eq!(config.unixsocketperm, Some(0o770));
+        assert_eq!(config.bind, "0.0.0.0");
+
+        let config = ServerConfig::from_args(args(
+            "--tls-port 6380 --tls-cert-file redis.crt --tls-key-file redis.key --tls-auth-clients optional",
+        ))?;
+        assert_eq!(config.tls_addr(), "0.0.0.0:6380");
+        assert_eq!(config.tls_cert_file, Some(PathBuf::from("redis.crt")));
+        assert_eq!(config.tls_key_file, Some(PathBuf::from("redis.key")));
+        assert_eq!(config.tls_ca_cert_file, None);
+        assert_eq!(config.tls_auth_clients, TlsAuthClients::Optional);
+
+        let config = ServerConfig::from_args(args("--requirepass foobared"))?;
+        assert_eq!(config.requirepass.as_deref(), Some("foobared"));
+        let config = ServerConfig::from_args(args("--aclfile /etc/redis/users.acl"))?;
+        assert_eq!(config.aclfile, Some(PathBuf::from("/etc/redis/users.acl")));
+
+        let config = ServerConfig::from_args(args("--save 900 1 300 10 --dir /var/lib/redis"))?;
+        assert_eq!(config.save, vec![(900, 1), (300, 10)]);
+        assert_eq!(
+            config.snapshot_path(),
+            PathBuf::from("/var/lib/redis/dump.srdb")
+        );
+        let config = ServerConfig::from_args(vec!["--save".to_string(), "".to_string()])?;
+        assert!(config.save.is_empty());
+        assert!(ServerConfig::from_args(args("--save 900")).is_err());
+        let config = ServerConfig::from_args(args("--snapshot-format rdb --dbfilename dump.rdb"))?;
+        assert_eq!(config.snapshot_format, SnapshotFormat::Rdb);
+        assert_eq!(config.snapshot_path(), PathBuf::from("./dump.rdb"));
+        assert!(ServerConfig::from_args(args("--snapshot-format json")).is_err());
+
+        let config = ServerConfig::from_args(args("--appendonly yes --appendfsync always"))?;
+        assert!(config.appendonly);
+        assert_eq!(config.appendfsync, AppendFsync::Always);
+        assert_eq!(config.aof_path(), PathBuf::from("./appendonly.aof"));
+        assert!(ServerConfig::from_args(args("--appendonly maybe")).is_err());
+
+        let config = ServerConfig::from_args(args(
+            "--replicaof 10.0.0.1 6379 --masterauth secret --repl-backlog-size 16mb",
+        ))?;
+        assert_eq!(config.replicaof, Some(("10.0.0.1".to_string(), 6379)));
+        assert_eq!(config.masterauth.as_deref(), Some("secret"));
+        assert!(config.replica_read_only);
+        assert_eq!(config.repl_backlog_size, 16 * 1024 * 1024);
+        assert_eq!(parse_memory("maxmemory", "100")?, 100);
+        assert_eq!(parse_memory("maxmemory", "2k")?, 2000);
+        assert!(ServerConfig::from_args(args("--repl-backlog-size 1xb")).is_err());
+        assert!(ServerConfig::from_args(args("--replicaof 10.0.0.1")).is_err());
+
+        let config = ServerConfig::from_args(args("--port 7000 --cluster-enabled yes"))?;
+        assert!(config.cluster_enabled);
+        assert_eq!(config.cluster_bus_port(), 17000);
+        let config =
+            ServerConfig::from_args(args("--cluster-port 7100 --cluster-node-timeout 500"))?;
+        assert_eq!(config.cluster_bus_port(), 7100);
+        assert_eq!(config.cluster_node_timeout, 500);
+        assert_eq!(ServerConfig::default().busy_reply_threshold, 5000);
+        let config = ServerConfig::from_args(args("--lua-time-limit 100"))?;
+        assert_eq!(config.busy_reply_threshold, 100);
+
+        let config = ServerConfig::from_args(args("--notify-keyspace-events Ex"))?;
+        let events = config.notify_keyspace_events;
+        assert!(events.contains(NotifyKeyspaceEvents::KEYEVENT));
+        assert!(events.contains(NotifyKeyspaceEvents::EXPIRED));
+        assert!(!events.contains(NotifyKeyspaceEvents::KEYSPACE));
+        assert!(!events.contains(NotifyKeyspaceEvents::GENERIC));
+        let events = NotifyKeyspaceEvents::parse("KA").unwrap();
+        assert!(events.contains(NotifyKeyspaceEvents::HASH));
+        assert!(!events.contains(NotifyKeyspaceEvents::NEW));
+        assert!(ServerConfig::from_args(args("--notify-keyspace-events Kq")).is_err());
+
+        let config = ServerConfig::from_args(args(
+            "--maxmemory 100mb --maxmemory-policy Volatile-TTL --maxmemory-samples 10",
+        ))?;
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+        assert_eq!(config.maxmemory_policy, MaxmemoryPolicy::VolatileTtl);
+        assert!(config.maxmemory_policy.is_volatile());
+        assert_eq!(config.maxmemory_samples, 10);
+        assert_eq!(
+            ServerConfig::default().maxmemory_policy,
+            MaxmemoryPolicy::NoEviction
+        );
+        assert!(ServerConfig::from_args(args("--maxmemory-policy allkeys-mru")).is_err());
+        assert!(ServerConfig::from_args(args("--maxmemory-samples 0")).is_err());
+
+        let config = ServerConfig::from_args(args(
+            "--hash-max-listpack-entries 512 --hash-max-ziplist-value 32",
+        ))?;
+        assert_eq!(config.hash_max_listpack_entries, 512);
+        assert_eq!(config.hash_max_listpack_value, 32);
+        assert_eq!(ServerConfig::default().hash_max_listpack_entries, 128);
+
+        assert!(ServerConfig::from_args(args("--port abc")).is_err());
+        assert!(ServerConfig::from_args(args("--tls-auth-clients maybe")).is_err());
+        assert!(ServerConfig::from_args(args("--no-such-option 1")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_from_file() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("simple-redis-{}.conf", std::process::id()));
+        std::fs::write(
+            &path,
+            "# comment\n\nbind 127.0.0.1\nPORT 6380\nunixsocket \"/tmp/redis.sock\"\n",
+        )?;
+
+        let config = ServerConfig::from_args(vec![
+            path.display().to_string(),
+            "--port".into(),
+            "6381".into(),
+        ])?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(config.tcp_addr(), "127.0.0.1:6381");
+        assert_eq!(config.unixsocket, Some(PathBuf::from("/tmp/redis.sock")));
+        Ok(())
+    }
+}
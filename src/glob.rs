@@ -0,0 +1,120 @@
+// Glob-style pattern matching with the same rules as redis' stringmatchlen:
+//   *      matches any sequence of characters (including none)
+//   ?      matches exactly one character
+//   [abc]  matches one of the listed characters, [^abc] any character except them, [a-z] a range
+//   \x     matches x literally (to match a literal *, ?, [ or \)
+// Used for ACL key / channel patterns, and anywhere else a client sends a pattern.
+
+// Iterative, keeping only the position of the last star to backtrack to: a later star can match everything
+// an earlier one could, so the earlier split points never need to be retried. This keeps it O(n * m) no matter
+// how many stars a client puts in a pattern.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // The pattern index right after the last star, and the string index that star is matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            while pattern.get(p) == Some(&b'*') {
+                p += 1;
+            }
+            star = Some((p, s));
+            continue;
+        }
+        if p < pattern.len() {
+            if let Some(len) = match_one(&pattern[p..], string[s]) {
+                p += len;
+                s += 1;
+                continue;
+            }
+        }
+        // Mismatch: let the last star swallow one more character and retry from there.
+        let Some((star_p, star_s)) = star else {
+            return false;
+        };
+        p = star_p;
+        s = star_s + 1;
+        star = Some((star_p, s));
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Matches c against the first element of the pattern, which is not a star.
+// Returns how many pattern bytes the element used if it matched.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern[0] {
+        b'?' => Some(1),
+        b'[' => {
+            let (matched, consumed) = match_class(&pattern[1..], c);
+            matched.then_some(1 + consumed)
+        }
+        // A backslash escapes the next character; a trailing backslash matches itself.
+        b'\\' if pattern.len() >= 2 => (pattern[1] == c).then_some(2),
+        p => (p == c).then_some(1),
+    }
+}
+
+// Matches c against a character class; class starts right after the '['.
+// Returns whether it matched and how many pattern bytes the class used (including the closing ']').
+fn match_class(class: &[u8], c: u8) -> (bool, usize) {
+    let mut i = 0;
+    let negate = class.first() == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < class.len() && class[i] != b']' {
+        if class[i] == b'\\' && i + 1 < class.len() {
+            matched |= class[i + 1] == c;
+            i += 2;
+        } else if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+            let (start, end) = if class[i] <= class[i + 2] {
+                (class[i], class[i + 2])
+            } else {
+                (class[i + 2], class[i])
+            };
+            matched |= (start..=end).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    // An unterminated class runs to the end of the pattern, like in redis.
+    let consumed = if i < class.len() { i + 1 } else { i };
+    (matched != negate, consumed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"cache:*", b"cache:user:1"));
+        assert!(!glob_match(b"cache:*", b"session:1"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(!glob_match(b"h[a-b]llo", b"hcllo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"*:*:*", b"a:b:c"));
+        assert!(!glob_match(b"exact", b"exactly"));
+        assert!(glob_match(b"a*", b"a"));
+        assert!(!glob_match(b"a*b", b"ab*"));
+        assert!(glob_match(b"*a*b", b"xxaxxbxab"));
+
+        // Many stars used to backtrack exponentially.
+        let pattern = b"*a*a*a*a*a*a*a*a*a*a*a*b";
+        assert!(!glob_match(pattern, &[b'a'; 4096]));
+        assert!(glob_match(pattern, &[&[b'a'; 4096][..], b"b"].concat()));
+    }
+}
@@ -1,7 +1,18 @@
+pub mod acl;
 mod backend;
+pub mod cluster;
 pub mod cmd;
+pub mod config;
+mod glob;
+pub mod module;
 pub mod network;
+pub mod persistence;
+pub mod pubsub;
+pub mod replication;
 mod resp;
+pub mod scripting;
+pub mod shutdown;
+pub mod tls;
 
 pub use backend::*;
 pub use resp::*;
@@ -1,8 +1,15 @@
 // This is the entry point of the application.
 // It initializes the server, listens for incoming TCP connections, and spawns tasks to handle each connection.
+// It also installs the signal handlers so the server can shut down gracefully.
 
 use anyhow::Result;
-use simple_redis::{network, Backend};
+use simple_redis::{
+    cluster,
+    config::{AppendFsync, ServerConfig},
+    network::{self, Listener},
+    persistence::{self, aof},
+    replication, shutdown, tls, Backend,
+};
 use tokio::net::TcpListener;
 use tracing::{info, warn};
 
@@ -10,37 +17,105 @@ use tracing::{info, warn};
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let addr = "0.0.0.0:6379";
-    info!("Simple-Redis-Server is listening on {}", addr);
-    let listener = TcpListener::bind(addr).await?;
+    // simple-redis [/path/to/redis.conf] [--port 6379] [--unixsocket /tmp/redis.sock] [--unixsocketperm 700]
+    //              [--tls-port 6380 --tls-cert-file redis.crt --tls-key-file redis.key --tls-ca-cert-file ca.crt]
+    let config = ServerConfig::from_args(std::env::args().skip(1))?;
 
     // Initializes the backend storage system (e.g., a key-value store).
     // This backend will be shared across all client connections.
-    let backend = Backend::new();
-    loop {
-        let (stream, raddr) = listener.accept().await?;
-        info!("Accepted connection from: {}", raddr);
-
-        // Clones the backend so that it can be shared with the task handling the connection.
-        // The backend is likely implemented with a thread-safe data structure like DashMap.
-        let cloned_backend = backend.clone();
-
-        // Spawns a new asynchronous task to handle the connection.
-        // This allows the server to continue accepting new connections while handling existing ones.
-        tokio::spawn(async move {
-            // network::stream_handler:
-            // Handles the logic for processing client requests over the stream.
-            // Likely includes parsing commands (e.g., SET, GET) and interacting with the backend.
-            match network::stream_handler(stream, cloned_backend).await {
-                Ok(_) => {
-                    info!("Connection from {} exited", raddr);
-                }
-                Err(e) => {
-                    warn!("handle error for {}: {:?}", raddr, e);
-                }
+    let backend = Backend::with_config(config);
+    // Users from the aclfile replace the ones derived from requirepass.
+    if backend.config().aclfile.is_some() {
+        backend.acl().load()?;
+    }
+    // The dataset is loaded before any listener is bound, so no client ever sees a partially loaded dataset.
+    // With appendonly yes the AOF is the source of truth; the snapshot is only used when there is no AOF yet.
+    let replayed = if backend.config().appendonly {
+        aof::load(&backend)?
+    } else {
+        None
+    };
+    match replayed {
+        Some(n) => info!(
+            "DB loaded from append only file {}: {} entries",
+            backend.config().aof_path().display(),
+            n
+        ),
+        None => {
+            if let Some(keys) = persistence::load(&backend)? {
+                info!(
+                    "DB loaded from {}: {} keys",
+                    backend.config().snapshot_path().display(),
+                    keys
+                );
             }
-        });
+        }
+    }
+    if backend.config().appendonly {
+        aof::open(&backend)?;
+    }
+
+    let config = backend.config();
+    let mut listeners = Vec::new();
+    // port 0 disables TCP, so the server can be reachable through the unix socket only.
+    if config.port != 0 {
+        let addr = config.tcp_addr();
+        info!("Simple-Redis-Server is listening on {}", addr);
+        listeners.push(Listener::from(TcpListener::bind(addr).await?));
+    }
+    if config.tls_port != 0 {
+        let acceptor = tls::build_acceptor(config)?;
+        let addr = config.tls_addr();
+        info!("Simple-Redis-Server is listening on {} (TLS)", addr);
+        listeners.push(Listener::Tls(TcpListener::bind(addr).await?, acceptor));
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.unixsocket {
+        info!(
+            "Simple-Redis-Server is listening on unix:{}",
+            path.display()
+        );
+        listeners.push(Listener::bind_unix(path, config.unixsocketperm)?);
+    }
+    if listeners.is_empty() {
+        anyhow::bail!("nothing to listen on: set a port, a tls-port or a unixsocket");
     }
+    if config.cluster_enabled {
+        let addr = format!("{}:{}", config.bind, config.cluster_bus_port());
+        info!("Cluster bus is listening on {}", addr);
+        let bus = TcpListener::bind(addr).await?;
+        tokio::spawn(cluster::run(bus, backend.clone()));
+    }
+
+    // Background saves when one of the `save <seconds> <changes>` points is reached.
+    tokio::spawn(persistence::autosave(backend.clone()));
+    if backend.config().appendonly && backend.config().appendfsync == AppendFsync::EverySec {
+        tokio::spawn(aof::fsync_every_second(backend.clone()));
+    }
+    // Removes expired keys in the background, the ones no client reads included.
+    tokio::spawn(network::active_expire(backend.clone()));
+    // Keeps the links to the replicas alive, and connects to the primary when configured with replicaof.
+    tokio::spawn(replication::ping_replicas(backend.clone()));
+    if let Some(master) = backend.config().replicaof.clone() {
+        replication::replicaof(&backend, Some(master));
+    }
+
+    // SIGINT (Ctrl-C) / SIGTERM start the same graceful shutdown as a plain SHUTDOWN command.
+    let signal_backend = backend.clone();
+    tokio::spawn(async move {
+        match shutdown::wait_for_signal().await {
+            Ok(()) => {
+                info!("Received shutdown signal, scheduling shutdown...");
+                signal_backend.shutdown().trigger(Default::default());
+            }
+            Err(e) => warn!("failed to listen for shutdown signals: {:?}", e),
+        }
+    });
+
+    // network::serve:
+    // Accepts connections on all listeners and spawns a task running network::stream_handler for each of them,
+    // until a shutdown is triggered; then it waits for the in-flight commands to finish and returns.
+    network::serve(listeners, backend).await
 }
 
 // step 1:
@@ -0,0 +1,376 @@
+// Native modules: commands and value types other crates add to the server without changing this one.
+// A module is registered at startup, before the server takes connections; like redis' modules, the registry is
+// per process and shared by every Backend.
+// A command is a type that parses itself from the request (TryFrom<RespArray>) and runs against the backend
+// (CommandExecutor), like the built-in ones, together with its CommandSpec: the ACL categories, whether it writes
+// (then it is logged to the AOF and the replication stream, and refused on a read-only replica) and where its keys
+// are (ACL key patterns, cluster slots). It can be queued in MULTI and called from scripts like any other command.
+// A value type is stored under a key next to the strings and hashes (see Backend::module_value / set_module_value).
+// Snapshots and DUMP payloads keep it as its type name and the bytes of ModuleType::encode; RDB files have no room
+// for it, so saving one with snapshot-format rdb fails (see rdb::encode) instead of losing the value.
+
+use crate::{
+    cmd::{
+        spec::{self, CommandSpec},
+        Command, CommandError, CommandExecutor,
+    },
+    Backend, RespArray, RespFrame,
+};
+use lazy_static::lazy_static;
+use std::any::Any;
+use std::fmt::Debug;
+use std::sync::RwLock;
+use thiserror::Error;
+
+type Parser = fn(RespArray) -> Result<Command, CommandError>;
+type Decoder = fn(&[u8]) -> Option<Box<dyn ModuleValue>>;
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Registry> = RwLock::new(Registry::default());
+}
+
+// A type whose values modules store under keys.
+pub trait ModuleType: Any + Clone + Debug + Send + Sync {
+    // The name values are saved under, so it must not change once some were saved.
+    const NAME: &'static str;
+
+    fn encode(&self) -> Vec<u8>;
+
+    // None if data is not a valid encoding; loading the snapshot (or RESTORE) fails then.
+    fn decode(data: &[u8]) -> Option<Self>;
+}
+
+// A value of some ModuleType, as the backend stores it.
+pub trait ModuleValue: Any + Debug + Send + Sync {
+    fn type_name(&self) -> &'static str;
+    fn encode(&self) -> Vec<u8>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: ModuleType> ModuleValue for T {
+    fn type_name(&self) -> &'static str {
+        T::NAME
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        ModuleType::encode(self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// A parsed module command, the Command::Module variant.
+#[derive(Debug)]
+pub struct ModuleCommand(Box<dyn DynCommand>);
+
+trait DynCommand: Debug + Send {
+    fn execute_boxed(self: Box<Self>, backend: &Backend) -> RespFrame;
+}
+
+impl<C: CommandExecutor + Debug + Send> DynCommand for C {
+    fn execute_boxed(self: Box<Self>, backend: &Backend) -> RespFrame {
+        (*self).execute(backend)
+    }
+}
+
+impl CommandExecutor for ModuleCommand {
+    fn execute(self, backend: &Backend) -> RespFrame {
+        self.0.execute_boxed(backend)
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ModuleError {
+    #[error("module '{0}' is already registered")]
+    ModuleExists(String),
+    #[error("command '{0}' already exists")]
+    CommandExists(String),
+    #[error("command '{0}': unknown category '{1}'")]
+    UnknownCategory(String, String),
+    #[error("value type '{0}' already exists")]
+    TypeExists(String),
+}
+
+// The commands and value types of a module, put together before it is registered.
+pub struct Module {
+    name: &'static str,
+    commands: Vec<(CommandSpec, Parser)>,
+    types: Vec<(&'static str, Decoder)>,
+}
+
+impl Module {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            commands: Vec::new(),
+            types: Vec::new(),
+        }
+    }
+
+    // Adds a command; spec.name is the name clients call it by.
+    pub fn command<C>(mut self, spec: CommandSpec) -> Self
+    where
+        C: TryFrom<RespArray, Error = CommandError> + CommandExecutor + Debug + Send + 'static,
+    {
+        self.commands.push((spec, parse::<C>));
+        self
+    }
+
+    pub fn value_type<T: ModuleType>(mut self) -> Self {
+        self.types.push((T::NAME, decode::<T>));
+        self
+    }
+}
+
+fn parse<C>(args: RespArray) -> Result<Command, CommandError>
+where
+    C: TryFrom<RespArray, Error = CommandError> + CommandExecutor + Debug + Send + 'static,
+{
+    Ok(Command::Module(ModuleCommand(Box::new(C::try_from(args)?))))
+}
+
+fn decode<T: ModuleType>(data: &[u8]) -> Option<Box<dyn ModuleValue>> {
+    T::decode(data).map(|value| Box::new(value) as Box<dyn ModuleValue>)
+}
+
+#[derive(Default)]
+struct Registry {
+    modules: Vec<&'static str>,
+    // The specs are leaked: they live as long as the process, like the built-in ones in spec::COMMAND_TABLE.
+    commands: Vec<(&'static CommandSpec, Parser)>,
+    types: Vec<(&'static str, Decoder)>,
+}
+
+// Makes the commands and value types of a module available. Nothing is registered if one of them clashes with
+// a command or type that exists already.
+pub fn register(module: Module) -> Result<(), ModuleError> {
+    let mut registry = REGISTRY.write().unwrap();
+    if registry.modules.contains(&module.name) {
+        return Err(ModuleError::ModuleExists(module.name.to_string()));
+    }
+    for (i, (spec, _)) in module.commands.iter().enumerate() {
+        let taken = |other: &CommandSpec| other.name.eq_ignore_ascii_case(spec.name);
+        let exists = spec::COMMAND_TABLE.iter().any(taken)
+            || registry.commands.iter().any(|(other, _)| taken(other))
+            || module.commands[..i].iter().any(|(other, _)| taken(other));
+        if exists {
+            return Err(ModuleError::CommandExists(spec.name.to_string()));
+        }
+        if let Some(category) = spec
+            .categories
+            .iter()
+            .find(|category| !spec::CATEGORIES.contains(category))
+        {
+            return Err(ModuleError::UnknownCategory(
+                spec.name.to_string(),
+                category.to_string(),
+            ));
+        }
+    }
+    for (i, (name, _)) in module.types.iter().enumerate() {
+        let exists = registry.types.iter().any(|(other, _)| other == name)
+            || module.types[..i].iter().any(|(other, _)| other == name);
+        if exists {
+            return Err(ModuleError::TypeExists(name.to_string()));
+        }
+    }
+
+    registry.modules.push(module.name);
+    for (spec, parser) in module.commands {
+        let spec: &'static CommandSpec = Box::leak(Box::new(spec));
+        registry.commands.push((spec, parser));
+    }
+    registry.types.extend(module.types);
+    Ok(())
+}
+
+// The names of the registered modules.
+pub fn modules() -> Vec<&'static str> {
+    REGISTRY.read().unwrap().modules.clone()
+}
+
+// The arguments of a command after its name, for the TryFrom of module commands.
+pub fn args(value: RespArray) -> Result<Vec<Vec<u8>>, CommandError> {
+    value
+        .0
+        .into_iter()
+        .skip(1)
+        .map(|arg| match arg {
+            RespFrame::BulkString(arg) => Ok(arg.0),
+            _ => Err(CommandError::InvalidArgument(
+                "Argument must be a BulkString".to_string(),
+            )),
+        })
+        .collect()
+}
+
+pub(crate) fn lookup_spec(name: &[u8]) -> Option<&'static CommandSpec> {
+    lookup(name).map(|(spec, _)| spec)
+}
+
+pub(crate) fn specs() -> Vec<&'static CommandSpec> {
+    let registry = REGISTRY.read().unwrap();
+    registry.commands.iter().map(|(spec, _)| *spec).collect()
+}
+
+// Parses a request for a module command; None if no module has a command of that name.
+pub(crate) fn parse_command(
+    name: &[u8],
+    value: RespArray,
+) -> Option<Result<Command, CommandError>> {
+    lookup(name).map(|(_, parser)| parser(value))
+}
+
+fn lookup(name: &[u8]) -> Option<(&'static CommandSpec, Parser)> {
+    let registry = REGISTRY.read().unwrap();
+    registry
+        .commands
+        .iter()
+        .find(|(spec, _)| spec.name.as_bytes().eq_ignore_ascii_case(name))
+        .copied()
+}
+
+// A saved value of a module type; None if no module registered the type or data does not decode.
+pub(crate) fn decode_value(type_name: &str, data: &[u8]) -> Option<Box<dyn ModuleValue>> {
+    let registry = REGISTRY.read().unwrap();
+    let (_, decoder) = registry.types.iter().find(|(name, _)| *name == type_name)?;
+    decoder(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::{rdb, snapshot::Snapshot};
+    use crate::BulkString;
+
+    // A counter type with COUNTER.INCR key / COUNTER.GET key.
+    #[derive(Debug, Clone, PartialEq)]
+    struct Counter(i64);
+
+    impl ModuleType for Counter {
+        const NAME: &'static str = "counter";
+
+        fn encode(&self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
+        }
+
+        fn decode(data: &[u8]) -> Option<Self> {
+            Some(Counter(i64::from_le_bytes(data.try_into().ok()?)))
+        }
+    }
+
+    #[derive(Debug)]
+    struct CounterIncr(String);
+
+    impl TryFrom<RespArray> for CounterIncr {
+        type Error = CommandError;
+        fn try_from(value: RespArray) -> Result<Self, Self::Error> {
+            match args(value)?.as_slice() {
+                [key] => Ok(CounterIncr(String::from_utf8_lossy(key).into_owned())),
+                _ => Err(CommandError::InvalidArgument(
+                    "wrong number of arguments".to_string(),
+                )),
+            }
+        }
+    }
+
+    impl CommandExecutor for CounterIncr {
+        fn execute(self, backend: &Backend) -> RespFrame {
+            let counter = backend
+                .module_value::<Counter>(&self.0)
+                .unwrap_or(Counter(0));
+            let counter = Counter(counter.0 + 1);
+            backend.set_module_value(self.0, counter.clone(), "counter.incr");
+            RespFrame::Integer(counter.0)
+        }
+    }
+
+    fn spec(name: &'static str, categories: &'static [&'static str]) -> CommandSpec {
+        CommandSpec {
+            name,
+            categories,
+            write: true,
+            first_key: 1,
+            last_key: 1,
+            step: 1,
+        }
+    }
+
+    fn request(args: &[&str]) -> RespFrame {
+        RespArray::new(
+            args.iter()
+                .map(|arg| BulkString::from(*arg).into())
+                .collect::<Vec<RespFrame>>(),
+        )
+        .into()
+    }
+
+    #[test]
+    fn test_register_and_run() {
+        let module = Module::new("counter")
+            .command::<CounterIncr>(spec("counter.incr", &["write", "fast"]))
+            .value_type::<Counter>();
+        assert_eq!(register(module), Ok(()));
+        assert!(modules().contains(&"counter"));
+
+        let backend = Backend::new();
+        let frame = request(&["COUNTER.INCR", "visits"]);
+        assert!(spec::is_write(&frame));
+        assert_eq!(
+            spec::lookup(b"counter.incr").map(|spec| spec.first_key),
+            Some(1)
+        );
+        for expected in 1..=2 {
+            let cmd = Command::try_from(frame.clone()).unwrap();
+            assert_eq!(cmd.execute(&backend), RespFrame::Integer(expected));
+        }
+        assert_eq!(backend.module_value::<Counter>("visits"), Some(Counter(2)));
+        assert!(backend.exists("visits"));
+        assert!(Command::try_from(request(&["counter.incr"])).is_err());
+
+        // The value is saved by its type name, in snapshots and DUMP payloads.
+        let restored = Backend::new();
+        let snapshot = Snapshot::decode(&Snapshot::from_backend(&backend).encode()).unwrap();
+        assert_eq!(snapshot.restore(&restored), 1);
+        assert_eq!(restored.module_value::<Counter>("visits"), Some(Counter(2)));
+        let payload = rdb::dump(&backend.value("visits").unwrap());
+        let value = rdb::parse_dump(&payload).unwrap();
+        assert!(restored.restore("copy".to_string(), value, None, false));
+        // An RDB file cannot hold it, so saving one fails rather than losing the value.
+        assert!(matches!(
+            rdb::encode(&Snapshot::from_backend(&backend)),
+            Err(crate::persistence::PersistenceError::Unsupported(_))
+        ));
+        assert_eq!(restored.module_value::<Counter>("copy"), Some(Counter(2)));
+        assert!(decode_value("counter", b"short").is_none());
+        assert!(decode_value("nosuchtype", b"").is_none());
+
+        assert!(backend.del("visits"));
+        assert_eq!(backend.module_value::<Counter>("visits"), None);
+    }
+
+    #[test]
+    fn test_register_conflicts() {
+        let conflict = |module: Module| register(module).unwrap_err();
+        assert_eq!(
+            conflict(Module::new("clash").command::<CounterIncr>(spec("GET", &["read"]))),
+            ModuleError::CommandExists("GET".to_string())
+        );
+        assert_eq!(
+            conflict(Module::new("clash").command::<CounterIncr>(spec("clash.cmd", &["nosuch"]))),
+            ModuleError::UnknownCategory("clash.cmd".to_string(), "nosuch".to_string())
+        );
+        assert_eq!(
+            conflict(
+                Module::new("clash")
+                    .value_type::<Counter>()
+                    .value_type::<Counter>()
+            ),
+            ModuleError::TypeExists("counter".to_string())
+        );
+        // Nothing of a refused module is registered.
+        assert!(!modules().contains(&"clash"));
+    }
+}
@@ -3,27 +3,66 @@
 
 // cmd: Contains the Command enum and CommandExecutor trait for parsing and executing commands.
 use crate::{
-    cmd::{Command, CommandExecutor},
-    Backend, RespDecode, RespEncode, RespError, RespFrame,
+    acl::AclDenied,
+    cmd::{spec, Command, CommandExecutor},
+    persistence,
+    persistence::aof::AofGuard,
+    pubsub::Subscription,
+    replication::{self, Feed, ReplicaSync},
+    shutdown::SHUTDOWN_TIMEOUT,
+    Backend, BulkString, RespArray, RespDecode, RespEncode, RespError, RespFrame, RespNull,
+    RespNullArray, RespPush, SimpleError, SimpleString, WatchedKeys,
 };
 use anyhow::Result;
 use futures::SinkExt;
 // tokio and tokio_util:
 // Used for asynchronous networking and framing (splitting streams into frames).
-use tokio::net::TcpStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{RwLock, TryLockError, TryLockResult};
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::{Handle, RuntimeFlavor},
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Decoder, Encoder, Framed};
-use tracing::info;
+use tokio_util::{
+    codec::{Decoder, Encoder, Framed},
+    task::TaskTracker,
+};
+use tracing::{info, warn};
+
+// A client that opens a TLS connection but never completes the handshake is dropped after this long.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a command waits before it tries again to take the exec barrier held by EXEC or a script.
+const EXEC_BARRIER_RETRY: Duration = Duration::from_millis(1);
+
+// Active expiry, like activeExpireCycle in redis: every ACTIVE_EXPIRE_PERIOD, keys with a TTL are sampled
+// ACTIVE_EXPIRE_KEYS at a time, and sampling goes on while more than a quarter of them had expired, for at most
+// ACTIVE_EXPIRE_BUDGET.
+const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_KEYS: usize = 20;
+const ACTIVE_EXPIRE_BUDGET: Duration = Duration::from_millis(25);
+
+// The ID of the next client connection (HELLO replies with it, the pub/sub broker tells subscribers apart by it).
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
 
 // RespFrameCodec:
 // A codec for encoding and decoding RESP frames.
 // Used with tokio_util::codec::Framed to handle streams of RESP frames.
 #[derive(Debug)]
-struct RespFrameCodec; // The term codec is short for "coder-decoder"
-                       // It refers to a system or component that:
-                       // Encodes structured data into a specific format (e.g., raw bytes for transmission).
-                       // Decodes data from that format back into structured data.
-                       // In the context of networking, a codec is used to handle the serialization and deserialization of data as it is sent and received over a network connection.
+pub(crate) struct RespFrameCodec; // The term codec is short for "coder-decoder"
+                                  // It refers to a system or component that:
+                                  // Encodes structured data into a specific format (e.g., raw bytes for transmission).
+                                  // Decodes data from that format back into structured data.
+                                  // In the context of networking, a codec is used to handle the serialization and deserialization of data as it is sent and received over a network connection.
 
 // RedisRequest:
 // Represents a client request.
@@ -36,18 +75,310 @@ struct RedisRequest {
     backend: Backend,
 }
 
+// ConnectionState:
+// Per-connection state that lives as long as the connection (unlike RedisRequest, which is per command).
+// user: the ACL user the connection is authenticated as, None until AUTH succeeds.
+// When the default user needs no password, connections start out authenticated as "default".
+// peer: the address of the client, for replicas (ROLE lists their IP and the port from REPLCONF listening-port).
+// replica: set by PSYNC; after the reply is sent the connection carries the replication stream.
+// log: how the writes of the connection went to the AOF and the replication stream.
+// asking: set by ASKING, for the next command only.
+// resp3: switched by HELLO; under RESP3 pub/sub messages are push frames and commands are allowed while subscribed.
+// subscription: created on the first (UN)SUBSCRIBE; while it has subscriptions (and the connection speaks RESP2)
+// the connection is in subscriber mode.
+// transaction: set by MULTI until EXEC or DISCARD.
+// watched: the keys WATCHed until EXEC, DISCARD or UNWATCH.
+#[derive(Debug)]
+struct ConnectionState {
+    id: u64,
+    user: Option<String>,
+    peer: String,
+    listening_port: u16,
+    replica: Option<ReplicaSync>,
+    log: WriteLog,
+    asking: bool,
+    resp3: bool,
+    subscription: Option<Subscription>,
+    transaction: Option<Transaction>,
+    watched: WatchedKeys,
+}
+
+// The commands queued after MULTI, each with the frame to log if it is a write.
+// aborted: a command failed to queue, EXEC discards the transaction.
+#[derive(Debug, Default)]
+struct Transaction {
+    commands: Vec<(Command, Option<RespFrame>)>,
+    aborted: bool,
+}
+
+// woff: the replication offset right after the last write of the connection, what WAIT waits for.
+// block: Some while EXEC or a script runs, true once the MULTI that opens the block was logged. The writes of a
+// block are logged between MULTI and EXEC, so a replica or an AOF load applies all of them or none.
+#[derive(Debug, Default)]
+struct WriteLog {
+    woff: u64,
+    block: Option<bool>,
+}
+
+impl ConnectionState {
+    fn new(backend: &Backend, peer: String) -> Self {
+        Self {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            user: (!backend.requires_auth()).then(|| "default".to_string()),
+            peer,
+            listening_port: 0,
+            replica: None,
+            log: WriteLog::default(),
+            asking: false,
+            resp3: false,
+            subscription: None,
+            transaction: None,
+            watched: WatchedKeys::new(backend),
+        }
+    }
+
+    fn subscribed(&self, backend: &Backend) -> bool {
+        self.subscription
+            .as_ref()
+            .is_some_and(|subscription| backend.pubsub.subscriptions(subscription) > 0)
+    }
+
+    // The frame a pub/sub message (or (un)subscribe confirmation) is sent as.
+    fn pubsub_frame(&self, message: Vec<RespFrame>) -> RespFrame {
+        if self.resp3 {
+            RespPush::new(message).into()
+        } else {
+            RespArray::new(message).into()
+        }
+    }
+
+    // The IP part of the peer address ("127.0.0.1:54321" -> "127.0.0.1", "[::1]:54321" -> "::1").
+    fn peer_ip(&self) -> &str {
+        let ip = self
+            .peer
+            .rsplit_once(':')
+            .map_or(self.peer.as_str(), |(ip, _)| ip);
+        ip.trim_start_matches('[').trim_end_matches(']')
+    }
+}
+
 // RedisResponse:
 // Represents a server response.
 // Contains:
-// frame: The RESP frame to send back to the client.
+// frames: The RESP frames to send back to the client, usually one ((UN)SUBSCRIBE confirms every channel separately).
+// quit: close the connection once they are sent.
 #[derive(Debug)]
 struct RedisResponse {
-    frame: RespFrame,
+    frames: Vec<RespFrame>,
+    quit: bool,
+}
+
+impl From<RespFrame> for RedisResponse {
+    fn from(frame: RespFrame) -> Self {
+        Self {
+            frames: vec![frame],
+            quit: false,
+        }
+    }
+}
+
+// Listener:
+// A socket the server accepts client connections on.
+// The server can listen on several of them at once (e.g. TCP for remote clients, TLS, and a unix socket for a sidecar);
+// all of them share the same Backend.
+pub enum Listener {
+    Tcp(TcpListener),
+    // Accepts TCP connections and wraps them in TLS (see tls::build_acceptor).
+    Tls(TcpListener, TlsAcceptor),
+    // The path is kept so the socket file can be removed when the server exits.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+// Connection:
+// An accepted client connection. Each variant wraps a different stream type,
+// but they all end up in the same (generic) stream_handler.
+enum Connection {
+    Tcp(TcpStream),
+    // The handshake has not happened yet: it runs in the connection task, so a slow client cannot block the accept loop.
+    Tls(TcpStream, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    // Binds a unix domain socket at path, replacing a stale socket file left behind by a previous run,
+    // and applies the permissions from unixsocketperm (e.g. 0o700) if given.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl Into<PathBuf>, perm: Option<u32>) -> Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = path.into();
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        if let Some(perm) = perm {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(perm))?;
+        }
+        Ok(Listener::Unix(listener, path))
+    }
+
+    // Returns the accepted connection together with a printable peer address for logging.
+    async fn accept(&self) -> std::io::Result<(Connection, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, raddr) = listener.accept().await?;
+                Ok((Connection::Tcp(stream), raddr.to_string()))
+            }
+            Listener::Tls(listener, acceptor) => {
+                let (stream, raddr) = listener.accept().await?;
+                Ok((
+                    Connection::Tls(stream, acceptor.clone()),
+                    format!("tls:{}", raddr),
+                ))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                // Unix socket peers are unnamed, so the socket path is the most useful thing to log.
+                Ok((Connection::Unix(stream), format!("unix:{}", path.display())))
+            }
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Accepts connections on every listener until a shutdown is triggered (by a signal or the SHUTDOWN command), then drains.
+// Every connection task is spawned on a TaskTracker so that, once the accept loops stop,
+// we can wait for all of them to finish the command they are executing before returning.
+pub async fn serve(listeners: Vec<Listener>, backend: Backend) -> Result<()> {
+    let tracker = TaskTracker::new();
+
+    // One accept loop per listener; they all hand their connections to the same tracker.
+    let mut accept_loops = JoinSet::new();
+    for listener in listeners {
+        accept_loops.spawn(accept_loop(listener, backend.clone(), tracker.clone()));
+    }
+    while let Some(ret) = accept_loops.join_next().await {
+        if let Err(e) = ret? {
+            // A broken listener must not leave the server half-alive: shut everything down.
+            warn!("accept loop failed: {:?}", e);
+            backend.shutdown.trigger(Default::default());
+        }
+    }
+    tracker.close();
+
+    let request = backend.shutdown.request();
+    info!("Shutting down: {:?}", request);
+    if !request.now
+        && tokio::time::timeout(SHUTDOWN_TIMEOUT, tracker.wait())
+            .await
+            .is_err()
+    {
+        warn!(
+            "{} connection(s) still busy after {:?}, shutting down anyway",
+            tracker.len(),
+            SHUTDOWN_TIMEOUT
+        );
+    }
+
+    // Saved only now, after the in-flight commands finished, so the snapshot holds every write the server accepted.
+    // The connections are gone and the error cannot be replied: without FORCE it fails serve (and the process).
+    let mut saved = Ok(());
+    if persistence::save_on_shutdown(&backend, request.save) {
+        info!("Saving the final snapshot before exiting...");
+        let cloned_backend = backend.clone();
+        saved = tokio::task::spawn_blocking(move || persistence::save(&cloned_backend)).await?;
+        match &saved {
+            Ok(()) => info!("DB saved on disk"),
+            Err(e) => warn!("Error trying to save the DB: {}", e),
+        }
+    }
+    if let Err(e) = backend.aof.fsync() {
+        warn!("Error trying to fsync the AOF: {}", e);
+    }
+    if !request.force {
+        saved?;
+    }
+    info!("Simple-Redis-Server is now ready to exit, bye bye...");
+    Ok(())
+}
+
+async fn accept_loop(listener: Listener, backend: Backend, tracker: TaskTracker) -> Result<()> {
+    let token = backend.shutdown.token();
+    loop {
+        let (conn, raddr) = tokio::select! {
+            // biased: check the shutdown first, so no new connection is accepted once it is triggered.
+            biased;
+            // Returning drops the listener, so clients get "connection refused" instead of hanging in the backlog.
+            _ = token.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        info!("Accepted connection from: {}", raddr);
+
+        // Clones the backend so that it can be shared with the task handling the connection.
+        let cloned_backend = backend.clone();
+        tracker.spawn(async move {
+            let ret = match conn {
+                Connection::Tcp(stream) => {
+                    stream_handler(stream, cloned_backend, raddr.clone()).await
+                }
+                Connection::Tls(stream, acceptor) => {
+                    let peer = stream
+                        .peer_addr()
+                        .map(|a| a.to_string())
+                        .unwrap_or_default();
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => stream_handler(stream, cloned_backend, peer).await,
+                        Ok(Err(e)) => Err(anyhow::anyhow!("TLS handshake failed: {}", e)),
+                        Err(_) => Err(anyhow::anyhow!("TLS handshake timed out")),
+                    }
+                }
+                #[cfg(unix)]
+                Connection::Unix(stream) => {
+                    stream_handler(stream, cloned_backend, raddr.clone()).await
+                }
+            };
+            match ret {
+                Ok(_) => {
+                    info!("Connection from {} exited", raddr);
+                }
+                Err(e) => {
+                    warn!("handle error for {}: {:?}", raddr, e);
+                }
+            }
+        });
+    }
 }
 
 // Handles a single client connection.
 // Reads data from the stream, processes commands, and writes responses back to the client.
-pub async fn stream_handler(stream: TcpStream, backend: Backend) -> Result<()> {
+// The connection is closed when the client disconnects or when the server shuts down;
+// in the latter case a command that is already executing still completes and gets its reply.
+// It is generic over the stream so TCP and unix socket connections (or anything else that is
+// AsyncRead + AsyncWrite, like an in-memory duplex in tests) are handled by exactly the same code.
+// peer is the printable address of the client.
+pub async fn stream_handler<S>(stream: S, backend: Backend, peer: String) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     // how to get a frame from the stream?
     // Create a Framed Stream:
     // Wraps the TcpStream with RespFrameCodec to handle RESP frame encoding/decoding.
@@ -66,23 +397,67 @@ pub async fn stream_handler(stream: TcpStream, backend: Backend) -> Result<()> {
     // The functionality of Framed is both a parser and a converter, depending on the context in which it is used.
     // It acts as a high-level abstraction for handling streams of data by combining a transport layer (e.g., TcpStream) with a codec (e.g., RespFrameCodec) to handle decoding (parsing) and encoding (converting).
     let mut framed = Framed::new(stream, RespFrameCodec); // The term codec is short for "coder-decoder"
+    let token = backend.shutdown.token();
+    let mut state = ConnectionState::new(&backend, peer);
     loop {
-        // Uses framed.next().await to read the next frame from the client.
-        match framed.next().await {
+        // Uses framed.next().await to read the next frame from the client,
+        // unless the server is shutting down, in which case the connection is closed.
+        // A subscriber also gets the messages published to its channels, whenever they come.
+        let next = tokio::select! {
+            biased;
+            _ = token.cancelled() => return Ok(()),
+            next = framed.next() => next,
+            message = next_message(&mut state.subscription) => {
+                match message {
+                    Some(message) => {
+                        let frame = state.pubsub_frame(message);
+                        framed.send(frame).await?;
+                        continue;
+                    }
+                    None => {
+                        info!("Closing the connection of subscriber {}", state.id);
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        match next {
             // If a frame is received:
             // Logs the frame.
             // Creates a RedisRequest with the frame and backend.
             // Passes the request to request_handler to process it.
             // Sends the response back to the client.
             Some(Ok(frame)) => {
-                info!("Received frame: {:?}", frame);
+                // AUTH (and HELLO ... AUTH, ACL SETUSER ... >password, MIGRATE ... AUTH) frames carry a password
+                // in clear text, keep it out of the logs.
+                if is_command(&frame, b"auth") {
+                    info!("Received frame: AUTH (redacted)");
+                } else if is_command(&frame, b"hello") {
+                    info!("Received frame: HELLO (redacted)");
+                } else if is_subcommand(&frame, b"acl", b"setuser") {
+                    info!("Received frame: ACL SETUSER (redacted)");
+                } else if is_command(&frame, b"migrate") {
+                    info!("Received frame: MIGRATE (redacted)");
+                } else {
+                    info!("Received frame: {:?}", frame);
+                }
                 let request = RedisRequest {
                     frame,
                     backend: backend.clone(),
                 };
-                let response = request_handler(request).await?;
-                info!("Sending response: {:?}", response.frame);
-                framed.send(response.frame).await?; // to send the response back to the client.
+                let response = request_handler(request, &mut state).await?;
+                info!("Sending response: {:?}", response.frames);
+                // to send the response back to the client.
+                for frame in response.frames {
+                    framed.feed(frame).await?;
+                }
+                framed.flush().await?;
+                if response.quit {
+                    return Ok(());
+                }
+                if let Some(sync) = state.replica.take() {
+                    return serve_replica(framed, sync, &backend).await;
+                }
             }
             Some(Err(e)) => return Err(e),
             None => return Ok(()), // If the stream ends (None), exits the loop.
@@ -97,12 +472,750 @@ pub async fn stream_handler(stream: TcpStream, backend: Backend) -> Result<()> {
 // the execution flow first calls TryFrom to parse the raw RESP frame into a structured Command,
 // and then it calls CommandExecutor to execute the parsed command.
 
-async fn request_handler(request: RedisRequest) -> Result<RedisResponse> {
+// Until the connection is authenticated, every command except AUTH is rejected with NOAUTH.
+// After that, the ACL of the connection's user decides whether the command (and the keys it touches) may run;
+// this check runs on the raw frame, before the command is parsed.
+// A RESP2 connection with subscriptions only takes the commands that manage them, PING and QUIT.
+// Inside MULTI the commands are queued instead, and run back to back by EXEC.
+async fn request_handler(
+    request: RedisRequest,
+    state: &mut ConnectionState,
+) -> Result<RedisResponse> {
     let (frame, backend) = (request.frame, request.backend);
-    let cmd = Command::try_from(frame)?;
+    // These (and QUIT) run right away inside MULTI, everything else is queued.
+    let queued = !["multi", "exec", "discard", "watch", "quit"]
+        .iter()
+        .any(|name| is_command(&frame, name.as_bytes()));
+    if let Err(reply) = check_request(&frame, &backend, state) {
+        // A command rejected while queuing fails the whole transaction, like one that does not parse.
+        if let Some(transaction) = state.transaction.as_mut().filter(|_| queued) {
+            transaction.aborted = true;
+        }
+        return Ok(reply.into());
+    }
+    // Like a rejected command, one refused for lack of memory fails the transaction it is queued in.
+    if backend.config.maxmemory > 0 && spec::is_denyoom(&frame) {
+        let freed = match exec_barrier(&backend, RwLock::try_read).await {
+            Ok(_barrier) => free_memory(&backend, &mut state.log.woff),
+            Err(busy) => Err(busy),
+        };
+        if let Err(reply) = freed {
+            if let Some(transaction) = state.transaction.as_mut().filter(|_| queued) {
+                transaction.aborted = true;
+            }
+            return Ok(reply.into());
+        }
+    }
+    let write = spec::is_write(&frame);
+    // Write commands are kept as they arrived, to be appended to the AOF and the replication stream once they succeeded.
+    let logged = write.then(|| frame.clone());
+
+    if let Some(transaction) = state.transaction.as_mut().filter(|_| queued) {
+        return Ok(queue(frame, logged, transaction).into());
+    }
+
+    // A command that does not parse gets an error reply, like it does when queued; the connection stays open.
+    let cmd = match Command::try_from(frame) {
+        Ok(cmd) => cmd,
+        Err(e) => return Ok(RespFrame::from(SimpleError::new(format!("ERR {}", e))).into()),
+    };
     info!("Executing command: {:?}", cmd);
-    let frame = cmd.execute(&backend);
-    Ok(RedisResponse { frame })
+    let frame = match cmd {
+        // Not in the middle of a transaction or a script, whose writes the snapshot would only have half of.
+        Command::PSync(psync) => {
+            let _barrier = match exec_barrier(&backend, RwLock::try_read).await {
+                Ok(barrier) => barrier,
+                Err(busy) => return Ok(busy.into()),
+            };
+            let (frame, sync) = replication::psync(
+                &backend,
+                psync.replid(),
+                psync.offset(),
+                state.peer_ip(),
+                state.listening_port,
+            );
+            state.replica = sync;
+            frame
+        }
+        // Blocks this connection (and only this one) until the replicas caught up with its last write.
+        Command::Wait(wait) => match wait.target(&backend) {
+            Ok((numreplicas, timeout)) => {
+                let acked =
+                    replication::wait(&backend, numreplicas, state.log.woff, false, timeout).await;
+                RespFrame::Integer(acked as i64)
+            }
+            Err(e) => e.into(),
+        },
+        Command::WaitAof(wait) => match wait.target(&backend) {
+            Ok((numreplicas, timeout)) => {
+                // Our own AOF has everything up to woff already; fsyncing it makes that durable.
+                let local = if backend.aof.is_enabled() {
+                    let cloned_backend = backend.clone();
+                    match tokio::task::spawn_blocking(move || cloned_backend.aof.fsync()).await? {
+                        Ok(()) => 1,
+                        Err(e) => {
+                            warn!("AOF fsync failed: {}", e);
+                            0
+                        }
+                    }
+                } else {
+                    0
+                };
+                let acked =
+                    replication::wait(&backend, numreplicas, state.log.woff, true, timeout).await;
+                RespArray::new(vec![
+                    RespFrame::Integer(local as i64),
+                    RespFrame::Integer(acked as i64),
+                ])
+                .into()
+            }
+            Err(e) => e.into(),
+        },
+        // The migrated keys are deleted with a DEL, which is what the AOF and the replicas get. The barrier is
+        // held exclusively so no write lands between the check for changed keys and the DEL. A key written to
+        // while it was in flight is kept here, since the target has the old value; so are all of them when a
+        // script keeps the server busy. MIGRATE with REPLACE moves them again.
+        Command::Migrate(migrate) => {
+            let (mut frame, keys, watched) = migrate.run(&backend).await;
+            if !keys.is_empty() {
+                match exec_barrier(&backend, RwLock::try_write).await {
+                    Ok(_barrier) => {
+                        let (keys, changed): (Vec<_>, Vec<_>) =
+                            keys.into_iter().partition(|key| !watched.changed(key));
+                        if !changed.is_empty() {
+                            frame = SimpleError::new(format!(
+                                "ERR Keys changed while being migrated and were kept: {}",
+                                changed.join(" ")
+                            ))
+                            .into();
+                        }
+                        if !keys.is_empty() {
+                            let mut args: Vec<RespFrame> = vec![BulkString::from("DEL").into()];
+                            args.extend(keys.into_iter().map(|key| BulkString::from(key).into()));
+                            let del: RespFrame = RespArray::new(args).into();
+                            let cmd = Command::try_from(del.clone())?;
+                            execute_logged(cmd, del, &backend, &mut state.log);
+                        }
+                    }
+                    Err(busy) => frame = busy,
+                }
+            }
+            frame
+        }
+        Command::Quit(quit) => {
+            return Ok(RedisResponse {
+                frames: vec![quit.execute(&backend)],
+                quit: true,
+            });
+        }
+        Command::Subscribe(subscribe) => {
+            let subscription = state
+                .subscription
+                .get_or_insert_with(|| Subscription::new(&backend, state.id));
+            let messages = subscribe.run(&backend, subscription);
+            return Ok(pubsub_response(state, messages));
+        }
+        Command::Unsubscribe(unsubscribe) => {
+            let subscription = state
+                .subscription
+                .get_or_insert_with(|| Subscription::new(&backend, state.id));
+            let messages = unsubscribe.run(&backend, subscription);
+            return Ok(pubsub_response(state, messages));
+        }
+        Command::Multi(_) => match state.transaction {
+            Some(_) => SimpleError::new("ERR MULTI calls can not be nested").into(),
+            None => {
+                state.transaction = Some(Transaction::default());
+                SimpleString::new("OK").into()
+            }
+        },
+        // Nothing runs if a watched key changed; the check is under the barrier too, so no other
+        // client can change them between the check and the commands.
+        Command::Exec(exec) => match state.transaction.take() {
+            None => exec.execute(&backend),
+            Some(transaction) => {
+                let _barrier = match exec_barrier(&backend, RwLock::try_write).await {
+                    Ok(barrier) => barrier,
+                    Err(busy) => {
+                        state.transaction = Some(transaction);
+                        return Ok(busy.into());
+                    }
+                };
+                let dirty = state.watched.dirty();
+                state.watched.clear();
+                if transaction.aborted {
+                    SimpleError::new("EXECABORT Transaction discarded because of previous errors.")
+                        .into()
+                } else if dirty && state.resp3 {
+                    RespFrame::Null(RespNull)
+                } else if dirty {
+                    RespFrame::NullArray(RespNullArray)
+                } else {
+                    // A transaction can hold scripts, so it may keep the thread busy for as long as they run.
+                    begin_block(&mut state.log);
+                    let frames = run_blocking(|| {
+                        transaction
+                            .commands
+                            .into_iter()
+                            .map(|(cmd, logged)| execute_command(cmd, logged, &backend, state))
+                            .collect::<Vec<_>>()
+                    });
+                    end_block(&backend, &mut state.log, RespArray::new(frames).into())
+                }
+            }
+        },
+        Command::Discard(discard) => match state.transaction.take() {
+            Some(_) => {
+                state.watched.clear();
+                SimpleString::new("OK").into()
+            }
+            None => discard.execute(&backend),
+        },
+        // SCRIPT only touches the script cache, and SCRIPT KILL (like FUNCTION KILL and SHUTDOWN NOSAVE) has to
+        // get through while a script keeps the server busy.
+        Command::Script(script) => script.execute(&backend),
+        Command::Function(function) if function.is_kill() => function.execute(&backend),
+        Command::Shutdown(shutdown) if shutdown.is_nosave() => shutdown.execute(&backend),
+        // A script runs alone, like a transaction.
+        cmd @ (Command::Eval(_) | Command::Fcall(_)) => {
+            match exec_barrier(&backend, RwLock::try_write).await {
+                Ok(_barrier) => {
+                    begin_block(&mut state.log);
+                    let reply = run_blocking(|| execute_command(cmd, logged, &backend, state));
+                    end_block(&backend, &mut state.log, reply)
+                }
+                Err(busy) => busy,
+            }
+        }
+        cmd => match exec_barrier(&backend, RwLock::try_read).await {
+            Ok(_barrier) => execute_command(cmd, logged, &backend, state),
+            Err(busy) => busy,
+        },
+    };
+    Ok(frame.into())
+}
+
+// The checks a command has to pass before it is parsed: authentication, ACL, subscriber mode,
+// read-only replica and cluster slots. The error is the reply to send instead.
+fn check_request(
+    frame: &RespFrame,
+    backend: &Backend,
+    state: &mut ConnectionState,
+) -> Result<(), RespFrame> {
+    let is_auth = is_command(frame, b"auth") || is_command(frame, b"hello");
+    if !is_auth {
+        let Some(user) = &state.user else {
+            return Err(SimpleError::new("NOAUTH Authentication required.").into());
+        };
+        if let Err(denied) = backend.acl.check(user, frame) {
+            let reply = denied.reply(user);
+            log_denied(backend, &denied, user);
+            // The user was deleted after it authenticated: the connection has to AUTH again.
+            if denied == AclDenied::NoUser {
+                state.user = None;
+            }
+            return Err(SimpleError::new(reply).into());
+        }
+    }
+    if !state.resp3 && state.subscribed(backend) {
+        let name = spec::lookup_frame(frame).map(|spec| spec.name);
+        if !matches!(
+            name,
+            Some(
+                "subscribe"
+                    | "psubscribe"
+                    | "ssubscribe"
+                    | "unsubscribe"
+                    | "punsubscribe"
+                    | "sunsubscribe"
+                    | "ping"
+                    | "quit"
+            )
+        ) {
+            return Err(SimpleError::new(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+                command_name(frame).to_ascii_lowercase()
+            ))
+            .into());
+        }
+    }
+
+    // A replica only takes writes from its primary, which do not come through here (see replication::apply).
+    let write = spec::is_write(frame);
+    if write && backend.config.replica_read_only && backend.replication.is_replica() {
+        return Err(
+            SimpleError::new("READONLY You can't write against a read only replica.").into(),
+        );
+    }
+    // In cluster mode the keys must be served by this node; otherwise the client is sent to the node that does.
+    let asking = std::mem::take(&mut state.asking);
+    if backend.config.cluster_enabled {
+        let exists = |key: &[u8]| std::str::from_utf8(key).is_ok_and(|key| backend.exists(key));
+        if let Err(redirect) = backend.cluster.check_frame(frame, asking, exists) {
+            return Err(redirect.reply().into());
+        }
+    }
+    Ok(())
+}
+
+// Records a refused command in ACL LOG.
+fn log_denied(backend: &Backend, denied: &AclDenied, user: &str) {
+    match denied {
+        AclDenied::NoUser => {}
+        AclDenied::Command(object) => backend.acl.log_denied("command", object, user),
+        AclDenied::Key(object) => backend.acl.log_denied("key", object, user),
+        AclDenied::Channel(object) => backend.acl.log_denied("channel", object, user),
+    }
+}
+
+// Parses a command sent inside MULTI and adds it to the transaction. A command that does not parse, is unknown
+// or cannot run in a transaction is not queued, and makes EXEC fail.
+fn queue(frame: RespFrame, logged: Option<RespFrame>, transaction: &mut Transaction) -> RespFrame {
+    let name = command_name(&frame);
+    let reply = match Command::try_from(frame) {
+        Err(e) => SimpleError::new(format!("ERR {}", e)),
+        Ok(Command::Unrecognized(_)) => SimpleError::new(format!("ERR unknown command '{}'", name)),
+        Ok(
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSync(_)
+            | Command::ReplConf(_)
+            | Command::Wait(_)
+            | Command::WaitAof(_)
+            | Command::Migrate(_)
+            | Command::Auth(_)
+            | Command::Hello(_)
+            | Command::Asking(_),
+        ) => SimpleError::new("ERR Command not allowed inside a transaction"),
+        Ok(cmd) => {
+            transaction.commands.push((cmd, logged));
+            return SimpleString::new("QUEUED").into();
+        }
+    };
+    transaction.aborted = true;
+    reply.into()
+}
+
+// Runs a command for redis.call / redis.pcall like the connection that runs the script would: its user's ACL
+// applies and writes are logged. Commands that need the connection itself cannot run in a script.
+fn script_call(
+    frame: RespFrame,
+    user: Option<&str>,
+    backend: &Backend,
+    log: &mut WriteLog,
+) -> RespFrame {
+    if let Some(user) = user {
+        if let Err(denied) = backend.acl.check(user, &frame) {
+            log_denied(backend, &denied, user);
+            return SimpleError::new(denied.reply(user)).into();
+        }
+    }
+    let write = spec::is_write(&frame);
+    if write && backend.config.replica_read_only && backend.replication.is_replica() {
+        return SimpleError::new("READONLY You can't write against a read only replica.").into();
+    }
+    if backend.config.maxmemory > 0 && spec::is_denyoom(&frame) {
+        if let Err(reply) = free_memory(backend, &mut log.woff) {
+            return reply;
+        }
+    }
+    let logged = write.then(|| frame.clone());
+    match Command::try_from(frame) {
+        Err(e) => SimpleError::new(format!("ERR {}", e)).into(),
+        Ok(Command::Unrecognized(_)) => {
+            SimpleError::new("ERR Unknown Redis command called from script").into()
+        }
+        Ok(
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSync(_)
+            | Command::ReplConf(_)
+            | Command::Wait(_)
+            | Command::WaitAof(_)
+            | Command::Migrate(_)
+            | Command::Auth(_)
+            | Command::Hello(_)
+            | Command::Asking(_)
+            | Command::Quit(_)
+            | Command::Multi(_)
+            | Command::Exec(_)
+            | Command::Discard(_)
+            | Command::Watch(_)
+            | Command::Unwatch(_)
+            | Command::Eval(_)
+            | Command::Script(_)
+            | Command::Fcall(_)
+            | Command::Function(_),
+        ) => SimpleError::new("ERR This Redis command is not allowed from script").into(),
+        Ok(cmd) => match logged {
+            Some(logged) => execute_logged(cmd, logged, backend, log),
+            None => cmd.execute(backend),
+        },
+    }
+}
+
+// Executes a command that completes right away, on its own or as part of EXEC; the caller holds the exec barrier.
+fn execute_command(
+    cmd: Command,
+    logged: Option<RespFrame>,
+    backend: &Backend,
+    state: &mut ConnectionState,
+) -> RespFrame {
+    match cmd {
+        // A successful AUTH authenticates the connection as that user; a failed one leaves the state as it was.
+        Command::Auth(auth) => {
+            let username = auth.username().to_string();
+            let frame = auth.execute(backend);
+            if matches!(frame, RespFrame::SimpleString(_)) {
+                state.user = Some(username);
+            }
+            frame
+        }
+        Command::Acl(acl) if acl.is_whoami() => match &state.user {
+            Some(user) => BulkString::from(user.as_str()).into(),
+            None => RespFrame::Null(RespNull),
+        },
+        Command::ReplConf(conf) => {
+            if let Some(port) = conf.listening_port() {
+                state.listening_port = port;
+            }
+            conf.execute(backend)
+        }
+        Command::Asking(asking) => {
+            let frame = asking.execute(backend);
+            state.asking = matches!(frame, RespFrame::SimpleString(_));
+            frame
+        }
+        Command::Hello(hello) => hello.run(backend, state.id, &mut state.user, &mut state.resp3),
+        Command::Watch(_) if state.transaction.is_some() => {
+            SimpleError::new("ERR WATCH inside MULTI is not allowed").into()
+        }
+        Command::Watch(watch) => {
+            watch.run(&mut state.watched);
+            SimpleString::new("OK").into()
+        }
+        Command::Unwatch(_) => {
+            state.watched.clear();
+            SimpleString::new("OK").into()
+        }
+        Command::Eval(eval) => {
+            let user = state.user.clone();
+            let log = &mut state.log;
+            eval.run(backend, &mut |frame| {
+                script_call(frame, user.as_deref(), backend, log)
+            })
+        }
+        Command::Fcall(fcall) => {
+            let user = state.user.clone();
+            let log = &mut state.log;
+            fcall.run(backend, &mut |frame| {
+                script_call(frame, user.as_deref(), backend, log)
+            })
+        }
+        // In subscriber mode PING replies like a message would, so the client can tell them apart.
+        Command::Ping(ping) if !state.resp3 && state.subscribed(backend) => {
+            let message = ping.message().unwrap_or_default();
+            RespArray::new(vec![
+                BulkString::from("pong").into(),
+                BulkString::from(message).into(),
+            ])
+            .into()
+        }
+        cmd => match logged {
+            Some(logged) => execute_logged(cmd, logged, backend, &mut state.log),
+            None => cmd.execute(backend),
+        },
+    }
+}
+
+// Runs f, which keeps the thread busy for as long as a script runs, without holding up the other connections:
+// on the multi-thread runtime the worker first hands its other tasks and the IO driver to another thread, so
+// other clients still get BUSY and SCRIPT KILL still gets through. The current-thread runtime has no other
+// thread to hand them to.
+fn run_blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+// Takes the exec barrier (lock is RwLock::try_read for a command, RwLock::try_write for EXEC and scripts) without
+// blocking the thread, so a long script does not hold up the tasks of other connections. Once a script has been
+// running for longer than busy-reply-threshold, the command is refused with BUSY instead.
+async fn exec_barrier<'a, G>(
+    backend: &'a Backend,
+    lock: impl Fn(&'a RwLock<()>) -> TryLockResult<G>,
+) -> Result<G, RespFrame> {
+    let threshold = Duration::from_millis(backend.config.busy_reply_threshold);
+    loop {
+        match lock(&backend.exec_barrier) {
+            Ok(barrier) => return Ok(barrier),
+            Err(TryLockError::WouldBlock) => {}
+            Err(e) => panic!("{}", e),
+        }
+        if backend.scripting.busy(threshold) {
+            return Err(SimpleError::new(
+                "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.",
+            )
+            .into());
+        }
+        tokio::time::sleep(EXEC_BARRIER_RETRY).await;
+    }
+}
+
+fn pubsub_response(state: &ConnectionState, messages: Vec<Vec<RespFrame>>) -> RedisResponse {
+    RedisResponse {
+        frames: messages
+            .into_iter()
+            .map(|message| state.pubsub_frame(message))
+            .collect(),
+        quit: false,
+    }
+}
+
+// The next message for the subscriber, if the connection is one; None once the broker disconnected it.
+async fn next_message(subscription: &mut Option<Subscription>) -> Option<Vec<RespFrame>> {
+    match subscription {
+        Some(subscription) => subscription.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Executes a write command and appends it to the AOF and the replication stream, holding both locks
+// across the execution, so the commands end up in the file and the stream in the order they were executed.
+// The first write of a block is preceded by a MULTI (see WriteLog). log.woff is set to the offset of the
+// stream after the command.
+fn execute_logged(
+    mut cmd: Command,
+    logged: RespFrame,
+    backend: &Backend,
+    log: &mut WriteLog,
+) -> RespFrame {
+    let logged = match &mut cmd {
+        Command::Restore(restore) => restore.pin_expire_time(logged),
+        _ => logged,
+    };
+    let mut aof = backend.aof.lock();
+    let mut feed = backend.replication.feed();
+    let frame = cmd.execute(backend);
+    if matches!(frame, RespFrame::Error(_)) {
+        return frame;
+    }
+    let mut frames = vec![logged];
+    if log.block == Some(false) {
+        frames.insert(0, command_frame("MULTI"));
+        log.block = Some(true);
+    }
+    append_logged(frames, &mut feed, &mut aof, &mut log.woff).unwrap_or(frame)
+}
+
+// Starts logging the writes that follow as one block: the commands of EXEC, or the writes of a script.
+fn begin_block(log: &mut WriteLog) {
+    log.block = Some(false);
+}
+
+// Ends the block begun by begin_block: an EXEC closes it if any write was logged. The error of a failed
+// append to the AOF replaces reply.
+fn end_block(backend: &Backend, log: &mut WriteLog, reply: RespFrame) -> RespFrame {
+    if log.block.take() != Some(true) {
+        return reply;
+    }
+    let mut aof = backend.aof.lock();
+    let mut feed = backend.replication.feed();
+    append_logged(
+        vec![command_frame("EXEC")],
+        &mut feed,
+        &mut aof,
+        &mut log.woff,
+    )
+    .unwrap_or(reply)
+}
+
+fn command_frame(name: &str) -> RespFrame {
+    RespArray::new(vec![BulkString::from(name).into()]).into()
+}
+
+// Appends frames to the replication stream and the AOF. Returns the error to reply with if the AOF failed.
+fn append_logged(
+    frames: Vec<RespFrame>,
+    feed: &mut Feed,
+    aof: &mut Option<AofGuard>,
+    woff: &mut u64,
+) -> Option<RespFrame> {
+    for frame in &frames {
+        feed.append(&frame.clone().encode());
+    }
+    *woff = feed.offset();
+    let aof = aof.as_mut()?;
+    match frames.into_iter().try_for_each(|frame| aof.append(frame)) {
+        Ok(()) => None,
+        Err(e) => {
+            // The write happened but is not durable: tell the client instead of acknowledging it.
+            warn!("Error writing to the AOF: {}", e);
+            Some(SimpleError::new(format!("ERR Error writing to the AOF: {}", e)).into())
+        }
+    }
+}
+
+// Called before a command that may add data when maxmemory is set: evicts keys by maxmemory-policy until the
+// dataset fits, or returns the OOM error to reply with if it cannot. The evicted keys are logged as DELs, so the
+// AOF and the replicas drop them too. The caller holds the exec barrier.
+fn free_memory(backend: &Backend, woff: &mut u64) -> Result<(), RespFrame> {
+    // A replica does not evict on its own, it waits for the DELs of its primary (replica-ignore-maxmemory).
+    if backend.replication.is_replica() {
+        return Ok(());
+    }
+    let mut aof = backend.aof.lock();
+    let mut feed = backend.replication.feed();
+    let (evicted, fits) = backend.evict();
+    for key in evicted {
+        log_del(key, &mut feed, &mut aof);
+        *woff = feed.offset();
+    }
+    match fits {
+        true => Ok(()),
+        false => {
+            Err(SimpleError::new("OOM command not allowed when used memory > 'maxmemory'.").into())
+        }
+    }
+}
+
+// Removes expired keys nobody reads, so their "expired" events fire and their memory is freed without a client
+// touching them. The keys are logged as DELs, like evicted ones. Runs until the server shuts down.
+// A replica leaves it to the DELs of its primary, and a busy script postpones it.
+pub async fn active_expire(backend: Backend) {
+    let token = backend.shutdown.token();
+    let mut interval = tokio::time::interval(ACTIVE_EXPIRE_PERIOD);
+    loop {
+        tokio::select! {
+            biased;
+            _ = token.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        if backend.replication.is_replica() {
+            continue;
+        }
+        let Ok(_barrier) = exec_barrier(&backend, RwLock::try_read).await else {
+            continue;
+        };
+        let started = std::time::Instant::now();
+        loop {
+            let mut aof = backend.aof.lock();
+            let mut feed = backend.replication.feed();
+            let (sampled, expired) = backend.expire_sample(ACTIVE_EXPIRE_KEYS);
+            let done = expired.len() * 4 <= sampled || started.elapsed() >= ACTIVE_EXPIRE_BUDGET;
+            for key in expired {
+                log_del(key, &mut feed, &mut aof);
+            }
+            if done {
+                break;
+            }
+        }
+    }
+}
+
+// Appends a DEL of a key that went away on its own (evicted, expired) to the replication stream and the AOF.
+fn log_del(key: String, feed: &mut Feed, aof: &mut Option<AofGuard>) {
+    let del: RespFrame = RespArray::new(vec![
+        BulkString::from("DEL").into(),
+        BulkString::from(key).into(),
+    ])
+    .into();
+    feed.append(&del.clone().encode());
+    if let Some(aof) = aof.as_mut() {
+        if let Err(e) = aof.append(del) {
+            warn!("Error writing to the AOF: {}", e);
+        }
+    }
+}
+
+// Serves a replica after PSYNC was answered: sends the snapshot (full resync) or the missed part of the stream
+// (partial resync), then forwards the stream until the replica disconnects or the server shuts down.
+async fn serve_replica<S>(
+    mut framed: Framed<S, RespFrameCodec>,
+    mut sync: ReplicaSync,
+    backend: &Backend,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ret = async {
+        if let Some(snapshot) = sync.snapshot.take() {
+            let payload = tokio::task::spawn_blocking(move || snapshot.encode()).await?;
+            let stream = framed.get_mut();
+            stream
+                .write_all(format!("${}\r\n", payload.len()).as_bytes())
+                .await?;
+            stream.write_all(&payload).await?;
+        }
+        framed.get_mut().write_all(&sync.backlog).await?;
+        framed.get_mut().flush().await?;
+
+        let token = backend.shutdown.token();
+        loop {
+            tokio::select! {
+                biased;
+                _ = token.cancelled() => return Ok(()),
+                data = sync.receiver.recv() => match data {
+                    Some(data) => {
+                        let stream = framed.get_mut();
+                        stream.write_all(&data).await?;
+                        stream.flush().await?;
+                    }
+                    // Dropped from the replicas, e.g. because this server became a replica of another primary.
+                    None => return Ok(()),
+                },
+                // The replica only sends REPLCONF ACK on this connection.
+                next = framed.next() => match next {
+                    Some(Ok(frame)) => {
+                        if let Ok(Command::ReplConf(conf)) = Command::try_from(frame) {
+                            if let Some((offset, aof_offset)) = conf.ack() {
+                                backend.replication.ack(sync.id, offset, aof_offset);
+                            }
+                        }
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+    .await;
+    backend.replication.remove_replica(sync.id);
+    ret
+}
+
+// The command name (the first element of the array) as sent, empty if the frame is not a command.
+fn command_name(frame: &RespFrame) -> String {
+    match frame {
+        RespFrame::Array(array) => match array.first() {
+            Some(RespFrame::BulkString(name)) => String::from_utf8_lossy(name).to_string(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+// Checks the command name (the first element of the array) without parsing the whole command.
+pub(crate) fn is_command(frame: &RespFrame, name: &[u8]) -> bool {
+    match frame {
+        RespFrame::Array(array) => matches!(
+            array.first(),
+            Some(RespFrame::BulkString(cmd)) if cmd.eq_ignore_ascii_case(name)
+        ),
+        _ => false,
+    }
+}
+
+// Like is_command, for the subcommand (the second element) of a container command.
+fn is_subcommand(frame: &RespFrame, name: &[u8], subcommand: &[u8]) -> bool {
+    match frame {
+        RespFrame::Array(array) if is_command(frame, name) => matches!(
+            array.get(1),
+            Some(RespFrame::BulkString(sub)) if sub.eq_ignore_ascii_case(subcommand)
+        ),
+        _ => false,
+    }
 }
 
 // Implements encoding and decoding for RESP frames.
@@ -170,6 +1283,1187 @@ impl Decoder for RespFrameCodec {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleString;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_serve_shutdown_command() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let backend = Backend::new();
+        let server = tokio::spawn(serve(vec![listener.into()], backend.clone()));
+
+        // An idle connection must not keep the server from exiting.
+        let idle = TcpStream::connect(addr).await?;
+
+        let mut client = TcpStream::connect(addr).await?;
+        client
+            .write_all(b"*3\r\n$3\r\nset\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
+            .await?;
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"+OK\r\n");
+
+        client
+            .write_all(b"*2\r\n$8\r\nshutdown\r\n$6\r\nnosave\r\n")
+            .await?;
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"+OK\r\n");
+
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        assert!(backend.shutdown().is_triggered());
+        assert_eq!(
+            backend.shutdown().request().save,
+            crate::shutdown::SaveMode::NoSave
+        );
+
+        // Both connections were closed by the server.
+        assert_eq!(client.read(&mut buf).await?, 0);
+        drop(idle);
+        assert!(TcpStream::connect(addr).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_saves_after_draining() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("simple-redis-drain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let config = crate::config::ServerConfig {
+            dir: dir.clone(),
+            ..Default::default()
+        };
+        let backend = Backend::with_config(config.clone());
+        let shutdown: RespFrame = RespArray::new(vec![
+            BulkString::from("shutdown").into(),
+            BulkString::from("save").into(),
+        ])
+        .into();
+        let reply = Command::try_from(shutdown)?.execute(&backend);
+        assert_eq!(reply, SimpleString::new("OK").into());
+        // Written after SHUTDOWN replied, while the connections drain: still in the snapshot.
+        backend.set("late".to_string(), BulkString::from("write").into());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        serve(vec![listener.into()], backend).await?;
+
+        let restarted = Backend::with_config(config);
+        crate::persistence::load(&restarted)?;
+        assert_eq!(
+            restarted.get("late"),
+            Some(BulkString::from("write").into())
+        );
+        std::fs::remove_dir_all(dir)?;
+
+        // A failed save is the error of serve, unless FORCE says to exit anyway.
+        for (force, failed) in [(false, true), (true, false)] {
+            let backend = Backend::with_config(crate::config::ServerConfig {
+                dir: PathBuf::from("/nonexistent/simple-redis"),
+                ..Default::default()
+            });
+            backend
+                .shutdown()
+                .trigger(crate::shutdown::ShutdownRequest {
+                    save: crate::shutdown::SaveMode::Save,
+                    now: false,
+                    force,
+                });
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            assert_eq!(serve(vec![listener.into()], backend).await.is_err(), failed);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_tcp_and_unix_socket() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = tcp.local_addr()?;
+        let path = std::env::temp_dir().join(format!("simple-redis-{}.sock", std::process::id()));
+        let unix = Listener::bind_unix(&path, Some(0o700))?;
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o700
+        );
+
+        // No save points, so the shutdown below does not leave a snapshot in the working directory.
+        let backend = Backend::with_config(crate::config::ServerConfig {
+            save: Vec::new(),
+            ..Default::default()
+        });
+        let server = tokio::spawn(serve(vec![tcp.into(), unix], backend.clone()));
+
+        // Written through the unix socket, read back through TCP: both listeners share one Backend.
+        let mut unix_client = UnixStream::connect(&path).await?;
+        unix_client
+            .write_all(b"*3\r\n$3\r\nset\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
+            .await?;
+        let mut buf = [0u8; 5];
+        unix_client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"+OK\r\n");
+
+        let mut tcp_client = TcpStream::connect(addr).await?;
+        tcp_client
+            .write_all(b"*2\r\n$3\r\nget\r\n$5\r\nhello\r\n")
+            .await?;
+        let mut buf = [0u8; 11];
+        tcp_client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"$5\r\nworld\r\n");
+
+        backend.shutdown().trigger(Default::default());
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        // The socket file is cleaned up when the server exits.
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_handler_requirepass() -> Result<()> {
+        let backend = Backend::with_config(crate::config::ServerConfig {
+            requirepass: Some("foobared".to_string()),
+            ..Default::default()
+        });
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(stream_handler(server, backend, String::new()));
+
+        // Replies in this test are all single-line, so read up to the first CRLF.
+        async fn roundtrip(client: &mut tokio::io::DuplexStream, req: &[u8]) -> Result<Vec<u8>> {
+            client.write_all(req).await?;
+            let mut buf = Vec::new();
+            while !buf.ends_with(b"\r\n") {
+                buf.push(client.read_u8().await?);
+            }
+            Ok(buf)
+        }
+
+        let get = b"*2\r\n$3\r\nget\r\n$5\r\nhello\r\n";
+        assert_eq!(
+            roundtrip(&mut client, get).await?,
+            b"-NOAUTH Authentication required.\r\n"
+        );
+        let wrong = b"*2\r\n$4\r\nauth\r\n$5\r\nwrong\r\n";
+        assert_eq!(
+            roundtrip(&mut client, wrong).await?,
+            b"-WRONGPASS invalid username-password pair or user is disabled.\r\n"
+        );
+        assert_eq!(
+            roundtrip(&mut client, get).await?,
+            b"-NOAUTH Authentication required.\r\n"
+        );
+        let auth = b"*2\r\n$4\r\nAUTH\r\n$8\r\nfoobared\r\n";
+        assert_eq!(roundtrip(&mut client, auth).await?, b"+OK\r\n");
+        assert_eq!(roundtrip(&mut client, get).await?, b"_\r\n");
+
+        drop(client);
+        handler.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_handler_parse_error() -> Result<()> {
+        let backend = Backend::new();
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(stream_handler(server, backend, String::new()));
+
+        async fn roundtrip(client: &mut tokio::io::DuplexStream, req: &[u8]) -> Result<Vec<u8>> {
+            client.write_all(req).await?;
+            let mut buf = Vec::new();
+            while !buf.ends_with(b"\r\n") {
+                buf.push(client.read_u8().await?);
+            }
+            Ok(buf)
+        }
+
+        // The command does not parse: the client gets the error and the connection stays usable.
+        let restore = b"*4\r\n$7\r\nrestore\r\n$1\r\nk\r\n$3\r\nabc\r\n$1\r\nx\r\n";
+        assert_eq!(
+            roundtrip(&mut client, restore).await?,
+            b"-ERR Invalid argument: value is not an integer or out of range\r\n"
+        );
+        let ping = b"*1\r\n$4\r\nping\r\n";
+        assert_eq!(roundtrip(&mut client, ping).await?, b"+PONG\r\n");
+
+        drop(client);
+        handler.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_handler_acl() -> Result<()> {
+        let backend = Backend::new();
+        backend
+            .acl()
+            .set_user("alice", &["on", ">pw", "~cache:*", "+@read", "+acl"])?;
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(stream_handler(server, backend.clone(), String::new()));
+
+        async fn roundtrip(client: &mut tokio::io::DuplexStream, req: &[u8]) -> Result<Vec<u8>> {
+            client.write_all(req).await?;
+            let mut buf = Vec::new();
+            while !buf.ends_with(b"\r\n") {
+                buf.push(client.read_u8().await?);
+            }
+            Ok(buf)
+        }
+
+        let whoami = b"*2\r\n$3\r\nacl\r\n$6\r\nwhoami\r\n";
+        assert_eq!(roundtrip(&mut client, whoami).await?, b"$7\r\n");
+        assert_eq!(roundtrip(&mut client, b"").await?, b"default\r\n");
+
+        let auth = b"*3\r\n$4\r\nauth\r\n$5\r\nalice\r\n$2\r\npw\r\n";
+        assert_eq!(roundtrip(&mut client, auth).await?, b"+OK\r\n");
+        assert_eq!(roundtrip(&mut client, whoami).await?, b"$5\r\n");
+        assert_eq!(roundtrip(&mut client, b"").await?, b"alice\r\n");
+
+        let get = b"*2\r\n$3\r\nget\r\n$7\r\ncache:1\r\n";
+        assert_eq!(roundtrip(&mut client, get).await?, b"_\r\n");
+        let get_other = b"*2\r\n$3\r\nget\r\n$5\r\nother\r\n";
+        assert_eq!(
+            roundtrip(&mut client, get_other).await?,
+            b"-NOPERM No permissions to access a key\r\n"
+        );
+        let set = b"*3\r\n$3\r\nset\r\n$7\r\ncache:1\r\n$1\r\nv\r\n";
+        assert_eq!(
+            roundtrip(&mut client, set).await?,
+            b"-NOPERM User alice has no permissions to run the 'set' command\r\n"
+        );
+
+        let reasons = backend
+            .acl()
+            .log_entries(10)
+            .into_iter()
+            .map(|e| (e.reason, e.object))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![("command", "set".to_string()), ("key", "other".to_string())]
+        );
+
+        drop(client);
+        handler.await??;
+        Ok(())
+    }
+
+    // Sends a command on a client connection and returns the reply.
+    async fn call<S: AsyncRead + AsyncWrite + Unpin>(
+        client: &mut Framed<S, RespFrameCodec>,
+        args: &[&str],
+    ) -> Result<RespFrame> {
+        let frames: Vec<RespFrame> = args
+            .iter()
+            .map(|arg| RespFrame::from(BulkString::from(*arg)))
+            .collect();
+        client.send(RespArray::new(frames).into()).await?;
+        match client.next().await {
+            Some(frame) => Ok(frame?),
+            None => anyhow::bail!("connection closed"),
+        }
+    }
+
+    // Polls GET on the replica until it returns the expected value.
+    async fn wait_for_value(
+        client: &mut Framed<TcpStream, RespFrameCodec>,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        let expected: RespFrame = BulkString::from(value).into();
+        for _ in 0..100 {
+            if call(client, &["get", key]).await? == expected {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        anyhow::bail!("{} was not replicated", key)
+    }
+
+    #[tokio::test]
+    async fn test_replication_between_two_servers() -> Result<()> {
+        let primary_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let primary_port = primary_listener.local_addr()?.port();
+        let replica_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let replica_port = replica_listener.local_addr()?.port();
+
+        let primary = Backend::with_config(crate::config::ServerConfig {
+            save: Vec::new(),
+            ..Default::default()
+        });
+        // The replica announces its port to the primary, so ROLE can list it.
+        let replica = Backend::with_config(crate::config::ServerConfig {
+            port: replica_port,
+            save: Vec::new(),
+            ..Default::default()
+        });
+        let primary_server = tokio::spawn(serve(vec![primary_listener.into()], primary.clone()));
+        let replica_server = tokio::spawn(serve(vec![replica_listener.into()], replica.clone()));
+
+        let mut primary_client = Framed::new(
+            TcpStream::connect(("127.0.0.1", primary_port)).await?,
+            RespFrameCodec,
+        );
+        let mut replica_client = Framed::new(
+            TcpStream::connect(("127.0.0.1", replica_port)).await?,
+            RespFrameCodec,
+        );
+
+        // Written before the replica connects: arrives with the full resync.
+        call(&mut primary_client, &["set", "before", "one"]).await?;
+        let port = primary_port.to_string();
+        let reply = call(&mut replica_client, &["replicaof", "127.0.0.1", &port]).await?;
+        assert_eq!(reply, SimpleString::new("OK").into());
+        wait_for_value(&mut replica_client, "before", "one").await?;
+
+        // Written afterwards: streamed to the replica.
+        call(&mut primary_client, &["set", "after", "two"]).await?;
+        wait_for_value(&mut replica_client, "after", "two").await?;
+        // WAIT asks the replica for an acknowledgement instead of waiting for its periodic one.
+        call(&mut primary_client, &["set", "acked", "three"]).await?;
+        let reply = tokio::time::timeout(
+            Duration::from_millis(900),
+            call(&mut primary_client, &["wait", "1", "0"]),
+        )
+        .await??;
+        assert_eq!(reply, RespFrame::Integer(1));
+        // The replica has no AOF, so it never acknowledges a write as fsynced.
+        let reply = call(&mut primary_client, &["waitaof", "0", "1", "50"]).await?;
+        assert_eq!(
+            reply,
+            RespArray::new(vec![RespFrame::Integer(0), RespFrame::Integer(0)]).into()
+        );
+
+        let reply = call(&mut replica_client, &["set", "local", "value"]).await?;
+        assert_eq!(
+            reply,
+            SimpleError::new("READONLY You can't write against a read only replica.").into()
+        );
+
+        let RespFrame::Array(role) = call(&mut primary_client, &["role"]).await? else {
+            panic!("ROLE did not return an array");
+        };
+        assert_eq!(role[0], BulkString::from("master").into());
+        let RespFrame::Array(replicas) = &role[2] else {
+            panic!("no replica list");
+        };
+        let RespFrame::Array(entry) = &replicas[0] else {
+            panic!("no replica entry");
+        };
+        assert_eq!(entry[0], BulkString::from("127.0.0.1").into());
+        assert_eq!(entry[1], BulkString::from(replica_port.to_string()).into());
+
+        let RespFrame::Array(role) = call(&mut replica_client, &["role"]).await? else {
+            panic!("ROLE did not return an array");
+        };
+        assert_eq!(role[0], BulkString::from("slave").into());
+        assert_eq!(role[3], BulkString::from("connected").into());
+
+        // Promoted back to a primary, the replica accepts writes again.
+        call(&mut replica_client, &["replicaof", "no", "one"]).await?;
+        let reply = call(&mut replica_client, &["set", "local", "value"]).await?;
+        assert_eq!(reply, SimpleString::new("OK").into());
+
+        replica.shutdown().trigger(Default::default());
+        primary.shutdown().trigger(Default::default());
+        tokio::time::timeout(Duration::from_secs(5), replica_server).await???;
+        tokio::time::timeout(Duration::from_secs(5), primary_server).await???;
+        Ok(())
+    }
+
+    // Starts a cluster node on localhost and returns its port with a client connection.
+    async fn start_cluster_node() -> Result<(Backend, u16, Framed<TcpStream, RespFrameCodec>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let bus = TcpListener::bind("127.0.0.1:0").await?;
+        let backend = Backend::with_config(crate::config::ServerConfig {
+            port,
+            cluster_enabled: true,
+            cluster_port: bus.local_addr()?.port(),
+            save: Vec::new(),
+            ..Default::default()
+        });
+        tokio::spawn(crate::cluster::run(bus, backend.clone()));
+        tokio::spawn(serve(vec![listener.into()], backend.clone()));
+        let client = Framed::new(
+            TcpStream::connect(("127.0.0.1", port)).await?,
+            RespFrameCodec,
+        );
+        Ok((backend, port, client))
+    }
+
+    #[tokio::test]
+    async fn test_cluster_of_three_nodes() -> Result<()> {
+        let (a, a_port, mut a_client) = start_cluster_node().await?;
+        let (b, b_port, mut b_client) = start_cluster_node().await?;
+        let (c, _, mut c_client) = start_cluster_node().await?;
+        let ok: RespFrame = SimpleString::new("OK").into();
+
+        for (client, first, last) in [
+            (&mut a_client, "0", "5460"),
+            (&mut b_client, "5461", "10922"),
+            (&mut c_client, "10923", "16383"),
+        ] {
+            assert_eq!(
+                call(client, &["cluster", "addslotsrange", first, last]).await?,
+                ok
+            );
+        }
+        // c only meets a, and learns about b from a's gossip.
+        let bus_port = |backend: &Backend| backend.config.cluster_bus_port().to_string();
+        let reply = call(
+            &mut a_client,
+            &[
+                "cluster",
+                "meet",
+                "127.0.0.1",
+                &b_port.to_string(),
+                &bus_port(&b),
+            ],
+        )
+        .await?;
+        assert_eq!(reply, ok);
+        let reply = call(
+            &mut c_client,
+            &[
+                "cluster",
+                "meet",
+                "127.0.0.1",
+                &a_port.to_string(),
+                &bus_port(&a),
+            ],
+        )
+        .await?;
+        assert_eq!(reply, ok);
+
+        // Until every node knows who serves every slot.
+        for _ in 0..100 {
+            let converged = [&a, &b, &c]
+                .iter()
+                .all(|node| node.cluster.info().slots_assigned == crate::cluster::SLOTS);
+            if converged {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let RespFrame::Array(slots) = call(&mut c_client, &["cluster", "slots"]).await? else {
+            panic!("CLUSTER SLOTS did not return an array");
+        };
+        assert_eq!(slots.len(), 3);
+
+        // "foo" is in slot 12182 (c), "bar" in slot 5061 (a).
+        let reply = call(&mut a_client, &["set", "foo", "1"]).await?;
+        assert_eq!(
+            reply,
+            SimpleError::new(format!("MOVED 12182 127.0.0.1:{}", c.config.port)).into()
+        );
+        assert_eq!(call(&mut a_client, &["set", "bar", "1"]).await?, ok);
+        let reply = call(&mut b_client, &["get", "bar"]).await?;
+        assert_eq!(
+            reply,
+            SimpleError::new(format!("MOVED 5061 127.0.0.1:{}", a_port)).into()
+        );
+        // Commands without keys run anywhere.
+        let reply = call(&mut b_client, &["cluster", "keyslot", "bar"]).await?;
+        assert_eq!(reply, RespFrame::Integer(5061));
+
+        let RespFrame::BulkString(nodes) = call(&mut b_client, &["cluster", "nodes"]).await? else {
+            panic!("CLUSTER NODES did not return a bulk string");
+        };
+        let nodes = String::from_utf8(nodes.0)?;
+        assert_eq!(nodes.lines().count(), 3);
+        assert!(nodes.contains(&format!("{} 127.0.0.1:{}@", b.cluster.myself(), b_port)));
+        assert!(nodes.contains("myself,master"));
+
+        // Shard channels are served by the owner of their slot, like keys.
+        let reply = call(&mut a_client, &["spublish", "foo", "hello"]).await?;
+        assert_eq!(
+            reply,
+            SimpleError::new(format!("MOVED 12182 127.0.0.1:{}", c.config.port)).into()
+        );
+        let reply = call(&mut c_client, &["ssubscribe", "foo", "bar"]).await?;
+        assert!(matches!(reply, RespFrame::Error(e) if e.0.starts_with("CROSSSLOT")));
+        let bulk = |s: &str| RespFrame::from(BulkString::from(s));
+        assert_eq!(
+            call(&mut c_client, &["ssubscribe", "foo"]).await?,
+            RespArray::new(vec![bulk("ssubscribe"), bulk("foo"), RespFrame::Integer(1)]).into()
+        );
+
+        // Moving a slot: the new owner bumps its epoch, and the others follow.
+        let reply = call(
+            &mut b_client,
+            &["cluster", "setslot", "12182", "node", &b.cluster.myself()],
+        )
+        .await?;
+        assert_eq!(reply, ok);
+        // The old owner unsubscribes the subscribers of the shard channels in the slot.
+        let reply = tokio::time::timeout(Duration::from_secs(10), c_client.next()).await?;
+        assert_eq!(
+            reply.unwrap()?,
+            RespArray::new(vec![
+                bulk("sunsubscribe"),
+                bulk("foo"),
+                RespFrame::Integer(0)
+            ])
+            .into()
+        );
+        let mut reply = RespFrame::Null(RespNull);
+        for _ in 0..100 {
+            reply = call(&mut c_client, &["get", "foo"]).await?;
+            if matches!(&reply, RespFrame::Error(e) if e.0.starts_with("MOVED")) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(
+            reply,
+            SimpleError::new(format!("MOVED 12182 127.0.0.1:{}", b_port)).into()
+        );
+
+        for node in [&a, &b, &c] {
+            node.shutdown().trigger(Default::default());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cluster_slot_migration() -> Result<()> {
+        let (a, a_port, mut a_client) = start_cluster_node().await?;
+        let (b, b_port, mut b_client) = start_cluster_node().await?;
+        let ok: RespFrame = SimpleString::new("OK").into();
+        let (a_id, b_id) = (a.cluster.myself(), b.cluster.myself());
+
+        let reply = call(&mut a_client, &["cluster", "addslotsrange", "0", "16383"]).await?;
+        assert_eq!(reply, ok);
+        let bus_port = b.config.cluster_bus_port().to_string();
+        let reply = call(
+            &mut a_client,
+            &[
+                "cluster",
+                "meet",
+                "127.0.0.1",
+                &b_port.to_string(),
+                &bus_port,
+            ],
+        )
+        .await?;
+        assert_eq!(reply, ok);
+        for _ in 0..100 {
+            if b.cluster.info().slots_assigned == crate::cluster::SLOTS {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        call(&mut a_client, &["set", "foo", "1"]).await?;
+        call(&mut a_client, &["set", "{foo}.bar", "2"]).await?;
+
+        // Slot 12182 ("foo") moves from a to b.
+        let reply = call(
+            &mut b_client,
+            &["cluster", "setslot", "12182", "importing", &a_id],
+        )
+        .await?;
+        assert_eq!(reply, ok);
+        let reply = call(
+            &mut a_client,
+            &["cluster", "setslot", "12182", "migrating", &b_id],
+        )
+        .await?;
+        assert_eq!(reply, ok);
+        let b_port = b_port.to_string();
+        let reply = call(
+            &mut a_client,
+            &[
+                "migrate",
+                "127.0.0.1",
+                &b_port,
+                "",
+                "0",
+                "1000",
+                "keys",
+                "{foo}.bar",
+            ],
+        )
+        .await?;
+        assert_eq!(reply, ok);
+
+        // a still serves the key it has, and sends the client to b for the other one.
+        let reply = call(&mut a_client, &["get", "foo"]).await?;
+        assert_eq!(reply, BulkString::from("1").into());
+        let reply = call(&mut a_client, &["get", "{foo}.bar"]).await?;
+        assert_eq!(
+            reply,
+            SimpleError::new(format!("ASK 12182 127.0.0.1:{}", b_port)).into()
+        );
+        // b serves the slot only to a client that is ASKING, for one command.
+        let reply = call(&mut b_client, &["get", "{foo}.bar"]).await?;
+        assert_eq!(
+            reply,
+            SimpleError::new(format!("MOVED 12182 127.0.0.1:{}", a_port)).into()
+        );
+        assert_eq!(call(&mut b_client, &["asking"]).await?, ok);
+        let reply = call(&mut b_client, &["get", "{foo}.bar"]).await?;
+        assert_eq!(reply, BulkString::from("2").into());
+        let reply = call(&mut b_client, &["get", "{foo}.bar"]).await?;
+        assert!(matches!(reply, RespFrame::Error(e) if e.0.starts_with("MOVED")));
+
+        let reply = call(
+            &mut a_client,
+            &["migrate", "127.0.0.1", &b_port, "foo", "0", "1000"],
+        )
+        .await?;
+        assert_eq!(reply, ok);
+        let reply = call(
+            &mut a_client,
+            &["migrate", "127.0.0.1", &b_port, "foo", "0", "1000"],
+        )
+        .await?;
+        assert_eq!(reply, SimpleString::new("NOKEY").into());
+        let reply = call(&mut a_client, &["cluster", "getkeysinslot", "12182", "10"]).await?;
+        assert_eq!(reply, RespArray::new(Vec::<RespFrame>::new()).into());
+
+        for client in [&mut b_client, &mut a_client] {
+            let reply = call(client, &["cluster", "setslot", "12182", "node", &b_id]).await?;
+            assert_eq!(reply, ok);
+        }
+        let reply = call(&mut a_client, &["get", "foo"]).await?;
+        assert_eq!(
+            reply,
+            SimpleError::new(format!("MOVED 12182 127.0.0.1:{}", b_port)).into()
+        );
+        let reply = call(&mut b_client, &["get", "foo"]).await?;
+        assert_eq!(reply, BulkString::from("1").into());
+
+        for node in [&a, &b] {
+            node.shutdown().trigger(Default::default());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrate_keeps_keys_written_in_flight() -> Result<()> {
+        let backend = Backend::new();
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let port = target.local_addr()?.port().to_string();
+        let bulk = |s: &str| RespFrame::from(BulkString::from(s));
+
+        // A target that takes everything, but only replies once k2 was written to on the source.
+        let source = backend.clone();
+        tokio::spawn(async move {
+            let (stream, _) = target.accept().await?;
+            let mut framed = Framed::new(stream, RespFrameCodec);
+            // SELECT and a RESTORE-ASKING for each key.
+            for _ in 0..3 {
+                framed.next().await;
+            }
+            source.set("k2".to_string(), bulk("new"));
+            for _ in 0..3 {
+                framed.send(SimpleString::new("OK").into()).await?;
+            }
+            anyhow::Ok(())
+        });
+
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(stream_handler(server, backend.clone(), String::new()));
+        let mut client = Framed::new(client, RespFrameCodec);
+        call(&mut client, &["set", "k1", "v"]).await?;
+        call(&mut client, &["set", "k2", "v"]).await?;
+        let reply = call(
+            &mut client,
+            &[
+                "migrate",
+                "127.0.0.1",
+                &port,
+                "",
+                "0",
+                "1000",
+                "keys",
+                "k1",
+                "k2",
+            ],
+        )
+        .await?;
+        assert_eq!(
+            reply,
+            SimpleError::new("ERR Keys changed while being migrated and were kept: k2").into()
+        );
+        assert_eq!(
+            call(&mut client, &["get", "k1"]).await?,
+            RespFrame::Null(RespNull)
+        );
+        assert_eq!(call(&mut client, &["get", "k2"]).await?, bulk("new"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_active_expire() -> Result<()> {
+        let mut config = crate::config::ServerConfig::default();
+        config.set("notify-keyspace-events", "Ex")?;
+        let backend = Backend::with_config(config);
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(stream_handler(server, backend.clone(), String::new()));
+        let mut subscriber = Framed::new(client, RespFrameCodec);
+        call(&mut subscriber, &["subscribe", "__keyevent@0__:expired"]).await?;
+
+        backend.set("k".to_string(), BulkString::from("v").into());
+        backend.expire_at("k", crate::backend::unix_time_ms() + 50);
+        tokio::spawn(active_expire(backend.clone()));
+
+        // Nobody reads the key, yet it goes away and the event arrives.
+        let message = tokio::time::timeout(Duration::from_secs(5), subscriber.next()).await?;
+        assert_eq!(
+            message.unwrap()?,
+            RespArray::new(vec![
+                BulkString::from("message").into(),
+                BulkString::from("__keyevent@0__:expired").into(),
+                BulkString::from("k").into(),
+            ])
+            .into()
+        );
+        assert!(!backend.map.contains_key("k"));
+        backend.shutdown().trigger(Default::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pubsub_between_connections() -> Result<()> {
+        let backend = Backend::new();
+        let connect = || {
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(stream_handler(server, backend.clone(), String::new()));
+            Framed::new(client, RespFrameCodec)
+        };
+        let mut subscriber = connect();
+        let mut publisher = connect();
+        let bulk = |s: &str| RespFrame::from(BulkString::from(s));
+        let array = |frames: Vec<RespFrame>| RespFrame::from(RespArray::new(frames));
+
+        assert_eq!(
+            call(&mut subscriber, &["subscribe", "news", "weather"]).await?,
+            array(vec![bulk("subscribe"), bulk("news"), RespFrame::Integer(1)])
+        );
+        assert_eq!(
+            subscriber.next().await.unwrap()?,
+            array(vec![
+                bulk("subscribe"),
+                bulk("weather"),
+                RespFrame::Integer(2)
+            ])
+        );
+        assert_eq!(
+            call(&mut subscriber, &["psubscribe", "n*"]).await?,
+            array(vec![bulk("psubscribe"), bulk("n*"), RespFrame::Integer(3)])
+        );
+
+        // Subscriber mode only allows a few commands.
+        assert_eq!(
+            call(&mut subscriber, &["GET", "key"]).await?,
+            SimpleError::new("ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT are allowed in this context").into()
+        );
+        assert_eq!(
+            call(&mut subscriber, &["ping"]).await?,
+            array(vec![bulk("pong"), bulk("")])
+        );
+
+        assert_eq!(
+            call(&mut publisher, &["publish", "news", "hello"]).await?,
+            RespFrame::Integer(2)
+        );
+        assert_eq!(
+            subscriber.next().await.unwrap()?,
+            array(vec![bulk("message"), bulk("news"), bulk("hello")])
+        );
+        assert_eq!(
+            subscriber.next().await.unwrap()?,
+            array(vec![
+                bulk("pmessage"),
+                bulk("n*"),
+                bulk("news"),
+                bulk("hello")
+            ])
+        );
+        assert_eq!(
+            call(&mut publisher, &["pubsub", "numpat"]).await?,
+            RespFrame::Integer(1)
+        );
+
+        // Under RESP3 messages are push frames and any command can run.
+        let mut subscriber3 = connect();
+        let RespFrame::Map(_) = call(&mut subscriber3, &["hello", "3"]).await? else {
+            panic!("HELLO 3 must reply with a map");
+        };
+        assert_eq!(
+            call(&mut subscriber3, &["subscribe", "weather"]).await?,
+            RespPush::new(vec![
+                bulk("subscribe"),
+                bulk("weather"),
+                RespFrame::Integer(1)
+            ])
+            .into()
+        );
+        assert_eq!(
+            call(&mut subscriber3, &["get", "key"]).await?,
+            RespFrame::Null(RespNull)
+        );
+        call(&mut publisher, &["publish", "weather", "sunny"]).await?;
+        assert_eq!(
+            subscriber3.next().await.unwrap()?,
+            RespPush::new(vec![bulk("message"), bulk("weather"), bulk("sunny")]).into()
+        );
+        assert_eq!(
+            subscriber.next().await.unwrap()?,
+            array(vec![bulk("message"), bulk("weather"), bulk("sunny")])
+        );
+        drop(subscriber3);
+
+        assert_eq!(
+            call(&mut subscriber, &["punsubscribe"]).await?,
+            array(vec![
+                bulk("punsubscribe"),
+                bulk("n*"),
+                RespFrame::Integer(2)
+            ])
+        );
+        assert_eq!(
+            call(&mut subscriber, &["quit"]).await?,
+            RespFrame::SimpleString(crate::SimpleString::new("OK"))
+        );
+        assert!(subscriber.next().await.is_none());
+        assert_eq!(
+            call(&mut publisher, &["pubsub", "channels"]).await?,
+            array(vec![])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocks_logged_as_multi_exec() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("simple-redis-blocks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let config = crate::config::ServerConfig {
+            dir: dir.clone(),
+            appendonly: true,
+            appendfsync: crate::config::AppendFsync::Always,
+            ..Default::default()
+        };
+        let backend = Backend::with_config(config.clone());
+        crate::persistence::aof::open(&backend)?;
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(stream_handler(server, backend.clone(), String::new()));
+        let mut client = Framed::new(client, RespFrameCodec);
+
+        call(&mut client, &["set", "a", "1"]).await?;
+        // A block without writes logs nothing.
+        call(&mut client, &["multi"]).await?;
+        call(&mut client, &["get", "a"]).await?;
+        call(&mut client, &["exec"]).await?;
+        call(&mut client, &["multi"]).await?;
+        call(&mut client, &["set", "b", "2"]).await?;
+        call(&mut client, &["get", "a"]).await?;
+        call(&mut client, &["set", "c", "3"]).await?;
+        call(&mut client, &["exec"]).await?;
+        call(
+            &mut client,
+            &["eval", "redis.call('set', KEYS[1], '4')", "1", "d"],
+        )
+        .await?;
+
+        let mut expected = Vec::new();
+        for args in [
+            &["set", "a", "1"][..],
+            &["MULTI"],
+            &["set", "b", "2"],
+            &["set", "c", "3"],
+            &["EXEC"],
+            &["MULTI"],
+            &["set", "d", "4"],
+            &["EXEC"],
+        ] {
+            let frame: RespFrame = RespArray::new(
+                args.iter()
+                    .map(|arg| BulkString::from(*arg).into())
+                    .collect::<Vec<RespFrame>>(),
+            )
+            .into();
+            expected.extend(frame.encode());
+        }
+        // After the snapshot preamble open() starts the file with.
+        let logged = std::fs::read(config.aof_path())?;
+        assert!(logged.ends_with(&expected));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_ttl_logged_absolute() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("simple-redis-restore-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let config = crate::config::ServerConfig {
+            dir: dir.clone(),
+            appendonly: true,
+            appendfsync: crate::config::AppendFsync::Always,
+            ..Default::default()
+        };
+        let backend = Backend::with_config(config.clone());
+        crate::persistence::aof::open(&backend)?;
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(stream_handler(server, backend.clone(), String::new()));
+        let mut client = Framed::new(client, RespFrameCodec);
+
+        call(&mut client, &["set", "source", "value"]).await?;
+        let RespFrame::BulkString(payload) = call(&mut client, &["dump", "source"]).await? else {
+            panic!("DUMP must reply with a bulk string");
+        };
+        let restore: RespFrame = RespArray::new(vec![
+            BulkString::from("restore").into(),
+            BulkString::from("short").into(),
+            BulkString::from("50").into(),
+            payload.clone().into(),
+        ])
+        .into();
+        client.send(restore).await?;
+        client.next().await.unwrap()?;
+        let restore: RespFrame = RespArray::new(vec![
+            BulkString::from("restore").into(),
+            BulkString::from("long").into(),
+            BulkString::from("100000").into(),
+            payload.into(),
+        ])
+        .into();
+        client.send(restore).await?;
+        client.next().await.unwrap()?;
+        assert!(backend.get("short").is_some());
+
+        // Replayed after the short TTL ran out, the key stays expired instead of living for another 50ms.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let restarted = Backend::with_config(config);
+        crate::persistence::aof::load(&restarted)?;
+        assert_eq!(restarted.get("short"), None);
+        assert_eq!(
+            restarted.get("long"),
+            Some(BulkString::from("value").into())
+        );
+        assert_eq!(restarted.expire_time("long"), backend.expire_time("long"));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_between_connections() -> Result<()> {
+        let backend = Backend::new();
+        let connect = || {
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(stream_handler(server, backend.clone(), String::new()));
+            Framed::new(client, RespFrameCodec)
+        };
+        let mut client = connect();
+        let mut other = connect();
+        let ok = RespFrame::from(SimpleString::new("OK"));
+        let queued = RespFrame::from(SimpleString::new("QUEUED"));
+        let bulk = |s: &str| RespFrame::from(BulkString::from(s));
+
+        assert_eq!(call(&mut client, &["multi"]).await?, ok);
+        assert_eq!(
+            call(&mut client, &["multi"]).await?,
+            SimpleError::new("ERR MULTI calls can not be nested").into()
+        );
+        assert_eq!(call(&mut client, &["set", "k", "v1"]).await?, queued);
+        assert_eq!(call(&mut client, &["get", "k"]).await?, queued);
+        // Nothing runs before EXEC, other connections do not see the queued write.
+        assert_eq!(
+            call(&mut other, &["get", "k"]).await?,
+            RespFrame::Null(RespNull)
+        );
+        assert_eq!(
+            call(&mut client, &["exec"]).await?,
+            RespArray::new(vec![ok.clone(), bulk("v1")]).into()
+        );
+        assert_eq!(call(&mut other, &["get", "k"]).await?, bulk("v1"));
+
+        // A command that does not parse discards the whole transaction.
+        assert_eq!(call(&mut client, &["multi"]).await?, ok);
+        assert_eq!(call(&mut client, &["set", "k", "v2"]).await?, queued);
+        assert!(matches!(
+            call(&mut client, &["get"]).await?,
+            RespFrame::Error(_)
+        ));
+        assert_eq!(
+            call(&mut client, &["exec"]).await?,
+            SimpleError::new("EXECABORT Transaction discarded because of previous errors.").into()
+        );
+        assert_eq!(call(&mut client, &["get", "k"]).await?, bulk("v1"));
+
+        assert_eq!(call(&mut client, &["multi"]).await?, ok);
+        assert_eq!(call(&mut client, &["set", "k", "v3"]).await?, queued);
+        assert_eq!(call(&mut client, &["discard"]).await?, ok);
+        assert_eq!(
+            call(&mut client, &["exec"]).await?,
+            SimpleError::new("ERR EXEC without MULTI").into()
+        );
+        assert_eq!(call(&mut client, &["get", "k"]).await?, bulk("v1"));
+
+        // A watched key written by another connection makes EXEC run nothing.
+        assert_eq!(call(&mut client, &["watch", "k"]).await?, ok);
+        assert_eq!(call(&mut other, &["set", "k", "other"]).await?, ok);
+        assert_eq!(call(&mut client, &["multi"]).await?, ok);
+        assert_eq!(
+            call(&mut client, &["watch", "k"]).await?,
+            SimpleError::new("ERR WATCH inside MULTI is not allowed").into()
+        );
+        assert_eq!(call(&mut client, &["set", "k", "v4"]).await?, queued);
+        assert_eq!(
+            call(&mut client, &["exec"]).await?,
+            RespFrame::NullArray(RespNullArray)
+        );
+        assert_eq!(call(&mut client, &["get", "k"]).await?, bulk("other"));
+
+        // EXEC unwatched the key, so the next transaction goes through.
+        assert_eq!(call(&mut other, &["set", "k", "other2"]).await?, ok);
+        assert_eq!(call(&mut client, &["multi"]).await?, ok);
+        assert_eq!(call(&mut client, &["set", "k", "v5"]).await?, queued);
+        assert_eq!(
+            call(&mut client, &["exec"]).await?,
+            RespArray::new(vec![ok.clone()]).into()
+        );
+        assert!(backend.watched.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_scripts_between_connections() -> Result<()> {
+        let backend = Backend::with_config(crate::config::ServerConfig {
+            busy_reply_threshold: 50,
+            ..Default::default()
+        });
+        let connect = || {
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(stream_handler(server, backend.clone(), String::new()));
+            Framed::new(client, RespFrameCodec)
+        };
+        let mut client = connect();
+        let mut other = connect();
+        let bulk = |s: &str| RespFrame::from(BulkString::from(s));
+
+        let script = "redis.call('set', KEYS[1], ARGV[1]); return redis.call('get', KEYS[1])";
+        assert_eq!(
+            call(&mut client, &["eval", script, "1", "k", "v"]).await?,
+            bulk("v")
+        );
+        let sha = crate::scripting::sha1hex(script.as_bytes());
+        assert_eq!(
+            call(&mut other, &["evalsha", &sha, "1", "k", "v2"]).await?,
+            bulk("v2")
+        );
+        assert_eq!(
+            call(&mut client, &["eval", "return redis.call('multi')", "0"]).await?,
+            SimpleError::new("ERR This Redis command is not allowed from script").into()
+        );
+
+        // A function loaded by one connection can be called by the others.
+        let library = "#!lua name=lib\nredis.register_function('setget', function(keys, args) \
+            redis.call('set', keys[1], args[1]); return redis.call('get', keys[1]) end)";
+        assert_eq!(
+            call(&mut client, &["function", "load", library]).await?,
+            bulk("lib")
+        );
+        assert_eq!(
+            call(&mut other, &["fcall", "setget", "1", "k", "v3"]).await?,
+            bulk("v3")
+        );
+        assert_eq!(
+            call(&mut other, &["fcall_ro", "setget", "1", "k", "v4"]).await?,
+            SimpleError::new("ERR Can not execute a script with write flag using *_ro command.")
+                .into()
+        );
+        assert_eq!(
+            call(&mut client, &["function", "delete", "lib"]).await?,
+            SimpleString::new("OK").into()
+        );
+
+        // A script that runs too long makes the server busy until SCRIPT KILL stops it.
+        client
+            .send(RespArray::new(vec![bulk("eval"), bulk("while true do end"), bulk("0")]).into())
+            .await?;
+        loop {
+            match call(&mut other, &["get", "k"]).await? {
+                RespFrame::Error(e) if e.starts_with("BUSY") => break,
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+        assert_eq!(
+            call(&mut other, &["script", "kill"]).await?,
+            SimpleString::new("OK").into()
+        );
+        assert_eq!(
+            client.next().await.unwrap()?,
+            SimpleError::new("ERR Script killed by user with SCRIPT KILL...").into()
+        );
+        assert_eq!(call(&mut other, &["get", "k"]).await?, bulk("v3"));
+        Ok(())
+    }
+
+    // The same over TCP: while the script runs, the runtime still has to drive the sockets of the other clients.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_busy_script_over_tcp() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let backend = Backend::with_config(crate::config::ServerConfig {
+            busy_reply_threshold: 50,
+            save: Vec::new(),
+            ..Default::default()
+        });
+        let server = tokio::spawn(serve(vec![listener.into()], backend.clone()));
+        let mut client = Framed::new(TcpStream::connect(addr).await?, RespFrameCodec);
+        let mut other = Framed::new(TcpStream::connect(addr).await?, RespFrameCodec);
+        let bulk = |s: &str| RespFrame::from(BulkString::from(s));
+
+        client
+            .send(RespArray::new(vec![bulk("eval"), bulk("while true do end"), bulk("0")]).into())
+            .await?;
+        loop {
+            match call(&mut other, &["ping"]).await? {
+                RespFrame::Error(e) if e.starts_with("BUSY") => break,
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+        assert_eq!(
+            call(&mut other, &["script", "kill"]).await?,
+            SimpleString::new("OK").into()
+        );
+        assert_eq!(
+            client.next().await.unwrap()?,
+            SimpleError::new("ERR Script killed by user with SCRIPT KILL...").into()
+        );
+        assert_eq!(
+            call(&mut client, &["ping"]).await?,
+            SimpleString::new("PONG").into()
+        );
+
+        backend.shutdown().trigger(Default::default());
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_handler_over_duplex() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(stream_handler(server, Backend::new(), String::new()));
+
+        client
+            .write_all(b"*2\r\n$3\r\nget\r\n$5\r\nhello\r\n")
+            .await?;
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"_\r\n");
+
+        drop(client);
+        handler.await??;
+        Ok(())
+    }
+}
+
 // Example Interaction
 
 // Client Sends a Command:
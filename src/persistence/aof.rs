@@ -0,0 +1,438 @@
+// The append-only file: every write command is appended to dir/appendfilename, as the RESP array it arrived as,
+// after it executed successfully. At startup the file is replayed through the normal Command::try_from + execute path.
+// The writes of a transaction or a script are logged between a MULTI and an EXEC and replayed only once the EXEC is
+// read; a block the file ends in is cut off, like a truncated command.
+//
+// Layout: an optional snapshot preamble (written by BGREWRITEAOF, see snapshot.rs) followed by RESP commands.
+//
+// Ordering: network::request_handler holds the AOF lock while it executes a write command and appends it,
+// so the order of the commands in the file is the order in which they changed the backend.
+//
+// BGREWRITEAOF compacts the file without stopping writes:
+// 1. Under the lock, start buffering every appended command and copy the dataset (like BGSAVE).
+// 2. On a background thread, write the copy as a snapshot preamble to a temporary file.
+// 3. Under the lock again, append the buffered commands to the temporary file and rename it over the AOF.
+
+use super::{snapshot::Snapshot, temp_path, write_file, PersistenceError};
+use crate::{
+    cmd::{Command, CommandExecutor},
+    config::AppendFsync,
+    network::is_command,
+    Backend, RespDecode, RespEncode, RespError, RespFrame,
+};
+use bytes::BytesMut;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+// None until open() is called, i.e. when appendonly is off.
+#[derive(Debug, Default)]
+pub struct Aof {
+    state: Mutex<Option<AofState>>,
+}
+
+#[derive(Debug)]
+struct AofState {
+    // Shared with the everysec fsync task, which syncs its clone without holding the lock.
+    file: Arc<File>,
+    path: PathBuf,
+    fsync: AppendFsync,
+    // Some while a BGREWRITEAOF is running: the commands appended since it copied the dataset.
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+// Returned by Aof::lock; appends go through it so they happen under the lock.
+pub struct AofGuard<'a>(MutexGuard<'a, Option<AofState>>);
+
+impl Aof {
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().is_some()
+    }
+
+    // Locks the AOF for a write command, or returns None when appendonly is off.
+    pub fn lock(&self) -> Option<AofGuard<'_>> {
+        let guard = self.state.lock().unwrap();
+        guard.is_some().then_some(AofGuard(guard))
+    }
+
+    // Flushes the appended commands to disk (the everysec policy, and the shutdown).
+    pub fn fsync(&self) -> std::io::Result<()> {
+        let file = self
+            .state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|state| state.file.clone());
+        match file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AofGuard<'_> {
+    pub fn append(&mut self, frame: RespFrame) -> std::io::Result<()> {
+        let Some(state) = self.0.as_mut() else {
+            return Ok(());
+        };
+        let data = frame.encode();
+        (&*state.file).write_all(&data)?;
+        if let Some(buffer) = &mut state.rewrite_buffer {
+            buffer.extend_from_slice(&data);
+        }
+        if state.fsync == AppendFsync::Always {
+            state.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+// Starts appending to dir/appendfilename. A missing file is created with a preamble of the current dataset,
+// so whatever was loaded from the snapshot is not lost the next time the server starts from the AOF.
+pub fn open(backend: &Backend) -> Result<(), PersistenceError> {
+    let path = backend.config.aof_path();
+    if !path.exists() {
+        let snapshot = {
+            let _barrier = backend.write_barrier.write().unwrap();
+            Snapshot::from_backend(backend)
+        };
+        write_file(&path, &snapshot.encode())?;
+    }
+    *backend.aof.state.lock().unwrap() = Some(AofState {
+        file: Arc::new(open_append(&path)?),
+        path,
+        fsync: backend.config.appendfsync,
+        rewrite_buffer: None,
+    });
+    Ok(())
+}
+
+// Replays dir/appendfilename into the backend; called once at startup, before any client can connect.
+// Returns the number of commands replayed (plus the keys of the preamble), or None if there is no AOF yet.
+// A command cut off at the end of the file (the server died in the middle of a write) is dropped and the file
+// is truncated to the last complete command, like redis with aof-load-truncated yes; anything else is an error.
+pub fn load(backend: &Backend) -> Result<Option<usize>, PersistenceError> {
+    let path = backend.config.aof_path();
+    let data = match std::fs::read(&path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        ret => ret?,
+    };
+
+    let mut loaded = 0;
+    let mut pos = 0;
+    if Snapshot::is_snapshot(&data) {
+        let (snapshot, len) = Snapshot::decode_prefix(&data)?;
+        loaded += snapshot.restore(backend);
+        pos = len;
+    }
+
+    // The offset of the MULTI and the commands of a block that has not reached its EXEC yet.
+    let mut block: Option<(usize, Vec<Command>)> = None;
+    let mut buf = BytesMut::from(&data[pos..]);
+    while !buf.is_empty() {
+        let frame = match RespFrame::decode(&mut buf) {
+            Ok(frame) => frame,
+            Err(RespError::NotComplete) => {
+                warn!(
+                    "AOF {} is truncated: dropping the last {} byte(s)",
+                    path.display(),
+                    buf.len()
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(pos as u64)?;
+                break;
+            }
+            Err(e) => {
+                return Err(PersistenceError::Corrupt(format!(
+                    "AOF at offset {}: {}",
+                    pos, e
+                )))
+            }
+        };
+        let start = pos;
+        pos = data.len() - buf.len();
+
+        if is_command(&frame, b"multi") {
+            block = Some((start, Vec::new()));
+            continue;
+        }
+        if is_command(&frame, b"exec") {
+            if let Some((_, cmds)) = block.take() {
+                let _barrier = backend.exec_barrier.write().unwrap();
+                loaded += cmds.len();
+                cmds.into_iter().for_each(|cmd| {
+                    cmd.execute(backend);
+                });
+            }
+            continue;
+        }
+        let cmd = Command::try_from(frame).map_err(|e| {
+            PersistenceError::Corrupt(format!("AOF command before offset {}: {}", pos, e))
+        })?;
+        match block.as_mut() {
+            Some((_, cmds)) => cmds.push(cmd),
+            None => {
+                cmd.execute(backend);
+                loaded += 1;
+            }
+        }
+    }
+    // The server died before it logged the EXEC: the transaction never happened.
+    if let Some((start, cmds)) = block {
+        warn!(
+            "AOF {} ends in a MULTI block: dropping its {} command(s)",
+            path.display(),
+            cmds.len()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(start as u64)?;
+    }
+
+    // Replaying is not a change that still needs to be saved.
+    backend.persistence.reset_dirty();
+    Ok(Some(loaded))
+}
+
+// Starts a rewrite on a background thread and returns immediately (BGREWRITEAOF).
+pub fn bgrewrite(backend: &Backend) -> Result<(), PersistenceError> {
+    let snapshot = {
+        let mut guard = backend.aof.state.lock().unwrap();
+        let state = guard.as_mut().ok_or(PersistenceError::AofDisabled)?;
+        if state.rewrite_buffer.is_some() {
+            return Err(PersistenceError::RewriteInProgress);
+        }
+        // Holding the AOF lock keeps out write commands, so every command is either
+        // in the copy or in the rewrite buffer (never in neither).
+        state.rewrite_buffer = Some(Vec::new());
+        let _barrier = backend.write_barrier.write().unwrap();
+        Snapshot::from_backend(backend)
+    };
+
+    let cloned_backend = backend.clone();
+    let spawned = std::thread::Builder::new()
+        .name("bgrewriteaof".to_string())
+        .spawn(move || match finish_rewrite(&cloned_backend, snapshot) {
+            Ok(()) => info!("Background AOF rewrite finished successfully"),
+            Err(e) => {
+                warn!("Background AOF rewrite failed: {}", e);
+                if let Some(state) = cloned_backend.aof.state.lock().unwrap().as_mut() {
+                    state.rewrite_buffer = None;
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        if let Some(state) = backend.aof.state.lock().unwrap().as_mut() {
+            state.rewrite_buffer = None;
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+pub fn rewrite_in_progress(backend: &Backend) -> bool {
+    matches!(&*backend.aof.state.lock().unwrap(), Some(state) if state.rewrite_buffer.is_some())
+}
+
+fn finish_rewrite(backend: &Backend, snapshot: Snapshot) -> Result<(), PersistenceError> {
+    let path = backend.config.aof_path();
+    let tmp = temp_path(&path);
+    // The bulk of the work happens without the lock; only the (short) tail is written while writes wait.
+    let mut file = File::create(&tmp)?;
+    file.write_all(&snapshot.encode())?;
+
+    let mut guard = backend.aof.state.lock().unwrap();
+    let state = guard.as_mut().ok_or(PersistenceError::AofDisabled)?;
+    let buffer = state.rewrite_buffer.take().unwrap_or_default();
+    let ret = (|| {
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &state.path)?;
+        open_append(&state.path)
+    })();
+    match ret {
+        Ok(file) => {
+            state.file = Arc::new(file);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e.into())
+        }
+    }
+}
+
+// Flushes the AOF once a second when appendfsync is everysec, until the server shuts down.
+pub async fn fsync_every_second(backend: Backend) {
+    let token = backend.shutdown.token();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            biased;
+            _ = token.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        let cloned_backend = backend.clone();
+        match tokio::task::spawn_blocking(move || cloned_backend.aof.fsync()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("AOF fsync failed: {}", e),
+            Err(e) => warn!("AOF fsync task failed: {}", e),
+        }
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, BulkString, RespArray};
+
+    fn test_config(name: &str) -> (ServerConfig, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("simple-redis-aof-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = ServerConfig {
+            dir: dir.clone(),
+            appendonly: true,
+            appendfsync: AppendFsync::Always,
+            ..Default::default()
+        };
+        (config, dir)
+    }
+
+    fn command(args: &[&str]) -> RespFrame {
+        RespArray::new(
+            args.iter()
+                .map(|arg| BulkString::from(*arg).into())
+                .collect::<Vec<RespFrame>>(),
+        )
+        .into()
+    }
+
+    // What request_handler does for a write command.
+    fn execute(backend: &Backend, args: &[&str]) {
+        let frame = command(args);
+        let mut aof = backend.aof.lock().unwrap();
+        Command::try_from(frame.clone()).unwrap().execute(backend);
+        aof.append(frame).unwrap();
+    }
+
+    #[test]
+    fn test_aof_append_and_replay() -> anyhow::Result<()> {
+        let (config, dir) = test_config("replay");
+        let backend = Backend::with_config(config.clone());
+        assert_eq!(load(&backend)?, None);
+        open(&backend)?;
+        execute(&backend, &["set", "hello", "world"]);
+        execute(&backend, &["hset", "map", "field", "value"]);
+        execute(&backend, &["set", "hello", "again"]);
+
+        let restarted = Backend::with_config(config.clone());
+        assert_eq!(load(&restarted)?, Some(3));
+        assert_eq!(
+            restarted.get("hello"),
+            Some(BulkString::from("again").into())
+        );
+        assert_eq!(
+            restarted.hget("map", "field"),
+            Some(BulkString::from("value").into())
+        );
+        assert_eq!(restarted.persistence.dirty(), 0);
+
+        // The server died in the middle of writing a command: the tail is dropped and the file repaired.
+        let path = config.aof_path();
+        let complete = std::fs::metadata(&path)?.len();
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(b"*3\r\n$3\r\nset\r\n$5\r\nhel")?;
+        let restarted = Backend::with_config(config.clone());
+        assert_eq!(load(&restarted)?, Some(3));
+        assert_eq!(std::fs::metadata(&path)?.len(), complete);
+
+        // Garbage in the middle of the file is not something to repair silently.
+        std::fs::write(&path, b"*1\r\n$3\r\nset\r\n!oops\r\n")?;
+        assert!(load(&Backend::with_config(config)).is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_aof_replays_blocks_whole() -> anyhow::Result<()> {
+        let (config, dir) = test_config("blocks");
+        let backend = Backend::with_config(config.clone());
+        open(&backend)?;
+        execute(&backend, &["set", "before", "1"]);
+        backend.aof.lock().unwrap().append(command(&["multi"]))?;
+        execute(&backend, &["set", "a", "1"]);
+        execute(&backend, &["set", "b", "1"]);
+        backend.aof.lock().unwrap().append(command(&["exec"]))?;
+
+        let restarted = Backend::with_config(config.clone());
+        assert_eq!(load(&restarted)?, Some(3));
+        assert_eq!(restarted.get("b"), Some(BulkString::from("1").into()));
+
+        // The server died between the writes of a block: none of them is replayed and the block is cut off.
+        let path = config.aof_path();
+        let complete = std::fs::metadata(&path)?.len();
+        backend.aof.lock().unwrap().append(command(&["multi"]))?;
+        execute(&backend, &["set", "c", "1"]);
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(b"*3\r\n$3\r\nset\r\n$1\r\nd")?;
+        let restarted = Backend::with_config(config.clone());
+        assert_eq!(load(&restarted)?, Some(3));
+        assert_eq!(restarted.get("c"), None);
+        assert_eq!(std::fs::metadata(&path)?.len(), complete);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_aof_rewrite() -> anyhow::Result<()> {
+        let (config, dir) = test_config("rewrite");
+        let backend = Backend::with_config(config.clone());
+        open(&backend)?;
+        for i in 0..100 {
+            execute(&backend, &["set", "counter", &i.to_string()]);
+        }
+        let before = std::fs::metadata(config.aof_path())?.len();
+
+        bgrewrite(&backend)?;
+        // Writes keep going while the rewrite runs, and end up in the new file.
+        execute(&backend, &["hset", "map", "field", "value"]);
+        while rewrite_in_progress(&backend) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        execute(&backend, &["set", "after", "rewrite"]);
+        assert!(std::fs::metadata(config.aof_path())?.len() < before);
+
+        let restarted = Backend::with_config(config);
+        load(&restarted)?;
+        assert_eq!(
+            restarted.get("counter"),
+            Some(BulkString::from("99").into())
+        );
+        assert_eq!(
+            restarted.hget("map", "field"),
+            Some(BulkString::from("value").into())
+        );
+        assert_eq!(
+            restarted.get("after"),
+            Some(BulkString::from("rewrite").into())
+        );
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+// Persistence: point-in-time snapshots of the whole dataset, written to dir/dbfilename.
+//
+// A snapshot is taken by SAVE (in the foreground), BGSAVE (on a background thread), automatically when one of
+// the `save <seconds> <changes>` points is reached, and when the server shuts down; it is loaded once at startup.
+//
+// Consistency: redis forks and lets the child write the copy-on-write pages of the parent.
+// Here every write to the keyspace holds Backend.write_barrier in shared mode, and taking a snapshot holds it
+// exclusively while the dataset is copied in memory. Writers are only paused for the copy; encoding the copy
+// and writing it to disk happen without holding the barrier.
+
+pub mod aof;
+pub mod rdb;
+pub mod snapshot;
+
+use crate::{backend::unix_time_ms, config::SnapshotFormat, shutdown::SaveMode, Backend};
+use snapshot::Snapshot;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+// After a failed background save, automatic saves are retried after this long instead of on every tick.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+#[derive(Error, Debug)]
+pub enum PersistenceError {
+    #[error("Background save already in progress")]
+    InProgress,
+    #[error("Background append only file rewriting already in progress")]
+    RewriteInProgress,
+    #[error("Append only file is not enabled (appendonly no)")]
+    AofDisabled,
+    #[error("Bad file format: {0}")]
+    Corrupt(String),
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// Bookkeeping shared by SAVE, BGSAVE, LASTSAVE and the automatic saves.
+// dirty: writes since the last successful save (what the save points are compared against).
+// lastsave / last_bgsave_try: unix time in seconds.
+// writer: held while a snapshot is captured and written, so two saves never interleave
+// and the file on disk always ends up holding the most recent one.
+#[derive(Debug)]
+pub struct Persistence {
+    dirty: AtomicU64,
+    lastsave: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+    last_bgsave_ok: AtomicBool,
+    last_bgsave_try: AtomicU64,
+    writer: Mutex<()>,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        // Like redis, LASTSAVE reports the startup time until the first save.
+        Self {
+            dirty: AtomicU64::new(0),
+            lastsave: AtomicU64::new(unix_time_ms() / 1000),
+            bgsave_in_progress: AtomicBool::new(false),
+            last_bgsave_ok: AtomicBool::new(true),
+            last_bgsave_try: AtomicU64::new(0),
+            writer: Mutex::new(()),
+        }
+    }
+}
+
+impl Persistence {
+    pub fn add_dirty(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset_dirty(&self) {
+        self.dirty.store(0, Ordering::Relaxed);
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    pub fn lastsave(&self) -> u64 {
+        self.lastsave.load(Ordering::Relaxed)
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress.load(Ordering::Acquire)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::Relaxed)
+    }
+}
+
+// Writes a snapshot in the calling thread (SAVE, shutdown). Waits for a background save that is still writing.
+pub fn save(backend: &Backend) -> Result<(), PersistenceError> {
+    let _writer = backend.persistence.writer.lock().unwrap();
+
+    // Only the copy happens under the barrier; dirty is read at the same moment,
+    // so writes that land while the file is being written still count towards the next save.
+    let (snapshot, dirty) = {
+        let _barrier = backend.write_barrier.write().unwrap();
+        (Snapshot::from_backend(backend), backend.persistence.dirty())
+    };
+
+    let data = match backend.config.snapshot_format {
+        SnapshotFormat::Native => snapshot.encode(),
+        SnapshotFormat::Rdb => rdb::encode(&snapshot)?,
+    };
+    write_file(&backend.config.snapshot_path(), &data)?;
+    backend
+        .persistence
+        .dirty
+        .fetch_sub(dirty, Ordering::Relaxed);
+    backend
+        .persistence
+        .lastsave
+        .store(unix_time_ms() / 1000, Ordering::Relaxed);
+    Ok(())
+}
+
+// Starts a save on a background thread and returns immediately (BGSAVE, save points).
+pub fn bgsave(backend: &Backend) -> Result<(), PersistenceError> {
+    let persistence = &backend.persistence;
+    if persistence.bgsave_in_progress.swap(true, Ordering::AcqRel) {
+        return Err(PersistenceError::InProgress);
+    }
+    persistence
+        .last_bgsave_try
+        .store(unix_time_ms() / 1000, Ordering::Relaxed);
+
+    let cloned_backend = backend.clone();
+    let spawned = std::thread::Builder::new()
+        .name("bgsave".to_string())
+        .spawn(move || {
+            let ret = save(&cloned_backend);
+            match &ret {
+                Ok(()) => info!("Background saving terminated with success"),
+                Err(e) => warn!("Background saving error: {}", e),
+            }
+            let persistence = &cloned_backend.persistence;
+            persistence
+                .last_bgsave_ok
+                .store(ret.is_ok(), Ordering::Relaxed);
+            persistence
+                .bgsave_in_progress
+                .store(false, Ordering::Release);
+        });
+    if let Err(e) = spawned {
+        persistence
+            .bgsave_in_progress
+            .store(false, Ordering::Release);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// Loads dir/dbfilename into the backend; called once at startup, before any client can connect.
+// The file may be one of our snapshots or an RDB file written by redis, whatever snapshot-format says.
+// Returns the number of keys loaded, or None when there is no snapshot yet (a fresh server).
+pub fn load(backend: &Backend) -> Result<Option<usize>, PersistenceError> {
+    let data = match std::fs::read(backend.config.snapshot_path()) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        ret => ret?,
+    };
+    let snapshot = if rdb::is_rdb(&data) {
+        rdb::decode(&data)?.snapshot
+    } else {
+        Snapshot::decode(&data)?
+    };
+    Ok(Some(snapshot.restore(backend)))
+}
+
+// Whether stopping the server with the given save mode has to write a snapshot first.
+pub fn save_on_shutdown(backend: &Backend, mode: SaveMode) -> bool {
+    match mode {
+        SaveMode::Save => true,
+        SaveMode::NoSave => false,
+        // Like redis, a plain shutdown saves when save points are configured.
+        // With no change since the last save (or since the snapshot was loaded) the file on disk is already up to date.
+        SaveMode::Default => !backend.config.save.is_empty() && backend.persistence.dirty() > 0,
+    }
+}
+
+// Checks the save points once a second and starts a background save when one of them is reached,
+// i.e. at least <changes> writes happened and at least <seconds> passed since the last save.
+// Runs until the server shuts down; the final save is done by the shutdown itself.
+pub async fn autosave(backend: Backend) {
+    let token = backend.shutdown.token();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            biased;
+            _ = token.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        if save_point_reached(&backend, unix_time_ms() / 1000) {
+            info!(
+                "{} changes since the last save, saving...",
+                backend.persistence.dirty()
+            );
+            if let Err(e) = bgsave(&backend) {
+                warn!("Failed to start a background save: {}", e);
+            }
+        }
+    }
+}
+
+fn save_point_reached(backend: &Backend, now: u64) -> bool {
+    let persistence = &backend.persistence;
+    if persistence.bgsave_in_progress() {
+        return false;
+    }
+    if !persistence.last_bgsave_ok()
+        && now.saturating_sub(persistence.last_bgsave_try.load(Ordering::Relaxed))
+            < BGSAVE_RETRY_DELAY
+    {
+        return false;
+    }
+    let dirty = persistence.dirty();
+    let elapsed = now.saturating_sub(persistence.lastsave());
+    backend
+        .config
+        .save
+        .iter()
+        .any(|&(seconds, changes)| dirty >= changes && elapsed >= seconds)
+}
+
+// Writes to a temporary file next to the target and renames it over the target once it is on disk,
+// so a crash in the middle of a save never leaves a truncated snapshot behind.
+fn write_file(path: &Path, data: &[u8]) -> Result<(), PersistenceError> {
+    use std::io::Write;
+
+    let tmp = temp_path(path);
+    let ret = (|| {
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    })();
+    if ret.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    Ok(ret?)
+}
+
+// temp-<pid>-<name> in the same directory, so the final rename never crosses file systems.
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("temp-{}-{}", std::process::id(), file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, BulkString};
+
+    fn test_config(name: &str) -> (ServerConfig, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "simple-redis-persistence-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = ServerConfig {
+            dir: dir.clone(),
+            save: vec![(60, 2)],
+            ..Default::default()
+        };
+        (config, dir)
+    }
+
+    #[test]
+    fn test_save_and_load() -> anyhow::Result<()> {
+        let (config, dir) = test_config("save");
+        let backend = Backend::with_config(config.clone());
+        assert_eq!(load(&backend)?, None);
+
+        backend.set("hello".to_string(), BulkString::from("world").into());
+        backend.hset(
+            "map".to_string(),
+            "field".to_string(),
+            BulkString::from("value").into(),
+        );
+        assert_eq!(backend.persistence.dirty(), 2);
+        assert!(save_on_shutdown(&backend, SaveMode::Default));
+        save(&backend)?;
+        assert_eq!(backend.persistence.dirty(), 0);
+        assert!(!save_on_shutdown(&backend, SaveMode::Default));
+        assert!(save_on_shutdown(&backend, SaveMode::Save));
+
+        let restarted = Backend::with_config(config.clone());
+        assert_eq!(load(&restarted)?, Some(2));
+        assert_eq!(
+            restarted.get("hello"),
+            Some(BulkString::from("world").into())
+        );
+        assert_eq!(restarted.persistence.dirty(), 0);
+
+        // A damaged file must stop the server from starting instead of silently losing data.
+        let path = config.snapshot_path();
+        let mut data = std::fs::read(&path)?;
+        data.truncate(data.len() - 3);
+        std::fs::write(&path, data)?;
+        assert!(load(&Backend::with_config(config)).is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_load_rdb() -> anyhow::Result<()> {
+        let (mut config, dir) = test_config("rdb");
+        config.snapshot_format = SnapshotFormat::Rdb;
+        let backend = Backend::with_config(config.clone());
+        backend.set("hello".to_string(), BulkString::from("world").into());
+        backend.expire_at("hello", 4102444800000);
+        save(&backend)?;
+        assert!(rdb::is_rdb(&std::fs::read(config.snapshot_path())?));
+
+        // Loading detects the format, whatever snapshot-format is set to now.
+        config.snapshot_format = SnapshotFormat::Native;
+        let restarted = Backend::with_config(config);
+        assert_eq!(load(&restarted)?, Some(1));
+        assert_eq!(
+            restarted.get("hello"),
+            Some(BulkString::from("world").into())
+        );
+        assert_eq!(restarted.expire_time("hello"), Some(4102444800000));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bgsave_and_save_points() -> anyhow::Result<()> {
+        let (config, dir) = test_config("bgsave");
+        let backend = Backend::with_config(config.clone());
+        let now = backend.persistence.lastsave();
+
+        backend.set("a".to_string(), BulkString::from("1").into());
+        // One change is below the save point, and so is a minute that has not passed yet.
+        assert!(!save_point_reached(&backend, now + 60));
+        backend.set("b".to_string(), BulkString::from("2").into());
+        assert!(!save_point_reached(&backend, now + 59));
+        assert!(save_point_reached(&backend, now + 60));
+
+        bgsave(&backend)?;
+        while backend.persistence.bgsave_in_progress() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(backend.persistence.last_bgsave_ok());
+        assert!(!save_point_reached(&backend, now + 60));
+
+        let restarted = Backend::with_config(config);
+        assert_eq!(load(&restarted)?, Some(2));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+// The compact encodings redis embeds inside RDB strings: LZF compression, ziplists, listpacks, intsets and zipmaps.
+// Each decoder turns the blob into the plain list of elements it holds; integers come back as their decimal string,
+// which is also how redis hands them to clients.
+// Only reading is needed: the RDB writer uses the plain (non-embedded) encodings, which every redis version loads.
+
+use super::super::PersistenceError;
+
+fn corrupt(what: &str) -> PersistenceError {
+    PersistenceError::Corrupt(format!("RDB {}", what))
+}
+
+// The most a chunk can produce per byte: a 3 byte back reference copies up to 7 + 255 + 2 bytes.
+const LZF_MAX_EXPANSION: usize = 88;
+
+// LZF (liblzf) as used by redis for strings longer than 20 bytes when rdbcompression is on.
+// The stream is a sequence of chunks:
+//   000LLLLL <L+1 literal bytes>
+//   LLLOOOOO [extra length byte if LLL = 7] OOOOOOOO   back reference: copy len + 2 bytes from offset + 1 bytes back
+// expected_len comes from the file (or a RESTORE payload), so it is only trusted as far as the input could expand to.
+pub fn lzf_decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>, PersistenceError> {
+    if expected_len > input.len().saturating_mul(LZF_MAX_EXPANSION) {
+        return Err(corrupt("LZF length larger than the data can hold"));
+    }
+    let mut out = Vec::with_capacity(expected_len);
+    let mut i = 0;
+    while i < input.len() {
+        if out.len() > expected_len {
+            return Err(corrupt("LZF data has the wrong length"));
+        }
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let len = ctrl + 1;
+            let literal = input
+                .get(i..i + len)
+                .ok_or_else(|| corrupt("LZF literal runs past the end"))?;
+            out.extend_from_slice(literal);
+            i += len;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or_else(|| corrupt("truncated LZF chunk"))? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or_else(|| corrupt("truncated LZF chunk"))? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1f) << 8) + low + 1;
+            let start = out
+                .len()
+                .checked_sub(back)
+                .ok_or_else(|| corrupt("LZF back reference before the start"))?;
+            // The reference may overlap the bytes being produced (run-length style), so copy byte by byte.
+            for k in 0..len + 2 {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    if out.len() != expected_len {
+        return Err(corrupt("LZF data has the wrong length"));
+    }
+    Ok(out)
+}
+
+// ziplist (lists, hashes and zsets before redis 7):
+//   <zlbytes: u32> <zltail: u32> <zllen: u16> <entry>* 0xFF
+//   entry: <prevlen: 1 byte, or 0xFE + u32> <encoding> <data>
+pub fn ziplist_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>, PersistenceError> {
+    let mut r = Cursor::new(blob, "ziplist");
+    r.skip(4 + 4 + 2)?;
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = r.u8()?;
+        if prevlen == 0xFF {
+            break;
+        }
+        if prevlen == 0xFE {
+            r.skip(4)?;
+        }
+        let enc = r.u8()?;
+        let entry = match enc >> 6 {
+            0b00 => r.take((enc & 0x3F) as usize)?.to_vec(),
+            0b01 => {
+                let len = (((enc & 0x3F) as usize) << 8) | r.u8()? as usize;
+                r.take(len)?.to_vec()
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(r.array()?) as usize;
+                r.take(len)?.to_vec()
+            }
+            _ => {
+                let value: i64 = match enc {
+                    0xC0 => i16::from_le_bytes(r.array()?) as i64,
+                    0xD0 => i32::from_le_bytes(r.array()?) as i64,
+                    0xE0 => i64::from_le_bytes(r.array()?),
+                    0xF0 => {
+                        let b: [u8; 3] = r.array()?;
+                        (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64
+                    }
+                    0xFE => r.u8()? as i8 as i64,
+                    0xF1..=0xFD => (enc & 0x0F) as i64 - 1,
+                    _ => return Err(corrupt("ziplist entry has an unknown encoding")),
+                };
+                value.to_string().into_bytes()
+            }
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// listpack (lists, hashes, zsets and sets since redis 7):
+//   <total bytes: u32> <count: u16> <entry>* 0xFF
+//   entry: <encoding> <data> <backlen: 1..5 bytes, the size of encoding + data>
+pub fn listpack_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>, PersistenceError> {
+    let mut r = Cursor::new(blob, "listpack");
+    r.skip(4 + 2)?;
+    let mut entries = Vec::new();
+    loop {
+        let start = r.pos;
+        let enc = r.u8()?;
+        if enc == 0xFF {
+            break;
+        }
+        let entry = if enc & 0x80 == 0 {
+            // 0xxxxxxx: 7 bit unsigned integer
+            int_entry(enc as i64)
+        } else if enc & 0xC0 == 0x80 {
+            // 10xxxxxx: string up to 63 bytes
+            r.take((enc & 0x3F) as usize)?.to_vec()
+        } else if enc & 0xE0 == 0xC0 {
+            // 110xxxxx yyyyyyyy: 13 bit signed integer
+            let raw = (((enc & 0x1F) as i64) << 8) | r.u8()? as i64;
+            int_entry(if raw >= 1 << 12 { raw - (1 << 13) } else { raw })
+        } else if enc & 0xF0 == 0xE0 {
+            // 1110xxxx yyyyyyyy: string up to 4095 bytes
+            let len = (((enc & 0x0F) as usize) << 8) | r.u8()? as usize;
+            r.take(len)?.to_vec()
+        } else {
+            match enc {
+                0xF0 => {
+                    let len = u32::from_le_bytes(r.array()?) as usize;
+                    r.take(len)?.to_vec()
+                }
+                0xF1 => int_entry(i16::from_le_bytes(r.array()?) as i64),
+                0xF2 => {
+                    let b: [u8; 3] = r.array()?;
+                    int_entry((i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64)
+                }
+                0xF3 => int_entry(i32::from_le_bytes(r.array()?) as i64),
+                0xF4 => int_entry(i64::from_le_bytes(r.array()?)),
+                _ => return Err(corrupt("listpack entry has an unknown encoding")),
+            }
+        };
+        r.skip(backlen_size(r.pos - start))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn int_entry(value: i64) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+// The backlen of a listpack entry stores its size 7 bits per byte.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+// intset (sets of integers): <encoding: u32 = 2, 4 or 8> <count: u32> <count integers of that size>
+pub fn intset_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>, PersistenceError> {
+    let mut r = Cursor::new(blob, "intset");
+    let width = u32::from_le_bytes(r.array()?);
+    let count = u32::from_le_bytes(r.array()?);
+    (0..count)
+        .map(|_| {
+            let value = match width {
+                2 => i16::from_le_bytes(r.array()?) as i64,
+                4 => i32::from_le_bytes(r.array()?) as i64,
+                8 => i64::from_le_bytes(r.array()?),
+                _ => return Err(corrupt("intset has an invalid encoding")),
+            };
+            Ok(int_entry(value))
+        })
+        .collect()
+}
+
+// zipmap (hashes in RDB files written before redis 2.6):
+//   <count: u8> (<len> <field> <len> <free: u8> <value> <free bytes>)* 0xFF
+//   len: one byte below 254, or 254 followed by a u32.
+pub fn zipmap_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>, PersistenceError> {
+    let mut r = Cursor::new(blob, "zipmap");
+    r.skip(1)?;
+    let mut entries = Vec::new();
+    while let Some(len) = zipmap_len(&mut r)? {
+        entries.push(r.take(len)?.to_vec());
+        let len = zipmap_len(&mut r)?.ok_or_else(|| corrupt("zipmap field without a value"))?;
+        let free = r.u8()? as usize;
+        entries.push(r.take(len)?.to_vec());
+        r.skip(free)?;
+    }
+    Ok(entries)
+}
+
+fn zipmap_len(r: &mut Cursor) -> Result<Option<usize>, PersistenceError> {
+    match r.u8()? {
+        0xFF => Ok(None),
+        0xFE => Ok(Some(u32::from_le_bytes(r.array()?) as usize)),
+        len => Ok(Some(len as usize)),
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    what: &'static str,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8], what: &'static str) -> Self {
+        Self { data, pos: 0, what }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PersistenceError> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| corrupt(&format!("{} is truncated", self.what)))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), PersistenceError> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, PersistenceError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], PersistenceError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(entries: Vec<Vec<u8>>) -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|e| String::from_utf8(e).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // "aaaaaaaaaa": one literal 'a', then a back reference of 9 bytes, 1 byte back.
+        let compressed = [0x00, b'a', 0xE0, 0x00, 0x00];
+        assert_eq!(lzf_decompress(&compressed, 10).unwrap(), b"aaaaaaaaaa");
+        assert!(lzf_decompress(&compressed, 11).is_err());
+        assert!(lzf_decompress(&[0x20, 0x05], 3).is_err());
+        // A length no input of this size can reach is refused before anything is allocated.
+        assert!(lzf_decompress(&compressed, 1 << 40).is_err());
+    }
+
+    #[test]
+    fn test_ziplist_and_listpack_entries() {
+        // ziplist: "ab", 12 (4 bit immediate), -2 (int8)
+        let ziplist = [
+            0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0x00, 0x02, b'a', b'b', 0x04, 0xFD, 0x02, 0xFE, 0xFE,
+            0xFF,
+        ];
+        assert_eq!(
+            strings(ziplist_entries(&ziplist).unwrap()),
+            ["ab", "12", "-2"]
+        );
+
+        // listpack: "ab", 5 (7 bit uint), -1 (13 bit int)
+        let listpack = [
+            0, 0, 0, 0, 3, 0, 0x82, b'a', b'b', 0x03, 0x05, 0x01, 0xDF, 0xFF, 0x02, 0xFF,
+        ];
+        assert_eq!(
+            strings(listpack_entries(&listpack).unwrap()),
+            ["ab", "5", "-1"]
+        );
+        assert!(listpack_entries(&listpack[..8]).is_err());
+    }
+
+    #[test]
+    fn test_intset_and_zipmap_entries() {
+        let intset = [2, 0, 0, 0, 2, 0, 0, 0, 0xFF, 0xFF, 0x01, 0x00];
+        assert_eq!(strings(intset_entries(&intset).unwrap()), ["-1", "1"]);
+
+        let zipmap = [1, 1, b'f', 2, 1, b'v', b'1', 0, 0xFF];
+        assert_eq!(strings(zipmap_entries(&zipmap).unwrap()), ["f", "v1"]);
+    }
+}
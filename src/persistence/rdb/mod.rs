@@ -0,0 +1,960 @@
+// Reading and writing the redis RDB format, so data can move between stock redis and this server.
+//
+// An RDB file is decoded into a Snapshot (see snapshot.rs) and restored like our own snapshots; a Snapshot is
+// encoded back into an RDB file that redis-server (6.0 and later) loads. Types this server has no dedicated
+// storage for are kept as RespFrames in Backend.map:
+//   list -> Array of bulk strings, set -> Set of bulk strings, sorted set -> Map of member -> Double score.
+//
+//   "REDIS" <4 digit version>
+//   0xFA <aux field> <aux value>                              metadata (redis-ver, ctime, ...)
+//   0xFE <db number>  0xFB <db size> <expires size>           start of a database
+//   0xF5 <library code>                                       a function library (redis 7)
+//   [0xFC <expire at: u64 ms> | 0xFD <expire at: u32 s>] [0xF8 <idle> | 0xF9 <freq>] <type> <key> <value>
+//   0xFF <crc64: u64>
+//
+// Lengths use redis' variable length encoding (6, 14, 32 or 64 bits); strings are a length and the bytes,
+// or an integer, or LZF-compressed. All multi-byte integers are little endian, except 32/64 bit lengths.
+
+mod encodings;
+
+use super::{
+    snapshot::{Entry, Snapshot, Value, CRC64},
+    PersistenceError,
+};
+use crate::{
+    backend::unix_time_ms, module, BulkString, RespDecode, RespEncode, RespFrame, RespMap, RespSet,
+};
+use bytes::BytesMut;
+use encodings::{
+    intset_entries, listpack_entries, lzf_decompress, ziplist_entries, zipmap_entries,
+};
+use tracing::warn;
+
+const MAGIC: &[u8] = b"REDIS";
+// The newest format version this parser understands (redis 7.4), and the version the writer produces.
+// Version 9 (redis 5/6) already has every type the writer uses, so older servers can load our files too.
+const MAX_VERSION: u32 = 12;
+const WRITE_VERSION: u32 = 9;
+
+const OP_SLOT_INFO: u8 = 0xF4;
+const OP_FUNCTION2: u8 = 0xF5;
+const OP_FUNCTION_PRE_GA: u8 = 0xF6;
+const OP_MODULE_AUX: u8 = 0xF7;
+const OP_IDLE: u8 = 0xF8;
+const OP_FREQ: u8 = 0xF9;
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_EXPIRETIME: u8 = 0xFD;
+const OP_SELECTDB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+// Not an RDB type, only used in DUMP payloads (see dump).
+const TYPE_RESP: u8 = 0xF0;
+// A value of a module type in a DUMP payload (our own as well): <type name> <data>.
+const TYPE_MODULE: u8 = 0xF1;
+// A hash with values that are not strings, in a DUMP payload: <count> (<field> <RESP encoding of the value>)*.
+const TYPE_RESP_HASH: u8 = 0xF2;
+
+// quicklist 2 node containers
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+// Special string encodings (a length byte starting with 11)
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+#[derive(Debug, Default)]
+pub struct Rdb {
+    pub version: u32,
+    // Aux fields in file order, e.g. ("redis-ver", "7.2.4").
+    pub aux: Vec<(String, String)>,
+    pub snapshot: Snapshot,
+    // Keys of databases other than 0, which this server does not have; they are dropped.
+    pub skipped: usize,
+}
+
+pub fn is_rdb(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn decode(data: &[u8]) -> Result<Rdb, PersistenceError> {
+    let mut r = Reader { data, pos: 0 };
+    if r.take(MAGIC.len())? != MAGIC {
+        return Err(corrupt("not an RDB file"));
+    }
+    let version = std::str::from_utf8(r.take(4)?)
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or_else(|| corrupt("invalid version"))?;
+    if version > MAX_VERSION {
+        return Err(unsupported(&format!("RDB version {}", version)));
+    }
+
+    let mut rdb = Rdb {
+        version,
+        ..Default::default()
+    };
+    let mut db = 0;
+    let mut expire_at = None;
+    loop {
+        let op = r.u8()?;
+        match op {
+            OP_EOF => break,
+            OP_AUX => {
+                let field = String::from_utf8_lossy(&r.string()?).into_owned();
+                let value = String::from_utf8_lossy(&r.string()?).into_owned();
+                rdb.aux.push((field, value));
+            }
+            OP_SELECTDB => db = r.len()?,
+            OP_RESIZEDB => {
+                r.len()?;
+                r.len()?;
+            }
+            OP_SLOT_INFO => {
+                r.len()?;
+                r.len()?;
+                r.len()?;
+            }
+            OP_EXPIRETIME_MS => expire_at = Some(u64::from_le_bytes(r.array()?)),
+            OP_EXPIRETIME => expire_at = Some(u32::from_le_bytes(r.array()?) as u64 * 1000),
+            // LRU / LFU information, which does not survive the import.
+            OP_IDLE => {
+                r.len()?;
+            }
+            OP_FREQ => {
+                r.u8()?;
+            }
+            OP_FUNCTION2 => {
+                let code = String::from_utf8(r.string()?)
+                    .map_err(|_| corrupt("function library is not valid utf-8"))?;
+                rdb.snapshot.functions.push(code);
+            }
+            OP_FUNCTION_PRE_GA | OP_MODULE_AUX => {
+                return Err(unsupported(&format!("RDB opcode {:#04x}", op)))
+            }
+            value_type => {
+                let key = r.string()?;
+                let value = r.value(value_type)?;
+                let expire_at = expire_at.take();
+                if db != 0 {
+                    rdb.skipped += 1;
+                    continue;
+                }
+                let key = String::from_utf8(key).map_err(|_| unsupported("non UTF-8 key"))?;
+                rdb.snapshot.entries.push(Entry {
+                    key,
+                    value,
+                    expire_at,
+                });
+            }
+        }
+    }
+
+    // Since version 5 the file ends with a CRC64 of everything before it; 0 means it was written with rdbchecksum no.
+    if version >= 5 {
+        let body_len = r.pos;
+        let checksum = u64::from_le_bytes(r.array()?);
+        if checksum != 0 && CRC64.checksum(&data[..body_len]) != checksum {
+            return Err(corrupt("checksum mismatch"));
+        }
+    }
+    if rdb.skipped > 0 {
+        warn!(
+            "RDB: dropped {} key(s) of databases other than 0",
+            rdb.skipped
+        );
+    }
+    Ok(rdb)
+}
+
+// Encodes a snapshot as an RDB file. A value that has no RDB type (integers are written as strings,
+// but e.g. a Null or an Array holding something else than bulk strings) fails the whole save:
+// leaving it out would lose it at the next restart.
+pub fn encode(snapshot: &Snapshot) -> Result<Vec<u8>, PersistenceError> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(format!("{:04}", WRITE_VERSION).as_bytes());
+    for (field, value) in [
+        ("redis-ver", "7.0.0".to_string()),
+        ("redis-bits", (usize::BITS).to_string()),
+        ("ctime", (unix_time_ms() / 1000).to_string()),
+    ] {
+        buf.push(OP_AUX);
+        put_string(&mut buf, field.as_bytes());
+        put_string(&mut buf, value.as_bytes());
+    }
+    // Only redis 7 knows this opcode, so a file with functions does not load in older versions.
+    for code in &snapshot.functions {
+        buf.push(OP_FUNCTION2);
+        put_string(&mut buf, code.as_bytes());
+    }
+
+    buf.push(OP_SELECTDB);
+    put_len(&mut buf, 0);
+    let expires = snapshot
+        .entries
+        .iter()
+        .filter(|e| e.expire_at.is_some())
+        .count();
+    buf.push(OP_RESIZEDB);
+    put_len(&mut buf, snapshot.entries.len() as u64);
+    put_len(&mut buf, expires as u64);
+
+    let mut keys = std::collections::HashSet::new();
+    for entry in &snapshot.entries {
+        // redis-server refuses to load a file with a key in it twice ("Duplicated key found in RDB file").
+        if !keys.insert(entry.key.as_str()) {
+            return Err(corrupt(&format!("would hold key '{}' twice", entry.key)));
+        }
+        let mut value = Vec::new();
+        // Redis stores module values through the module itself, which stock redis-server does not have.
+        if let Value::Module { type_name, .. } = &entry.value {
+            return Err(unsupported(&format!(
+                "key '{}' holds a value of module type '{}', which RDB files cannot hold (use snapshot-format native)",
+                entry.key, type_name
+            )));
+        }
+        let Some(value_type) = encode_value(&mut value, &entry.value) else {
+            return Err(unsupported(&format!(
+                "key '{}' holds a value without an RDB type (use snapshot-format native)",
+                entry.key
+            )));
+        };
+        if let Some(at) = entry.expire_at {
+            buf.push(OP_EXPIRETIME_MS);
+            buf.extend_from_slice(&at.to_le_bytes());
+        }
+        buf.push(value_type);
+        put_string(&mut buf, entry.key.as_bytes());
+        buf.extend_from_slice(&value);
+    }
+
+    buf.push(OP_EOF);
+    let checksum = CRC64.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    Ok(buf)
+}
+
+// The DUMP payload of a value: the value as it is written to an RDB file (type byte and encoding),
+// then the RDB version (u16) and a CRC64 of everything before it. This is the layout redis uses,
+// so payloads move between this server and redis-server in both directions.
+// Values redis has no type for (e.g. a RESP3 double stored with SET, or a hash holding one) are kept as their
+// RESP encoding under a type byte of our own, and values of module types as their type name and data under another;
+// only this server can RESTORE those.
+pub fn dump(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match (encode_value(&mut buf, value), value) {
+        (Some(value_type), _) => buf.insert(0, value_type),
+        (None, Value::String(frame)) => {
+            buf.clear();
+            buf.push(TYPE_RESP);
+            put_string(&mut buf, &frame.clone().encode());
+        }
+        (None, Value::Module { type_name, data }) => {
+            buf.push(TYPE_MODULE);
+            put_string(&mut buf, type_name.as_bytes());
+            put_string(&mut buf, data);
+        }
+        (None, Value::Hash(fields)) => {
+            buf.clear();
+            buf.push(TYPE_RESP_HASH);
+            put_len(&mut buf, fields.len() as u64);
+            for (field, value) in fields {
+                put_string(&mut buf, field.as_bytes());
+                put_string(&mut buf, &value.clone().encode());
+            }
+        }
+    }
+    buf.extend_from_slice(&(WRITE_VERSION as u16).to_le_bytes());
+    let checksum = CRC64.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+// Whether the footer of a DUMP payload is intact and names an RDB version this parser understands.
+pub fn is_valid_dump(payload: &[u8]) -> bool {
+    let Some(body_len) = payload.len().checked_sub(10) else {
+        return false;
+    };
+    let version = u16::from_le_bytes([payload[body_len], payload[body_len + 1]]);
+    let checksum = u64::from_le_bytes(payload[body_len + 2..].try_into().unwrap());
+    version as u32 <= MAX_VERSION && CRC64.checksum(&payload[..body_len + 2]) == checksum
+}
+
+// Decodes a DUMP payload whose footer has been checked with is_valid_dump.
+pub fn parse_dump(payload: &[u8]) -> Result<Value, PersistenceError> {
+    let body = &payload[..payload.len().saturating_sub(10)];
+    let mut r = Reader { data: body, pos: 0 };
+    let value = match r.u8()? {
+        TYPE_RESP => {
+            let mut buf = BytesMut::from(&r.string()?[..]);
+            let frame = RespFrame::decode(&mut buf).map_err(|e| corrupt(&e.to_string()))?;
+            if !buf.is_empty() {
+                return Err(corrupt("trailing bytes after a value"));
+            }
+            Value::String(frame)
+        }
+        TYPE_RESP_HASH => {
+            let mut fields = Vec::new();
+            for _ in 0..r.count()? {
+                let field = String::from_utf8(r.string()?)
+                    .map_err(|_| corrupt("hash field is not valid utf-8"))?;
+                let mut buf = BytesMut::from(&r.string()?[..]);
+                let value = RespFrame::decode(&mut buf).map_err(|e| corrupt(&e.to_string()))?;
+                if !buf.is_empty() {
+                    return Err(corrupt("trailing bytes after a value"));
+                }
+                fields.push((field, value));
+            }
+            Value::Hash(fields)
+        }
+        TYPE_MODULE => {
+            let type_name = String::from_utf8(r.string()?)
+                .map_err(|_| corrupt("module type name is not valid utf-8"))?;
+            let data = r.string()?;
+            if module::decode_value(&type_name, &data).is_none() {
+                return Err(unsupported(&format!("module type '{}'", type_name)));
+            }
+            Value::Module { type_name, data }
+        }
+        value_type => r.value(value_type)?,
+    };
+    if r.pos != body.len() {
+        return Err(corrupt("trailing bytes after a value"));
+    }
+    Ok(value)
+}
+
+// The FUNCTION DUMP payload: every library as 0xF5 <code>, with the footer of a DUMP payload (see dump).
+// This is what redis sends as well, so libraries move between this server and redis-server 7.
+pub fn dump_functions(codes: &[String]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for code in codes {
+        buf.push(OP_FUNCTION2);
+        put_string(&mut buf, code.as_bytes());
+    }
+    buf.extend_from_slice(&(WRITE_VERSION as u16).to_le_bytes());
+    let checksum = CRC64.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+// Decodes a FUNCTION DUMP payload whose footer has been checked with is_valid_dump into the code of its libraries.
+pub fn parse_functions(payload: &[u8]) -> Result<Vec<String>, PersistenceError> {
+    let body = &payload[..payload.len().saturating_sub(10)];
+    let mut r = Reader { data: body, pos: 0 };
+    let mut codes = Vec::new();
+    while r.pos < body.len() {
+        if r.u8()? != OP_FUNCTION2 {
+            return Err(corrupt("not a function library"));
+        }
+        let code = String::from_utf8(r.string()?)
+            .map_err(|_| corrupt("function library is not valid utf-8"))?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &Value) -> Option<u8> {
+    match value {
+        Value::Hash(fields) => {
+            put_len(buf, fields.len() as u64);
+            for (field, value) in fields {
+                put_string(buf, field.as_bytes());
+                put_string(buf, &string_bytes(value)?);
+            }
+            Some(TYPE_HASH)
+        }
+        Value::String(RespFrame::Array(items)) => {
+            let items = items.iter().map(string_bytes).collect::<Option<Vec<_>>>()?;
+            put_len(buf, items.len() as u64);
+            items.iter().for_each(|item| put_string(buf, item));
+            Some(TYPE_LIST)
+        }
+        Value::String(RespFrame::Set(members)) => {
+            let members = members
+                .iter()
+                .map(string_bytes)
+                .collect::<Option<Vec<_>>>()?;
+            put_len(buf, members.len() as u64);
+            members.iter().for_each(|member| put_string(buf, member));
+            Some(TYPE_SET)
+        }
+        Value::String(RespFrame::Map(members)) => {
+            let scores = members
+                .iter()
+                .map(|(member, score)| match score {
+                    RespFrame::Double(score) => Some((member, *score)),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            put_len(buf, scores.len() as u64);
+            for (member, score) in scores {
+                put_string(buf, member.as_bytes());
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+            Some(TYPE_ZSET_2)
+        }
+        Value::String(frame) => {
+            put_string(buf, &string_bytes(frame)?);
+            Some(TYPE_STRING)
+        }
+        Value::Module { .. } => None,
+    }
+}
+
+// The bytes of a value that redis stores as a string.
+fn string_bytes(frame: &RespFrame) -> Option<Vec<u8>> {
+    match frame {
+        RespFrame::BulkString(s) => Some(s.0.clone()),
+        RespFrame::SimpleString(s) => Some(s.0.as_bytes().to_vec()),
+        RespFrame::Integer(i) => Some(i.to_string().into_bytes()),
+        _ => None,
+    }
+}
+
+fn put_len(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.push(0x40 | (len >> 8) as u8);
+        buf.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_len(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn corrupt(reason: &str) -> PersistenceError {
+    PersistenceError::Corrupt(format!("RDB {}", reason))
+}
+
+fn unsupported(what: &str) -> PersistenceError {
+    PersistenceError::Unsupported(what.to_string())
+}
+
+fn bulk(bytes: Vec<u8>) -> RespFrame {
+    BulkString::new(bytes).into()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PersistenceError> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| corrupt("unexpected end of file"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PersistenceError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], PersistenceError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    // Returns the length, or Err(special encoding) for a string stored as an integer / LZF.
+    fn raw_len(&mut self) -> Result<Result<u64, u8>, PersistenceError> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0b00 => Ok((first & 0x3F) as u64),
+            0b01 => Ok((((first & 0x3F) as u64) << 8) | self.u8()? as u64),
+            0b10 if first == 0x80 => Ok(u32::from_be_bytes(self.array()?) as u64),
+            0b10 if first == 0x81 => Ok(u64::from_be_bytes(self.array()?)),
+            0b10 => return Err(corrupt("invalid length encoding")),
+            _ => Err(first & 0x3F),
+        })
+    }
+
+    fn len(&mut self) -> Result<u64, PersistenceError> {
+        self.raw_len()?
+            .map_err(|_| corrupt("unexpected string encoding for a length"))
+    }
+
+    fn count(&mut self) -> Result<usize, PersistenceError> {
+        let len = self.len()?;
+        // A count can never be larger than the rest of the file, which also keeps a corrupt count from allocating.
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(corrupt("length larger than the file"));
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, PersistenceError> {
+        match self.raw_len()? {
+            Ok(len) => {
+                let len = usize::try_from(len).map_err(|_| corrupt("string too long"))?;
+                Ok(self.take(len)?.to_vec())
+            }
+            Err(ENC_INT8) => Ok((self.u8()? as i8).to_string().into_bytes()),
+            Err(ENC_INT16) => Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Err(ENC_INT32) => Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Err(ENC_LZF) => {
+                let compressed_len = self.count()?;
+                let len = self.len()? as usize;
+                lzf_decompress(self.take(compressed_len)?, len)
+            }
+            Err(_) => Err(corrupt("unknown string encoding")),
+        }
+    }
+
+    fn strings(&mut self) -> Result<Vec<Vec<u8>>, PersistenceError> {
+        let count = self.count()?;
+        (0..count).map(|_| self.string()).collect()
+    }
+
+    fn value(&mut self, value_type: u8) -> Result<Value, PersistenceError> {
+        Ok(match value_type {
+            TYPE_STRING => Value::String(bulk(self.string()?)),
+            TYPE_LIST => list(self.strings()?),
+            TYPE_LIST_ZIPLIST => list(ziplist_entries(&self.string()?)?),
+            TYPE_LIST_QUICKLIST => {
+                let mut items = Vec::new();
+                for _ in 0..self.count()? {
+                    items.extend(ziplist_entries(&self.string()?)?);
+                }
+                list(items)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut items = Vec::new();
+                for _ in 0..self.count()? {
+                    match self.len()? {
+                        QUICKLIST_NODE_PLAIN => items.push(self.string()?),
+                        QUICKLIST_NODE_PACKED => items.extend(listpack_entries(&self.string()?)?),
+                        _ => return Err(corrupt("unknown quicklist node container")),
+                    }
+                }
+                list(items)
+            }
+            TYPE_SET => set(self.strings()?),
+            TYPE_SET_INTSET => set(intset_entries(&self.string()?)?),
+            TYPE_SET_LISTPACK => set(listpack_entries(&self.string()?)?),
+            TYPE_HASH => {
+                // field, value, field, value ...; the length counts the pairs.
+                let count = self.count()?;
+                let entries = (0..count * 2)
+                    .map(|_| self.string())
+                    .collect::<Result<Vec<_>, _>>()?;
+                hash(entries)?
+            }
+            TYPE_HASH_ZIPMAP => hash(zipmap_entries(&self.string()?)?)?,
+            TYPE_HASH_ZIPLIST => hash(ziplist_entries(&self.string()?)?)?,
+            TYPE_HASH_LISTPACK => hash(listpack_entries(&self.string()?)?)?,
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let count = self.count()?;
+                let mut pairs = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let member = self.string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_le_bytes(self.array()?)
+                    } else {
+                        self.string_double()?
+                    };
+                    pairs.push((member, score));
+                }
+                zset(pairs)?
+            }
+            TYPE_ZSET_ZIPLIST => zset(score_pairs(ziplist_entries(&self.string()?)?)?)?,
+            TYPE_ZSET_LISTPACK => zset(score_pairs(listpack_entries(&self.string()?)?)?)?,
+            other => return Err(unsupported(&format!("RDB value type {}", other))),
+        })
+    }
+
+    // The score format of the old ZSET type: a length byte and the score as text,
+    // with 253 / 254 / 255 standing for NaN / +inf / -inf.
+    fn string_double(&mut self) -> Result<f64, PersistenceError> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(self.take(len as usize)?),
+        }
+    }
+}
+
+fn list(items: Vec<Vec<u8>>) -> Value {
+    Value::String(RespFrame::Array(crate::RespArray::new(
+        items.into_iter().map(bulk).collect::<Vec<_>>(),
+    )))
+}
+
+fn set(members: Vec<Vec<u8>>) -> Value {
+    Value::String(RespFrame::Set(RespSet::new(
+        members.into_iter().map(bulk).collect::<Vec<_>>(),
+    )))
+}
+
+fn hash(entries: Vec<Vec<u8>>) -> Result<Value, PersistenceError> {
+    let mut fields = Vec::with_capacity(entries.len() / 2);
+    let mut entries = entries.into_iter();
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        let field = String::from_utf8(field).map_err(|_| unsupported("non UTF-8 hash field"))?;
+        fields.push((field, bulk(value)));
+    }
+    Ok(Value::Hash(fields))
+}
+
+fn zset(pairs: Vec<(Vec<u8>, f64)>) -> Result<Value, PersistenceError> {
+    let mut map = RespMap::new();
+    for (member, score) in pairs {
+        let member =
+            String::from_utf8(member).map_err(|_| unsupported("non UTF-8 sorted set member"))?;
+        map.0.insert(member, RespFrame::Double(score));
+    }
+    Ok(Value::String(RespFrame::Map(map)))
+}
+
+// ziplist / listpack encoded sorted sets alternate member and score.
+fn score_pairs(entries: Vec<Vec<u8>>) -> Result<Vec<(Vec<u8>, f64)>, PersistenceError> {
+    let mut pairs = Vec::with_capacity(entries.len() / 2);
+    let mut entries = entries.into_iter();
+    while let (Some(member), Some(score)) = (entries.next(), entries.next()) {
+        pairs.push((member, parse_score(&score)?));
+    }
+    Ok(pairs)
+}
+
+fn parse_score(bytes: &[u8]) -> Result<f64, PersistenceError> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| match s {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        })
+        .ok_or_else(|| corrupt("invalid sorted set score"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Backend;
+    use std::path::PathBuf;
+
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/rdb")
+            .join(name)
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = fixture_path(name);
+        std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    }
+
+    // The dumps of real servers, captured by fixtures/rdb/capture.sh.
+    fn server_dumps() -> Vec<(&'static str, Vec<u8>)> {
+        [("6.2", "redis-6.2.rdb"), ("7.2", "redis-7.2.rdb")]
+            .into_iter()
+            .map(|(version, name)| (version, fixture(name)))
+            .collect()
+    }
+
+    // Runs redis-check-rdb (or $REDIS_CHECK_RDB) on data.
+    fn check_rdb(data: &[u8], name: &str) -> std::process::Output {
+        let path =
+            std::env::temp_dir().join(format!("simple-redis-{}-{}.rdb", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let program =
+            std::env::var("REDIS_CHECK_RDB").unwrap_or_else(|_| "redis-check-rdb".to_string());
+        let output = std::process::Command::new(&program).arg(&path).output();
+        std::fs::remove_file(&path).unwrap();
+        output.unwrap_or_else(|e| panic!("{}: {}", program, e))
+    }
+
+    fn bulk_str(s: &str) -> RespFrame {
+        BulkString::from(s).into()
+    }
+
+    #[test]
+    fn test_decode_plain_types() -> anyhow::Result<()> {
+        let rdb = decode(&fixture("plain-types.rdb"))?;
+        assert_eq!(rdb.version, 9);
+        assert!(rdb
+            .aux
+            .contains(&("redis-ver".to_string(), "6.2.14".to_string())));
+
+        let backend = Backend::new();
+        rdb.snapshot.restore(&backend);
+        assert_eq!(backend.get("string"), Some(bulk_str("hello world")));
+        assert_eq!(backend.get("int8"), Some(bulk_str("-7")));
+        assert_eq!(backend.get("int32"), Some(bulk_str("1000000")));
+        assert_eq!(backend.get("lzf"), Some(bulk_str(&"abcdefgh".repeat(16))));
+        assert_eq!(
+            backend.get("list"),
+            Some(crate::RespArray::new(vec![bulk_str("a"), bulk_str("b"), bulk_str("c")]).into())
+        );
+        assert_eq!(
+            backend.get("set"),
+            Some(RespSet::new(vec![bulk_str("x"), bulk_str("y")]).into())
+        );
+        assert_eq!(backend.hget("hash", "field"), Some(bulk_str("value")));
+        let Some(RespFrame::Map(zset)) = backend.get("zset") else {
+            panic!("zset is not a map");
+        };
+        assert_eq!(zset.0.get("one"), Some(&RespFrame::Double(1.0)));
+        assert_eq!(zset.0.get("inf"), Some(&RespFrame::Double(f64::INFINITY)));
+        let Some(RespFrame::Map(zset)) = backend.get("zset2") else {
+            panic!("zset2 is not a map");
+        };
+        assert_eq!(zset.0.get("pi"), Some(&RespFrame::Double(3.25)));
+        assert_eq!(backend.expire_time("expiring"), Some(4102444800000));
+        assert_eq!(backend.expire_time("expiring-seconds"), Some(4102444800000));
+        // The key of db 1 is dropped.
+        assert_eq!(backend.get("other-db"), None);
+        assert_eq!(rdb.skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_compact_encodings() -> anyhow::Result<()> {
+        // ziplist, intset and zipmap encodings (redis 6) and listpack encodings (redis 7).
+        for name in ["ziplist-encodings.rdb", "listpack-encodings.rdb"] {
+            let backend = Backend::new();
+            decode(&fixture(name))?.snapshot.restore(&backend);
+
+            assert_eq!(
+                backend.get("list"),
+                Some(
+                    crate::RespArray::new(vec![bulk_str("a"), bulk_str("12"), bulk_str("-300")])
+                        .into()
+                ),
+                "{}",
+                name
+            );
+            assert_eq!(backend.hget("hash", "f1"), Some(bulk_str("v1")), "{}", name);
+            assert_eq!(
+                backend.hget("hash", "f2"),
+                Some(bulk_str("100")),
+                "{}",
+                name
+            );
+            let Some(RespFrame::Map(zset)) = backend.get("zset") else {
+                panic!("{}: zset is not a map", name);
+            };
+            assert_eq!(zset.0.get("a"), Some(&RespFrame::Double(1.5)), "{}", name);
+            assert_eq!(zset.0.get("b"), Some(&RespFrame::Double(2.0)), "{}", name);
+            let Some(RespFrame::Set(set)) = backend.get("set") else {
+                panic!("{}: set is not a set", name);
+            };
+            assert_eq!(set.len(), 3, "{}", name);
+        }
+
+        let backend = Backend::new();
+        decode(&fixture("ziplist-encodings.rdb"))?
+            .snapshot
+            .restore(&backend);
+        assert_eq!(backend.hget("zipmap", "f"), Some(bulk_str("v")));
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs fixtures/rdb/redis-6.2.rdb and redis-7.2.rdb, captured by fixtures/rdb/capture.sh"]
+    fn test_decode_server_dumps() -> anyhow::Result<()> {
+        for (version, data) in server_dumps() {
+            let rdb = decode(&data)?;
+            assert!(
+                rdb.aux
+                    .iter()
+                    .any(|(k, v)| k == "redis-ver" && v.starts_with(version)),
+                "{}",
+                version
+            );
+            let backend = Backend::new();
+            rdb.snapshot.restore(&backend);
+
+            assert_eq!(backend.get("string"), Some(bulk_str("hello world")));
+            assert_eq!(backend.get("int"), Some(bulk_str("1000000")));
+            assert_eq!(backend.get("lzf"), Some(bulk_str(&"abcdefgh".repeat(16))));
+            assert_eq!(
+                backend.get("list"),
+                Some(
+                    crate::RespArray::new(vec![bulk_str("a"), bulk_str("12"), bulk_str("-300")])
+                        .into()
+                ),
+                "{}",
+                version
+            );
+            for (key, len) in [("set", 3), ("intset", 3)] {
+                let Some(RespFrame::Set(set)) = backend.get(key) else {
+                    panic!("{}: {} is not a set", version, key);
+                };
+                assert_eq!(set.len(), len, "{}", version);
+            }
+            assert_eq!(backend.hget("hash", "f2"), Some(bulk_str("100")));
+            assert_eq!(backend.hget("bighash", "f200"), Some(bulk_str("v200")));
+            let Some(RespFrame::Map(zset)) = backend.get("zset") else {
+                panic!("{}: zset is not a map", version);
+            };
+            assert_eq!(zset.0.get("a"), Some(&RespFrame::Double(1.5)));
+            let Some(RespFrame::Map(zset)) = backend.get("bigzset") else {
+                panic!("{}: bigzset is not a map", version);
+            };
+            assert_eq!(zset.0.len(), 200, "{}", version);
+            assert_eq!(backend.expire_time("expiring"), Some(4102444800000));
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs redis-check-rdb on the PATH (or $REDIS_CHECK_RDB)"]
+    fn test_encode_passes_redis_check_rdb() -> anyhow::Result<()> {
+        for name in [
+            "plain-types.rdb",
+            "ziplist-encodings.rdb",
+            "listpack-encodings.rdb",
+        ] {
+            let encoded = encode(&decode(&fixture(name))?.snapshot)?;
+            let output = check_rdb(&encoded, name);
+            assert!(
+                output.status.success(),
+                "{}: {}",
+                name,
+                String::from_utf8_lossy(&output.stdout)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_rejects_corruption() {
+        let mut data = fixture("plain-types.rdb");
+        let n = data.len();
+        data[n - 20] ^= 0xFF;
+        assert!(decode(&data).is_err());
+        assert!(decode(&fixture("plain-types.rdb")[..40]).is_err());
+        assert!(decode(b"REDIS0099\xff").is_err());
+    }
+
+    #[test]
+    fn test_dump_payload() -> anyhow::Result<()> {
+        // DUMP of a string "hello" in redis 7.2: type 0, the string, version 11 and the CRC64.
+        let redis = b"\x00\x05hello\x0b\x00\x0a\xad\x62\x05\x98\xab\xc9\x83";
+        assert!(is_valid_dump(redis));
+        assert_eq!(parse_dump(redis)?, Value::String(bulk_str("hello")));
+
+        for value in [
+            Value::String(bulk_str("hello")),
+            Value::Hash(vec![("field".to_string(), bulk_str("value"))]),
+            Value::String(crate::RespArray::new(vec![bulk_str("a"), bulk_str("b")]).into()),
+            Value::String(RespFrame::Double(1.5)),
+            Value::Hash(vec![
+                ("f".to_string(), RespFrame::Double(1.5)),
+                ("g".to_string(), bulk_str("value")),
+            ]),
+        ] {
+            let payload = dump(&value);
+            assert!(is_valid_dump(&payload));
+            assert_eq!(parse_dump(&payload)?, value);
+        }
+
+        let mut payload = dump(&Value::String(bulk_str("hello")));
+        assert_eq!(&payload[..7], b"\x00\x05hello");
+        payload[2] = b'j';
+        assert!(!is_valid_dump(&payload));
+        assert!(!is_valid_dump(b"short"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_roundtrip() -> anyhow::Result<()> {
+        let snapshot = decode(&fixture("plain-types.rdb"))?.snapshot;
+        let encoded = encode(&snapshot)?;
+        assert!(encoded.starts_with(b"REDIS0009"));
+        let decoded = decode(&encoded)?;
+        assert_eq!(decoded.snapshot.entries.len(), snapshot.entries.len());
+        for (a, b) in decoded.snapshot.entries.iter().zip(&snapshot.entries) {
+            assert_eq!(a, b);
+        }
+
+        // A value without an RDB type fails the save instead of being lost.
+        for value in [
+            Value::String(RespFrame::Null(crate::RespNull)),
+            Value::Hash(vec![("f".to_string(), RespFrame::Double(1.5))]),
+        ] {
+            let snapshot = Snapshot {
+                entries: vec![Entry {
+                    key: "k".to_string(),
+                    value,
+                    expire_at: None,
+                }],
+                ..Default::default()
+            };
+            assert!(matches!(
+                encode(&snapshot),
+                Err(PersistenceError::Unsupported(_))
+            ));
+        }
+
+        // Neither is a key that is in the snapshot twice.
+        let entry = Entry {
+            key: "k".to_string(),
+            value: Value::String(bulk_str("v")),
+            expire_at: None,
+        };
+        let snapshot = Snapshot {
+            entries: vec![entry.clone(), entry],
+            ..Default::default()
+        };
+        assert!(matches!(
+            encode(&snapshot),
+            Err(PersistenceError::Corrupt(_))
+        ));
+
+        let snapshot = Snapshot {
+            functions: vec![
+                "#!lua name=lib\nredis.register_function('f', function() end)".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(decode(&encode(&snapshot)?)?.snapshot, snapshot);
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_dump_payload() -> anyhow::Result<()> {
+        let codes = vec!["#!lua name=a\n".to_string(), "#!lua name=b\n".to_string()];
+        let payload = dump_functions(&codes);
+        assert!(is_valid_dump(&payload));
+        assert_eq!(parse_functions(&payload)?, codes);
+        assert!(parse_functions(&dump_functions(&[]))?.is_empty());
+        // A DUMP payload of a key is not one of libraries.
+        assert!(parse_functions(&dump(&Value::String(bulk_str("hello")))).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,489 @@
+// The snapshot file format: a point-in-time copy of the whole dataset.
+//
+//   "SREDIS" <version: u8>
+//   one record per key:
+//     [0xFC <expire at, unix time in ms: u64>]       only for keys with a TTL
+//     0x00 <key> <value>                              a string (an entry of Backend.map)
+//     0x01 <key> <count: u32> (<field> <value>)*      a hash (an entry of Backend.hmap)
+//     0x02 <key> <type name> <data>                   a value of a module type (see module.rs)
+//   0xF5 <code>                                       a function library, one record each
+//   0xFF <crc64 of everything before it: u64>
+//
+// The version goes up whenever a record type is added, so an older build refuses a newer file as unsupported
+// rather than as corrupt; a newer build still reads every older version.
+//   1: strings and hashes
+//   2: function libraries (0xF5)
+//   3: values of module types (0x02)
+//
+// Integers are little endian; keys and fields are <len: u32> <bytes>.
+// Values are stored as their RESP encoding (length-prefixed as well), because map can hold any RespFrame, not only bulk strings.
+// The checksum uses the same CRC64 variant as redis (Jones polynomial, reflected).
+
+use super::PersistenceError;
+use crate::{
+    backend::{unix_time_ms, Hash},
+    module,
+    scripting::RestorePolicy,
+    Backend, RespDecode, RespEncode, RespFrame,
+};
+use bytes::BytesMut;
+use crc::{Crc, CRC_64_REDIS};
+use tracing::warn;
+
+const MAGIC: &[u8] = b"SREDIS";
+const VERSION: u8 = 3;
+
+const TYPE_STRING: u8 = 0x00;
+const TYPE_HASH: u8 = 0x01;
+const TYPE_MODULE: u8 = 0x02;
+const OP_FUNCTION: u8 = 0xF5;
+const OP_EXPIRE_MS: u8 = 0xFC;
+const OP_EOF: u8 = 0xFF;
+
+pub(crate) const CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(RespFrame),
+    Hash(Vec<(String, RespFrame)>),
+    // A value of a module type, as its type name and ModuleType::encode.
+    Module { type_name: String, data: Vec<u8> },
+}
+
+// A key that exists both in map and in hmap is stored as two entries with the same key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub expire_at: Option<u64>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub entries: Vec<Entry>,
+    // The code of every function library.
+    pub functions: Vec<String>,
+}
+
+impl Snapshot {
+    // Copies the current content of the backend, leaving out keys that have already expired.
+    // The caller is responsible for making this a consistent view (see persistence::save).
+    pub fn from_backend(backend: &Backend) -> Self {
+        let now = unix_time_ms();
+        let expire_at = |key: &str| backend.expires.get(key).map(|at| *at);
+        let alive = |at: Option<u64>| at.is_none_or(|at| at > now);
+
+        let mut entries = Vec::with_capacity(
+            backend.map.len() + backend.hmap.len() + backend.module_values.len(),
+        );
+        for item in backend.map.iter() {
+            let at = expire_at(item.key());
+            if alive(at) {
+                entries.push(Entry {
+                    key: item.key().clone(),
+                    value: Value::String(item.value().clone()),
+                    expire_at: at,
+                });
+            }
+        }
+        for item in backend.hmap.iter() {
+            let at = expire_at(item.key());
+            if alive(at) {
+                let fields = item
+                    .value()
+                    .iter()
+                    .map(|(field, value)| (field.clone(), value.clone()))
+                    .collect();
+                entries.push(Entry {
+                    key: item.key().clone(),
+                    value: Value::Hash(fields),
+                    expire_at: at,
+                });
+            }
+        }
+        for item in backend.module_values.iter() {
+            let at = expire_at(item.key());
+            if alive(at) {
+                entries.push(Entry {
+                    key: item.key().clone(),
+                    value: Value::Module {
+                        type_name: item.value().type_name().to_string(),
+                        data: item.value().encode(),
+                    },
+                    expire_at: at,
+                });
+            }
+        }
+        let functions = backend
+            .scripting
+            .libraries()
+            .into_iter()
+            .map(|library| library.code)
+            .collect();
+        Self { entries, functions }
+    }
+
+    // Inserts every entry into the backend and returns how many were loaded; the function libraries replace
+    // the ones the backend had. Keys whose TTL ran out while the server was down are skipped.
+    // This is a restore, not a write by a client, so it does not count as a change for the save points.
+    pub fn restore(self, backend: &Backend) -> usize {
+        // They loaded when the snapshot was taken; failing now (e.g. an RDB file of a redis with other libraries)
+        // leaves the functions out, not the data.
+        if let Err(e) = backend
+            .scripting
+            .restore_libraries(&self.functions, RestorePolicy::Flush)
+        {
+            warn!("Function libraries of the snapshot not loaded: {}", e);
+        }
+        let now = unix_time_ms();
+        let mut loaded = 0;
+        for entry in self.entries {
+            if entry.expire_at.is_some_and(|at| at <= now) {
+                continue;
+            }
+            if let Some(at) = entry.expire_at {
+                backend.expires.insert(entry.key.clone(), at);
+            }
+            let key = entry.key;
+            match entry.value {
+                Value::String(value) => {
+                    backend.map.insert(key.clone(), value);
+                }
+                Value::Hash(fields) => {
+                    let hash = Hash::from_fields(fields, &backend.config);
+                    backend.hmap.insert(key.clone(), hash);
+                }
+                // Checked when the snapshot was decoded.
+                Value::Module { type_name, data } => {
+                    if let Some(value) = module::decode_value(&type_name, &data) {
+                        backend.module_values.insert(key.clone(), value);
+                    }
+                }
+            }
+            backend.account(&key);
+            loaded += 1;
+        }
+        loaded
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+
+        for code in &self.functions {
+            buf.push(OP_FUNCTION);
+            put_bytes(&mut buf, code.as_bytes());
+        }
+        for entry in &self.entries {
+            if let Some(at) = entry.expire_at {
+                buf.push(OP_EXPIRE_MS);
+                buf.extend_from_slice(&at.to_le_bytes());
+            }
+            match &entry.value {
+                Value::String(value) => {
+                    buf.push(TYPE_STRING);
+                    put_bytes(&mut buf, entry.key.as_bytes());
+                    put_bytes(&mut buf, &value.clone().encode());
+                }
+                Value::Hash(fields) => {
+                    buf.push(TYPE_HASH);
+                    put_bytes(&mut buf, entry.key.as_bytes());
+                    buf.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+                    for (field, value) in fields {
+                        put_bytes(&mut buf, field.as_bytes());
+                        put_bytes(&mut buf, &value.clone().encode());
+                    }
+                }
+                Value::Module { type_name, data } => {
+                    buf.push(TYPE_MODULE);
+                    put_bytes(&mut buf, entry.key.as_bytes());
+                    put_bytes(&mut buf, type_name.as_bytes());
+                    put_bytes(&mut buf, data);
+                }
+            }
+        }
+
+        buf.push(OP_EOF);
+        let checksum = CRC64.checksum(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, PersistenceError> {
+        let (snapshot, len) = Self::decode_prefix(data)?;
+        if len != data.len() {
+            return Err(corrupt("trailing bytes after the checksum"));
+        }
+        Ok(snapshot)
+    }
+
+    // Whether data starts like a snapshot (an AOF rewritten by BGREWRITEAOF begins with one).
+    pub fn is_snapshot(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    // Decodes a snapshot at the start of data, which may continue with something else,
+    // and returns it together with the number of bytes it took (checksum included).
+    // Nothing is returned unless the checksum matches, so a damaged file is never half-loaded.
+    pub fn decode_prefix(data: &[u8]) -> Result<(Self, usize), PersistenceError> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(corrupt("not a snapshot file"));
+        }
+        let version = reader.u8()?;
+        if version == 0 {
+            return Err(corrupt("invalid version 0"));
+        }
+        if version > VERSION {
+            return Err(PersistenceError::Unsupported(format!(
+                "snapshot version {}",
+                version
+            )));
+        }
+
+        let mut entries = Vec::new();
+        let mut functions = Vec::new();
+        let mut expire_at = None;
+        loop {
+            match reader.u8()? {
+                OP_FUNCTION if expire_at.is_none() => functions.push(reader.code()?),
+                OP_EXPIRE_MS => expire_at = Some(reader.u64()?),
+                TYPE_STRING => {
+                    let key = reader.string()?;
+                    let value = Value::String(reader.frame()?);
+                    entries.push(Entry {
+                        key,
+                        value,
+                        expire_at: expire_at.take(),
+                    });
+                }
+                TYPE_HASH => {
+                    let key = reader.string()?;
+                    let count = reader.u32()? as usize;
+                    let mut fields = Vec::with_capacity(count.min(1024));
+                    for _ in 0..count {
+                        fields.push((reader.string()?, reader.frame()?));
+                    }
+                    entries.push(Entry {
+                        key,
+                        value: Value::Hash(fields),
+                        expire_at: expire_at.take(),
+                    });
+                }
+                // A value no registered module can load makes the whole snapshot unreadable, like in redis.
+                TYPE_MODULE => {
+                    let key = reader.string()?;
+                    let type_name = reader.string()?;
+                    let data = reader.bytes()?.to_vec();
+                    if module::decode_value(&type_name, &data).is_none() {
+                        return Err(corrupt(&format!(
+                            "value of module type '{}' cannot be loaded",
+                            type_name
+                        )));
+                    }
+                    entries.push(Entry {
+                        key,
+                        value: Value::Module { type_name, data },
+                        expire_at: expire_at.take(),
+                    });
+                }
+                OP_EOF if expire_at.is_none() => break,
+                OP_EOF => return Err(corrupt("unexpected end of file marker")),
+                other => return Err(corrupt(&format!("unknown record type {:#04x}", other))),
+            }
+        }
+
+        let body_len = reader.pos;
+        if CRC64.checksum(&data[..body_len]) != reader.u64()? {
+            return Err(corrupt("checksum mismatch"));
+        }
+        Ok((Self { entries, functions }, reader.pos))
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn corrupt(reason: &str) -> PersistenceError {
+    PersistenceError::Corrupt(reason.to_string())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PersistenceError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| corrupt("unexpected end of file"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PersistenceError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, PersistenceError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, PersistenceError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], PersistenceError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, PersistenceError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| corrupt("key is not valid utf-8"))
+    }
+
+    fn code(&mut self) -> Result<String, PersistenceError> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| corrupt("function library is not valid utf-8"))
+    }
+
+    fn frame(&mut self) -> Result<RespFrame, PersistenceError> {
+        let mut buf = BytesMut::from(self.bytes()?);
+        let frame = RespFrame::decode(&mut buf).map_err(|e| corrupt(&e.to_string()))?;
+        if !buf.is_empty() {
+            return Err(corrupt("trailing bytes after a value"));
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BulkString, RespArray, RespNull};
+
+    fn sample() -> Snapshot {
+        Snapshot {
+            entries: vec![
+                Entry {
+                    key: "hello".to_string(),
+                    value: Value::String(BulkString::from("world").into()),
+                    expire_at: None,
+                },
+                Entry {
+                    key: "session".to_string(),
+                    value: Value::String(
+                        RespArray::new(vec![1.into(), RespFrame::Null(RespNull)]).into(),
+                    ),
+                    expire_at: Some(u64::MAX),
+                },
+                Entry {
+                    key: "map".to_string(),
+                    value: Value::Hash(vec![
+                        ("a".to_string(), BulkString::from("1").into()),
+                        ("b".to_string(), BulkString::from("").into()),
+                    ]),
+                    expire_at: None,
+                },
+            ],
+            functions: vec![
+                "#!lua name=lib\nredis.register_function('f', function() end)".to_string(),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_snapshot_encode_decode() -> anyhow::Result<()> {
+        let snapshot = sample();
+        let data = snapshot.encode();
+        assert!(data.starts_with(b"SREDIS\x03"));
+        assert_eq!(Snapshot::decode(&data)?, snapshot);
+
+        assert_eq!(
+            Snapshot::decode(&Snapshot::default().encode())?,
+            Snapshot::default()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_decode_rejects_corruption() {
+        let data = sample().encode();
+
+        let mut flipped = data.clone();
+        flipped[10] ^= 0x01;
+        assert!(matches!(
+            Snapshot::decode(&flipped),
+            Err(PersistenceError::Corrupt(_))
+        ));
+        assert!(Snapshot::decode(&data[..data.len() - 1]).is_err());
+        assert!(Snapshot::decode(b"SREDIS").is_err());
+
+        // A file from a newer version is not corrupt, just unsupported.
+        let mut newer = data.clone();
+        newer[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(
+            Snapshot::decode(&newer),
+            Err(PersistenceError::Unsupported(_))
+        ));
+
+        // Followed by something else, the snapshot is still readable as a prefix, but not as a whole file.
+        let mut extended = data.clone();
+        extended.extend_from_slice(b"*1\r\n$4\r\nping\r\n");
+        assert!(Snapshot::decode(&extended).is_err());
+        let (_, len) = Snapshot::decode_prefix(&extended).unwrap();
+        assert_eq!(len, data.len());
+    }
+
+    #[test]
+    fn test_snapshot_from_backend_and_restore() {
+        let backend = Backend::new();
+        backend.set("hello".to_string(), BulkString::from("world").into());
+        backend.hset(
+            "map".to_string(),
+            "field".to_string(),
+            BulkString::from("value").into(),
+        );
+        backend.set("gone".to_string(), BulkString::from("soon").into());
+        backend.expire_at("gone", 1);
+        let ttl = unix_time_ms() + 60_000;
+        backend.expire_at("map", ttl);
+        backend
+            .scripting
+            .load_library(
+                "#!lua name=lib\nredis.register_function('f', function() end)",
+                false,
+            )
+            .unwrap();
+
+        let snapshot = Snapshot::from_backend(&backend);
+        // The expired key is not part of the snapshot.
+        assert_eq!(snapshot.entries.len(), 2);
+
+        let restored = Backend::new();
+        assert_eq!(
+            Snapshot::decode(&snapshot.encode())
+                .unwrap()
+                .restore(&restored),
+            2
+        );
+        assert_eq!(
+            restored.get("hello"),
+            Some(BulkString::from("world").into())
+        );
+        assert_eq!(
+            restored.hget("map", "field"),
+            Some(BulkString::from("value").into())
+        );
+        assert_eq!(restored.expire_time("map"), Some(ttl));
+        assert_eq!(restored.get("gone"), None);
+        assert_eq!(restored.scripting.libraries()[0].name, "lib");
+    }
+}
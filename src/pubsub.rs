@@ -0,0 +1,567 @@
+// Publish/subscribe, following redis.
+//
+// A connection that subscribes to a channel (or a pattern of channels) is registered with the Broker together
+// with the sending half of a queue; its connection task writes what comes out of the queue to the socket while
+// it keeps reading commands (see network::stream_handler). PUBLISH puts the message into the queue of every
+// subscriber of the channel and of every subscriber of a matching pattern, so it never waits for a client.
+// A subscriber that does not keep up is disconnected once MAX_PENDING messages are waiting for it, like redis
+// does when the pubsub client-output-buffer-limit is reached.
+//
+// Shard channels (SSUBSCRIBE / SPUBLISH) are a separate namespace: in cluster mode a shard channel belongs to
+// the hash slot of its name like a key does, and is only served by the node owning the slot (the commands are
+// redirected like key commands, see cluster::Cluster::check_frame), so messages are never fanned out to the
+// whole cluster. When the slot moves to another node its subscribers are unsubscribed (remove_slots), so they
+// subscribe again at the new owner.
+//
+// The server itself publishes keyspace notifications (__keyspace@0__:<key> / __keyevent@0__:<event>) from the
+// write paths of the Backend, when notify-keyspace-events enables them (see Backend::notify).
+
+use crate::{cluster::key_hash_slot, glob::glob_match, Backend, BulkString, RespFrame};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+// Messages queued for a subscriber before it is disconnected.
+pub const MAX_PENDING: usize = 10_000;
+
+// What a subscription is for: one channel, every channel matching a glob-style pattern, or one shard channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl Kind {
+    // The first element of the (un)subscribe confirmations.
+    pub fn subscribe_reply(&self) -> &'static str {
+        match self {
+            Kind::Channel => "subscribe",
+            Kind::Pattern => "psubscribe",
+            Kind::Shard => "ssubscribe",
+        }
+    }
+
+    pub fn unsubscribe_reply(&self) -> &'static str {
+        match self {
+            Kind::Channel => "unsubscribe",
+            Kind::Pattern => "punsubscribe",
+            Kind::Shard => "sunsubscribe",
+        }
+    }
+}
+
+// A message for a subscriber: the elements of the array (RESP2) or push (RESP3) frame its connection sends.
+pub type Message = Vec<RespFrame>;
+
+#[derive(Debug, Default)]
+pub struct Broker {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    subscribers: HashMap<u64, Subscriber>,
+    // The IDs of the subscribers of each channel / pattern.
+    channels: HashMap<String, HashSet<u64>>,
+    patterns: HashMap<String, HashSet<u64>>,
+    shards: HashMap<String, HashSet<u64>>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    tx: mpsc::Sender<Message>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    shards: HashSet<String>,
+}
+
+impl Subscriber {
+    fn names(&mut self, kind: Kind) -> &mut HashSet<String> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shards,
+        }
+    }
+
+    // The count in the (un)subscribe confirmations: shard channels are counted on their own, like redis.
+    fn count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Channel | Kind::Pattern => self.channels.len() + self.patterns.len(),
+            Kind::Shard => self.shards.len(),
+        }
+    }
+
+    fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shards.len()
+    }
+}
+
+impl State {
+    fn index(&mut self, kind: Kind) -> &mut HashMap<String, HashSet<u64>> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shards,
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        let Some(subscriber) = self.subscribers.remove(&id) else {
+            return;
+        };
+        for (kind, names) in [
+            (Kind::Channel, subscriber.channels),
+            (Kind::Pattern, subscriber.patterns),
+            (Kind::Shard, subscriber.shards),
+        ] {
+            for name in names {
+                remove_from(self.index(kind), &name, id);
+            }
+        }
+    }
+}
+
+fn remove_from(index: &mut HashMap<String, HashSet<u64>>, name: &str, id: u64) {
+    if let Some(ids) = index.get_mut(name) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            index.remove(name);
+        }
+    }
+}
+
+impl Broker {
+    // Subscribes the connection to the channels (or patterns). Returns the number of subscriptions of the
+    // connection after each of them, for the confirmations.
+    pub fn subscribe(
+        &self,
+        subscription: &Subscription,
+        kind: Kind,
+        names: &[String],
+    ) -> Vec<usize> {
+        let mut state = self.state.lock().unwrap();
+        let id = subscription.id;
+        // Disconnected for being too slow: the connection is about to close.
+        let Some(subscriber) = state.subscribers.get_mut(&id) else {
+            return vec![0; names.len()];
+        };
+        let mut counts = Vec::with_capacity(names.len());
+        let mut added = Vec::new();
+        for name in names {
+            let new = subscriber.names(kind).insert(name.clone());
+            counts.push(subscriber.count(kind));
+            if new {
+                added.push(name.clone());
+            }
+        }
+        for name in added {
+            state.index(kind).entry(name).or_default().insert(id);
+        }
+        counts
+    }
+
+    // Unsubscribes the connection from the channels (or patterns), from all of them if names is empty.
+    // Returns each channel with the number of subscriptions left after it; a single None channel when there
+    // was nothing to unsubscribe from.
+    pub fn unsubscribe(
+        &self,
+        subscription: &Subscription,
+        kind: Kind,
+        names: &[String],
+    ) -> Vec<(Option<String>, usize)> {
+        let mut state = self.state.lock().unwrap();
+        let id = subscription.id;
+        let Some(subscriber) = state.subscribers.get_mut(&id) else {
+            if names.is_empty() {
+                return vec![(None, 0)];
+            }
+            return names.iter().map(|name| (Some(name.clone()), 0)).collect();
+        };
+        let names = if names.is_empty() {
+            let mut all = subscriber.names(kind).iter().cloned().collect::<Vec<_>>();
+            all.sort();
+            all
+        } else {
+            names.to_vec()
+        };
+        if names.is_empty() {
+            return vec![(None, subscriber.count(kind))];
+        }
+
+        let mut replies = Vec::with_capacity(names.len());
+        let mut removed = Vec::new();
+        for name in names {
+            if subscriber.names(kind).remove(&name) {
+                removed.push(name.clone());
+            }
+            replies.push((Some(name), subscriber.count(kind)));
+        }
+        for name in removed {
+            remove_from(state.index(kind), &name, id);
+        }
+        replies
+    }
+
+    // The number of channels and patterns the connection is subscribed to.
+    pub fn subscriptions(&self, subscription: &Subscription) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .subscribers
+            .get(&subscription.id)
+            .map_or(0, |subscriber| subscriber.subscriptions())
+    }
+
+    // Delivers a message to the subscribers of the channel and of the patterns matching it.
+    // Returns the number of subscribers that got it.
+    pub fn publish(&self, channel: &str, message: &[u8]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut deliveries = Vec::new();
+        if let Some(ids) = state.channels.get(channel) {
+            for id in ids {
+                let message = vec![
+                    BulkString::from("message").into(),
+                    BulkString::from(channel).into(),
+                    BulkString::from(message).into(),
+                ];
+                deliveries.push((*id, message));
+            }
+        }
+        for (pattern, ids) in &state.patterns {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                continue;
+            }
+            for id in ids {
+                let message = vec![
+                    BulkString::from("pmessage").into(),
+                    BulkString::from(pattern.as_str()).into(),
+                    BulkString::from(channel).into(),
+                    BulkString::from(message).into(),
+                ];
+                deliveries.push((*id, message));
+            }
+        }
+        deliver(&mut state, deliveries)
+    }
+
+    // SPUBLISH: delivers a message to the subscribers of the shard channel.
+    pub fn spublish(&self, channel: &str, message: &[u8]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let deliveries = state.shards.get(channel).map_or(Vec::new(), |ids| {
+            ids.iter()
+                .map(|id| {
+                    let message = vec![
+                        BulkString::from("smessage").into(),
+                        BulkString::from(channel).into(),
+                        BulkString::from(message).into(),
+                    ];
+                    (*id, message)
+                })
+                .collect()
+        });
+        deliver(&mut state, deliveries)
+    }
+
+    // Unsubscribes everyone from the shard channels in the slots, which this node does not serve any more.
+    // Every subscriber is told with a sunsubscribe message, like redis does.
+    pub fn remove_slots(&self, slots: &[u16]) {
+        let mut state = self.state.lock().unwrap();
+        let channels = state
+            .shards
+            .keys()
+            .filter(|channel| slots.contains(&key_hash_slot(channel.as_bytes())))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut deliveries = Vec::new();
+        for channel in channels {
+            let ids = state.shards.remove(&channel).unwrap_or_default();
+            for id in ids {
+                let Some(subscriber) = state.subscribers.get_mut(&id) else {
+                    continue;
+                };
+                subscriber.shards.remove(&channel);
+                let message = vec![
+                    BulkString::from("sunsubscribe").into(),
+                    BulkString::from(channel.as_str()).into(),
+                    RespFrame::Integer(subscriber.count(Kind::Shard) as i64),
+                ];
+                deliveries.push((id, message));
+            }
+        }
+        deliver(&mut state, deliveries);
+    }
+
+    // PUBSUB CHANNELS / SHARDCHANNELS: the channels (kind Channel) or shard channels (kind Shard) with at least
+    // one subscriber, optionally only those matching a pattern.
+    pub fn channels(&self, kind: Kind, pattern: Option<&str>) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut channels = state
+            .index(kind)
+            .keys()
+            .filter(|channel| {
+                pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), channel.as_bytes()))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels
+    }
+
+    // PUBSUB NUMSUB / SHARDNUMSUB: the number of subscribers of a channel or shard channel (patterns not counted).
+    pub fn numsub(&self, kind: Kind, channel: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.index(kind).get(channel).map_or(0, |ids| ids.len())
+    }
+
+    // PUBSUB NUMPAT: the number of patterns with at least one subscriber.
+    pub fn numpat(&self) -> usize {
+        self.state.lock().unwrap().patterns.len()
+    }
+}
+
+// Puts the messages into the queues of the subscribers. Returns the number of messages queued.
+fn deliver(state: &mut State, deliveries: Vec<(u64, Message)>) -> usize {
+    let mut receivers = 0;
+    let mut slow = Vec::new();
+    for (id, message) in deliveries {
+        let Some(subscriber) = state.subscribers.get(&id) else {
+            continue;
+        };
+        match subscriber.tx.try_send(message) {
+            Ok(()) => receivers += 1,
+            Err(mpsc::error::TrySendError::Full(_)) => slow.push(id),
+            // The connection is going away, its Subscription unregisters it.
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+    // Dropping the sender ends the subscriber's queue, which closes its connection.
+    for id in slow {
+        warn!(
+            "Disconnecting subscriber {}: more than {} messages pending",
+            id, MAX_PENDING
+        );
+        state.remove(id);
+    }
+    receivers
+}
+
+// The receiving side of a subscriber, owned by its connection; created on its first (UN)SUBSCRIBE.
+// Dropping it (when the connection closes) removes the subscriber and its subscriptions from the broker.
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+    rx: mpsc::Receiver<Message>,
+    backend: Backend,
+}
+
+impl Subscription {
+    // id identifies the connection, it must be unique.
+    pub fn new(backend: &Backend, id: u64) -> Self {
+        let (tx, rx) = mpsc::channel(MAX_PENDING);
+        let subscriber = Subscriber {
+            tx,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shards: HashSet::new(),
+        };
+        let mut state = backend.pubsub.state.lock().unwrap();
+        state.subscribers.insert(id, subscriber);
+        Self {
+            id,
+            rx,
+            backend: backend.clone(),
+        }
+    }
+
+    // The next message to send to the client; None once the broker disconnected the subscriber.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.backend.pubsub.state.lock().unwrap().remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(parts: &[&str]) -> Message {
+        parts
+            .iter()
+            .map(|part| BulkString::from(*part).into())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_channels_and_patterns() {
+        let backend = Backend::new();
+        let broker = &backend.pubsub;
+        let mut a = Subscription::new(&backend, 1);
+        let mut b = Subscription::new(&backend, 2);
+
+        let names = [
+            "news".to_string(),
+            "weather".to_string(),
+            "news".to_string(),
+        ];
+        assert_eq!(broker.subscribe(&a, Kind::Channel, &names), vec![1, 2, 2]);
+        assert_eq!(
+            broker.subscribe(&b, Kind::Pattern, &["n*".to_string()]),
+            vec![1]
+        );
+        assert_eq!(
+            broker.channels(Kind::Channel, None),
+            vec!["news", "weather"]
+        );
+        assert_eq!(broker.channels(Kind::Channel, Some("w*")), vec!["weather"]);
+        assert_eq!(broker.numsub(Kind::Channel, "news"), 1);
+        assert_eq!(broker.numpat(), 1);
+
+        assert_eq!(broker.publish("news", b"hello"), 2);
+        assert_eq!(a.recv().await, Some(message(&["message", "news", "hello"])));
+        assert_eq!(
+            b.recv().await,
+            Some(message(&["pmessage", "n*", "news", "hello"]))
+        );
+        assert_eq!(broker.publish("sports", b"goal"), 0);
+
+        // Without arguments, from everything.
+        assert_eq!(
+            broker.unsubscribe(&a, Kind::Channel, &[]),
+            vec![
+                (Some("news".to_string()), 1),
+                (Some("weather".to_string()), 0)
+            ]
+        );
+        assert_eq!(broker.unsubscribe(&a, Kind::Channel, &[]), vec![(None, 0)]);
+        assert_eq!(broker.subscriptions(&a), 0);
+        assert_eq!(broker.publish("news", b"again"), 1);
+        drop(b);
+        assert_eq!(broker.numpat(), 0);
+        assert_eq!(broker.publish("news", b"again"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shard_channels() {
+        let backend = Backend::new();
+        let broker = &backend.pubsub;
+        let mut a = Subscription::new(&backend, 1);
+        let names = ["orders".to_string(), "users".to_string()];
+        // Shard channels are counted on their own.
+        broker.subscribe(&a, Kind::Channel, &["orders".to_string()]);
+        assert_eq!(broker.subscribe(&a, Kind::Shard, &names), vec![1, 2]);
+        assert_eq!(broker.subscriptions(&a), 3);
+        assert_eq!(broker.channels(Kind::Shard, None), vec!["orders", "users"]);
+        assert_eq!(broker.numsub(Kind::Shard, "users"), 1);
+
+        // A shard channel is separate from the channel of the same name.
+        assert_eq!(broker.spublish("orders", b"new"), 1);
+        assert_eq!(
+            a.recv().await,
+            Some(message(&["smessage", "orders", "new"]))
+        );
+
+        // Losing the slot of a shard channel unsubscribes its subscribers.
+        broker.remove_slots(&[key_hash_slot(b"users")]);
+        assert_eq!(
+            a.recv().await,
+            Some(vec![
+                BulkString::from("sunsubscribe").into(),
+                BulkString::from("users").into(),
+                RespFrame::Integer(1),
+            ])
+        );
+        assert_eq!(broker.channels(Kind::Shard, None), vec!["orders"]);
+        assert_eq!(broker.spublish("users", b"gone"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_notifications() {
+        use crate::config::{NotifyKeyspaceEvents, ServerConfig};
+
+        let backend = Backend::with_config(ServerConfig {
+            notify_keyspace_events: NotifyKeyspaceEvents::parse("KEg$hxn").unwrap(),
+            ..Default::default()
+        });
+        let mut keyspace = Subscription::new(&backend, 1);
+        let mut keyevent = Subscription::new(&backend, 2);
+        let broker = &backend.pubsub;
+        broker.subscribe(&keyspace, Kind::Pattern, &["__keyspace@0__:*".to_string()]);
+        let events = ["new", "set", "hset", "expire", "expired", "del"]
+            .map(|event| format!("__keyevent@0__:{}", event));
+        broker.subscribe(&keyevent, Kind::Channel, &events);
+
+        backend.set("a".to_string(), BulkString::from("1").into());
+        backend.set("a".to_string(), BulkString::from("2").into());
+        backend.hset(
+            "h".to_string(),
+            "f".to_string(),
+            BulkString::from("v").into(),
+        );
+        backend.expire_at("a", 1);
+        assert!(!backend.exists("a"));
+        assert!(backend.del("h"));
+        assert!(!backend.del("h"));
+
+        let expected = [
+            ("new", "a"),
+            ("set", "a"),
+            ("set", "a"),
+            ("new", "h"),
+            ("hset", "h"),
+            ("expire", "a"),
+            ("expired", "a"),
+            ("del", "h"),
+        ];
+        for (event, key) in expected {
+            let channel = format!("__keyspace@0__:{}", key);
+            assert_eq!(
+                keyspace.recv().await,
+                Some(message(&["pmessage", "__keyspace@0__:*", &channel, event]))
+            );
+            let channel = format!("__keyevent@0__:{}", event);
+            assert_eq!(
+                keyevent.recv().await,
+                Some(message(&["message", &channel, key]))
+            );
+        }
+
+        // Without notify-keyspace-events nothing is published.
+        let backend = Backend::new();
+        let mut subscription = Subscription::new(&backend, 1);
+        backend
+            .pubsub
+            .subscribe(&subscription, Kind::Pattern, &["*".to_string()]);
+        backend.set("a".to_string(), BulkString::from("1").into());
+        assert_eq!(backend.pubsub.publish("check", b"done"), 1);
+        assert_eq!(
+            subscription.recv().await,
+            Some(message(&["pmessage", "*", "check", "done"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_disconnected() {
+        let backend = Backend::new();
+        let mut subscription = Subscription::new(&backend, 1);
+        backend
+            .pubsub
+            .subscribe(&subscription, Kind::Channel, &["news".to_string()]);
+        for _ in 0..MAX_PENDING {
+            assert_eq!(backend.pubsub.publish("news", b"x"), 1);
+        }
+        assert_eq!(backend.pubsub.publish("news", b"x"), 0);
+        assert_eq!(backend.pubsub.numsub(Kind::Channel, "news"), 0);
+        // The queued messages are still delivered, then the queue ends.
+        for _ in 0..MAX_PENDING {
+            assert!(subscription.recv().await.is_some());
+        }
+        assert_eq!(subscription.recv().await, None);
+    }
+}
@@ -0,0 +1,902 @@
+// Primary / replica replication, following the redis protocol.
+//
+// Every write command a primary executes is appended, in RESP, to the replication stream; the position in that
+// stream is the replication offset. Together with the replication ID (a random name for the history of the dataset)
+// the offset identifies exactly which writes a dataset contains.
+//
+// A replica connects like a client and sends PSYNC <replid> <offset + 1>:
+//   +FULLRESYNC <replid> <offset>   followed by $<len>\r\n<snapshot>, then the stream from <offset> on
+//   +CONTINUE <replid>              followed by the part of the stream the replica missed, then the live stream
+// A partial resync is possible when the primary still has the missing bytes in its backlog, a ring buffer holding
+// the last repl-backlog-size bytes of the stream. The replica applies everything it receives and keeps its own
+// backlog of the same stream, so replicas can be chained and a promoted replica (REPLICAOF NO ONE) still accepts
+// partial resyncs for the old history, under its previous ID (replid2).
+//
+// Replicas acknowledge how far they got with REPLCONF ACK <offset> [FACK <offset fsynced to their AOF>], every
+// second and whenever the primary asks with REPLCONF GETACK * in the stream; WAIT and WAITAOF compare these offsets
+// with the offset right after the last write of the waiting client.
+//
+// Consistency: write commands are executed and appended to the stream while holding Replication.state, and a full
+// sync captures the snapshot and registers the replica under the same lock; a write is thus either in the snapshot
+// or in the stream that follows it, never in both or in neither. The writes of a transaction or a script come
+// between a MULTI and an EXEC; a replica holds them back until the EXEC and applies them together under the exec
+// barrier, so its clients never see half of them.
+
+use crate::{
+    cmd::{spec, Command, CommandExecutor},
+    network::is_command,
+    persistence::{aof, rdb, snapshot::Snapshot},
+    Backend, BulkString, RespArray, RespDecode, RespEncode, RespError, RespFrame,
+};
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, RwLock, TryLockError, TryLockResult,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, Notify},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+// How often the primary sends a PING down the stream, and how long a replica waits for any data
+// before it considers the link dead (redis' repl-ping-replica-period and repl-timeout).
+const PING_PERIOD: Duration = Duration::from_secs(10);
+const TIMEOUT: Duration = Duration::from_secs(60);
+// Delay between two attempts to connect to the primary.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// How often a replica reports its offset to the primary.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+// How long the stream waits before it tries again to take the exec barrier held by a client's EXEC or script.
+const EXEC_BARRIER_RETRY: Duration = Duration::from_millis(1);
+
+#[derive(Debug)]
+pub struct Replication {
+    state: Mutex<State>,
+    next_replica_id: AtomicU64,
+    // Wakes the clients blocked in WAIT / WAITAOF when a replica acknowledges an offset.
+    acks: Notify,
+}
+
+#[derive(Debug)]
+struct State {
+    replid: String,
+    // The previous replication ID, valid up to second_replid_offset, after this server was promoted or
+    // switched to a primary that continued its history.
+    replid2: String,
+    second_replid_offset: Option<u64>,
+    offset: u64,
+    // Created when the first replica syncs (or when this server becomes a replica).
+    backlog: Option<Backlog>,
+    replicas: Vec<Replica>,
+    // Set when this server is a replica.
+    master: Option<MasterLink>,
+}
+
+// The stream bytes at offsets start+1 ..= start+buf.len() (offsets count from 1, like in PSYNC).
+#[derive(Debug)]
+struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    start: u64,
+}
+
+#[derive(Debug)]
+struct Replica {
+    id: u64,
+    addr: String,
+    listening_port: u16,
+    sender: mpsc::UnboundedSender<Bytes>,
+    // The last offsets the replica acknowledged as received and as fsynced to its AOF.
+    ack_offset: u64,
+    aof_ack_offset: u64,
+}
+
+#[derive(Debug)]
+struct MasterLink {
+    host: String,
+    port: u16,
+    status: LinkStatus,
+    // Cancelled when the server stops replicating from this primary.
+    token: CancellationToken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStatus {
+    Connecting,
+    Sync,
+    Connected,
+}
+
+// What ROLE reports.
+#[derive(Debug, PartialEq)]
+pub enum RoleInfo {
+    Master {
+        offset: u64,
+        // (ip, listening port, acknowledged offset) of every connected replica
+        replicas: Vec<(String, u16, u64)>,
+    },
+    Replica {
+        host: String,
+        port: u16,
+        status: LinkStatus,
+        offset: u64,
+    },
+}
+
+// Handed to the connection of a replica after PSYNC was answered (see network::serve_replica).
+#[derive(Debug)]
+pub struct ReplicaSync {
+    pub id: u64,
+    // Full resync: the dataset at the offset the stream starts from.
+    pub snapshot: Option<Snapshot>,
+    // Partial resync: the part of the stream the replica missed.
+    pub backlog: Vec<u8>,
+    pub receiver: mpsc::UnboundedReceiver<Bytes>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State {
+                replid: new_replid(),
+                replid2: "0".repeat(40),
+                second_replid_offset: None,
+                offset: 0,
+                backlog: None,
+                replicas: Vec::new(),
+                master: None,
+            }),
+            next_replica_id: AtomicU64::new(1),
+            acks: Notify::new(),
+        }
+    }
+}
+
+impl Replication {
+    pub fn is_replica(&self) -> bool {
+        self.state.lock().unwrap().master.is_some()
+    }
+
+    pub fn replid(&self) -> String {
+        self.state.lock().unwrap().replid.clone()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    pub fn role(&self) -> RoleInfo {
+        let state = self.state.lock().unwrap();
+        match &state.master {
+            Some(master) => RoleInfo::Replica {
+                host: master.host.clone(),
+                port: master.port,
+                status: master.status,
+                offset: state.offset,
+            },
+            None => RoleInfo::Master {
+                offset: state.offset,
+                replicas: state
+                    .replicas
+                    .iter()
+                    .map(|r| (r.addr.clone(), r.listening_port, r.ack_offset))
+                    .collect(),
+            },
+        }
+    }
+
+    // Bytes of the stream held in the backlog.
+    pub fn backlog_size(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .backlog
+            .as_ref()
+            .map_or(0, |backlog| backlog.buf.len())
+    }
+
+    // Locks the replication stream. Write commands hold the returned guard while they execute,
+    // so the stream has them in execution order (see the comment at the top of the file).
+    pub(crate) fn feed(&self) -> Feed<'_> {
+        Feed(self.state.lock().unwrap())
+    }
+
+    pub(crate) fn remove_replica(&self, id: u64) {
+        self.state.lock().unwrap().replicas.retain(|r| r.id != id);
+    }
+
+    // REPLCONF ACK from the replica: aof_offset is only sent by replicas with an AOF.
+    pub(crate) fn ack(&self, id: u64, offset: u64, aof_offset: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|r| r.id == id) {
+            replica.ack_offset = replica.ack_offset.max(offset);
+            if let Some(aof_offset) = aof_offset {
+                replica.aof_ack_offset = replica.aof_ack_offset.max(aof_offset);
+            }
+        }
+        drop(state);
+        self.acks.notify_waiters();
+    }
+
+    // How many replicas acknowledged the stream up to offset (fsynced to their AOF, with aof).
+    fn count_acks(&self, offset: u64, aof: bool) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .replicas
+            .iter()
+            .filter(|r| {
+                let acked = if aof { r.aof_ack_offset } else { r.ack_offset };
+                acked >= offset
+            })
+            .count()
+    }
+}
+
+pub(crate) struct Feed<'a>(MutexGuard<'a, State>);
+
+impl Feed<'_> {
+    // Appends to the stream: to the backlog, and to every connected replica.
+    // Before there is a backlog nobody is listening, and the offset does not move (like in redis).
+    pub fn offset(&self) -> u64 {
+        self.0.offset
+    }
+
+    pub fn append(&mut self, data: &[u8]) {
+        let state = &mut *self.0;
+        let Some(backlog) = state.backlog.as_mut() else {
+            return;
+        };
+        backlog.push(data);
+        state.offset += data.len() as u64;
+        let bytes = Bytes::copy_from_slice(data);
+        // A replica whose connection is gone has dropped its receiver.
+        state
+            .replicas
+            .retain(|replica| replica.sender.send(bytes.clone()).is_ok());
+    }
+}
+
+impl Backlog {
+    fn new(capacity: usize, start: u64) -> Self {
+        Self {
+            buf: VecDeque::new(),
+            capacity: capacity.max(1),
+            start,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+        let excess = self.buf.len().saturating_sub(self.capacity);
+        self.buf.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    // The stream from offset (the first byte the replica does not have) to the end,
+    // or None if part of it is no longer (or not yet) in the backlog.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let end = self.start + self.buf.len() as u64;
+        if offset <= self.start || offset > end + 1 {
+            return None;
+        }
+        Some(
+            self.buf
+                .range((offset - self.start - 1) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+// Answers PSYNC <replid> <offset> from a replica: decides between a partial and a full resync, and registers the
+// replica so it receives the stream from the point its data ends at. Returns the reply for the replica and, unless
+// the reply is an error, what the connection has to send after it.
+pub fn psync(
+    backend: &Backend,
+    replid: &str,
+    offset: i64,
+    addr: &str,
+    listening_port: u16,
+) -> (RespFrame, Option<ReplicaSync>) {
+    let mut state = backend.replication.state.lock().unwrap();
+    // A replica can only serve a consistent dataset when it is in sync with its own primary.
+    if state
+        .master
+        .as_ref()
+        .is_some_and(|master| master.status != LinkStatus::Connected)
+    {
+        return (
+            crate::SimpleError::new("NOMASTERLINK Can't SYNC while not connected with my master")
+                .into(),
+            None,
+        );
+    }
+    let offset = u64::try_from(offset).ok();
+    let known_history = replid == state.replid
+        || (replid == state.replid2
+            && offset
+                .zip(state.second_replid_offset)
+                .is_some_and(|(offset, until)| offset <= until));
+
+    let current_offset = state.offset;
+    let capacity = backend.config.repl_backlog_size;
+    let backlog = state
+        .backlog
+        .get_or_insert_with(|| Backlog::new(capacity, current_offset));
+    let partial = known_history
+        .then(|| offset.and_then(|offset| backlog.since(offset)))
+        .flatten();
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let id = backend
+        .replication
+        .next_replica_id
+        .fetch_add(1, Ordering::Relaxed);
+    state.replicas.push(Replica {
+        id,
+        addr: addr.to_string(),
+        listening_port,
+        sender,
+        ack_offset: 0,
+        aof_ack_offset: 0,
+    });
+
+    let (reply, snapshot, backlog) = match partial {
+        Some(missing) => {
+            info!(
+                "Partial resynchronization request from {} accepted, sending {} bytes of backlog",
+                addr,
+                missing.len()
+            );
+            (format!("CONTINUE {}", state.replid), None, missing)
+        }
+        None => {
+            info!("Starting a full resynchronization for replica {}", addr);
+            let _barrier = backend.write_barrier.write().unwrap();
+            let snapshot = Snapshot::from_backend(backend);
+            (
+                format!("FULLRESYNC {} {}", state.replid, state.offset),
+                Some(snapshot),
+                Vec::new(),
+            )
+        }
+    };
+    let sync = ReplicaSync {
+        id,
+        snapshot,
+        backlog,
+        receiver,
+    };
+    (crate::SimpleString::new(reply).into(), Some(sync))
+}
+
+// REPLICAOF <host> <port> / REPLICAOF NO ONE. Returns the status reply.
+pub fn replicaof(backend: &Backend, master: Option<(String, u16)>) -> &'static str {
+    let mut state = backend.replication.state.lock().unwrap();
+    match master {
+        None => {
+            let Some(link) = state.master.take() else {
+                return "OK";
+            };
+            link.token.cancel();
+            info!("MASTER MODE enabled (user request)");
+            // Keep the history under the old ID, so the other replicas of the old primary can continue from us.
+            shift_replid(&mut state);
+            state.replicas.clear();
+            "OK"
+        }
+        Some((host, port)) => {
+            if state
+                .master
+                .as_ref()
+                .is_some_and(|link| link.host == host && link.port == port)
+            {
+                return "OK Already connected to specified master";
+            }
+            if let Some(link) = state.master.take() {
+                link.token.cancel();
+            }
+            info!("Connecting to MASTER {}:{}", host, port);
+            // Our replicas have to follow the new history: they reconnect and resync from us.
+            state.replicas.clear();
+            let token = backend.shutdown.token().child_token();
+            state.master = Some(MasterLink {
+                host: host.clone(),
+                port,
+                status: LinkStatus::Connecting,
+                token: token.clone(),
+            });
+            tokio::spawn(replica_link(backend.clone(), host, port, token));
+            "OK"
+        }
+    }
+}
+
+// Sends a PING to the replicas every PING_PERIOD, so they can tell an idle primary from a dead link.
+pub async fn ping_replicas(backend: Backend) {
+    let token = backend.shutdown.token();
+    let mut interval = tokio::time::interval(PING_PERIOD);
+    loop {
+        tokio::select! {
+            biased;
+            _ = token.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        let mut feed = backend.replication.feed();
+        if feed.0.master.is_none() && !feed.0.replicas.is_empty() {
+            feed.append(&command(&["PING"]));
+        }
+    }
+}
+
+// Blocks until numreplicas replicas acknowledged the stream up to offset (fsynced to their AOF, with aof),
+// the timeout passes or the server shuts down, and returns how many did.
+pub async fn wait(
+    backend: &Backend,
+    numreplicas: usize,
+    offset: u64,
+    aof: bool,
+    timeout: Option<Duration>,
+) -> usize {
+    let replication = &backend.replication;
+    let acked = replication.count_acks(offset, aof);
+    if acked >= numreplicas {
+        return acked;
+    }
+    // Ask for acknowledgements now rather than waiting for the periodic ones.
+    {
+        let mut feed = replication.feed();
+        if !feed.0.replicas.is_empty() {
+            feed.append(&command(&["REPLCONF", "GETACK", "*"]));
+        }
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let token = backend.shutdown.token();
+    loop {
+        // Registered before counting, so an acknowledgement in between is not missed.
+        let notified = replication.acks.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        let acked = replication.count_acks(offset, aof);
+        if acked >= numreplicas {
+            return acked;
+        }
+        tokio::select! {
+            _ = token.cancelled() => return acked,
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => return replication.count_acks(offset, aof),
+            _ = notified => {}
+        }
+    }
+}
+
+fn shift_replid(state: &mut State) {
+    state.replid2 = std::mem::replace(&mut state.replid, new_replid());
+    state.second_replid_offset = Some(state.offset + 1);
+}
+
+// 40 random hex characters, the form of replication IDs (and of cluster node IDs).
+pub(crate) fn new_replid() -> String {
+    let mut bytes = [0u8; 20];
+    getrandom::getrandom(&mut bytes).expect("no random source available");
+    hex::encode(bytes)
+}
+
+fn command(args: &[&str]) -> Vec<u8> {
+    RespFrame::Array(RespArray::new(
+        args.iter()
+            .map(|arg| BulkString::from(*arg).into())
+            .collect::<Vec<RespFrame>>(),
+    ))
+    .encode()
+}
+
+// The replica side: keeps a connection to the primary, reconnecting (and resyncing) when it is lost,
+// until REPLICAOF points elsewhere or the server shuts down.
+async fn replica_link(backend: Backend, host: String, port: u16, token: CancellationToken) {
+    loop {
+        let ret = tokio::select! {
+            _ = token.cancelled() => return,
+            ret = sync_with_master(&backend, &host, port, &token) => ret,
+        };
+        if let Err(e) = ret {
+            warn!("Connection with MASTER {}:{} lost: {}", host, port, e);
+        }
+        set_link_status(&backend, &token, LinkStatus::Connecting);
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+        }
+    }
+}
+
+// Updates the status, unless the link has been replaced in the meantime.
+fn set_link_status(backend: &Backend, token: &CancellationToken, status: LinkStatus) {
+    let mut state = backend.replication.state.lock().unwrap();
+    if let Some(link) = state.master.as_mut() {
+        if !token.is_cancelled() {
+            link.status = status;
+        }
+    }
+}
+
+async fn sync_with_master(
+    backend: &Backend,
+    host: &str,
+    port: u16,
+    token: &CancellationToken,
+) -> Result<()> {
+    let mut conn = MasterConnection {
+        stream: TcpStream::connect((host, port)).await?,
+        buf: BytesMut::new(),
+    };
+    info!("MASTER <-> REPLICA sync started");
+
+    let config = &backend.config;
+    if let Some(password) = &config.masterauth {
+        match &config.masteruser {
+            Some(user) => conn.call(&["AUTH", user, password]).await?,
+            None => conn.call(&["AUTH", password]).await?,
+        };
+    }
+    conn.call(&["PING"]).await?;
+    conn.call(&["REPLCONF", "listening-port", &config.port.to_string()])
+        .await?;
+    conn.call(&["REPLCONF", "capa", "psync2"]).await?;
+
+    let (replid, offset) = {
+        let state = backend.replication.state.lock().unwrap();
+        (state.replid.clone(), state.offset)
+    };
+    set_link_status(backend, token, LinkStatus::Sync);
+    let reply = conn
+        .call(&["PSYNC", &replid, &(offset + 1).to_string()])
+        .await?;
+    let mut parts = reply.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+            let offset = offset.parse()?;
+            let payload = conn.read_payload().await?;
+            full_sync(backend, replid, offset, &payload)?;
+        }
+        (Some("CONTINUE"), new_replid, None) => {
+            info!("Successful partial resynchronization with master");
+            let mut state = backend.replication.state.lock().unwrap();
+            if let Some(new_replid) = new_replid.filter(|id| *id != state.replid) {
+                // The primary continues our history under a new ID (it was promoted).
+                let new_replid = new_replid.to_string();
+                state.replid2 = std::mem::replace(&mut state.replid, new_replid);
+                state.second_replid_offset = Some(state.offset + 1);
+                state.replicas.clear();
+            }
+        }
+        _ => bail!("unexpected reply to PSYNC: {}", reply),
+    }
+    set_link_status(backend, token, LinkStatus::Connected);
+    info!("MASTER <-> REPLICA sync: finished with success");
+
+    let mut acks = tokio::time::interval(ACK_PERIOD);
+    let mut last_data = Instant::now();
+    // The frames of a MULTI ... EXEC block received so far.
+    let mut block: Option<Vec<(RespFrame, Bytes)>> = None;
+    loop {
+        tokio::select! {
+            ret = conn.read_frame() => {
+                let (frame, raw) = ret?;
+                last_data = Instant::now();
+                let getack = is_getack(&frame);
+                let exec = is_command(&frame, b"exec");
+                match block.as_mut() {
+                    None if is_command(&frame, b"multi") => block = Some(vec![(frame, raw)]),
+                    None => {
+                        let _barrier = exec_barrier(backend, RwLock::try_read).await;
+                        apply(backend, vec![(frame, raw)]);
+                    }
+                    Some(frames) => {
+                        frames.push((frame, raw));
+                        if exec {
+                            let frames = block.take().unwrap_or_default();
+                            let _barrier = exec_barrier(backend, RwLock::try_write).await;
+                            apply(backend, frames);
+                        }
+                    }
+                }
+                if getack {
+                    send_ack(&mut conn, backend).await?;
+                }
+            }
+            _ = acks.tick() => {
+                if last_data.elapsed() > TIMEOUT {
+                    bail!("timeout, no data from the master");
+                }
+                send_ack(&mut conn, backend).await?;
+            }
+        }
+    }
+}
+
+fn is_getack(frame: &RespFrame) -> bool {
+    match frame {
+        RespFrame::Array(array) => matches!(
+            (array.first(), array.get(1)),
+            (Some(RespFrame::BulkString(cmd)), Some(RespFrame::BulkString(option)))
+                if cmd.eq_ignore_ascii_case(b"replconf") && option.eq_ignore_ascii_case(b"getack")
+        ),
+        _ => false,
+    }
+}
+
+// REPLCONF ACK <offset>, plus FACK <offset> when the stream up to offset is fsynced to our AOF.
+async fn send_ack(conn: &mut MasterConnection, backend: &Backend) -> Result<()> {
+    let offset = backend.replication.offset().to_string();
+    let mut args = vec!["REPLCONF", "ACK", &offset];
+    // apply() writes to the AOF before the stream, so everything up to offset is in the file already.
+    let cloned_backend = backend.clone();
+    let fsynced = backend.aof.is_enabled()
+        && tokio::task::spawn_blocking(move || cloned_backend.aof.fsync())
+            .await?
+            .is_ok();
+    if fsynced {
+        args.extend(["FACK", &offset]);
+    }
+    conn.stream.write_all(&command(&args)).await?;
+    Ok(())
+}
+
+// Replaces the dataset with the snapshot the primary sent and takes over its replication ID and offset.
+fn full_sync(backend: &Backend, replid: &str, offset: u64, payload: &[u8]) -> Result<()> {
+    // A primary running stock redis sends an RDB file.
+    let snapshot = if rdb::is_rdb(payload) {
+        rdb::decode(payload)?.snapshot
+    } else {
+        Snapshot::decode(payload)?
+    };
+    let mut state = backend.replication.state.lock().unwrap();
+    let keys = {
+        let _barrier = backend.write_barrier.write().unwrap();
+        backend.clear();
+        snapshot.restore(backend)
+    };
+    info!("MASTER <-> REPLICA sync: loaded {} keys", keys);
+    state.replid = replid.to_string();
+    state.replid2 = "0".repeat(40);
+    state.second_replid_offset = None;
+    state.offset = offset;
+    state.backlog = Some(Backlog::new(backend.config.repl_backlog_size, offset));
+    // The history changed under our own replicas: they have to resync.
+    state.replicas.clear();
+    drop(state);
+
+    // The AOF still describes the old dataset.
+    if backend.aof.is_enabled() {
+        if let Err(e) = aof::bgrewrite(backend) {
+            warn!("Failed to rewrite the AOF after the full sync: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// Applies a part of the primary's stream, a single command or a whole MULTI ... EXEC block: write commands are
+// executed and logged to our AOF (between the MULTI and the EXEC of their block), and everything, including
+// PINGs, goes into our own stream so the offsets stay identical. The caller holds the exec barrier.
+fn apply(backend: &Backend, frames: Vec<(RespFrame, Bytes)>) {
+    let mut aof = backend.aof.lock();
+    let mut feed = backend.replication.feed();
+    for (frame, raw) in frames {
+        let logged = if is_command(&frame, b"multi") || is_command(&frame, b"exec") {
+            true
+        } else if spec::is_write(&frame) {
+            match Command::try_from(frame.clone()) {
+                Ok(cmd) => match cmd.execute(backend) {
+                    RespFrame::Error(e) => {
+                        warn!("Error applying a command from the master: {:?}", e);
+                        false
+                    }
+                    _ => true,
+                },
+                Err(e) => {
+                    warn!("Invalid command from the master: {}", e);
+                    false
+                }
+            }
+        } else {
+            false
+        };
+        if let Some(aof) = aof.as_mut().filter(|_| logged) {
+            if let Err(e) = aof.append(frame) {
+                warn!("Error writing to the AOF: {}", e);
+            }
+        }
+        feed.append(&raw);
+    }
+}
+
+// Takes the exec barrier like a client command would (see network::exec_barrier), but waits for as long as a
+// script runs instead of giving up with BUSY: the stream cannot skip a command.
+async fn exec_barrier<'a, G>(
+    backend: &'a Backend,
+    lock: impl Fn(&'a RwLock<()>) -> TryLockResult<G>,
+) -> G {
+    loop {
+        match lock(&backend.exec_barrier) {
+            Ok(barrier) => return barrier,
+            Err(TryLockError::WouldBlock) => {}
+            Err(e) => panic!("{}", e),
+        }
+        tokio::time::sleep(EXEC_BARRIER_RETRY).await;
+    }
+}
+
+struct MasterConnection {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl MasterConnection {
+    async fn fill(&mut self) -> Result<()> {
+        if self.stream.read_buf(&mut self.buf).await? == 0 {
+            bail!("connection closed by the master");
+        }
+        Ok(())
+    }
+
+    // Reads the next frame, together with the bytes it was encoded in.
+    async fn read_frame(&mut self) -> Result<(RespFrame, Bytes)> {
+        loop {
+            match RespFrame::expect_length(&self.buf) {
+                Ok(len) if len <= self.buf.len() => {
+                    let raw = self.buf.split_to(len).freeze();
+                    let frame = RespFrame::decode(&mut BytesMut::from(&raw[..]))?;
+                    return Ok((frame, raw));
+                }
+                Ok(_) | Err(RespError::NotComplete) => self.fill().await?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // Sends a command of the handshake and returns the status reply; an error reply fails the handshake.
+    async fn call(&mut self, args: &[&str]) -> Result<String> {
+        self.stream.write_all(&command(args)).await?;
+        let (frame, _) = self.read_frame().await?;
+        match frame {
+            RespFrame::SimpleString(s) => Ok(s.0),
+            RespFrame::Error(e) => bail!("{} failed: {}", args[0], e.0),
+            other => Ok(format!("{:?}", other)),
+        }
+    }
+
+    // The snapshot after +FULLRESYNC: $<len>\r\n<len bytes>, without a trailing CRLF.
+    // While the primary prepares it, it may send newlines to keep the connection alive.
+    async fn read_payload(&mut self) -> Result<Vec<u8>> {
+        let len = loop {
+            while self.buf.first() == Some(&b'\n') {
+                self.buf.advance(1);
+            }
+            if let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buf.split_to(end + 2);
+                let len = std::str::from_utf8(&line[..end])?
+                    .strip_prefix('$')
+                    .and_then(|len| len.parse::<usize>().ok())
+                    .ok_or_else(|| anyhow!("invalid sync payload header"))?;
+                break len;
+            }
+            self.fill().await?;
+        };
+        while self.buf.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buf.split_to(len).to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8, 100);
+        assert_eq!(backlog.since(101), Some(Vec::new()));
+        backlog.push(b"abcdef");
+        assert_eq!(backlog.since(101), Some(b"abcdef".to_vec()));
+        assert_eq!(backlog.since(104), Some(b"def".to_vec()));
+        assert_eq!(backlog.since(107), Some(Vec::new()));
+        assert_eq!(backlog.since(108), None);
+
+        // The oldest bytes are dropped once the capacity is reached.
+        backlog.push(b"ghij");
+        assert_eq!(backlog.start, 102);
+        assert_eq!(backlog.since(102), None);
+        assert_eq!(backlog.since(103), Some(b"cdefghij".to_vec()));
+    }
+
+    #[test]
+    fn test_psync_partial_and_full() {
+        let backend = Backend::new();
+        backend.set("a".to_string(), BulkString::from("1").into());
+
+        let (reply, sync) = psync(&backend, "?", -1, "127.0.0.1", 6380);
+        let replid = backend.replication.replid();
+        assert_eq!(
+            reply,
+            crate::SimpleString::new(format!("FULLRESYNC {} 0", replid)).into()
+        );
+        let mut sync = sync.unwrap();
+        assert_eq!(sync.snapshot.as_ref().unwrap().entries.len(), 1);
+
+        let set = command(&["SET", "b", "2"]);
+        backend.replication.feed().append(&set);
+        assert_eq!(backend.replication.offset(), set.len() as u64);
+        assert_eq!(sync.receiver.try_recv().unwrap(), Bytes::from(set.clone()));
+
+        // A replica that has the first 4 bytes of the stream gets the rest.
+        let (reply, sync) = psync(&backend, &replid, 5, "127.0.0.1", 6381);
+        assert_eq!(
+            reply,
+            crate::SimpleString::new(format!("CONTINUE {}", replid)).into()
+        );
+        assert_eq!(sync.unwrap().backlog, set[4..].to_vec());
+        assert_eq!(
+            backend.replication.role(),
+            RoleInfo::Master {
+                offset: set.len() as u64,
+                replicas: vec![
+                    ("127.0.0.1".to_string(), 6380, 0),
+                    ("127.0.0.1".to_string(), 6381, 0)
+                ],
+            }
+        );
+
+        // An unknown history needs a full resync.
+        let (reply, _) = psync(&backend, "other", 5, "127.0.0.1", 6382);
+        assert!(matches!(reply, RespFrame::SimpleString(s) if s.0.starts_with("FULLRESYNC")));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_acks() {
+        let backend = Backend::new();
+        let (_, sync) = psync(&backend, "?", -1, "127.0.0.1", 6380);
+        let sync = sync.unwrap();
+        let set = command(&["SET", "a", "1"]);
+        let offset = {
+            let mut feed = backend.replication.feed();
+            feed.append(&set);
+            feed.offset()
+        };
+
+        // Nobody acknowledged the write yet.
+        let acked = wait(&backend, 1, offset, false, Some(Duration::from_millis(10))).await;
+        assert_eq!(acked, 0);
+
+        let waiter = {
+            let backend = backend.clone();
+            tokio::spawn(async move { wait(&backend, 1, offset, false, None).await })
+        };
+        tokio::task::yield_now().await;
+        backend.replication.ack(sync.id, offset, None);
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(5), waiter)
+                .await
+                .unwrap()
+                .unwrap(),
+            1
+        );
+        // Without FACK the write does not count as fsynced on the replica.
+        let acked = wait(&backend, 1, offset, true, Some(Duration::from_millis(10))).await;
+        assert_eq!(acked, 0);
+    }
+}
@@ -14,11 +14,12 @@
     - double: ",[<+|->]<integral>[.<fractional>][<E|e>[sign]<exponent>]\r\n"
     - map: "%<number-of-entries>\r\n<key-1><value-1>...<key-n><value-n>"
     - set: "~<number-of-elements>\r\n<element-1>...<element-n>"
+    - push: "><number-of-elements>\r\n<element-1>...<element-n>"
  */
 
 use crate::{
     BulkString, RespArray, RespDecode, RespError, RespFrame, RespMap, RespNull, RespNullArray,
-    RespNullBulkString, RespSet, SimpleError, SimpleString,
+    RespNullBulkString, RespPush, RespSet, SimpleError, SimpleString,
 };
 use bytes::{Buf, BytesMut};
 
@@ -71,6 +72,8 @@ impl RespDecode for RespFrame {
                     }
                 }
             }
+            // An empty array ("*0\r\n") is shorter than a null array, it must not wait for more data.
+            Some(b'*') if !buf.starts_with(b"*-") => Ok(RespArray::decode(buf)?.into()),
             Some(b'*') => {
                 // try null array first
                 match RespNullArray::decode(buf) {
@@ -102,6 +105,10 @@ impl RespDecode for RespFrame {
                 let frame = RespSet::decode(buf)?;
                 Ok(frame.into())
             }
+            Some(b'>') => {
+                let frame = RespPush::decode(buf)?;
+                Ok(frame.into())
+            }
             None => Err(RespError::NotComplete),
             _ => Err(RespError::InvalidFrameType(format!(
                 "expect_length: unknown frame type: {:?}",
@@ -115,6 +122,7 @@ impl RespDecode for RespFrame {
         match iter.peek() {
             Some(b'*') => RespArray::expect_length(buf),
             Some(b'~') => RespSet::expect_length(buf),
+            Some(b'>') => RespPush::expect_length(buf),
             Some(b'%') => RespMap::expect_length(buf),
             Some(b'$') => BulkString::expect_length(buf),
             Some(b':') => i64::expect_length(buf),
@@ -424,6 +432,30 @@ impl RespDecode for RespSet {
         calc_total_length(buf, end, len, Self::PREFIX)
     }
 }
+// - push: "><number-of-elements>\r\n<element-1>...<element-n>"
+impl RespDecode for RespPush {
+    const PREFIX: &'static str = ">";
+    fn decode(buf: &mut BytesMut) -> Result<Self, RespError> {
+        let (end, len) = parse_length(buf, Self::PREFIX)?;
+        let total_len = calc_total_length(buf, end, len, Self::PREFIX)?;
+        if buf.len() < total_len {
+            return Err(RespError::NotComplete);
+        }
+        buf.advance(end + CRLF_LEN);
+
+        let mut frames = Vec::with_capacity(len);
+        for _ in 0..len {
+            frames.push(RespFrame::decode(buf)?);
+        }
+        Ok(RespPush::new(frames))
+    }
+
+    fn expect_length(buf: &[u8]) -> Result<usize, RespError> {
+        let (end, len) = parse_length(buf, Self::PREFIX)?;
+        calc_total_length(buf, end, len, Self::PREFIX)
+    }
+}
+
 // the implementations of RespDecode for RespArray and RespSet are very similar.
 // Both implementations follow a similar structure to decode their respective types from a buffer.
 // The main differences are in the prefixes they use and the types they return.
@@ -576,11 +608,11 @@ fn calc_total_length(buf: &[u8], end: usize, len: usize, prefix: &str) -> Result
     let mut total = end + CRLF_LEN;
     let mut data = &buf[total..];
     match prefix {
-        "*" | "~" => {
+        "*" | "~" | ">" => {
             // find nth CRLF in the buffer, for array and set, we need to find 1 CRLF for each element
             for _ in 0..len {
                 let len = RespFrame::expect_length(data)?;
-                data = &data[len..];
+                data = data.get(len..).ok_or(RespError::NotComplete)?; // the element may not have arrived completely yet
                 total += len;
             }
             Ok(total)
@@ -593,11 +625,11 @@ fn calc_total_length(buf: &[u8], end: usize, len: usize, prefix: &str) -> Result
                 // step 1: 计算第_个 key 的长度
                 let len = SimpleString::expect_length(data)?; // 调用了 extract_simple_frame_data，但并没有改变 buf 的内容
                                                               // step 2: 从第_个 key 之后，引用 buf 的剩余部分，以便计算第_个 value 的长度
-                data = &data[len..];
+                data = data.get(len..).ok_or(RespError::NotComplete)?;
                 total += len;
                 // step 3: 计算第_个 value 的长度
                 let len = RespFrame::expect_length(data)?;
-                data = &data[len..];
+                data = data.get(len..).ok_or(RespError::NotComplete)?;
                 total += len;
             }
             Ok(total)
@@ -776,6 +808,16 @@ mod tests {
         let frame = RespArray::decode(&mut buf)?;
         assert_eq!(frame, RespArray::new([b"set".into(), b"hello".into()]));
 
+        // An element cut off in the middle of its data is incomplete too.
+        buf.extend_from_slice(b"*2\r\n$3\r\nset\r\n$5\r\nhel");
+        let ret = RespArray::decode(&mut buf);
+        assert_eq!(ret.unwrap_err(), RespError::NotComplete);
+
+        buf.clear();
+        buf.extend_from_slice(b"*0\r\n");
+        let frame = RespFrame::decode(&mut buf)?;
+        assert_eq!(frame, RespArray::new(Vec::<RespFrame>::new()).into());
+
         Ok(())
     }
 
@@ -828,6 +870,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_push_decode() -> Result<()> {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b">2\r\n$7\r\nmessage\r\n$4\r\nne");
+        assert_eq!(RespFrame::decode(&mut buf), Err(RespError::NotComplete));
+
+        buf.extend_from_slice(b"ws\r\n");
+        let frame = RespFrame::decode(&mut buf)?;
+        assert_eq!(
+            frame,
+            RespPush::new(vec![
+                BulkString::new(b"message".to_vec()).into(),
+                BulkString::new(b"news".to_vec()).into()
+            ])
+            .into()
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_calc_array_length() -> Result<()> {
         let buf = b"*2\r\n$3\r\nset\r\n$5\r\nhello\r\n";
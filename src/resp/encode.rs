@@ -17,11 +17,12 @@
     - double: ",[<+|->]<integral>[.<fractional>][<E|e>[sign]<exponent>]\r\n"
     - map: "%<number-of-entries>\r\n<key-1><value-1>...<key-n><value-n>"
     - set: "~<number-of-elements>\r\n<element-1>...<element-n>"
+    - push: "><number-of-elements>\r\n<element-1>...<element-n>"
  */
 
 use crate::{
     BulkString, RespArray, RespEncode, RespMap, RespNull, RespNullArray, RespNullBulkString,
-    RespSet, SimpleError, SimpleString,
+    RespPush, RespSet, SimpleError, SimpleString,
 };
 
 const BUF_CAP: usize = 4096; // is this the size of bytes or bits?  4096 bytes
@@ -178,6 +179,18 @@ impl RespEncode for RespSet {
     }
 }
 
+// - push: "><number-of-elements>\r\n<element-1>...<element-n>"
+impl RespEncode for RespPush {
+    fn encode(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(BUF_CAP);
+        buf.extend_from_slice(&format!(">{}\r\n", self.len()).into_bytes());
+        for frame in self.0 {
+            buf.extend_from_slice(&frame.encode());
+        }
+        buf
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::RespFrame;
@@ -306,6 +319,16 @@ mod tests {
             b"~2\r\n*2\r\n:+1234\r\n#t\r\n$5\r\nworld\r\n"
         );
     }
+    #[test]
+    fn test_push_encode() {
+        let frame: RespFrame = RespPush::new([
+            BulkString::new("message").into(),
+            BulkString::new("news").into(),
+        ])
+        .into();
+        assert_eq!(frame.encode(), b">2\r\n$7\r\nmessage\r\n$4\r\nnews\r\n");
+    }
+
     // RespSet 与 RespArray 的区别在于，两者虽然都是用 Vec 存储，但 RespSet 特意用于与 把不同类型的元素通过 enum 统一封装为统一类型的 RespFrame，而 RespArray 则是用于存储相同类型的元素。
     // If you want a collection of values (not just unique values) of different types with only values and no keys, you can use a Vec in combination with an enum to encapsulate the different types. This allows you to store a heterogeneous collection of values in a single vector.
     // 当然，这只是刻意为之，rust 原生库中有 BTreeSet 和 HashSet 用于存储相同类型的元素，而 BTreeMap 和 HashMap 用于存储不同类型的元素。
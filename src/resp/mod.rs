@@ -60,6 +60,7 @@ pub enum RespFrame {
     Double(f64),
     Map(RespMap),
     Set(RespSet),
+    Push(RespPush),
 }
 // RespFrame is like a container for all the types that implement the RespEncode trait.
 
@@ -105,6 +106,9 @@ pub struct RespNullBulkString;
 pub struct RespMap(pub(crate) BTreeMap<String, RespFrame>);
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct RespSet(pub(crate) Vec<RespFrame>);
+// RESP3 out-of-band data, e.g. pub/sub messages: an array the client does not take as the reply to a command.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct RespPush(pub(crate) Vec<RespFrame>);
 
 impl Deref for SimpleString {
     type Target = String;
@@ -160,6 +164,14 @@ impl Deref for RespSet {
     }
 }
 
+impl Deref for RespPush {
+    type Target = Vec<RespFrame>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 impl SimpleString {
     pub fn new(s: impl Into<String>) -> Self {
         SimpleString(s.into())
@@ -203,6 +215,12 @@ impl RespSet {
     }
 }
 
+impl RespPush {
+    pub fn new(s: impl Into<Vec<RespFrame>>) -> Self {
+        RespPush(s.into())
+    }
+}
+
 impl From<&str> for SimpleString {
     fn from(s: &str) -> Self {
         SimpleString(s.to_string())
@@ -0,0 +1,471 @@
+// Function libraries (FUNCTION / FCALL / FCALL_RO).
+// A library is Lua code whose first line is "#!lua name=<library>"; it registers its functions with
+// redis.register_function(name, callback) or redis.register_function{function_name = ..., callback = ..., flags = {...}}.
+// Loading a library runs the code once to learn its functions (redis.call is not available at that point). FCALL runs
+// the code again in a fresh interpreter, like every script, and calls the function with a table of keys and one of
+// arguments. A function with the no-writes flag is read-only, and the only kind FCALL_RO runs.
+// Libraries are part of the dataset: snapshots keep them, and FUNCTION LOAD / DELETE / RESTORE / FLUSH are logged.
+
+use super::{error_message, interpreter, strings, ScriptError, Scripting, KILL_CHECK_INSTRUCTIONS};
+use crate::{RespFrame, SimpleError};
+use mlua::{HookTriggers, Lua, Table, Value, Variadic};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+const FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+// How long the code of a library may run while it is loaded, so an endless loop cannot hang FUNCTION LOAD.
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+// The registry table redis.register_function fills: function name -> {callback = ..., flags = {...}}.
+const REGISTRY: &str = "functions";
+
+#[derive(Debug, Clone)]
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    pub functions: Vec<LibraryFunction>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LibraryFunction {
+    pub name: String,
+    pub flags: Vec<String>,
+}
+
+// What FUNCTION RESTORE does with the libraries that already exist: drop them all first, fail if one of the
+// restored libraries exists (the default), or replace those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePolicy {
+    Flush,
+    Append,
+    Replace,
+}
+
+impl Scripting {
+    // Loads a library, replacing the one with the same name if replace is set; returns the name of the library.
+    // The error is the reply.
+    pub fn load_library(&self, code: &str, replace: bool) -> Result<String, String> {
+        let library = compile(code)?;
+        let mut libraries = self.libraries.lock().unwrap();
+        check(&libraries, &library, replace)?;
+        let name = library.name.clone();
+        libraries.insert(name.clone(), library);
+        Ok(name)
+    }
+
+    pub fn delete_library(&self, name: &str) -> bool {
+        self.libraries.lock().unwrap().remove(name).is_some()
+    }
+
+    pub fn flush_libraries(&self) {
+        self.libraries.lock().unwrap().clear();
+    }
+
+    // Every library, ordered by name.
+    pub fn libraries(&self) -> Vec<Library> {
+        self.libraries.lock().unwrap().values().cloned().collect()
+    }
+
+    // Loads the code of several libraries (FUNCTION RESTORE, a snapshot). Either all of them are loaded, or,
+    // when one fails, none and the libraries stay as they were.
+    pub fn restore_libraries(&self, codes: &[String], policy: RestorePolicy) -> Result<(), String> {
+        let compiled = codes
+            .iter()
+            .map(|code| compile(code))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut libraries = self.libraries.lock().unwrap();
+        let mut restored = match policy {
+            RestorePolicy::Flush => BTreeMap::new(),
+            RestorePolicy::Append | RestorePolicy::Replace => libraries.clone(),
+        };
+        for library in compiled {
+            check(&restored, &library, policy == RestorePolicy::Replace)?;
+            restored.insert(library.name.clone(), library);
+        }
+        *libraries = restored;
+        Ok(())
+    }
+
+    // Calls a function (FCALL, or FCALL_RO with read_only) and converts what it returns into the reply.
+    // call executes the commands of redis.call / redis.pcall.
+    pub fn fcall(
+        &self,
+        name: &str,
+        keys: Vec<Vec<u8>>,
+        args: Vec<Vec<u8>>,
+        read_only: bool,
+        call: &mut dyn FnMut(RespFrame) -> RespFrame,
+    ) -> RespFrame {
+        let found = self.libraries.lock().unwrap().values().find_map(|library| {
+            library
+                .functions
+                .iter()
+                .find(|function| function.name == name)
+                .map(|function| {
+                    let no_writes = function.flags.iter().any(|flag| flag == "no-writes");
+                    (library.code.clone(), no_writes)
+                })
+        });
+        let Some((code, no_writes)) = found else {
+            return SimpleError::new("ERR Function not found").into();
+        };
+        if read_only && !no_writes {
+            return SimpleError::new(
+                "ERR Can not execute a script with write flag using *_ro command.",
+            )
+            .into();
+        }
+        self.execute(no_writes, call, |lua| {
+            let functions = register(lua, &code)?;
+            let function: Table = functions.get(name)?;
+            let callback: mlua::Function = function.get("callback")?;
+            callback.call((strings(lua, keys)?, strings(lua, args)?))
+        })
+    }
+}
+
+// Runs the code of a library to learn its name and functions.
+fn compile(code: &str) -> Result<Library, String> {
+    let name = library_name(code)?;
+    let lua = interpreter().map_err(error_message)?;
+    let started = Instant::now();
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(KILL_CHECK_INSTRUCTIONS),
+        move |_, _| {
+            if started.elapsed() > LOAD_TIMEOUT {
+                return Err(mlua::Error::external(ScriptError(
+                    "ERR FUNCTION LOAD timeout".to_string(),
+                )));
+            }
+            Ok(())
+        },
+    );
+    let functions = register(&lua, code).map_err(error_message)?;
+    let mut functions = functions
+        .pairs::<String, Table>()
+        .map(|pair| {
+            let (name, function) = pair?;
+            Ok(LibraryFunction {
+                name,
+                flags: function.get("flags")?,
+            })
+        })
+        .collect::<mlua::Result<Vec<_>>>()
+        .map_err(error_message)?;
+    functions.sort_by(|a, b| a.name.cmp(&b.name));
+    if functions.is_empty() {
+        return Err("ERR No functions registered".to_string());
+    }
+    Ok(Library {
+        name,
+        code: code.to_string(),
+        functions,
+    })
+}
+
+// Whether library can be added to libraries: its name is new (or replace is set) and so are its functions.
+fn check(
+    libraries: &BTreeMap<String, Library>,
+    library: &Library,
+    replace: bool,
+) -> Result<(), String> {
+    if !replace && libraries.contains_key(&library.name) {
+        return Err(format!("ERR Library '{}' already exists", library.name));
+    }
+    for other in libraries
+        .values()
+        .filter(|other| other.name != library.name)
+    {
+        if let Some(function) = library
+            .functions
+            .iter()
+            .find(|function| other.functions.iter().any(|f| f.name == function.name))
+        {
+            return Err(format!("ERR Function {} already exists", function.name));
+        }
+    }
+    Ok(())
+}
+
+// The name of a library, from its first line: "#!lua name=<name>".
+fn library_name(code: &str) -> Result<String, String> {
+    let Some(shebang) = code.lines().next().and_then(|line| line.strip_prefix("#!")) else {
+        return Err("ERR Missing library metadata".to_string());
+    };
+    let mut parts = shebang.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("ERR Engine '{}' not found", engine));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value),
+            None => return Err(format!("ERR Invalid metadata value given: {}", part)),
+        }
+    }
+    let Some(name) = name else {
+        return Err("ERR Library name was not given".to_string());
+    };
+    if !valid_name(name) {
+        return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+    }
+    Ok(name.to_string())
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+// Runs the code of a library with redis.register_function and returns the functions it registered.
+fn register<'lua>(lua: &'lua Lua, code: &str) -> mlua::Result<Table<'lua>> {
+    let functions = lua.create_table()?;
+    lua.set_named_registry_value(REGISTRY, functions.clone())?;
+    let redis: Table = lua.globals().get("redis")?;
+    redis.set("register_function", lua.create_function(register_function)?)?;
+    // Lua does not skip the "#!" line of a string; blanking it keeps the line numbers of errors right.
+    let body = &code[code.find('\n').unwrap_or(code.len())..];
+    lua.load(body).set_name("@user_function").exec()?;
+    // Functions can only be registered while the library loads.
+    redis.set("register_function", Value::Nil)?;
+    Ok(functions)
+}
+
+fn register_function<'lua>(lua: &'lua Lua, args: Variadic<Value<'lua>>) -> mlua::Result<()> {
+    let fail = |message: &str| mlua::Error::external(ScriptError(format!("ERR {}", message)));
+    let (name, callback, flags) = match args.as_slice() {
+        [Value::Table(table)] => (
+            table.get("function_name")?,
+            table.get("callback")?,
+            table.get("flags")?,
+        ),
+        [name, callback] => (name.clone(), callback.clone(), Value::Nil),
+        _ => return Err(fail("wrong number of arguments to redis.register_function")),
+    };
+    let Value::String(name) = name else {
+        return Err(fail(
+            "function_name argument given to redis.register_function must be a string",
+        ));
+    };
+    let Value::Function(callback) = callback else {
+        return Err(fail(
+            "callback argument given to redis.register_function must be a function",
+        ));
+    };
+    let flags = match flags {
+        Value::Nil => Vec::new(),
+        Value::Table(flags) => flags
+            .sequence_values::<String>()
+            .collect::<mlua::Result<Vec<_>>>()
+            .map_err(|_| fail("unknown flag given"))?,
+        _ => return Err(fail(
+            "flags argument to redis.register_function must be a table representing function flags",
+        )),
+    };
+    if !flags.iter().all(|flag| FLAGS.contains(&flag.as_str())) {
+        return Err(fail("unknown flag given"));
+    }
+    let name = name.to_str()?;
+    if !valid_name(name) {
+        return Err(fail("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+    }
+
+    let functions: Table = lua.named_registry_value(REGISTRY)?;
+    if functions.contains_key(name)? {
+        return Err(fail("Function already exists in the library"));
+    }
+    let function = lua.create_table()?;
+    function.set("callback", callback)?;
+    function.set("flags", lua.create_sequence_from(flags)?)?;
+    functions.set(name, function)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BulkString, RespNull};
+
+    const LIBRARY: &str = "#!lua name=mylib
+local function echo(keys, args)
+    return {keys[1], args[1]}
+end
+redis.register_function('echo', echo)
+redis.register_function{
+    function_name = 'get',
+    callback = function(keys) return redis.call('get', keys[1]) end,
+    flags = {'no-writes'},
+}
+redis.register_function('set', function(keys, args) return redis.call('set', keys[1], args[1]) end)
+";
+
+    #[test]
+    fn test_load_and_fcall() {
+        let scripting = Scripting::default();
+        assert_eq!(
+            scripting.load_library(LIBRARY, false),
+            Ok("mylib".to_string())
+        );
+        assert_eq!(
+            scripting.load_library(LIBRARY, false),
+            Err("ERR Library 'mylib' already exists".to_string())
+        );
+        assert!(scripting.load_library(LIBRARY, true).is_ok());
+        assert_eq!(
+            scripting.load_library(&LIBRARY.replace("mylib", "other"), false),
+            Err("ERR Function echo already exists".to_string())
+        );
+        let libraries = scripting.libraries();
+        assert_eq!(libraries.len(), 1);
+        let functions = libraries[0]
+            .functions
+            .iter()
+            .map(|function| (function.name.as_str(), function.flags.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            functions,
+            vec![
+                ("echo", vec![]),
+                ("get", vec!["no-writes".to_string()]),
+                ("set", vec![])
+            ]
+        );
+
+        let mut call = |frame: RespFrame| match frame {
+            RespFrame::Array(args) if args[0] == BulkString::from("get").into() => {
+                BulkString::from("value").into()
+            }
+            _ => BulkString::from("OK").into(),
+        };
+        let fcall = |name: &str, read_only: bool, call: &mut dyn FnMut(RespFrame) -> RespFrame| {
+            scripting.fcall(
+                name,
+                vec![b"k".to_vec()],
+                vec![b"v".to_vec()],
+                read_only,
+                call,
+            )
+        };
+        assert_eq!(
+            fcall("echo", false, &mut call),
+            crate::RespArray::new(vec![
+                BulkString::from("k").into(),
+                BulkString::from("v").into()
+            ])
+            .into()
+        );
+        assert_eq!(
+            fcall("get", true, &mut call),
+            BulkString::from("value").into()
+        );
+        assert_eq!(
+            fcall("set", true, &mut call),
+            SimpleError::new("ERR Can not execute a script with write flag using *_ro command.")
+                .into()
+        );
+        assert_eq!(
+            fcall("nosuch", false, &mut call),
+            SimpleError::new("ERR Function not found").into()
+        );
+
+        // A no-writes function cannot write, even through FCALL.
+        let code = "#!lua name=ro\nredis.register_function{function_name = 'ro', callback = function() return redis.call('set', 'k', 'v') end, flags = {'no-writes'}}";
+        assert!(scripting.load_library(code, false).is_ok());
+        assert_eq!(
+            fcall("ro", false, &mut call),
+            SimpleError::new("ERR Write commands are not allowed from read-only scripts.").into()
+        );
+
+        assert!(scripting.delete_library("ro"));
+        assert!(!scripting.delete_library("ro"));
+        scripting.flush_libraries();
+        assert!(scripting.libraries().is_empty());
+        let mut no_call = |_| RespFrame::Null(RespNull);
+        assert_eq!(
+            fcall("echo", false, &mut no_call),
+            SimpleError::new("ERR Function not found").into()
+        );
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let scripting = Scripting::default();
+        let load = |code: &str| scripting.load_library(code, false).unwrap_err();
+        assert_eq!(load("return 1"), "ERR Missing library metadata");
+        assert_eq!(load("#!js name=lib\n"), "ERR Engine 'js' not found");
+        assert_eq!(load("#!lua\n"), "ERR Library name was not given");
+        assert_eq!(
+            load("#!lua name=lib x=y\n"),
+            "ERR Invalid metadata value given: x=y"
+        );
+        assert_eq!(load("#!lua name=lib\n"), "ERR No functions registered");
+        assert_eq!(
+            load("#!lua name=lib\nredis.register_function('f', function() end, 1)"),
+            "ERR wrong number of arguments to redis.register_function"
+        );
+        assert_eq!(
+            load("#!lua name=lib\nredis.register_function{function_name = 'f', callback = function() end, flags = {'fast'}}"),
+            "ERR unknown flag given"
+        );
+        assert_eq!(
+            load("#!lua name=lib\nredis.register_function('f-1', function() end)"),
+            "ERR Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"
+        );
+        // redis.call is not there while the library loads; the error points at the line of the code.
+        assert!(load("#!lua name=lib\nredis.call('ping')").starts_with(
+            "ERR Error running script: user_function:2: attempt to call field 'call'"
+        ));
+        assert_eq!(
+            load("#!lua name=lib\nwhile true do end"),
+            "ERR FUNCTION LOAD timeout"
+        );
+        assert!(scripting.libraries().is_empty());
+    }
+
+    #[test]
+    fn test_restore_libraries() {
+        let scripting = Scripting::default();
+        let library = |name: &str, function: &str| {
+            format!(
+                "#!lua name={}\nredis.register_function('{}', function() return 1 end)",
+                name, function
+            )
+        };
+        scripting.load_library(&library("a", "fa"), false).unwrap();
+
+        // Append refuses an existing library, and nothing is restored then.
+        assert_eq!(
+            scripting.restore_libraries(
+                &[library("b", "fb"), library("a", "fa")],
+                RestorePolicy::Append
+            ),
+            Err("ERR Library 'a' already exists".to_string())
+        );
+        assert_eq!(scripting.libraries().len(), 1);
+        scripting
+            .restore_libraries(
+                &[library("a", "fa2"), library("b", "fb")],
+                RestorePolicy::Replace,
+            )
+            .unwrap();
+        let names = |scripting: &Scripting| {
+            scripting
+                .libraries()
+                .into_iter()
+                .flat_map(|library| library.functions)
+                .map(|function| function.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&scripting), vec!["fa2", "fb"]);
+        scripting
+            .restore_libraries(&[library("c", "fc")], RestorePolicy::Flush)
+            .unwrap();
+        assert_eq!(names(&scripting), vec!["fc"]);
+    }
+}
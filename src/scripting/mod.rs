@@ -0,0 +1,472 @@
+// Lua scripting (EVAL / EVALSHA / SCRIPT, and the function libraries of FUNCTION / FCALL, see functions.rs).
+// Every script runs in a fresh Lua 5.1 interpreter with the base, table, string and math libraries, KEYS and ARGV
+// (EVAL only, a function gets them as arguments), and the redis table: redis.call / redis.pcall run a command through the same parsing and execution as a client
+// command would (the caller provides that, see network::script_call), redis.status_reply / redis.error_reply build
+// replies and redis.sha1hex hashes a string.
+// Values are converted like redis does: a Lua number becomes an integer (truncated), a string a bulk string,
+// true 1, false and nil a null, a table with an ok / err field a status / error reply, any other table an array
+// (up to its first nil). In the other direction a status reply becomes {ok = ...}, an error {err = ...} and a null false.
+// Scripts are cached by the SHA1 of their body, for EVALSHA.
+// A script runs alone (network::request_handler holds the exec barrier exclusively); once it ran longer than
+// busy-reply-threshold, other clients get BUSY and SCRIPT KILL can stop it, unless it already wrote.
+// A read-only script (FCALL_RO, or a function with the no-writes flag) cannot run write commands.
+
+mod functions;
+
+pub use functions::{Library, LibraryFunction, RestorePolicy};
+
+use crate::{cmd::spec, BulkString, RespArray, RespFrame, RespNull, SimpleError, SimpleString};
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use sha1::{Digest, Sha1};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// How many VM instructions run between two checks for SCRIPT KILL.
+const KILL_CHECK_INSTRUCTIONS: u32 = 1000;
+
+const KILLED: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+const READ_ONLY: &str = "ERR Write commands are not allowed from read-only scripts.";
+
+#[derive(Debug, Default)]
+pub struct Scripting {
+    // SHA1 (lowercase hex) -> body of every script run by EVAL or loaded by SCRIPT LOAD.
+    scripts: Mutex<HashMap<String, String>>,
+    // Library name -> library loaded by FUNCTION LOAD / RESTORE or from a snapshot.
+    libraries: Mutex<BTreeMap<String, Library>>,
+    running: Mutex<Option<Running>>,
+}
+
+// The script being executed.
+// wrote: it already changed the dataset, so killing it would leave half of its changes behind.
+#[derive(Debug)]
+struct Running {
+    started: Instant,
+    killed: Arc<AtomicBool>,
+    wrote: bool,
+}
+
+// A reply that ends the script, raised by redis.call (the error of the command) or when the script is killed.
+#[derive(Debug, Error)]
+#[error("{0}")]
+struct ScriptError(String);
+
+impl Scripting {
+    // Caches the script, returns its SHA1.
+    pub fn load(&self, body: &str) -> String {
+        let sha = sha1hex(body.as_bytes());
+        self.scripts
+            .lock()
+            .unwrap()
+            .insert(sha.clone(), body.to_string());
+        sha
+    }
+
+    pub fn get(&self, sha: &str) -> Option<String> {
+        self.scripts
+            .lock()
+            .unwrap()
+            .get(&sha.to_ascii_lowercase())
+            .cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.get(sha).is_some()
+    }
+
+    pub fn flush(&self) {
+        self.scripts.lock().unwrap().clear();
+    }
+
+    // Whether a script has been running for at least threshold.
+    pub fn busy(&self, threshold: Duration) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|running| running.started.elapsed() >= threshold)
+    }
+
+    // Stops the running script; the error is the reply for SCRIPT KILL.
+    pub fn kill(&self) -> Result<(), &'static str> {
+        match self.running.lock().unwrap().as_ref() {
+            None => Err("NOTBUSY No scripts in execution right now."),
+            Some(running) if running.wrote => Err("UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command."),
+            Some(running) => {
+                running.killed.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    // Runs a script and converts what it returns into the reply. call executes the commands of redis.call / redis.pcall.
+    pub fn run(
+        &self,
+        body: &str,
+        keys: Vec<Vec<u8>>,
+        args: Vec<Vec<u8>>,
+        call: &mut dyn FnMut(RespFrame) -> RespFrame,
+    ) -> RespFrame {
+        self.execute(false, call, |lua| {
+            let globals = lua.globals();
+            globals.set("KEYS", strings(lua, keys)?)?;
+            globals.set("ARGV", strings(lua, args)?)?;
+            lua.load(body).set_name("@user_script").eval()
+        })
+    }
+
+    // Runs script in a fresh interpreter as the running script (see busy / kill), with redis.call and redis.pcall.
+    fn execute<F>(
+        &self,
+        read_only: bool,
+        call: &mut dyn FnMut(RespFrame) -> RespFrame,
+        script: F,
+    ) -> RespFrame
+    where
+        F: for<'lua> FnOnce(&'lua Lua) -> mlua::Result<Value<'lua>>,
+    {
+        let killed = Arc::new(AtomicBool::new(false));
+        *self.running.lock().unwrap() = Some(Running {
+            started: Instant::now(),
+            killed: killed.clone(),
+            wrote: false,
+        });
+        let ret = self.eval(read_only, killed, call, script);
+        *self.running.lock().unwrap() = None;
+        match ret {
+            Ok(frame) => frame,
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn eval<F>(
+        &self,
+        read_only: bool,
+        killed: Arc<AtomicBool>,
+        call: &mut dyn FnMut(RespFrame) -> RespFrame,
+        script: F,
+    ) -> mlua::Result<RespFrame>
+    where
+        F: for<'lua> FnOnce(&'lua Lua) -> mlua::Result<Value<'lua>>,
+    {
+        let lua = interpreter()?;
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(KILL_CHECK_INSTRUCTIONS),
+            move |_, _| {
+                if killed.load(Ordering::Relaxed) {
+                    return Err(mlua::Error::external(ScriptError(KILLED.to_string())));
+                }
+                Ok(())
+            },
+        );
+
+        let call = RefCell::new(call);
+        let (call, running) = (&call, &self.running);
+        lua.scope(|scope| {
+            let redis: Table = lua.globals().get("redis")?;
+            redis.set(
+                "call",
+                scope.create_function(move |lua, args| {
+                    command(lua, args, true, read_only, call, running)
+                })?,
+            )?;
+            redis.set(
+                "pcall",
+                scope.create_function(move |lua, args| {
+                    command(lua, args, false, read_only, call, running)
+                })?,
+            )?;
+            let value = script(&lua)?;
+            Ok(to_frame(value))
+        })
+    }
+}
+
+// redis.call (raise) and redis.pcall: redis.call raises the error of a command, redis.pcall returns it as {err = ...}.
+fn command<'lua>(
+    lua: &'lua Lua,
+    args: Variadic<Value<'lua>>,
+    raise: bool,
+    read_only: bool,
+    call: &RefCell<&mut dyn FnMut(RespFrame) -> RespFrame>,
+    running: &Mutex<Option<Running>>,
+) -> mlua::Result<Value<'lua>> {
+    let frame = command_frame(args)?;
+    let write = spec::is_write(&frame);
+    let reply = if write && read_only {
+        SimpleError::new(READ_ONLY).into()
+    } else {
+        (*call.borrow_mut())(frame)
+    };
+    if let RespFrame::Error(e) = &reply {
+        if raise {
+            return Err(mlua::Error::external(ScriptError(e.0.clone())));
+        }
+    } else if write {
+        if let Some(running) = running.lock().unwrap().as_mut() {
+            running.wrote = true;
+        }
+    }
+    to_lua(lua, reply)
+}
+
+// A fresh interpreter with the parts of the redis table that do not run commands.
+fn interpreter() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    let globals = lua.globals();
+    // No access to the file system.
+    globals.set("loadfile", Value::Nil)?;
+    globals.set("dofile", Value::Nil)?;
+
+    let redis = lua.create_table()?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, status: mlua::String| reply_table(lua, "ok", status))?,
+    )?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, error: mlua::String| reply_table(lua, "err", error))?,
+    )?;
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_, s: mlua::String| Ok(sha1hex(s.as_bytes())))?,
+    )?;
+    globals.set("redis", redis)?;
+    // The tables hold on to the interpreter, they have to go before it is returned.
+    drop(globals);
+    Ok(lua)
+}
+
+// KEYS / ARGV.
+fn strings<'lua>(lua: &'lua Lua, values: Vec<Vec<u8>>) -> mlua::Result<Table<'lua>> {
+    let strings = values
+        .into_iter()
+        .map(|value| lua.create_string(value))
+        .collect::<mlua::Result<Vec<_>>>()?;
+    lua.create_sequence_from(strings)
+}
+
+fn reply_table<'lua>(
+    lua: &'lua Lua,
+    field: &str,
+    value: mlua::String<'lua>,
+) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(field, value)?;
+    Ok(table)
+}
+
+pub(crate) fn sha1hex(data: &[u8]) -> String {
+    hex::encode(Sha1::digest(data))
+}
+
+// The command redis.call / redis.pcall was called with; only strings and numbers can be arguments.
+fn command_frame(args: Variadic<Value>) -> mlua::Result<RespFrame> {
+    if args.is_empty() {
+        return Err(mlua::Error::external(ScriptError(
+            "ERR Please specify at least one argument for this redis lib call".to_string(),
+        )));
+    }
+    let args = args
+        .into_iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(BulkString::from(s.as_bytes()).into()),
+            Value::Integer(i) => Ok(BulkString::from(i.to_string()).into()),
+            Value::Number(n) => Ok(BulkString::from(n.to_string()).into()),
+            _ => Err(mlua::Error::external(ScriptError(
+                "ERR Lua redis lib command arguments must be strings or integers".to_string(),
+            ))),
+        })
+        .collect::<mlua::Result<Vec<RespFrame>>>()?;
+    Ok(RespArray::new(args).into())
+}
+
+// A command reply as a Lua value.
+fn to_lua<'lua>(lua: &'lua Lua, frame: RespFrame) -> mlua::Result<Value<'lua>> {
+    let sequence = |frames: Vec<RespFrame>| -> mlua::Result<Value> {
+        let values = frames
+            .into_iter()
+            .map(|frame| to_lua(lua, frame))
+            .collect::<mlua::Result<Vec<_>>>()?;
+        Ok(Value::Table(lua.create_sequence_from(values)?))
+    };
+    Ok(match frame {
+        RespFrame::SimpleString(s) => {
+            Value::Table(reply_table(lua, "ok", lua.create_string(&s.0)?)?)
+        }
+        RespFrame::Error(e) => Value::Table(reply_table(lua, "err", lua.create_string(&e.0)?)?),
+        RespFrame::Integer(i) => Value::Integer(i),
+        RespFrame::BulkString(s) => Value::String(lua.create_string(&s.0)?),
+        RespFrame::NullBulkString(_) | RespFrame::NullArray(_) | RespFrame::Null(_) => {
+            Value::Boolean(false)
+        }
+        RespFrame::Boolean(true) => Value::Integer(1),
+        RespFrame::Boolean(false) => Value::Boolean(false),
+        RespFrame::Double(d) => Value::String(lua.create_string(d.to_string())?),
+        RespFrame::Array(frames) => sequence(frames.0)?,
+        RespFrame::Set(frames) => sequence(frames.0)?,
+        RespFrame::Push(frames) => sequence(frames.0)?,
+        // A flat list of field, value, like RESP2 sends a map.
+        RespFrame::Map(map) => sequence(
+            map.0
+                .into_iter()
+                .flat_map(|(key, value)| [BulkString::from(key).into(), value])
+                .collect(),
+        )?,
+    })
+}
+
+// What a script returned, as the reply.
+fn to_frame(value: Value) -> RespFrame {
+    match value {
+        Value::Boolean(true) => RespFrame::Integer(1),
+        Value::Integer(i) => RespFrame::Integer(i),
+        Value::Number(n) => RespFrame::Integer(n as i64),
+        Value::String(s) => BulkString::from(s.as_bytes()).into(),
+        Value::Table(table) => {
+            if let Ok(mlua::Value::String(err)) = table.raw_get("err") {
+                return SimpleError::new(err.to_string_lossy()).into();
+            }
+            if let Ok(mlua::Value::String(ok)) = table.raw_get("ok") {
+                return SimpleString::new(ok.to_string_lossy()).into();
+            }
+            let mut frames = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, Value>(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(value) => frames.push(to_frame(value)),
+                }
+            }
+            RespArray::new(frames).into()
+        }
+        _ => RespFrame::Null(RespNull),
+    }
+}
+
+// The reply for a script that failed: the error of redis.call as it was, or what went wrong in Lua.
+fn error_reply(e: mlua::Error) -> RespFrame {
+    SimpleError::new(error_message(e)).into()
+}
+
+fn error_message(e: mlua::Error) -> String {
+    match &e {
+        mlua::Error::CallbackError { cause, .. } => error_message(cause.as_ref().clone()),
+        mlua::Error::ExternalError(err) => match err.downcast_ref::<ScriptError>() {
+            Some(ScriptError(reply)) => reply.clone(),
+            None => format!("ERR Error running script: {}", err),
+        },
+        mlua::Error::SyntaxError { message, .. } => {
+            format!("ERR Error compiling script: {}", message)
+        }
+        mlua::Error::RuntimeError(message) => format!("ERR Error running script: {}", message),
+        e => format!("ERR Error running script: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(body: &str, call: &mut dyn FnMut(RespFrame) -> RespFrame) -> RespFrame {
+        Scripting::default().run(body, vec![b"k".to_vec()], vec![b"v".to_vec()], call)
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut no_call = |_| RespFrame::Null(RespNull);
+        assert_eq!(run("return 3.7", &mut no_call), RespFrame::Integer(3));
+        assert_eq!(
+            run("return {1, 'two', {ok = 'fine'}, nil, 5}", &mut no_call),
+            RespArray::new(vec![
+                RespFrame::Integer(1),
+                BulkString::from("two").into(),
+                SimpleString::new("fine").into(),
+            ])
+            .into()
+        );
+        assert_eq!(
+            run("return {KEYS[1], ARGV[1]}", &mut no_call),
+            RespArray::new(vec![
+                BulkString::from("k").into(),
+                BulkString::from("v").into()
+            ])
+            .into()
+        );
+        assert_eq!(
+            run("return redis.error_reply('MY error')", &mut no_call),
+            SimpleError::new("MY error").into()
+        );
+        assert_eq!(run("return false", &mut no_call), RespFrame::Null(RespNull));
+        assert_eq!(run("return true", &mut no_call), RespFrame::Integer(1));
+        assert!(matches!(
+            run("return +", &mut no_call),
+            RespFrame::Error(e) if e.starts_with("ERR Error compiling script")
+        ));
+        assert_eq!(
+            run("return redis.sha1hex('')", &mut no_call),
+            BulkString::from("da39a3ee5e6b4b0d3255bfef95601890afd80709").into()
+        );
+    }
+
+    #[test]
+    fn test_call_and_pcall() {
+        let mut call = |frame: RespFrame| match frame {
+            RespFrame::Array(args) if args[0] == BulkString::from("get").into() => {
+                BulkString::from("value").into()
+            }
+            RespFrame::Array(args) if args[0] == BulkString::from("ping").into() => {
+                SimpleString::new("PONG").into()
+            }
+            _ => SimpleError::new("ERR unknown command").into(),
+        };
+        assert_eq!(
+            run("return redis.call('get', KEYS[1]) .. '!'", &mut call),
+            BulkString::from("value!").into()
+        );
+        assert_eq!(
+            run("return redis.call('ping')['ok']", &mut call),
+            BulkString::from("PONG").into()
+        );
+        // redis.call ends the script with the error, redis.pcall hands it to the script.
+        assert_eq!(
+            run("redis.call('nosuch'); return 1", &mut call),
+            SimpleError::new("ERR unknown command").into()
+        );
+        assert_eq!(
+            run("return redis.pcall('nosuch')['err']", &mut call),
+            BulkString::from("ERR unknown command").into()
+        );
+        assert_eq!(
+            run("return redis.call('get', {})", &mut call),
+            SimpleError::new("ERR Lua redis lib command arguments must be strings or integers")
+                .into()
+        );
+    }
+
+    #[test]
+    fn test_kill() {
+        let scripting = Arc::new(Scripting::default());
+        assert!(scripting.kill().is_err());
+        let killer = scripting.clone();
+        let handle = std::thread::spawn(move || loop {
+            if killer.busy(Duration::ZERO) && killer.kill().is_ok() {
+                return;
+            }
+            std::thread::yield_now();
+        });
+        let mut no_call = |_| RespFrame::Null(RespNull);
+        assert_eq!(
+            scripting.run("while true do end", vec![], vec![], &mut no_call),
+            SimpleError::new(KILLED).into()
+        );
+        handle.join().unwrap();
+        assert!(!scripting.busy(Duration::ZERO));
+    }
+}
@@ -0,0 +1,114 @@
+// This module coordinates a graceful shutdown of the server.
+// A shutdown can be started by a signal (SIGINT / SIGTERM) or by a client sending the SHUTDOWN command.
+// Either way, the flow is the same:
+// 1. The accept loop stops accepting new connections.
+// 2. Every connection task is notified through a CancellationToken and exits after finishing the command it is executing.
+// 3. The server waits (with a deadline) for the connection tasks to finish, then optionally saves a snapshot and exits.
+
+use std::{sync::Mutex, time::Duration};
+use tokio_util::sync::CancellationToken;
+
+// How long the server waits for in-flight connections to finish before giving up on them
+// (the equivalent of redis' shutdown-timeout, which also defaults to 10 seconds).
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What should happen to the dataset when the server stops.
+// Default: save only if persistence is configured (the behaviour of a plain SHUTDOWN or a signal).
+// Save: always save, even if no save points are configured (SHUTDOWN SAVE).
+// NoSave: never save (SHUTDOWN NOSAVE).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaveMode {
+    #[default]
+    Default,
+    Save,
+    NoSave,
+}
+
+// The options of a single shutdown request.
+// now: do not wait for in-flight connections to finish.
+// force: exit cleanly even if the final save fails (otherwise the failure is reported as an error of network::serve).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShutdownRequest {
+    pub save: SaveMode,
+    pub now: bool,
+    pub force: bool,
+}
+
+// Shared by all tasks through the Backend.
+// CancellationToken is cheap to clone, and every clone observes the same cancellation,
+// so each connection task holds its own clone and waits on token.cancelled().
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    request: Mutex<Option<ShutdownRequest>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    // Starts the shutdown. The first request wins: a second SHUTDOWN (or a second Ctrl-C)
+    // while the server is already draining does not change the options of the first one.
+    pub fn trigger(&self, request: ShutdownRequest) {
+        let mut guard = self.request.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(request);
+        }
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    // Returns the request that started the shutdown, or the default options if none was recorded.
+    pub fn request(&self) -> ShutdownRequest {
+        self.request.lock().unwrap().unwrap_or_default()
+    }
+}
+
+// Resolves when the process receives SIGINT (Ctrl-C) or, on unix, SIGTERM (sent by `kill` and by most deploy tools).
+pub async fn wait_for_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate())?;
+        tokio::select! {
+            ret = tokio::signal::ctrl_c() => ret,
+            _ = term.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_first_request_wins() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_triggered());
+        assert_eq!(shutdown.request(), ShutdownRequest::default());
+
+        let first = ShutdownRequest {
+            save: SaveMode::NoSave,
+            now: true,
+            force: false,
+        };
+        shutdown.trigger(first);
+        shutdown.trigger(ShutdownRequest::default());
+
+        assert!(shutdown.is_triggered());
+        assert!(shutdown.token().is_cancelled());
+        assert_eq!(shutdown.request(), first);
+    }
+}
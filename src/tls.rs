@@ -0,0 +1,250 @@
+// TLS support for client connections, built on rustls.
+// The TLS listener accepts plain TCP connections and performs the handshake in the connection task;
+// after that the decrypted stream goes through the same stream_handler as every other connection.
+
+use crate::config::{ServerConfig, TlsAuthClients};
+use anyhow::{anyhow, Context, Result};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    },
+    TlsAcceptor,
+};
+
+// Builds the acceptor for the tls-port listener from tls-cert-file, tls-key-file and,
+// if client certificates are verified, tls-ca-cert-file.
+pub fn build_acceptor(config: &ServerConfig) -> Result<TlsAcceptor> {
+    let cert_file = config
+        .tls_cert_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("tls-port is set but tls-cert-file is missing"))?;
+    let key_file = config
+        .tls_key_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("tls-port is set but tls-key-file is missing"))?;
+
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+
+    // Use ring explicitly instead of relying on a process-wide default provider.
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match config.tls_auth_clients {
+        TlsAuthClients::No => builder.with_no_client_auth(),
+        auth => {
+            let ca_file = config.tls_ca_cert_file.as_ref().ok_or_else(|| {
+                anyhow!("tls-ca-cert-file is required to authenticate clients (or set tls-auth-clients no)")
+            })?;
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if auth == TlsAuthClients::Optional {
+                verifier.allow_unauthenticated().build()?
+            } else {
+                verifier.build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+
+    let server_config = builder.with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?
+        .ok_or_else(|| anyhow!("no private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        network::{serve, Listener},
+        Backend,
+    };
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::{path::PathBuf, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+    // A throwaway CA with a server certificate for "localhost" and a client certificate, both signed by it.
+    struct TestPki {
+        dir: PathBuf,
+        ca_pem: String,
+        client_cert_pem: String,
+        client_key_pem: String,
+    }
+
+    impl TestPki {
+        fn generate(name: &str) -> Result<Self> {
+            let dir = std::env::temp_dir().join(format!(
+                "simple-redis-tls-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir)?;
+
+            let ca_key = KeyPair::generate()?;
+            let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca_cert = ca_params.self_signed(&ca_key)?;
+
+            let server_key = KeyPair::generate()?;
+            let server_cert = CertificateParams::new(vec!["localhost".to_string()])?.signed_by(
+                &server_key,
+                &ca_cert,
+                &ca_key,
+            )?;
+
+            let client_key = KeyPair::generate()?;
+            let client_cert = CertificateParams::new(vec!["client".to_string()])?.signed_by(
+                &client_key,
+                &ca_cert,
+                &ca_key,
+            )?;
+
+            std::fs::write(dir.join("ca.crt"), ca_cert.pem())?;
+            std::fs::write(dir.join("redis.crt"), server_cert.pem())?;
+            std::fs::write(dir.join("redis.key"), server_key.serialize_pem())?;
+
+            Ok(Self {
+                dir,
+                ca_pem: ca_cert.pem(),
+                client_cert_pem: client_cert.pem(),
+                client_key_pem: client_key.serialize_pem(),
+            })
+        }
+
+        fn server_config(&self, auth: TlsAuthClients) -> ServerConfig {
+            ServerConfig {
+                tls_cert_file: Some(self.dir.join("redis.crt")),
+                tls_key_file: Some(self.dir.join("redis.key")),
+                tls_ca_cert_file: Some(self.dir.join("ca.crt")),
+                tls_auth_clients: auth,
+                ..Default::default()
+            }
+        }
+
+        fn connector(&self, with_client_cert: bool) -> Result<TlsConnector> {
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut self.ca_pem.as_bytes()) {
+                roots.add(cert?)?;
+            }
+            let builder = rustls::ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots);
+            let config = if with_client_cert {
+                let certs = rustls_pemfile::certs(&mut self.client_cert_pem.as_bytes())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let key = rustls_pemfile::private_key(&mut self.client_key_pem.as_bytes())?
+                    .ok_or_else(|| anyhow!("no client key"))?;
+                builder.with_client_auth_cert(certs, key)?
+            } else {
+                builder.with_no_client_auth()
+            };
+            Ok(TlsConnector::from(Arc::new(config)))
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Sends GET hello over TLS and returns the raw reply, or the error of the handshake / the read.
+    async fn tls_get(connector: TlsConnector, addr: std::net::SocketAddr) -> Result<Vec<u8>> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        stream
+            .write_all(b"*2\r\n$3\r\nget\r\n$5\r\nhello\r\n")
+            .await?;
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf.to_vec())
+    }
+
+    #[test]
+    fn test_build_acceptor_requires_files() -> Result<()> {
+        let config = ServerConfig {
+            tls_port: 6380,
+            ..Default::default()
+        };
+        assert!(build_acceptor(&config).is_err());
+
+        let pki = TestPki::generate("files")?;
+        let mut config = pki.server_config(TlsAuthClients::Yes);
+        assert!(build_acceptor(&config).is_ok());
+        config.tls_ca_cert_file = None;
+        assert!(build_acceptor(&config).is_err());
+        config.tls_auth_clients = TlsAuthClients::No;
+        assert!(build_acceptor(&config).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener_with_client_auth() -> Result<()> {
+        let pki = TestPki::generate("auth")?;
+        let acceptor = build_acceptor(&pki.server_config(TlsAuthClients::Yes))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let backend = Backend::new();
+        let server = tokio::spawn(serve(
+            vec![Listener::Tls(listener, acceptor)],
+            backend.clone(),
+        ));
+
+        assert_eq!(tls_get(pki.connector(true)?, addr).await?, b"_\r\n");
+        // Without a client certificate the server rejects the handshake.
+        assert!(tls_get(pki.connector(false)?, addr).await.is_err());
+
+        backend.shutdown().trigger(Default::default());
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener_with_optional_client_auth() -> Result<()> {
+        let pki = TestPki::generate("optional")?;
+        let acceptor = build_acceptor(&pki.server_config(TlsAuthClients::Optional))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let backend = Backend::new();
+        let server = tokio::spawn(serve(
+            vec![Listener::Tls(listener, acceptor)],
+            backend.clone(),
+        ));
+
+        assert_eq!(tls_get(pki.connector(true)?, addr).await?, b"_\r\n");
+        assert_eq!(tls_get(pki.connector(false)?, addr).await?, b"_\r\n");
+
+        backend.shutdown().trigger(Default::default());
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        Ok(())
+    }
+}